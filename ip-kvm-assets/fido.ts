@@ -0,0 +1,40 @@
+interface FidoPresence {
+    pending: string | null;
+}
+
+// 安全密钥等待确认时显示 Touch 按钮
+function poll_fido_presence() {
+    fetch(target_api_base() + "/fido/presence").then(async function (response: Response) {
+        // 服务器没有启用 --fido-store 时不再轮询
+        if (response.status == 404) {
+            return;
+        }
+        let fido_span = document.getElementById("fido_span") as HTMLSpanElement;
+        if (response.ok) {
+            let presence: FidoPresence = await response.json();
+            fido_span.hidden = presence.pending === null;
+            (document.getElementById("fido_pending") as HTMLSpanElement).innerText = presence.pending ?? "";
+        }
+        setTimeout(poll_fido_presence, 500);
+    }).catch(function () {
+        setTimeout(poll_fido_presence, 500);
+    });
+}
+
+function fido_decide(action: string) {
+    fetch(target_api_base() + "/fido/" + action, {method: "POST"}).then(async function (response: Response) {
+        if (!response.ok) {
+            alert(`Fido ${action} failed: ${await response.text()}`);
+        }
+    });
+}
+
+function fido_touch_button_on_click() {
+    fido_decide("touch");
+}
+
+function fido_deny_button_on_click() {
+    fido_decide("deny");
+}
+
+poll_fido_presence();
@@ -0,0 +1,75 @@
+let gamepad_socket: WebSocket | null = null;
+let gamepad_prev_report: string = "";
+
+function init_gamepad_ws() {
+    gamepad_socket = new WebSocket("ws://" + location.host + target_api_base() + '/ws/gamepad');
+    gamepad_socket.onclose = function (event: CloseEvent) {
+        // 服务器没有启用 --gamepad 时连接会失败，不弹窗
+        console.log(`gamepad websocket closed, code=${event.code}`);
+        gamepad_socket = null;
+    };
+}
+
+function gamepad_axis(value: number): number {
+    return Math.max(-127, Math.min(127, Math.round(value * 127)));
+}
+
+// 按照 Gamepad API 的 standard mapping 转换成 hid 报告
+function gamepad_report(gamepad: Gamepad): ArrayBuffer {
+    let pressed = (i: number) => i < gamepad.buttons.length && gamepad.buttons[i].pressed;
+    let buttons = 0;
+    // 12-15 是方向键，放到 hat 里
+    for (let i = 0; i < 16; i++) {
+        let button_id = i < 12 ? i : i + 4;
+        if (pressed(button_id)) {
+            buttons |= 1 << i;
+        }
+    }
+    let up = pressed(12), down = pressed(13), left = pressed(14), right = pressed(15);
+    let hat = 8;
+    if (up && right) hat = 1;
+    else if (right && down) hat = 3;
+    else if (down && left) hat = 5;
+    else if (left && up) hat = 7;
+    else if (up) hat = 0;
+    else if (right) hat = 2;
+    else if (down) hat = 4;
+    else if (left) hat = 6;
+
+    let axis = (i: number) => i < gamepad.axes.length ? gamepad_axis(gamepad.axes[i]) : 0;
+    let trigger = (i: number) => i < gamepad.buttons.length ? Math.round(gamepad.buttons[i].value * 255) : 0;
+
+    let buffer = new ArrayBuffer(9);
+    let view = new DataView(buffer);
+    view.setUint16(0, buttons, true);
+    view.setUint8(2, hat);
+    view.setInt8(3, axis(0));
+    view.setInt8(4, axis(1));
+    view.setInt8(5, axis(2));
+    view.setInt8(6, axis(3));
+    view.setUint8(7, trigger(6));
+    view.setUint8(8, trigger(7));
+    return buffer;
+}
+
+function poll_gamepad() {
+    let gamepad = navigator.getGamepads().find((gamepad) => gamepad != null && gamepad.connected);
+    if (gamepad != null && gamepad_socket != null && gamepad_socket.readyState == WebSocket.OPEN) {
+        let report = gamepad_report(gamepad);
+        let report_str = new Uint8Array(report).join(",");
+        // 只在状态变化时发送
+        if (report_str != gamepad_prev_report) {
+            gamepad_prev_report = report_str;
+            gamepad_socket.send(report);
+        }
+    }
+    requestAnimationFrame(poll_gamepad);
+}
+
+window.addEventListener("gamepadconnected", function () {
+    if (gamepad_socket == null) {
+        init_gamepad_ws();
+    }
+});
+
+requestAnimationFrame(poll_gamepad);
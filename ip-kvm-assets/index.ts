@@ -8,6 +8,15 @@ function resize_video() {
     img.height = window.innerHeight - 45;
 }
 
+// 通过 ?target=1 控制第二个 UDC 对应的机器
+function target_api_base(): string {
+    let target = new URLSearchParams(location.search).get("target");
+    if (target == null) {
+        return "/v1";
+    }
+    return `/v1/targets/${encodeURIComponent(target)}`;
+}
+
 function init_stream_url_input() {
     let stream_url_input = document.getElementById("stream_url") as HTMLInputElement;
 
@@ -0,0 +1,34 @@
+interface JigglerConfig {
+    enabled: boolean;
+    interval_secs: number;
+    mode: string;
+}
+
+function init_jiggler() {
+    fetch(target_api_base() + "/jiggler").then(async function (response: Response) {
+        if (!response.ok) {
+            return;
+        }
+        let config: JigglerConfig = await response.json();
+        let jiggler_checkbox = document.getElementById("jiggler_checkbox") as HTMLInputElement;
+        jiggler_checkbox.checked = config.enabled;
+    });
+}
+
+function jiggler_checkbox_on_change() {
+    let jiggler_checkbox = document.getElementById("jiggler_checkbox") as HTMLInputElement;
+    fetch(target_api_base() + "/jiggler").then(async function (response: Response) {
+        let config: JigglerConfig = await response.json();
+        config.enabled = jiggler_checkbox.checked;
+        response = await fetch(target_api_base() + "/jiggler", {
+            method: "PUT",
+            headers: {"Content-Type": "application/json"},
+            body: JSON.stringify(config),
+        });
+        if (!response.ok) {
+            alert(`Set jiggler failed: ${await response.text()}`);
+        }
+    });
+}
+
+init_jiggler();
@@ -1,8 +1,9 @@
 let mouse_socket: WebSocket | null = null;
 let mouse_legacy_socket: WebSocket | null = null;
+let mouse_relative_socket: WebSocket | null = null;
 
 function init_mouse_ws() {
-    mouse_socket = new WebSocket("ws://" + location.host + '/v1/ws/mouse');
+    mouse_socket = new WebSocket("ws://" + location.host + target_api_base() + '/ws/mouse');
     mouse_socket.onclose = function (event: CloseEvent) {
         if (event.wasClean) {
             alert(`[close] Connection closed cleanly, code=${event.code} reason=${event.reason}`);
@@ -16,7 +17,7 @@ function init_mouse_ws() {
 }
 
 function init_mouse_legacy_ws() {
-    mouse_legacy_socket = new WebSocket("ws://" + location.host + '/v1/ws/mouse_legacy');
+    mouse_legacy_socket = new WebSocket("ws://" + location.host + target_api_base() + '/ws/mouse_legacy');
     mouse_legacy_socket.onclose = function (event: CloseEvent) {
         if (event.wasClean) {
             alert(`[close] Connection closed cleanly, code=${event.code} reason=${event.reason}`);
@@ -29,10 +30,22 @@ function init_mouse_legacy_ws() {
     };
 }
 
+function init_mouse_relative_ws() {
+    mouse_relative_socket = new WebSocket("ws://" + location.host + target_api_base() + '/ws/mouse_relative');
+    mouse_relative_socket.onclose = function (event: CloseEvent) {
+        if (event.wasClean) {
+            alert(`[close] Connection closed cleanly, code=${event.code} reason=${event.reason}`);
+        } else {
+            alert('[close] Connection died');
+        }
+        init_mouse_relative_ws();
+    };
+}
 
 function init_mouse() {
     init_mouse_ws();
     init_mouse_legacy_ws();
+    init_mouse_relative_ws();
 
     let img: HTMLImageElement = document.getElementById("video") as HTMLImageElement;
 
@@ -80,6 +93,10 @@ function init_mouse() {
     img.addEventListener("mousedown", function (event: MouseEvent) {
         event.preventDefault();
         event.stopPropagation();
+        if (document.pointerLockElement === img) {
+            send_mouse_relative_data(event.buttons, 0, 0, 0);
+            return;
+        }
         let button = document.getElementById("mouse_mode_button") as HTMLButtonElement;
         if (button.textContent == 'true') {
             let pos = translate_pos(event.offsetX, event.offsetY, img.width, img.height, 0x7fff);
@@ -94,6 +111,10 @@ function init_mouse() {
     img.addEventListener("mouseup", function (event: MouseEvent) {
         event.preventDefault();
         event.stopPropagation();
+        if (document.pointerLockElement === img) {
+            send_mouse_relative_data(event.buttons, 0, 0, 0);
+            return;
+        }
         let button = document.getElementById("mouse_mode_button") as HTMLButtonElement;
         if (button.textContent == 'true') {
             let pos = translate_pos(event.offsetX, event.offsetY, img.width, img.height, 0x7fff);
@@ -115,6 +136,10 @@ function init_mouse() {
         event.stopPropagation();
         let button = document.getElementById("mouse_mode_button") as HTMLButtonElement;
         let wheel = -event.deltaY;
+        if (document.pointerLockElement === img) {
+            send_mouse_relative_data(event.buttons, 0, 0, wheel / 80);
+            return;
+        }
         if (button.textContent == 'true') {
             let pos = translate_pos(event.offsetX, event.offsetY, img.width, img.height, 0x7fff);
             send_mouse_data(event.buttons, pos[0], pos[1], wheel / 80);
@@ -124,6 +149,11 @@ function init_mouse() {
         }
     });
     img.addEventListener("mousemove", function (event: MouseEvent) {
+        // 锁定指针后浏览器只提供相对移动，按 1:1 发送
+        if (document.pointerLockElement === img) {
+            send_mouse_relative_data(event.buttons, event.movementX, event.movementY, 0);
+            return;
+        }
         let button = document.getElementById("mouse_mode_button") as HTMLButtonElement;
         if (button.textContent == 'true') {
             let pos = translate_pos(event.offsetX, event.offsetY, img.width, img.height, 0x7fff);
@@ -259,6 +289,27 @@ function send_mouse_data(button: number, x: number, y: number, wheel: number) {
     mouse_socket.send(buffer);
 }
 
+function send_mouse_relative_data(button: number, x: number, y: number, wheel: number) {
+    if (mouse_relative_socket == null) {
+        return;
+    }
+    x = Math.max(-32767, Math.min(32767, Math.round(x)));
+    y = Math.max(-32767, Math.min(32767, Math.round(y)));
+    wheel = Math.max(-127, Math.min(127, Math.round(wheel)));
+    let buffer = new ArrayBuffer(6);
+    let view = new DataView(buffer);
+    view.setUint8(0, button & 0xff);
+    view.setInt16(1, x, true);
+    view.setInt16(3, y, true);
+    view.setInt8(5, wheel);
+    mouse_relative_socket.send(buffer);
+}
+
+function pointer_lock_button_on_click() {
+    let img: HTMLImageElement = document.getElementById("video") as HTMLImageElement;
+    img.requestPointerLock();
+}
+
 function mouse_mode_button_on_click() {
     let button = document.getElementById("mouse_mode_button") as HTMLButtonElement;
     if (button.textContent == "true") {
@@ -0,0 +1,48 @@
+let udc_state_socket: WebSocket | null = null;
+
+interface UdcState {
+    udc: string;
+    state: string;
+    configured: boolean;
+}
+
+function init_udc_state_ws() {
+    udc_state_socket = new WebSocket("ws://" + location.host + target_api_base() + '/ws/udc_state');
+    udc_state_socket.onclose = function (event: CloseEvent) {
+        let udc_state_label = document.getElementById("udc_state") as HTMLSpanElement;
+        udc_state_label.textContent = "disconnected";
+        udc_state_label.style.color = "gray";
+        // 服务器重启时不弹窗，稍后自动重连
+        setTimeout(init_udc_state_ws, 1000);
+    };
+    udc_state_socket.onmessage = function (event: MessageEvent) {
+        let udc_state: UdcState = JSON.parse(event.data);
+        let udc_state_label = document.getElementById("udc_state") as HTMLSpanElement;
+        udc_state_label.textContent = `${udc_state.udc}: ${udc_state.state}`;
+        udc_state_label.style.color = udc_state.configured ? "green" : "red";
+    }
+}
+
+init_udc_state_ws();
+
+function usb_reconnect_button_on_click() {
+    fetch(target_api_base() + "/usb/reconnect", {
+        method: "POST",
+        headers: {"Content-Type": "application/json"},
+        body: JSON.stringify({delay_ms: 1000}),
+    }).then(async function (response: Response) {
+        if (!response.ok) {
+            alert(`USB reconnect failed: ${await response.text()}`);
+        }
+    });
+}
+
+function release_all_button_on_click() {
+    fetch(target_api_base() + "/input/release-all", {
+        method: "POST",
+    }).then(async function (response: Response) {
+        if (!response.ok) {
+            alert(`Release all failed: ${await response.text()}`);
+        }
+    });
+}
@@ -0,0 +1,168 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::{http::StatusCode, Extension, Json};
+use serde::{Deserialize, Serialize};
+use tokio::{sync::RwLock, time::Instant};
+
+use usb_otg::hid::{
+    hid_composite::HID_COMPOSITE_RECV_LENGTH,
+    keyboard::KeyboardProtocol,
+    vendor::{self, VendorDecoder, VendorMessage, VENDOR_MESSAGE_MAX_LENGTH},
+};
+
+use crate::{
+    api_error::{self, ApiError},
+    metrics::METRICS,
+    DeviceCtx,
+};
+
+// 代理程序 -> KVM
+const AGENT_MSG_OS_STATE: u8 = 0x01;
+const AGENT_MSG_RESOLUTION: u8 = 0x02;
+const AGENT_MSG_CLIPBOARD: u8 = 0x03;
+// KVM -> 代理程序
+const KVM_MSG_CLIPBOARD: u8 = 0x81;
+const KVM_MSG_QUERY: u8 = 0x82;
+
+// 代理程序会定时发送系统状态，超过这个时间没有消息认为已经退出
+const AGENT_TIMEOUT: Duration = Duration::from_secs(15);
+
+#[derive(Clone, Copy, Serialize)]
+pub struct Resolution {
+    width: u16,
+    height: u16,
+}
+
+#[derive(Default)]
+struct AgentState {
+    last_seen: Option<Instant>,
+    os_state: Option<serde_json::Value>,
+    resolution: Option<Resolution>,
+    clipboard: Option<String>,
+}
+
+// 通过复合 hid 设备上的厂商自定义报告与目标机器上的代理程序通信
+#[derive(Default)]
+pub struct Agent {
+    decoder: Mutex<VendorDecoder>,
+    state: Mutex<AgentState>,
+    // 一条消息的报告不能和其它消息交错
+    send_lock: tokio::sync::Mutex<()>,
+}
+
+impl Agent {
+    pub fn recv(&self, id: usize, report: &[u8; HID_COMPOSITE_RECV_LENGTH]) {
+        let Some(message) = self.decoder.lock().unwrap().push(report) else {
+            return;
+        };
+        let VendorMessage { msg_type, data } = message;
+        let mut state = self.state.lock().unwrap();
+        state.last_seen = Some(Instant::now());
+        match msg_type {
+            AGENT_MSG_OS_STATE => match serde_json::from_slice(&data) {
+                Ok(os_state) => state.os_state = Some(os_state),
+                Err(err) => log::warn!("Target {id} agent invalid os state: {err}"),
+            },
+            AGENT_MSG_RESOLUTION => {
+                if data.len() != 4 {
+                    log::warn!("Target {id} agent invalid resolution: {data:?}");
+                    return;
+                }
+                state.resolution = Some(Resolution {
+                    width: u16::from_le_bytes([data[0], data[1]]),
+                    height: u16::from_le_bytes([data[2], data[3]]),
+                });
+            }
+            AGENT_MSG_CLIPBOARD => match String::from_utf8(data) {
+                Ok(clipboard) => state.clipboard = Some(clipboard),
+                Err(err) => log::warn!("Target {id} agent invalid clipboard: {err}"),
+            },
+            _ => log::warn!("Target {id} agent unknown message type: {msg_type:#x}"),
+        }
+    }
+
+    async fn send(
+        &self,
+        device_ctx: &DeviceCtx,
+        msg_type: u8,
+        data: &[u8],
+    ) -> api_error::Result<()> {
+        if data.len() >= VENDOR_MESSAGE_MAX_LENGTH {
+            return Err(ApiError::new(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                anyhow::anyhow!("message must be shorter than {VENDOR_MESSAGE_MAX_LENGTH} bytes"),
+            ));
+        }
+        // boot 协议下主机会把报告当成键盘输入
+        if device_ctx.keyboard_device.protocol() == KeyboardProtocol::Boot {
+            return Err(ApiError::new(
+                StatusCode::CONFLICT,
+                anyhow::anyhow!("host is using boot protocol"),
+            ));
+        }
+        let _send_guard = self.send_lock.lock().await;
+        let queue = &device_ctx.hid_composite_device.hid_composite_report_queue;
+        for report in vendor::encode(msg_type, data) {
+            let res = queue.push(report).await;
+            METRICS.hid_report_sent(device_ctx.id, "hid_composite", "agent", res.is_ok());
+            res?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+pub struct AgentOutput {
+    connected: bool,
+    last_seen_ms: Option<u64>,
+    os_state: Option<serde_json::Value>,
+    resolution: Option<Resolution>,
+    clipboard: Option<String>,
+}
+
+pub async fn get_agent(
+    Extension(device_ctx): Extension<Arc<RwLock<DeviceCtx>>>,
+) -> api_error::Result<Json<AgentOutput>> {
+    let device_ctx = device_ctx.read().await;
+    let state = device_ctx.agent.state.lock().unwrap();
+    let last_seen = state.last_seen.map(|last_seen| last_seen.elapsed());
+    Ok(Json(AgentOutput {
+        connected: last_seen.is_some_and(|last_seen| last_seen < AGENT_TIMEOUT),
+        last_seen_ms: last_seen.map(|last_seen| last_seen.as_millis() as u64),
+        os_state: state.os_state.clone(),
+        resolution: state.resolution,
+        clipboard: state.clipboard.clone(),
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct ClipboardInput {
+    text: String,
+}
+
+pub async fn put_clipboard(
+    Extension(device_ctx): Extension<Arc<RwLock<DeviceCtx>>>,
+    Json(input): Json<ClipboardInput>,
+) -> api_error::Result<String> {
+    let device_ctx = device_ctx.read().await;
+    device_ctx
+        .agent
+        .send(&device_ctx, KVM_MSG_CLIPBOARD, input.text.as_bytes())
+        .await?;
+    Ok("null".into())
+}
+
+// 让代理程序重新发送所有状态
+pub async fn post_query(
+    Extension(device_ctx): Extension<Arc<RwLock<DeviceCtx>>>,
+) -> api_error::Result<String> {
+    let device_ctx = device_ctx.read().await;
+    device_ctx
+        .agent
+        .send(&device_ctx, KVM_MSG_QUERY, &[])
+        .await?;
+    Ok("null".into())
+}
@@ -10,6 +10,15 @@ pub struct ApiError {
 
 pub type Result<T> = std::result::Result<T, ApiError>;
 
+impl ApiError {
+    pub fn new<E: Into<anyhow::Error>>(status_code: StatusCode, err: E) -> Self {
+        Self {
+            status_code,
+            error: err.into(),
+        }
+    }
+}
+
 // Tell axum how to convert `AppError` into a response.
 impl IntoResponse for ApiError {
     fn into_response(self) -> Response {
@@ -0,0 +1,373 @@
+use std::{net::SocketAddr, path::Path, sync::Arc, time::Duration};
+
+use axum::{
+    body::Bytes,
+    extract::{
+        self,
+        ws::{Message, WebSocket},
+        ConnectInfo, WebSocketUpgrade,
+    },
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use futures::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::{broadcast, RwLock},
+    time,
+};
+
+use usb_otg::hid::{self, custom::CustomHidDevice, hidg};
+use util::error;
+
+use crate::{
+    api_error::{self, ApiError},
+    metrics::METRICS,
+    DeviceCtx, HID_RECOVER_BACKOFF_MIN,
+};
+
+const CUSTOM_HID_NAME_MAX_LENGTH: usize = 32;
+// hidg 的报告长度上限是一个高速中断端点的包大小
+const CUSTOM_HID_REPORT_LENGTH_MAX: u16 = 1024;
+
+// --custom-hid 配置文件是这个结构的数组
+#[derive(Clone, Deserialize)]
+pub struct CustomHidConfig {
+    name: String,
+    // 十六进制字符串
+    #[serde(with = "hex")]
+    report_desc: Vec<u8>,
+    report_length: u16,
+    #[serde(default)]
+    subclass: u8,
+    #[serde(default)]
+    protocol: u8,
+    // 只通过控制端点接收 OUT 报告
+    #[serde(default)]
+    no_out_endpoint: bool,
+}
+
+impl CustomHidConfig {
+    pub fn load(path: &Path) -> error::Result<Vec<Self>> {
+        let data = std::fs::read(path).map_err(|err| error::ErrorKind::io(err, path))?;
+        let configs: Vec<Self> = serde_json::from_slice(&data).map_err(|err| {
+            error::ErrorKind::custom(format!("Parse {} failed: {err}", path.display()))
+        })?;
+        Self::validate(&configs)?;
+        Ok(configs)
+    }
+
+    fn validate(configs: &[Self]) -> error::Result<()> {
+        for (i, config) in configs.iter().enumerate() {
+            // 名字会作为 configfs 目录名和 URL 的一部分
+            if config.name.is_empty()
+                || config.name.len() > CUSTOM_HID_NAME_MAX_LENGTH
+                || !config
+                    .name
+                    .chars()
+                    .all(|ch| ch.is_ascii_alphanumeric() || ch == '_')
+            {
+                Err(error::ErrorKind::custom(format!(
+                    "custom hid name must be 1 to {CUSTOM_HID_NAME_MAX_LENGTH} characters of [A-Za-z0-9_], got {:?}",
+                    config.name
+                )))?;
+            }
+            if configs[..i].iter().any(|other| other.name == config.name) {
+                Err(error::ErrorKind::custom(format!(
+                    "custom hid {} specified more than once",
+                    config.name
+                )))?;
+            }
+            if config.report_desc.is_empty() {
+                Err(error::ErrorKind::custom(format!(
+                    "custom hid {} report_desc is empty",
+                    config.name
+                )))?;
+            }
+            if config.report_length == 0 || config.report_length > CUSTOM_HID_REPORT_LENGTH_MAX {
+                Err(error::ErrorKind::custom(format!(
+                    "custom hid {} report_length must be between 1 and {CUSTOM_HID_REPORT_LENGTH_MAX}",
+                    config.name
+                )))?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn function_name(&self) -> String {
+        format!("hid.custom_{}", self.name)
+    }
+
+    pub fn fho(&self) -> hid::FunctionHidOpts {
+        hid::FunctionHidOpts {
+            major: 0,
+            minor: 0,
+            no_out_endpoint: self.no_out_endpoint as u8,
+            subclass: self.subclass,
+            protocol: self.protocol,
+            report_length: self.report_length,
+            report_desc: self.report_desc.clone(),
+        }
+    }
+}
+
+pub struct CustomHid {
+    pub name: String,
+    // hid_health 中的名字，启动时生成，整个进程期间有效
+    pub health_name: &'static str,
+    pub device: CustomHidDevice,
+}
+
+impl CustomHid {
+    pub async fn new(config: &CustomHidConfig, minor: i32) -> error::Result<Self> {
+        Ok(Self {
+            name: config.name.clone(),
+            health_name: Box::leak(format!("custom_{}", config.name).into_boxed_str()),
+            device: CustomHidDevice::new(minor, config.report_length).await?,
+        })
+    }
+
+    pub async fn run_reader(&self, device_ctx: &DeviceCtx) {
+        let mut backoff = HID_RECOVER_BACKOFF_MIN;
+        loop {
+            match self.device.recv().await {
+                Ok(()) => {
+                    backoff = HID_RECOVER_BACKOFF_MIN;
+                    device_ctx.hid_health.set_ok(self.health_name);
+                }
+                Err(err) if hidg::is_ignore(&err) => {}
+                Err(err) => {
+                    device_ctx
+                        .recover_hid_dev(self.health_name, &err, &mut backoff)
+                        .await;
+                }
+            }
+        }
+    }
+
+    // 长度不对是调用方的错误，不能算作设备写入失败
+    fn check_report(&self, report: &[u8]) -> Result<(), String> {
+        let report_length = self.device.report_length();
+        if report.is_empty() || report.len() > report_length {
+            return Err(format!(
+                "report length must be between 1 and {report_length}, got {}",
+                report.len()
+            ));
+        }
+        Ok(())
+    }
+
+    async fn send(&self, device_ctx: &DeviceCtx, report: &[u8]) -> error::Result<()> {
+        let res = self.device.send(report).await;
+        METRICS.hid_report_sent(device_ctx.id, self.health_name, "send", res.is_ok());
+        if let Err(err) = &res {
+            device_ctx.hid_write_failed(self.health_name, err).await;
+        }
+        res
+    }
+}
+
+fn not_found(name: &str) -> ApiError {
+    ApiError::new(
+        StatusCode::NOT_FOUND,
+        anyhow::anyhow!("custom hid {name} not found"),
+    )
+}
+
+#[derive(Serialize)]
+pub struct CustomHidOutput {
+    name: String,
+    report_length: usize,
+}
+
+pub async fn get_custom_hids(
+    Extension(device_ctx): Extension<Arc<RwLock<DeviceCtx>>>,
+) -> api_error::Result<Json<Vec<CustomHidOutput>>> {
+    let device_ctx = device_ctx.read().await;
+    Ok(Json(
+        device_ctx
+            .custom_hids
+            .iter()
+            .map(|custom_hid| CustomHidOutput {
+                name: custom_hid.name.clone(),
+                report_length: custom_hid.device.report_length(),
+            })
+            .collect(),
+    ))
+}
+
+// 请求体就是原始的 IN 报告
+pub async fn post_report(
+    Extension(device_ctx): Extension<Arc<RwLock<DeviceCtx>>>,
+    extract::Path(name): extract::Path<String>,
+    body: Bytes,
+) -> api_error::Result<String> {
+    let device_ctx = device_ctx.read().await;
+    let custom_hid = device_ctx
+        .custom_hid(&name)
+        .ok_or_else(|| not_found(&name))?;
+    custom_hid
+        .check_report(&body)
+        .map_err(|err| ApiError::new(StatusCode::BAD_REQUEST, anyhow::anyhow!(err)))?;
+    custom_hid.send(&device_ctx, &body).await?;
+    device_ctx.jiggler.touch();
+    Ok("null".into())
+}
+
+pub async fn ws_handler(
+    ws: WebSocketUpgrade,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(device_ctx): Extension<Arc<RwLock<DeviceCtx>>>,
+    extract::Path(name): extract::Path<String>,
+) -> Response {
+    if device_ctx.read().await.custom_hid(&name).is_none() {
+        return not_found(&name).into_response();
+    }
+    println!("custom hid {name} client at {addr} connected.");
+    ws.on_upgrade(move |socket| handle_socket(device_ctx, name, socket, addr))
+        .into_response()
+}
+
+// 二进制消息作为 IN 报告发送，主机发来的 OUT 报告以二进制消息推送
+async fn handle_socket(
+    device_ctx: Arc<RwLock<DeviceCtx>>,
+    name: String,
+    socket: WebSocket,
+    who: SocketAddr,
+) {
+    let device_ctx = device_ctx.read().await;
+    let _client_guard = METRICS.websocket_client(device_ctx.id, "custom_hid");
+    let custom_hid = device_ctx.custom_hid(&name).unwrap();
+    let (mut sender, mut receiver) = socket.split();
+    let mut out_receiver = custom_hid.device.subscribe();
+    let mut ping_interval = time::interval(Duration::from_millis(1000));
+
+    loop {
+        tokio::select! {
+            res = out_receiver.recv() => {
+                match res {
+                    Ok(report) => {
+                        if sender.send(Message::Binary(report)).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(count)) => {
+                        log::warn!("custom hid {name} client {who} lagged {count} reports");
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+            _ = ping_interval.tick() => {
+                if sender.send(Message::Ping(vec![1, 2, 3])).await.is_err() {
+                    break;
+                }
+            }
+            msg = receiver.next() => {
+                match msg {
+                    Some(Ok(Message::Binary(report))) => {
+                        // 报告长度错误时以文本消息回复客户端
+                        if let Err(err) = custom_hid.check_report(&report) {
+                            if sender.send(Message::Text(err)).await.is_err() {
+                                break;
+                            }
+                            continue;
+                        }
+                        if let Err(err) = custom_hid.send(&device_ctx, &report).await {
+                            log::error!("custom hid {name} send failed: {err}");
+                        }
+                        device_ctx.jiggler.touch();
+                    }
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    println!("Websocket context {} destroyed", who);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(json: &str) -> error::Result<Vec<CustomHidConfig>> {
+        let configs: Vec<CustomHidConfig> = serde_json::from_str(json)
+            .map_err(|err| error::ErrorKind::custom(format!("Parse failed: {err}")))?;
+        CustomHidConfig::validate(&configs)?;
+        Ok(configs)
+    }
+
+    #[test]
+    fn accept_config() {
+        let configs = parse(
+            r#"[
+                {"name": "pad_1", "report_desc": "05010904a101c0", "report_length": 8},
+                {"name": "Vendor", "report_desc": "06ff00", "report_length": 1024,
+                 "subclass": 1, "protocol": 2, "no_out_endpoint": true}
+            ]"#,
+        )
+        .unwrap();
+        assert_eq!(
+            configs[0].report_desc,
+            [0x05, 0x01, 0x09, 0x04, 0xa1, 0x01, 0xc0]
+        );
+        // 可选字段的默认值
+        let fho = configs[0].fho();
+        assert_eq!((fho.subclass, fho.protocol, fho.no_out_endpoint), (0, 0, 0));
+        assert_eq!(configs[0].function_name(), "hid.custom_pad_1");
+        let fho = configs[1].fho();
+        assert_eq!((fho.subclass, fho.protocol, fho.no_out_endpoint), (1, 2, 1));
+        assert_eq!(fho.report_length, 1024);
+        assert!(parse("[]").unwrap().is_empty());
+    }
+
+    #[test]
+    fn reject_name() {
+        for name in [
+            "",
+            "a-b",
+            "a.b",
+            "a b",
+            "名字",
+            &"a".repeat(CUSTOM_HID_NAME_MAX_LENGTH + 1),
+        ] {
+            let json =
+                format!(r#"[{{"name": {name:?}, "report_desc": "00", "report_length": 1}}]"#);
+            assert!(parse(&json).is_err(), "{name:?}");
+        }
+        let name = "a".repeat(CUSTOM_HID_NAME_MAX_LENGTH);
+        let json = format!(r#"[{{"name": {name:?}, "report_desc": "00", "report_length": 1}}]"#);
+        assert!(parse(&json).is_ok());
+        // 名字不能重复
+        assert!(parse(
+            r#"[{"name": "a", "report_desc": "00", "report_length": 1},
+                {"name": "a", "report_desc": "00", "report_length": 1}]"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn reject_report_desc() {
+        for report_desc in ["", "0", "zz", "0x05", "05 01"] {
+            let json =
+                format!(r#"[{{"name": "a", "report_desc": {report_desc:?}, "report_length": 1}}]"#);
+            assert!(parse(&json).is_err(), "{report_desc:?}");
+        }
+        assert!(parse(r#"[{"name": "a", "report_desc": "0A0b", "report_length": 1}]"#).is_ok());
+    }
+
+    #[test]
+    fn reject_report_length() {
+        for report_length in ["0", "1025", "-1", "65536"] {
+            let json = format!(
+                r#"[{{"name": "a", "report_desc": "00", "report_length": {report_length}}}]"#
+            );
+            assert!(parse(&json).is_err(), "{report_length}");
+        }
+        // 缺少必填字段
+        assert!(parse(r#"[{"name": "a", "report_desc": "00"}]"#).is_err());
+        assert!(parse(r#"[{"name": "a", "report_length": 1}]"#).is_err());
+    }
+}
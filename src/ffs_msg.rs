@@ -0,0 +1,533 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::{
+    body::Body,
+    extract::State,
+    http::{header, Request, StatusCode, Uri},
+    Extension, Json,
+};
+use serde::{Deserialize, Serialize};
+use tokio::{runtime::Handle, sync::RwLock, time};
+
+use usb_otg::ffs::{
+    block_backend::{
+        self, BlockBackend, FileBackend, FileOverlayBackend, MemoryBackend, Overlay, OverlayBackend,
+    },
+    msg::FfsMsg,
+};
+use util::error;
+
+use crate::{
+    api_error::{self, ApiError},
+    mass_storage, AppState, Client, DeviceCtx,
+};
+
+// 内存覆盖层和内存盘都保存在内存中
+const FFS_MSG_OVERLAY_MAX: u64 = 256 * 1024 * 1024;
+const FFS_MSG_MEMORY_MAX: u64 = 256 * 1024 * 1024;
+const HTTP_BACKEND_TIMEOUT: Duration = Duration::from_secs(30);
+const OVERLAY_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+// 文件覆盖层的位图只在 flush 时保存，定时保存避免断电后丢失已经写入的扇区
+fn spawn_overlay_flush(overlay: &Arc<dyn Overlay>) {
+    let overlay = Arc::downgrade(overlay);
+    tokio::spawn(async move {
+        let mut interval = time::interval(OVERLAY_FLUSH_INTERVAL);
+        interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            // 弹出后覆盖层被释放，不再保存
+            let Some(overlay) = overlay.upgrade() else {
+                break;
+            };
+            if let Ok(Err(err)) = tokio::task::spawn_blocking(move || overlay.flush()).await {
+                log::error!("Flush overlay failed: {err}");
+            }
+        }
+    });
+}
+
+// 通过 Range 请求按需读取远程镜像，只读
+pub struct HttpBackend {
+    client: Client,
+    url: Uri,
+    size: u64,
+    handle: Handle,
+}
+
+impl HttpBackend {
+    pub async fn open(client: Client, url: &str) -> anyhow::Result<Self> {
+        let url: Uri = url.parse()?;
+        if url.scheme_str() != Some("http") {
+            anyhow::bail!("only http urls are supported");
+        }
+        let mut backend = Self {
+            client,
+            url,
+            size: 0,
+            handle: Handle::current(),
+        };
+        // 顺便确认服务器支持 Range
+        let (content_range, _) = backend.get_range(0, 1).await?;
+        backend.size = content_range
+            .rsplit_once('/')
+            .and_then(|(_, size)| size.parse().ok())
+            .ok_or_else(|| anyhow::anyhow!("invalid Content-Range: {content_range}"))?;
+        Ok(backend)
+    }
+
+    async fn get_range(&self, offset: u64, len: usize) -> anyhow::Result<(String, Vec<u8>)> {
+        let req = Request::get(&self.url)
+            .header(
+                header::RANGE,
+                format!("bytes={offset}-{}", offset + len as u64 - 1),
+            )
+            .body(Body::empty())?;
+        let res = time::timeout(HTTP_BACKEND_TIMEOUT, self.client.request(req)).await??;
+        if res.status() != StatusCode::PARTIAL_CONTENT {
+            anyhow::bail!("server does not support range requests: {}", res.status());
+        }
+        let content_range = res
+            .headers()
+            .get(header::CONTENT_RANGE)
+            .and_then(|content_range| content_range.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        let body = time::timeout(
+            HTTP_BACKEND_TIMEOUT,
+            axum::body::to_bytes(Body::new(res.into_body()), len),
+        )
+        .await??;
+        if body.len() != len {
+            anyhow::bail!("expect {len} bytes, got {}", body.len());
+        }
+        Ok((content_range, body.to_vec()))
+    }
+}
+
+impl BlockBackend for HttpBackend {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_only(&self) -> bool {
+        true
+    }
+
+    // 在 U 盘线程中调用，可以阻塞等待
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> error::Result<()> {
+        let (_, data) = self
+            .handle
+            .block_on(self.get_range(offset, buf.len()))
+            .map_err(|err| error::ErrorKind::custom(format!("read {}: {err}", self.url)))?;
+        buf.copy_from_slice(&data);
+        Ok(())
+    }
+
+    fn write_at(&self, _offset: u64, _data: &[u8]) -> error::Result<()> {
+        Err(error::ErrorKind::custom("http backend is read only".into()))?
+    }
+}
+
+#[derive(Clone, Serialize)]
+pub struct MediumOutput {
+    backend: &'static str,
+    name: String,
+    size: u64,
+    cdrom: bool,
+    read_only: bool,
+    overlay: bool,
+    // 覆盖层保存在文件中，拔出后还在
+    persistent_overlay: bool,
+    written_sectors: Option<u64>,
+}
+
+struct Medium {
+    output: MediumOutput,
+    overlay: Option<Arc<dyn Overlay>>,
+}
+
+// FunctionFS 实现的 U 盘，以及当前插入的存储
+pub struct FfsMassStorage {
+    pub msg: Arc<FfsMsg>,
+    medium: Mutex<Option<Medium>>,
+}
+
+impl FfsMassStorage {
+    pub fn new(msg: Arc<FfsMsg>) -> Self {
+        Self {
+            msg,
+            medium: Mutex::new(None),
+        }
+    }
+}
+
+fn ffs_mass_storage(device_ctx: &DeviceCtx) -> api_error::Result<&FfsMassStorage> {
+    device_ctx.ffs_msg.as_ref().ok_or_else(|| {
+        ApiError::new(
+            StatusCode::NOT_FOUND,
+            anyhow::anyhow!("ffs mass storage is not enabled"),
+        )
+    })
+}
+
+fn no_overlay() -> ApiError {
+    ApiError::new(
+        StatusCode::CONFLICT,
+        anyhow::anyhow!("medium has no overlay"),
+    )
+}
+
+// 插入的文件镜像，主机弹出后覆盖层还可能继续使用
+pub async fn image_in_use(app_state: &AppState, image_name: &str) -> bool {
+    for device_ctx in &app_state.targets {
+        let device_ctx = device_ctx.read().await;
+        let Some(ffs_msg) = &device_ctx.ffs_msg else {
+            continue;
+        };
+        if ffs_msg
+            .medium
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|medium| {
+                medium.output.backend == "file" && medium.output.name == image_name
+            })
+        {
+            return true;
+        }
+    }
+    false
+}
+
+#[derive(Serialize)]
+pub struct MassStorageOutput {
+    // 主机是否已经启用了这个功能
+    enabled: bool,
+    medium: Option<MediumOutput>,
+}
+
+pub async fn get_mass_storage(
+    Extension(device_ctx): Extension<Arc<RwLock<DeviceCtx>>>,
+) -> api_error::Result<Json<MassStorageOutput>> {
+    let device_ctx = device_ctx.read().await;
+    let ffs_msg = ffs_mass_storage(&device_ctx)?;
+    let mut medium = ffs_msg.medium.lock().unwrap();
+    if !ffs_msg.msg.has_medium() {
+        *medium = None;
+    }
+    Ok(Json(MassStorageOutput {
+        enabled: ffs_msg.msg.is_enabled(),
+        medium: medium.as_ref().map(|medium| MediumOutput {
+            written_sectors: medium
+                .overlay
+                .as_ref()
+                .map(|overlay| overlay.written_sectors()),
+            ..medium.output.clone()
+        }),
+    }))
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "backend", rename_all = "snake_case")]
+pub enum BackendInput {
+    // IP_KVM_IMAGES_PATH 中的镜像
+    File { image_name: String },
+    Http { url: String },
+    Memory { size: u64 },
+}
+
+#[derive(Deserialize)]
+pub struct MediumInput {
+    #[serde(flatten)]
+    backend: BackendInput,
+    #[serde(default)]
+    cdrom: bool,
+    #[serde(default)]
+    read_only: bool,
+    // 写入保存在覆盖层中，不修改原来的存储。文件镜像的覆盖层是文件，其它是内存
+    #[serde(default)]
+    overlay: bool,
+}
+
+pub async fn put_medium(
+    State(app_state): State<Arc<AppState>>,
+    Extension(device_ctx): Extension<Arc<RwLock<DeviceCtx>>>,
+    Json(input): Json<MediumInput>,
+) -> api_error::Result<String> {
+    let device_ctx = device_ctx.read().await;
+    let ffs_msg = ffs_mass_storage(&device_ctx)?;
+    let bad_request = |err: anyhow::Error| ApiError::new(StatusCode::BAD_REQUEST, err);
+    // 新的存储准备好之后才弹出原来的存储，出错时原来的存储不受影响
+    let mut overlay_paths = None;
+    let mut current_overlay = None;
+    let (backend, backend_name, name): (Arc<dyn BlockBackend>, _, _) = match input.backend {
+        BackendInput::File { image_name } => {
+            let path = mass_storage::get_image_path(&image_name)?;
+            if input.overlay {
+                // 重新插入同一个镜像时继续使用正在使用的覆盖层，同一个覆盖层文件不能打开两次
+                current_overlay = ffs_msg
+                    .medium
+                    .lock()
+                    .unwrap()
+                    .as_ref()
+                    .filter(|medium| {
+                        medium.output.persistent_overlay && medium.output.name == image_name
+                    })
+                    .and_then(|medium| medium.overlay.clone());
+                if current_overlay.is_none() {
+                    overlay_paths =
+                        Some(mass_storage::get_overlay_paths(&image_name, device_ctx.id)?);
+                }
+            }
+            // 有覆盖层时不需要写原文件
+            let read_only = input.read_only || input.overlay;
+            let backend = tokio::task::spawn_blocking(move || FileBackend::open(&path, read_only))
+                .await?
+                .map_err(|err| bad_request(err.into()))?;
+            (Arc::new(backend), "file", image_name)
+        }
+        BackendInput::Http { url } => {
+            let backend = HttpBackend::open(app_state.http_client.clone(), &url)
+                .await
+                .map_err(bad_request)?;
+            (Arc::new(backend), "http", url)
+        }
+        BackendInput::Memory { size } => {
+            if size == 0 || size > FFS_MSG_MEMORY_MAX {
+                return Err(bad_request(anyhow::anyhow!(
+                    "size must be between 1 and {FFS_MSG_MEMORY_MAX}"
+                )));
+            }
+            (
+                Arc::new(MemoryBackend::new(size as usize)),
+                "memory",
+                String::new(),
+            )
+        }
+    };
+    // 继续使用的覆盖层已经在定时保存
+    let reuse_overlay = current_overlay.is_some();
+    let overlay: Option<Arc<dyn Overlay>> = match overlay_paths {
+        _ if reuse_overlay => current_overlay,
+        // 同一个镜像上次保留下来的覆盖层会继续使用
+        Some((data_path, bitmap_path)) => {
+            let base = backend.clone();
+            let overlay = tokio::task::spawn_blocking(move || {
+                FileOverlayBackend::open(base, &data_path, &bitmap_path)
+            })
+            .await??;
+            Some(Arc::new(overlay))
+        }
+        None if input.overlay => Some(Arc::new(OverlayBackend::new(
+            backend.clone(),
+            FFS_MSG_OVERLAY_MAX,
+        ))),
+        None => None,
+    };
+    let backend = match &overlay {
+        Some(overlay) => overlay.clone() as Arc<dyn BlockBackend>,
+        None => backend,
+    };
+    let output = MediumOutput {
+        backend: backend_name,
+        name,
+        size: backend.size(),
+        cdrom: input.cdrom,
+        read_only: input.read_only || backend.read_only(),
+        overlay: overlay.is_some(),
+        persistent_overlay: overlay.as_ref().is_some_and(|overlay| overlay.persistent()),
+        written_sectors: None,
+    };
+    log::info!(
+        "Target {} insert {} medium {:?}",
+        device_ctx.id,
+        output.backend,
+        output.name
+    );
+    if let Some(overlay) = overlay
+        .as_ref()
+        .filter(|overlay| !reuse_overlay && overlay.persistent())
+    {
+        spawn_overlay_flush(overlay);
+    }
+    let mut medium = ffs_msg.medium.lock().unwrap();
+    ffs_msg.msg.eject();
+    ffs_msg.msg.insert(backend, input.cdrom, input.read_only);
+    *medium = Some(Medium { output, overlay });
+    Ok("null".into())
+}
+
+// 文件覆盖层在弹出后保留，下次插入同一个镜像时继续使用
+pub async fn delete_medium(
+    Extension(device_ctx): Extension<Arc<RwLock<DeviceCtx>>>,
+) -> api_error::Result<String> {
+    let device_ctx = device_ctx.read().await;
+    let ffs_msg = ffs_mass_storage(&device_ctx)?;
+    let mut medium = ffs_msg.medium.lock().unwrap();
+    ffs_msg.msg.eject();
+    *medium = None;
+    Ok("null".into())
+}
+
+// 覆盖层中被写过的扇区，[起始扇区, 扇区数]
+pub async fn get_written_sectors(
+    Extension(device_ctx): Extension<Arc<RwLock<DeviceCtx>>>,
+) -> api_error::Result<Json<Vec<(u64, u64)>>> {
+    let device_ctx = device_ctx.read().await;
+    let ffs_msg = ffs_mass_storage(&device_ctx)?;
+    let medium = ffs_msg.medium.lock().unwrap();
+    let Some(overlay) = medium.as_ref().and_then(|medium| medium.overlay.clone()) else {
+        return Err(no_overlay());
+    };
+    drop(medium);
+    Ok(Json(overlay.written_ranges()))
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum OverlayActionInput {
+    // 删除覆盖层
+    Discard,
+    // 保留覆盖层文件
+    Keep,
+    // 合并成 IP_KVM_IMAGES_PATH 中的新镜像，然后删除覆盖层
+    Commit { image_name: String },
+}
+
+// 结束这次插入：弹出存储，再处理覆盖层
+pub async fn post_overlay(
+    Extension(device_ctx): Extension<Arc<RwLock<DeviceCtx>>>,
+    Json(input): Json<OverlayActionInput>,
+) -> api_error::Result<String> {
+    let device_ctx = device_ctx.read().await;
+    let ffs_msg = ffs_mass_storage(&device_ctx)?;
+    let commit_path = match &input {
+        OverlayActionInput::Commit { image_name } => {
+            Some(mass_storage::get_image_path(image_name)?)
+        }
+        _ => None,
+    };
+    let overlay = {
+        let mut medium = ffs_msg.medium.lock().unwrap();
+        let Some(overlay) = medium.as_ref().and_then(|medium| medium.overlay.clone()) else {
+            return Err(no_overlay());
+        };
+        if matches!(input, OverlayActionInput::Keep) && !overlay.persistent() {
+            return Err(ApiError::new(
+                StatusCode::CONFLICT,
+                anyhow::anyhow!("memory overlay can not be kept"),
+            ));
+        }
+        ffs_msg.msg.eject();
+        *medium = None;
+        overlay
+    };
+    log::info!(
+        "Target {} overlay {}",
+        device_ctx.id,
+        match input {
+            OverlayActionInput::Discard => "discard",
+            OverlayActionInput::Keep => "keep",
+            OverlayActionInput::Commit { .. } => "commit",
+        }
+    );
+    // 导出大镜像需要较长时间
+    tokio::task::spawn_blocking(move || -> error::Result<()> {
+        if let Some(path) = commit_path {
+            block_backend::export_image(overlay.as_ref(), &path)?;
+        }
+        if !matches!(input, OverlayActionInput::Keep) {
+            overlay.remove()?;
+        }
+        Ok(())
+    })
+    .await??;
+    Ok("null".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{http::HeaderMap, response::IntoResponse, routing, Router};
+
+    use super::*;
+
+    // 本地的 HTTP 服务器，range 为 false 时总是返回整个文件
+    async fn serve(data: Vec<u8>, range: bool) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new().route(
+            "/disk.img",
+            routing::get(move |headers: HeaderMap| {
+                let data = data.clone();
+                async move {
+                    let requested = headers
+                        .get(header::RANGE)
+                        .and_then(|value| value.to_str().ok()?.strip_prefix("bytes="))
+                        .and_then(|value| value.split_once('-'))
+                        .and_then(|(start, end)| Some((start.parse().ok()?, end.parse().ok()?)));
+                    match requested {
+                        Some((start, end)) if range && start <= end && end < data.len() => (
+                            StatusCode::PARTIAL_CONTENT,
+                            [(
+                                header::CONTENT_RANGE,
+                                format!("bytes {start}-{end}/{}", data.len()),
+                            )],
+                            data[start..=end].to_vec(),
+                        )
+                            .into_response(),
+                        _ => data.into_response(),
+                    }
+                }
+            }),
+        );
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{addr}/disk.img")
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn http_backend_reads_ranges() {
+        let data: Vec<u8> = (0..4096).map(|i| (i % 251) as u8).collect();
+        let url = serve(data.clone(), true).await;
+        let backend = Arc::new(
+            HttpBackend::open(crate::new_http_client(), &url)
+                .await
+                .unwrap(),
+        );
+        assert_eq!(backend.size(), 4096);
+        assert!(backend.read_only());
+
+        // read_at 在 U 盘线程中调用
+        let res = tokio::task::spawn_blocking(move || {
+            let mut buf = [0_u8; 512];
+            backend.read_at(1000, &mut buf).unwrap();
+            let mut tail = [0_u8; 96];
+            backend.read_at(4000, &mut tail).unwrap();
+            // 超出文件时服务器返回整个文件
+            let past_end = backend.read_at(4000, &mut [0_u8; 512]).is_err();
+            let write = backend.write_at(0, &[0]).is_err();
+            (buf, tail, past_end, write)
+        })
+        .await
+        .unwrap();
+        assert_eq!(res.0, data[1000..1512]);
+        assert_eq!(res.1, data[4000..]);
+        assert!(res.2);
+        assert!(res.3);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn http_backend_requires_range() {
+        let url = serve(vec![0; 1024], false).await;
+        assert!(HttpBackend::open(crate::new_http_client(), &url)
+            .await
+            .is_err());
+        let url = url.replacen("http", "https", 1);
+        assert!(HttpBackend::open(crate::new_http_client(), &url)
+            .await
+            .is_err());
+    }
+}
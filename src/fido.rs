@@ -0,0 +1,507 @@
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc, Mutex,
+};
+use std::time::Duration;
+
+use axum::{http::StatusCode, Extension, Json};
+use serde::Serialize;
+use tokio::{
+    sync::{mpsc, Notify, RwLock},
+    time::{self, Instant},
+};
+
+use usb_otg::hid::{
+    fido::{self as ctaphid, CtaphidAssembler, CtaphidMessage, CtaphidPacket, FidoDevice},
+    hidg,
+};
+
+use crate::{
+    api_error::{self, ApiError},
+    fido_authenticator::{self as authenticator, Authenticator, CredentialInfo},
+    hid_health, DeviceCtx, HID_RECOVER_BACKOFF_MIN,
+};
+
+// 等待操作员确认的最长时间，之后返回超时
+const PRESENCE_TIMEOUT: Duration = Duration::from_secs(30);
+const KEEPALIVE_INTERVAL: Duration = Duration::from_millis(100);
+// U2F 没有 keepalive，主机会不断重试，确认在这段时间内有效
+const U2F_TOUCH_TIMEOUT: Duration = Duration::from_secs(10);
+// 主机停止重试后 U2F 请求不再显示
+const U2F_PENDING_TIMEOUT: Duration = Duration::from_secs(2);
+
+const CTAPHID_PROTOCOL_VERSION: u8 = 2;
+
+struct PendingRequest {
+    description: String,
+    // U2F 请求的数据，主机重试时会发送相同的数据
+    u2f: Option<Vec<u8>>,
+    updated: Instant,
+}
+
+#[derive(Default)]
+struct PresenceState {
+    pending: Option<PendingRequest>,
+    decision: Option<bool>,
+    cancelled: bool,
+    // 确认只对被确认的那个 U2F 请求有效
+    u2f_touched: Option<(Vec<u8>, Instant)>,
+}
+
+// 模拟安全密钥上的按钮，由操作员在网页上点击
+#[derive(Default)]
+struct Presence {
+    state: Mutex<PresenceState>,
+    notify: Notify,
+}
+
+impl Presence {
+    fn pending(&self) -> Option<String> {
+        let state = self.state.lock().unwrap();
+        let pending = state.pending.as_ref()?;
+        if pending.u2f.is_some() && pending.updated.elapsed() > U2F_PENDING_TIMEOUT {
+            return None;
+        }
+        Some(pending.description.clone())
+    }
+
+    fn request(&self, description: String) {
+        let mut state = self.state.lock().unwrap();
+        state.pending = Some(PendingRequest {
+            description,
+            u2f: None,
+            updated: Instant::now(),
+        });
+        state.decision = None;
+        state.cancelled = false;
+    }
+
+    fn poll(&self) -> Option<Result<(), u8>> {
+        let state = self.state.lock().unwrap();
+        if state.cancelled {
+            return Some(Err(authenticator::CTAP2_ERR_KEEPALIVE_CANCEL));
+        }
+        match state.decision? {
+            true => Some(Ok(())),
+            false => Some(Err(authenticator::CTAP2_ERR_OPERATION_DENIED)),
+        }
+    }
+
+    fn finish(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.pending = None;
+        state.decision = None;
+    }
+
+    fn cancel(&self) {
+        self.state.lock().unwrap().cancelled = true;
+        self.notify.notify_waiters();
+    }
+
+    // 没有确认时记录请求，让主机稍后重试
+    fn take_u2f(&self, description: String, request: &[u8]) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if let Some((touched_request, touched)) = &state.u2f_touched {
+            if touched.elapsed() >= U2F_TOUCH_TIMEOUT {
+                state.u2f_touched = None;
+            } else if touched_request == request {
+                state.u2f_touched = None;
+                state.pending = None;
+                return true;
+            }
+        }
+        state.pending = Some(PendingRequest {
+            description,
+            u2f: Some(request.to_vec()),
+            updated: Instant::now(),
+        });
+        false
+    }
+
+    fn decide(&self, approve: bool) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let Some(pending) = &state.pending else {
+            return false;
+        };
+        if let Some(request) = &pending.u2f {
+            if approve {
+                state.u2f_touched = Some((request.clone(), Instant::now()));
+            }
+            state.pending = None;
+        } else {
+            state.decision = Some(approve);
+        }
+        drop(state);
+        self.notify.notify_waiters();
+        true
+    }
+}
+
+// 虚拟的 FIDO2/U2F 安全密钥
+pub struct Fido {
+    pub device: FidoDevice,
+    // 执行请求时会写文件，放到阻塞线程里处理
+    authenticator: Arc<Mutex<Authenticator>>,
+    presence: Presence,
+    // 同一时间只处理一个 MSG 或 CBOR 请求
+    busy_cid: Mutex<Option<u32>>,
+    next_cid: AtomicU32,
+}
+
+impl Fido {
+    pub fn new(device: FidoDevice, authenticator: Authenticator) -> Self {
+        Self {
+            device,
+            authenticator: Arc::new(Mutex::new(authenticator)),
+            presence: Default::default(),
+            busy_cid: Mutex::new(None),
+            next_cid: AtomicU32::new(1),
+        }
+    }
+
+    async fn send(&self, device_ctx: &DeviceCtx, cid: u32, cmd: u8, data: &[u8]) {
+        if let Err(err) = self.device.send(cid, cmd, data).await {
+            log::error!("fido_device.send failed: {err}");
+            device_ctx
+                .hid_write_failed(hid_health::HID_DEV_FIDO, &err)
+                .await;
+        }
+    }
+
+    async fn send_error(&self, device_ctx: &DeviceCtx, cid: u32, code: u8) {
+        self.send(device_ctx, cid, ctaphid::CTAPHID_ERROR, &[code])
+            .await;
+    }
+
+    // 读取主机发来的包，简单的命令直接回复，MSG 和 CBOR 交给 run_processor
+    pub async fn run_reader(&self, device_ctx: &DeviceCtx, sender: mpsc::Sender<CtaphidMessage>) {
+        let mut assembler = CtaphidAssembler::default();
+        let mut backoff = HID_RECOVER_BACKOFF_MIN;
+        loop {
+            let recv = self.device.recv_packet();
+            let res = match assembler.next_deadline() {
+                Some(deadline) => match time::timeout_at(deadline.into(), recv).await {
+                    Ok(res) => res,
+                    Err(_) => {
+                        for cid in assembler.expire(std::time::Instant::now()) {
+                            self.send_error(device_ctx, cid, ctaphid::CTAPHID_ERR_MSG_TIMEOUT)
+                                .await;
+                        }
+                        continue;
+                    }
+                },
+                None => recv.await,
+            };
+            let packet = match res {
+                Ok(packet) => packet,
+                Err(err) if hidg::is_ignore(&err) => continue,
+                Err(err) => {
+                    device_ctx
+                        .recover_hid_dev(hid_health::HID_DEV_FIDO, &err, &mut backoff)
+                        .await;
+                    continue;
+                }
+            };
+            backoff = HID_RECOVER_BACKOFF_MIN;
+            device_ctx.hid_health.set_ok(hid_health::HID_DEV_FIDO);
+            match assembler.push(&packet, std::time::Instant::now()) {
+                CtaphidPacket::Pending => {}
+                CtaphidPacket::Error { cid, code } => self.send_error(device_ctx, cid, code).await,
+                CtaphidPacket::Message(message) => {
+                    self.dispatch(device_ctx, message, &sender).await
+                }
+            }
+        }
+    }
+
+    async fn dispatch(
+        &self,
+        device_ctx: &DeviceCtx,
+        message: CtaphidMessage,
+        sender: &mpsc::Sender<CtaphidMessage>,
+    ) {
+        let cid = message.cid;
+        if cid == 0 {
+            self.send_error(device_ctx, cid, ctaphid::CTAPHID_ERR_INVALID_CHANNEL)
+                .await;
+            return;
+        }
+        if message.cmd == ctaphid::CTAPHID_INIT {
+            if message.data.len() != 8 {
+                self.send_error(device_ctx, cid, ctaphid::CTAPHID_ERR_INVALID_LEN)
+                    .await;
+                return;
+            }
+            let new_cid = if cid == ctaphid::CTAPHID_BROADCAST_CID {
+                self.next_cid.fetch_add(1, Ordering::Relaxed)
+            } else {
+                // 在已有通道上 INIT 表示重新同步，放弃正在进行的请求
+                if *self.busy_cid.lock().unwrap() == Some(cid) {
+                    self.presence.cancel();
+                }
+                cid
+            };
+            let mut response = message.data;
+            response.extend_from_slice(&new_cid.to_be_bytes());
+            response.extend_from_slice(&[
+                CTAPHID_PROTOCOL_VERSION,
+                0,
+                1,
+                0,
+                ctaphid::CTAPHID_CAPABILITY_WINK | ctaphid::CTAPHID_CAPABILITY_CBOR,
+            ]);
+            self.send(device_ctx, cid, ctaphid::CTAPHID_INIT, &response)
+                .await;
+            return;
+        }
+        if cid == ctaphid::CTAPHID_BROADCAST_CID {
+            self.send_error(device_ctx, cid, ctaphid::CTAPHID_ERR_INVALID_CHANNEL)
+                .await;
+            return;
+        }
+        match message.cmd {
+            ctaphid::CTAPHID_PING => {
+                self.send(device_ctx, cid, ctaphid::CTAPHID_PING, &message.data)
+                    .await
+            }
+            ctaphid::CTAPHID_WINK => {
+                log::info!("Target {} fido wink", device_ctx.id);
+                self.send(device_ctx, cid, ctaphid::CTAPHID_WINK, &[]).await
+            }
+            // CANCEL 不需要回复
+            ctaphid::CTAPHID_CANCEL => {
+                if *self.busy_cid.lock().unwrap() == Some(cid) {
+                    self.presence.cancel();
+                }
+            }
+            ctaphid::CTAPHID_MSG | ctaphid::CTAPHID_CBOR => {
+                {
+                    let mut busy_cid = self.busy_cid.lock().unwrap();
+                    if busy_cid.is_none() {
+                        *busy_cid = Some(cid);
+                        drop(busy_cid);
+                        let _ = sender.try_send(message);
+                        return;
+                    }
+                }
+                self.send_error(device_ctx, cid, ctaphid::CTAPHID_ERR_CHANNEL_BUSY)
+                    .await;
+            }
+            _ => {
+                self.send_error(device_ctx, cid, ctaphid::CTAPHID_ERR_INVALID_CMD)
+                    .await
+            }
+        }
+    }
+
+    // 处理可能需要等待操作员确认的请求
+    pub async fn run_processor(
+        &self,
+        device_ctx: &DeviceCtx,
+        mut receiver: mpsc::Receiver<CtaphidMessage>,
+    ) {
+        while let Some(message) = receiver.recv().await {
+            let response = if message.cmd == ctaphid::CTAPHID_CBOR {
+                self.process_cbor(device_ctx, message.cid, &message.data)
+                    .await
+            } else {
+                self.process_msg(&message.data).await
+            };
+            self.send(device_ctx, message.cid, message.cmd, &response)
+                .await;
+            *self.busy_cid.lock().unwrap() = None;
+        }
+    }
+
+    async fn process_cbor(&self, device_ctx: &DeviceCtx, cid: u32, data: &[u8]) -> Vec<u8> {
+        let res = async {
+            let req = authenticator::parse_ctap2(data)?;
+            let description = self.authenticator.lock().unwrap().ctap2_check(&req)?;
+            if let Some(description) = description {
+                self.wait_presence(device_ctx, cid, description).await?;
+            }
+            let authenticator = self.authenticator.clone();
+            tokio::task::spawn_blocking(move || authenticator.lock().unwrap().ctap2_execute(req))
+                .await
+                .unwrap_or(Err(authenticator::CTAP1_ERR_OTHER))
+        }
+        .await;
+        match res {
+            Ok(data) => [&[authenticator::CTAP2_OK], &data[..]].concat(),
+            Err(code) => vec![code],
+        }
+    }
+
+    async fn process_msg(&self, data: &[u8]) -> Vec<u8> {
+        let res = async {
+            let req = authenticator::parse_apdu(data)?;
+            let description = self.authenticator.lock().unwrap().u2f_check(&req)?;
+            if let Some(description) = description {
+                if !self.presence.take_u2f(description, data) {
+                    return Err(authenticator::U2F_SW_CONDITIONS_NOT_SATISFIED);
+                }
+            }
+            let authenticator = self.authenticator.clone();
+            tokio::task::spawn_blocking(move || authenticator.lock().unwrap().u2f_execute(req))
+                .await
+                .unwrap_or(Err(authenticator::U2F_SW_CONDITIONS_NOT_SATISFIED))
+        }
+        .await;
+        match res {
+            Ok(mut data) => {
+                data.extend_from_slice(&authenticator::U2F_SW_NO_ERROR.to_be_bytes());
+                data
+            }
+            Err(sw) => sw.to_be_bytes().to_vec(),
+        }
+    }
+
+    // 等待时定时发送 keepalive，否则主机会认为设备没有响应
+    async fn wait_presence(
+        &self,
+        device_ctx: &DeviceCtx,
+        cid: u32,
+        description: String,
+    ) -> Result<(), u8> {
+        log::info!(
+            "Target {} fido wait for touch: {description}",
+            device_ctx.id
+        );
+        self.presence.request(description);
+        let deadline = Instant::now() + PRESENCE_TIMEOUT;
+        let res = loop {
+            let notified = self.presence.notify.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            if let Some(res) = self.presence.poll() {
+                break res;
+            }
+            if Instant::now() >= deadline {
+                break Err(authenticator::CTAP2_ERR_USER_ACTION_TIMEOUT);
+            }
+            self.send(
+                device_ctx,
+                cid,
+                ctaphid::CTAPHID_KEEPALIVE,
+                &[ctaphid::CTAPHID_STATUS_UPNEEDED],
+            )
+            .await;
+            tokio::select! {
+                _ = notified => {}
+                _ = time::sleep(KEEPALIVE_INTERVAL) => {}
+            }
+        };
+        self.presence.finish();
+        res
+    }
+}
+
+#[derive(Serialize)]
+pub struct FidoPresenceOutput {
+    pending: Option<String>,
+}
+
+fn fido_not_enabled() -> ApiError {
+    ApiError::new(
+        StatusCode::NOT_FOUND,
+        anyhow::anyhow!("fido is not enabled"),
+    )
+}
+
+pub async fn get_presence(
+    Extension(device_ctx): Extension<Arc<RwLock<DeviceCtx>>>,
+) -> api_error::Result<Json<FidoPresenceOutput>> {
+    let device_ctx = device_ctx.read().await;
+    let fido = device_ctx.fido.as_ref().ok_or_else(fido_not_enabled)?;
+    Ok(Json(FidoPresenceOutput {
+        pending: fido.presence.pending(),
+    }))
+}
+
+async fn decide(device_ctx: Arc<RwLock<DeviceCtx>>, approve: bool) -> api_error::Result<String> {
+    let device_ctx = device_ctx.read().await;
+    let fido = device_ctx.fido.as_ref().ok_or_else(fido_not_enabled)?;
+    if !fido.presence.decide(approve) {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            anyhow::anyhow!("no pending fido request"),
+        ));
+    }
+    log::info!("Target {} fido approve: {approve}", device_ctx.id);
+    Ok("null".into())
+}
+
+pub async fn post_touch(
+    Extension(device_ctx): Extension<Arc<RwLock<DeviceCtx>>>,
+) -> api_error::Result<String> {
+    decide(device_ctx, true).await
+}
+
+pub async fn post_deny(
+    Extension(device_ctx): Extension<Arc<RwLock<DeviceCtx>>>,
+) -> api_error::Result<String> {
+    decide(device_ctx, false).await
+}
+
+pub async fn get_credentials(
+    Extension(device_ctx): Extension<Arc<RwLock<DeviceCtx>>>,
+) -> api_error::Result<Json<Vec<CredentialInfo>>> {
+    let device_ctx = device_ctx.read().await;
+    let fido = device_ctx.fido.as_ref().ok_or_else(fido_not_enabled)?;
+    let credentials = fido.authenticator.lock().unwrap().credentials();
+    Ok(Json(credentials))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn u2f_touch_approves_only_its_request() {
+        let presence = Presence::default();
+        assert!(!presence.take_u2f("a".to_string(), b"request a"));
+        assert_eq!(presence.pending().as_deref(), Some("a"));
+        assert!(presence.decide(true));
+        assert_eq!(presence.pending(), None);
+
+        // 其它请求不能使用这次确认
+        assert!(!presence.take_u2f("b".to_string(), b"request b"));
+        assert_eq!(presence.pending().as_deref(), Some("b"));
+        assert!(presence.take_u2f("a".to_string(), b"request a"));
+        assert_eq!(presence.pending(), None);
+        // 确认只能使用一次
+        assert!(!presence.take_u2f("a".to_string(), b"request a"));
+    }
+
+    #[test]
+    fn u2f_deny_does_not_approve() {
+        let presence = Presence::default();
+        assert!(!presence.take_u2f("a".to_string(), b"request a"));
+        assert!(presence.decide(false));
+        assert!(!presence.take_u2f("a".to_string(), b"request a"));
+        // 没有等待的请求时不能确认
+        presence.finish();
+        assert!(!presence.decide(true));
+    }
+
+    #[test]
+    fn ctap2_decision() {
+        let presence = Presence::default();
+        presence.request("make credential".to_string());
+        assert_eq!(presence.poll(), None);
+        assert!(presence.decide(false));
+        assert_eq!(
+            presence.poll(),
+            Some(Err(authenticator::CTAP2_ERR_OPERATION_DENIED))
+        );
+        presence.request("get assertion".to_string());
+        assert_eq!(presence.poll(), None);
+        assert!(presence.decide(true));
+        assert_eq!(presence.poll(), Some(Ok(())));
+        presence.finish();
+        assert_eq!(presence.pending(), None);
+        // CTAP2 的确认不会留给 U2F 请求
+        assert!(!presence.take_u2f("a".to_string(), b"request a"));
+    }
+}
@@ -0,0 +1,902 @@
+use std::path::{Path, PathBuf};
+
+use ciborium::Value;
+use p256::ecdsa::{signature::Signer, Signature, SigningKey};
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use util::error;
+
+pub const CTAP2_OK: u8 = 0x00;
+pub const CTAP1_ERR_INVALID_COMMAND: u8 = 0x01;
+pub const CTAP2_ERR_CBOR_UNEXPECTED_TYPE: u8 = 0x11;
+pub const CTAP2_ERR_INVALID_CBOR: u8 = 0x12;
+pub const CTAP2_ERR_MISSING_PARAMETER: u8 = 0x14;
+pub const CTAP2_ERR_CREDENTIAL_EXCLUDED: u8 = 0x19;
+pub const CTAP2_ERR_UNSUPPORTED_ALGORITHM: u8 = 0x26;
+pub const CTAP2_ERR_OPERATION_DENIED: u8 = 0x27;
+pub const CTAP2_ERR_INVALID_OPTION: u8 = 0x2c;
+pub const CTAP2_ERR_KEEPALIVE_CANCEL: u8 = 0x2d;
+pub const CTAP2_ERR_NO_CREDENTIALS: u8 = 0x2e;
+pub const CTAP2_ERR_USER_ACTION_TIMEOUT: u8 = 0x2f;
+pub const CTAP2_ERR_NOT_ALLOWED: u8 = 0x30;
+pub const CTAP2_ERR_PIN_AUTH_INVALID: u8 = 0x33;
+pub const CTAP2_ERR_PIN_NOT_SET: u8 = 0x35;
+pub const CTAP1_ERR_OTHER: u8 = 0x7f;
+
+const CTAP2_MAKE_CREDENTIAL: u8 = 0x01;
+const CTAP2_GET_ASSERTION: u8 = 0x02;
+const CTAP2_GET_INFO: u8 = 0x04;
+const CTAP2_GET_NEXT_ASSERTION: u8 = 0x08;
+const CTAP2_RESET: u8 = 0x07;
+const CTAP2_SELECTION: u8 = 0x0b;
+
+pub const U2F_SW_NO_ERROR: u16 = 0x9000;
+pub const U2F_SW_CONDITIONS_NOT_SATISFIED: u16 = 0x6985;
+pub const U2F_SW_WRONG_DATA: u16 = 0x6a80;
+pub const U2F_SW_WRONG_LENGTH: u16 = 0x6700;
+pub const U2F_SW_INS_NOT_SUPPORTED: u16 = 0x6d00;
+pub const U2F_SW_CLA_NOT_SUPPORTED: u16 = 0x6e00;
+
+const U2F_REGISTER: u8 = 0x01;
+const U2F_AUTHENTICATE: u8 = 0x02;
+const U2F_VERSION: u8 = 0x03;
+const U2F_AUTH_CHECK_ONLY: u8 = 0x07;
+const U2F_AUTH_ENFORCE: u8 = 0x03;
+const U2F_AUTH_DONT_ENFORCE: u8 = 0x08;
+
+const COSE_ALG_ES256: i64 = -7;
+// 只能装下 16 字节
+const AAGUID: &[u8; 16] = b"ip-kvm-fido-auth";
+const MAX_MSG_SIZE: u64 = 1200;
+const AUTH_DATA_FLAG_UP: u8 = 0x01;
+const AUTH_DATA_FLAG_AT: u8 = 0x40;
+const CREDENTIAL_ID_LEN: usize = 32;
+
+#[derive(Serialize, Deserialize)]
+struct FidoStore {
+    #[serde(with = "hex::serde")]
+    attestation_key: Vec<u8>,
+    credentials: Vec<Credential>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Credential {
+    #[serde(with = "hex::serde")]
+    id: Vec<u8>,
+    // U2F 注册时只知道 rp id 的哈希
+    rp_id: Option<String>,
+    #[serde(with = "hex::serde")]
+    rp_id_hash: Vec<u8>,
+    #[serde(with = "hex::serde")]
+    private_key: Vec<u8>,
+    #[serde(default, with = "hex::serde")]
+    user_id: Vec<u8>,
+    user_name: Option<String>,
+    resident: bool,
+    sign_count: u32,
+}
+
+#[derive(Serialize)]
+pub struct CredentialInfo {
+    id: String,
+    rp_id: Option<String>,
+    user_name: Option<String>,
+    resident: bool,
+    sign_count: u32,
+}
+
+pub struct MakeCredentialRequest {
+    client_data_hash: Vec<u8>,
+    rp_id: String,
+    user_id: Vec<u8>,
+    user_name: Option<String>,
+    exclude_list: Vec<Vec<u8>>,
+    resident: bool,
+    // 客户端用空的 pinUvAuthParam 让用户选择设备
+    pin_probe: bool,
+}
+
+pub struct GetAssertionRequest {
+    rp_id: String,
+    client_data_hash: Vec<u8>,
+    allow_list: Vec<Vec<u8>>,
+    up: bool,
+}
+
+pub enum Ctap2Request {
+    GetInfo,
+    MakeCredential(MakeCredentialRequest),
+    GetAssertion(GetAssertionRequest),
+    GetNextAssertion,
+    Reset,
+    Selection,
+}
+
+pub enum U2fRequest {
+    Version,
+    Register {
+        challenge: Vec<u8>,
+        application: Vec<u8>,
+    },
+    Authenticate {
+        control: u8,
+        challenge: Vec<u8>,
+        application: Vec<u8>,
+        key_handle: Vec<u8>,
+    },
+}
+
+// 软件实现的认证器，凭据保存在 JSON 文件里
+pub struct Authenticator {
+    store_path: PathBuf,
+    store: FidoStore,
+    attestation_key: SigningKey,
+}
+
+impl Authenticator {
+    pub fn open(store_path: &Path) -> error::Result<Self> {
+        let store = match std::fs::read(store_path) {
+            Ok(data) => serde_json::from_slice(&data).map_err(|err| {
+                error::ErrorKind::custom(format!(
+                    "Parse fido store {} failed: {err}",
+                    store_path.display()
+                ))
+            })?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => FidoStore {
+                attestation_key: SigningKey::random(&mut OsRng).to_bytes().to_vec(),
+                credentials: Vec::new(),
+            },
+            Err(err) => Err(error::ErrorKind::io(err, store_path))?,
+        };
+        let attestation_key = SigningKey::from_slice(&store.attestation_key).map_err(|err| {
+            error::ErrorKind::custom(format!("Invalid fido attestation key: {err}"))
+        })?;
+        let ret = Self {
+            store_path: store_path.to_path_buf(),
+            store,
+            attestation_key,
+        };
+        ret.save()?;
+        Ok(ret)
+    }
+
+    fn save(&self) -> error::Result<()> {
+        let data = serde_json::to_vec_pretty(&self.store).unwrap();
+        // 先写临时文件再改名，避免写到一半断电丢失所有凭据
+        let tmp_path = PathBuf::from(format!("{}.tmp", self.store_path.display()));
+        std::fs::write(&tmp_path, data).map_err(|err| error::ErrorKind::io(err, &tmp_path))?;
+        std::fs::rename(&tmp_path, &self.store_path)
+            .map_err(|err| error::ErrorKind::io(err, &self.store_path))?;
+        Ok(())
+    }
+
+    fn save_ctap(&self) -> Result<(), u8> {
+        self.save().map_err(|err| {
+            log::error!("Save fido store failed: {err}");
+            CTAP1_ERR_OTHER
+        })
+    }
+
+    pub fn credentials(&self) -> Vec<CredentialInfo> {
+        self.store
+            .credentials
+            .iter()
+            .map(|credential| CredentialInfo {
+                id: hex::encode(&credential.id),
+                rp_id: credential.rp_id.clone(),
+                user_name: credential.user_name.clone(),
+                resident: credential.resident,
+                sign_count: credential.sign_count,
+            })
+            .collect()
+    }
+
+    fn find_credential(&self, rp_id_hash: &[u8], id: &[u8]) -> Option<usize> {
+        self.store
+            .credentials
+            .iter()
+            .position(|credential| credential.rp_id_hash == rp_id_hash && credential.id == id)
+    }
+
+    fn find_assertion_credential(&self, req: &GetAssertionRequest) -> Option<usize> {
+        let rp_id_hash = Sha256::digest(req.rp_id.as_bytes());
+        if req.allow_list.is_empty() {
+            // 没有 allowList 时只能用常驻凭据，优先用最新创建的
+            self.store.credentials.iter().rposition(|credential| {
+                credential.resident && credential.rp_id_hash[..] == rp_id_hash[..]
+            })
+        } else {
+            req.allow_list
+                .iter()
+                .find_map(|id| self.find_credential(&rp_id_hash, id))
+        }
+    }
+
+    fn new_credential(
+        &mut self,
+        rp_id: Option<String>,
+        rp_id_hash: Vec<u8>,
+        user_id: Vec<u8>,
+        user_name: Option<String>,
+        resident: bool,
+    ) -> Result<(Credential, SigningKey), u8> {
+        let key = SigningKey::random(&mut OsRng);
+        let mut id = vec![0_u8; CREDENTIAL_ID_LEN];
+        OsRng.fill_bytes(&mut id);
+        if resident {
+            // 同一个用户只保留一个常驻凭据
+            self.store.credentials.retain(|credential| {
+                !(credential.resident
+                    && credential.rp_id_hash == rp_id_hash
+                    && credential.user_id == user_id)
+            });
+        }
+        let credential = Credential {
+            id,
+            rp_id,
+            rp_id_hash,
+            private_key: key.to_bytes().to_vec(),
+            user_id,
+            user_name,
+            resident,
+            sign_count: 0,
+        };
+        self.store.credentials.push(credential.clone());
+        self.save_ctap()?;
+        Ok((credential, key))
+    }
+
+    // 需要用户确认时返回显示给操作员的说明
+    pub fn ctap2_check(&self, req: &Ctap2Request) -> Result<Option<String>, u8> {
+        match req {
+            Ctap2Request::GetInfo | Ctap2Request::GetNextAssertion => Ok(None),
+            Ctap2Request::MakeCredential(req) => Ok(Some(format!("Register {}", req.rp_id))),
+            Ctap2Request::GetAssertion(req) => {
+                if self.find_assertion_credential(req).is_none() {
+                    return Err(CTAP2_ERR_NO_CREDENTIALS);
+                }
+                Ok(req.up.then(|| format!("Login {}", req.rp_id)))
+            }
+            Ctap2Request::Reset => Ok(Some("Reset all credentials".into())),
+            Ctap2Request::Selection => Ok(Some("Select authenticator".into())),
+        }
+    }
+
+    // 用户已经确认过（如果需要）
+    pub fn ctap2_execute(&mut self, req: Ctap2Request) -> Result<Vec<u8>, u8> {
+        let value = match req {
+            Ctap2Request::GetInfo => get_info(),
+            Ctap2Request::MakeCredential(req) => self.make_credential(req)?,
+            Ctap2Request::GetAssertion(req) => self.get_assertion(req)?,
+            // 每次只返回一个凭据
+            Ctap2Request::GetNextAssertion => Err(CTAP2_ERR_NOT_ALLOWED)?,
+            Ctap2Request::Reset => {
+                self.store.credentials.clear();
+                self.save_ctap()?;
+                return Ok(Vec::new());
+            }
+            Ctap2Request::Selection => return Ok(Vec::new()),
+        };
+        let mut data = Vec::new();
+        ciborium::ser::into_writer(&value, &mut data).map_err(|_| CTAP1_ERR_OTHER)?;
+        Ok(data)
+    }
+
+    fn make_credential(&mut self, req: MakeCredentialRequest) -> Result<Value, u8> {
+        if req.pin_probe {
+            Err(CTAP2_ERR_PIN_NOT_SET)?;
+        }
+        let rp_id_hash = Sha256::digest(req.rp_id.as_bytes()).to_vec();
+        if req
+            .exclude_list
+            .iter()
+            .any(|id| self.find_credential(&rp_id_hash, id).is_some())
+        {
+            Err(CTAP2_ERR_CREDENTIAL_EXCLUDED)?;
+        }
+        let (credential, key) = self.new_credential(
+            Some(req.rp_id),
+            rp_id_hash,
+            req.user_id,
+            req.user_name,
+            req.resident,
+        )?;
+
+        let mut auth_data = credential.rp_id_hash.clone();
+        auth_data.push(AUTH_DATA_FLAG_UP | AUTH_DATA_FLAG_AT);
+        auth_data.extend_from_slice(&credential.sign_count.to_be_bytes());
+        auth_data.extend_from_slice(AAGUID);
+        auth_data.extend_from_slice(&(credential.id.len() as u16).to_be_bytes());
+        auth_data.extend_from_slice(&credential.id);
+        ciborium::ser::into_writer(&cose_key(&key), &mut auth_data).map_err(|_| CTAP1_ERR_OTHER)?;
+
+        // 使用凭据自己的密钥做 packed 自签名证明
+        let mut signed_data = auth_data.clone();
+        signed_data.extend_from_slice(&req.client_data_hash);
+        let signature: Signature = key.sign(&signed_data);
+
+        Ok(Value::Map(vec![
+            (1.into(), "packed".into()),
+            (2.into(), Value::Bytes(auth_data)),
+            (
+                3.into(),
+                Value::Map(vec![
+                    ("alg".into(), COSE_ALG_ES256.into()),
+                    (
+                        "sig".into(),
+                        Value::Bytes(signature.to_der().as_bytes().to_vec()),
+                    ),
+                ]),
+            ),
+        ]))
+    }
+
+    fn get_assertion(&mut self, req: GetAssertionRequest) -> Result<Value, u8> {
+        let index = self
+            .find_assertion_credential(&req)
+            .ok_or(CTAP2_ERR_NO_CREDENTIALS)?;
+        let credential = &mut self.store.credentials[index];
+        // 计数到达上限后保持不变，不能回绕到更小的值
+        credential.sign_count = credential.sign_count.saturating_add(1);
+        let credential = credential.clone();
+        self.save_ctap()?;
+        let key = SigningKey::from_slice(&credential.private_key).map_err(|_| CTAP1_ERR_OTHER)?;
+
+        let mut auth_data = credential.rp_id_hash.clone();
+        auth_data.push(if req.up { AUTH_DATA_FLAG_UP } else { 0 });
+        auth_data.extend_from_slice(&credential.sign_count.to_be_bytes());
+        let mut signed_data = auth_data.clone();
+        signed_data.extend_from_slice(&req.client_data_hash);
+        let signature: Signature = key.sign(&signed_data);
+
+        let mut response = vec![
+            (
+                1.into(),
+                Value::Map(vec![
+                    ("id".into(), Value::Bytes(credential.id)),
+                    ("type".into(), "public-key".into()),
+                ]),
+            ),
+            (2.into(), Value::Bytes(auth_data)),
+            (
+                3.into(),
+                Value::Bytes(signature.to_der().as_bytes().to_vec()),
+            ),
+        ];
+        if credential.resident {
+            response.push((
+                4.into(),
+                Value::Map(vec![("id".into(), Value::Bytes(credential.user_id))]),
+            ));
+        }
+        Ok(Value::Map(response))
+    }
+
+    pub fn u2f_check(&self, req: &U2fRequest) -> Result<Option<String>, u16> {
+        match req {
+            U2fRequest::Version => Ok(None),
+            U2fRequest::Register { .. } => Ok(Some("Register (U2F)".into())),
+            U2fRequest::Authenticate {
+                control,
+                application,
+                key_handle,
+                ..
+            } => {
+                let Some(index) = self.find_credential(application, key_handle) else {
+                    Err(U2F_SW_WRONG_DATA)?
+                };
+                match *control {
+                    // 凭据存在时规范要求返回这个状态码
+                    U2F_AUTH_CHECK_ONLY => Err(U2F_SW_CONDITIONS_NOT_SATISFIED),
+                    U2F_AUTH_ENFORCE => {
+                        let rp_id = self.store.credentials[index].rp_id.as_deref();
+                        Ok(Some(format!("Login {} (U2F)", rp_id.unwrap_or("unknown"))))
+                    }
+                    U2F_AUTH_DONT_ENFORCE => Ok(None),
+                    _ => Err(U2F_SW_WRONG_DATA),
+                }
+            }
+        }
+    }
+
+    pub fn u2f_execute(&mut self, req: U2fRequest) -> Result<Vec<u8>, u16> {
+        match req {
+            U2fRequest::Version => Ok(b"U2F_V2".to_vec()),
+            U2fRequest::Register {
+                challenge,
+                application,
+            } => {
+                let (credential, key) = self
+                    .new_credential(None, application, Vec::new(), None, false)
+                    .map_err(|_| U2F_SW_CONDITIONS_NOT_SATISFIED)?;
+                let public_key = key.verifying_key().to_encoded_point(false);
+
+                let mut signed_data = vec![0x00];
+                signed_data.extend_from_slice(&credential.rp_id_hash);
+                signed_data.extend_from_slice(&challenge);
+                signed_data.extend_from_slice(&credential.id);
+                signed_data.extend_from_slice(public_key.as_bytes());
+                let signature: Signature = self.attestation_key.sign(&signed_data);
+
+                let mut response = vec![0x05];
+                response.extend_from_slice(public_key.as_bytes());
+                response.push(credential.id.len() as u8);
+                response.extend_from_slice(&credential.id);
+                response.extend_from_slice(&self.attestation_certificate());
+                response.extend_from_slice(signature.to_der().as_bytes());
+                Ok(response)
+            }
+            U2fRequest::Authenticate {
+                control,
+                challenge,
+                application,
+                key_handle,
+            } => {
+                let index = self
+                    .find_credential(&application, &key_handle)
+                    .ok_or(U2F_SW_WRONG_DATA)?;
+                let credential = &mut self.store.credentials[index];
+                credential.sign_count = credential.sign_count.saturating_add(1);
+                let credential = credential.clone();
+                self.save().map_err(|err| {
+                    log::error!("Save fido store failed: {err}");
+                    U2F_SW_CONDITIONS_NOT_SATISFIED
+                })?;
+                let key = SigningKey::from_slice(&credential.private_key)
+                    .map_err(|_| U2F_SW_WRONG_DATA)?;
+
+                let flags = if control == U2F_AUTH_ENFORCE {
+                    AUTH_DATA_FLAG_UP
+                } else {
+                    0
+                };
+                let mut response = vec![flags];
+                response.extend_from_slice(&credential.sign_count.to_be_bytes());
+                let mut signed_data = application;
+                signed_data.extend_from_slice(&response);
+                signed_data.extend_from_slice(&challenge);
+                let signature: Signature = key.sign(&signed_data);
+                response.extend_from_slice(signature.to_der().as_bytes());
+                Ok(response)
+            }
+        }
+    }
+
+    // U2F 注册响应必须带 X.509 证明证书，用证明密钥自签一个最简单的
+    fn attestation_certificate(&self) -> Vec<u8> {
+        const OID_ECDSA_WITH_SHA256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+        const OID_EC_PUBLIC_KEY: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+        const OID_PRIME256V1: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+        const OID_COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+
+        let signature_algorithm = der(0x30, &der(0x06, OID_ECDSA_WITH_SHA256));
+        let name = der(
+            0x30,
+            &der(
+                0x31,
+                &der(
+                    0x30,
+                    &[der(0x06, OID_COMMON_NAME), der(0x0c, b"ip-kvm FIDO")].concat(),
+                ),
+            ),
+        );
+        let validity = der(
+            0x30,
+            &[der(0x17, b"200101000000Z"), der(0x17, b"491231235959Z")].concat(),
+        );
+        let public_key = self.attestation_key.verifying_key().to_encoded_point(false);
+        let subject_public_key_info = der(
+            0x30,
+            &[
+                der(
+                    0x30,
+                    &[der(0x06, OID_EC_PUBLIC_KEY), der(0x06, OID_PRIME256V1)].concat(),
+                ),
+                der(0x03, &[&[0x00], public_key.as_bytes()].concat()),
+            ]
+            .concat(),
+        );
+        let tbs_certificate = der(
+            0x30,
+            &[
+                // v3
+                der(0xa0, &der(0x02, &[0x02])),
+                der(0x02, &[0x01]),
+                signature_algorithm.clone(),
+                name.clone(),
+                validity,
+                name,
+                subject_public_key_info,
+            ]
+            .concat(),
+        );
+        let signature: Signature = self.attestation_key.sign(&tbs_certificate);
+        der(
+            0x30,
+            &[
+                tbs_certificate,
+                signature_algorithm,
+                der(0x03, &[&[0x00], signature.to_der().as_bytes()].concat()),
+            ]
+            .concat(),
+        )
+    }
+}
+
+fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut ret = vec![tag];
+    let len = content.len();
+    if len < 0x80 {
+        ret.push(len as u8);
+    } else if len <= 0xff {
+        ret.extend_from_slice(&[0x81, len as u8]);
+    } else {
+        ret.push(0x82);
+        ret.extend_from_slice(&(len as u16).to_be_bytes());
+    }
+    ret.extend_from_slice(content);
+    ret
+}
+
+fn cose_key(key: &SigningKey) -> Value {
+    let point = key.verifying_key().to_encoded_point(false);
+    Value::Map(vec![
+        // kty: EC2
+        (1.into(), 2.into()),
+        (3.into(), COSE_ALG_ES256.into()),
+        // crv: P-256
+        ((-1).into(), 1.into()),
+        ((-2).into(), Value::Bytes(point.x().unwrap().to_vec())),
+        ((-3).into(), Value::Bytes(point.y().unwrap().to_vec())),
+    ])
+}
+
+fn get_info() -> Value {
+    Value::Map(vec![
+        (
+            1.into(),
+            Value::Array(vec!["U2F_V2".into(), "FIDO_2_0".into()]),
+        ),
+        (3.into(), Value::Bytes(AAGUID.to_vec())),
+        (
+            4.into(),
+            Value::Map(vec![
+                ("rk".into(), true.into()),
+                ("up".into(), true.into()),
+                ("plat".into(), false.into()),
+            ]),
+        ),
+        (5.into(), MAX_MSG_SIZE.into()),
+    ])
+}
+
+fn map_get(map: &[(Value, Value)], key: i64) -> Option<&Value> {
+    map.iter()
+        .find(|(k, _)| k.as_integer().map(i128::from) == Some(key as i128))
+        .map(|(_, v)| v)
+}
+
+fn map_get_text<'a>(map: &'a [(Value, Value)], key: &str) -> Option<&'a Value> {
+    map.iter()
+        .find(|(k, _)| k.as_text() == Some(key))
+        .map(|(_, v)| v)
+}
+
+fn required(map: &[(Value, Value)], key: i64) -> Result<&Value, u8> {
+    map_get(map, key).ok_or(CTAP2_ERR_MISSING_PARAMETER)
+}
+
+fn as_bytes(value: &Value) -> Result<Vec<u8>, u8> {
+    value
+        .as_bytes()
+        .cloned()
+        .ok_or(CTAP2_ERR_CBOR_UNEXPECTED_TYPE)
+}
+
+fn as_map(value: &Value) -> Result<&[(Value, Value)], u8> {
+    value
+        .as_map()
+        .map(Vec::as_slice)
+        .ok_or(CTAP2_ERR_CBOR_UNEXPECTED_TYPE)
+}
+
+fn as_text(value: &Value) -> Result<String, u8> {
+    value
+        .as_text()
+        .map(str::to_string)
+        .ok_or(CTAP2_ERR_CBOR_UNEXPECTED_TYPE)
+}
+
+// 读取凭据描述符列表中的 id
+fn credential_ids(value: Option<&Value>) -> Result<Vec<Vec<u8>>, u8> {
+    let Some(value) = value else {
+        return Ok(Vec::new());
+    };
+    let list = value.as_array().ok_or(CTAP2_ERR_CBOR_UNEXPECTED_TYPE)?;
+    list.iter()
+        .map(|descriptor| {
+            as_bytes(map_get_text(as_map(descriptor)?, "id").ok_or(CTAP2_ERR_MISSING_PARAMETER)?)
+        })
+        .collect()
+}
+
+// 不支持用户验证（PIN、指纹），要求 uv 时直接拒绝
+fn option(options: Option<&[(Value, Value)]>, name: &str, default: bool) -> Result<bool, u8> {
+    let Some(value) = options.and_then(|options| map_get_text(options, name)) else {
+        return Ok(default);
+    };
+    value.as_bool().ok_or(CTAP2_ERR_CBOR_UNEXPECTED_TYPE)
+}
+
+pub fn parse_ctap2(data: &[u8]) -> Result<Ctap2Request, u8> {
+    let Some((&cmd, params)) = data.split_first() else {
+        Err(CTAP1_ERR_INVALID_COMMAND)?
+    };
+    let params = || -> Result<Vec<(Value, Value)>, u8> {
+        let value: Value = ciborium::de::from_reader(params).map_err(|_| CTAP2_ERR_INVALID_CBOR)?;
+        value.into_map().map_err(|_| CTAP2_ERR_CBOR_UNEXPECTED_TYPE)
+    };
+    match cmd {
+        CTAP2_GET_INFO => Ok(Ctap2Request::GetInfo),
+        CTAP2_MAKE_CREDENTIAL => {
+            let params = params()?;
+            let client_data_hash = as_bytes(required(&params, 1)?)?;
+            let rp = as_map(required(&params, 2)?)?;
+            let rp_id = as_text(map_get_text(rp, "id").ok_or(CTAP2_ERR_MISSING_PARAMETER)?)?;
+            let user = as_map(required(&params, 3)?)?;
+            let user_id = as_bytes(map_get_text(user, "id").ok_or(CTAP2_ERR_MISSING_PARAMETER)?)?;
+            let user_name = map_get_text(user, "name")
+                .and_then(|name| name.as_text())
+                .map(str::to_string);
+            let algorithms = required(&params, 4)?
+                .as_array()
+                .ok_or(CTAP2_ERR_CBOR_UNEXPECTED_TYPE)?;
+            let es256 = algorithms.iter().any(|param| {
+                param
+                    .as_map()
+                    .and_then(|param| map_get_text(param, "alg"))
+                    .and_then(Value::as_integer)
+                    .map(i128::from)
+                    == Some(COSE_ALG_ES256 as i128)
+            });
+            if !es256 {
+                Err(CTAP2_ERR_UNSUPPORTED_ALGORITHM)?;
+            }
+            let exclude_list = credential_ids(map_get(&params, 5))?;
+            let options = map_get(&params, 7).map(as_map).transpose()?;
+            if option(options, "uv", false)? {
+                Err(CTAP2_ERR_INVALID_OPTION)?;
+            }
+            let resident = option(options, "rk", false)?;
+            let pin_probe = match map_get(&params, 8) {
+                Some(pin_auth) => {
+                    if !as_bytes(pin_auth)?.is_empty() {
+                        Err(CTAP2_ERR_PIN_AUTH_INVALID)?;
+                    }
+                    true
+                }
+                None => false,
+            };
+            Ok(Ctap2Request::MakeCredential(MakeCredentialRequest {
+                client_data_hash,
+                rp_id,
+                user_id,
+                user_name,
+                exclude_list,
+                resident,
+                pin_probe,
+            }))
+        }
+        CTAP2_GET_ASSERTION => {
+            let params = params()?;
+            let rp_id = as_text(required(&params, 1)?)?;
+            let client_data_hash = as_bytes(required(&params, 2)?)?;
+            let allow_list = credential_ids(map_get(&params, 3))?;
+            let options = map_get(&params, 5).map(as_map).transpose()?;
+            if option(options, "uv", false)? {
+                Err(CTAP2_ERR_INVALID_OPTION)?;
+            }
+            if map_get(&params, 6).is_some() {
+                Err(CTAP2_ERR_PIN_AUTH_INVALID)?;
+            }
+            Ok(Ctap2Request::GetAssertion(GetAssertionRequest {
+                rp_id,
+                client_data_hash,
+                allow_list,
+                up: option(options, "up", true)?,
+            }))
+        }
+        CTAP2_GET_NEXT_ASSERTION => Ok(Ctap2Request::GetNextAssertion),
+        CTAP2_RESET => Ok(Ctap2Request::Reset),
+        CTAP2_SELECTION => Ok(Ctap2Request::Selection),
+        _ => Err(CTAP1_ERR_INVALID_COMMAND),
+    }
+}
+
+// 支持短格式和扩展格式的 APDU
+pub fn parse_apdu(data: &[u8]) -> Result<U2fRequest, u16> {
+    if data.len() < 4 {
+        Err(U2F_SW_WRONG_LENGTH)?;
+    }
+    let (cla, ins, p1) = (data[0], data[1], data[2]);
+    if cla != 0 {
+        Err(U2F_SW_CLA_NOT_SUPPORTED)?;
+    }
+    let body = match data.len() {
+        4 | 5 => &[][..],
+        _ if data[4] == 0 && data.len() >= 7 => {
+            let len = u16::from_be_bytes([data[5], data[6]]) as usize;
+            data.get(7..7 + len).ok_or(U2F_SW_WRONG_LENGTH)?
+        }
+        _ => {
+            let len = data[4] as usize;
+            data.get(5..5 + len).ok_or(U2F_SW_WRONG_LENGTH)?
+        }
+    };
+    match ins {
+        U2F_VERSION => Ok(U2fRequest::Version),
+        U2F_REGISTER => {
+            if body.len() != 64 {
+                Err(U2F_SW_WRONG_LENGTH)?;
+            }
+            Ok(U2fRequest::Register {
+                challenge: body[..32].to_vec(),
+                application: body[32..].to_vec(),
+            })
+        }
+        U2F_AUTHENTICATE => {
+            if body.len() < 65 || body.len() != 65 + body[64] as usize {
+                Err(U2F_SW_WRONG_LENGTH)?;
+            }
+            Ok(U2fRequest::Authenticate {
+                control: p1,
+                challenge: body[..32].to_vec(),
+                application: body[32..64].to_vec(),
+                key_handle: body[65..].to_vec(),
+            })
+        }
+        _ => Err(U2F_SW_INS_NOT_SUPPORTED),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ctap2(cmd: u8, params: Vec<(Value, Value)>) -> Vec<u8> {
+        let mut data = vec![cmd];
+        ciborium::ser::into_writer(&Value::Map(params), &mut data).unwrap();
+        data
+    }
+
+    fn make_credential_params(alg: i64) -> Vec<(Value, Value)> {
+        vec![
+            (1.into(), Value::Bytes(vec![0; 32])),
+            (
+                2.into(),
+                Value::Map(vec![("id".into(), "example.com".into())]),
+            ),
+            (
+                3.into(),
+                Value::Map(vec![
+                    ("id".into(), Value::Bytes(vec![1, 2, 3])),
+                    ("name".into(), "user".into()),
+                ]),
+            ),
+            (
+                4.into(),
+                Value::Array(vec![Value::Map(vec![
+                    ("alg".into(), alg.into()),
+                    ("type".into(), "public-key".into()),
+                ])]),
+            ),
+        ]
+    }
+
+    #[test]
+    fn parse_make_credential() {
+        let data = ctap2(
+            CTAP2_MAKE_CREDENTIAL,
+            make_credential_params(COSE_ALG_ES256),
+        );
+        let Ok(Ctap2Request::MakeCredential(req)) = parse_ctap2(&data) else {
+            panic!("make credential expected");
+        };
+        assert_eq!(req.rp_id, "example.com");
+        assert_eq!(req.user_id, [1, 2, 3]);
+        assert_eq!(req.user_name.as_deref(), Some("user"));
+        assert!(!req.resident);
+        assert!(!req.pin_probe);
+    }
+
+    #[test]
+    fn parse_ctap2_errors() {
+        assert!(matches!(parse_ctap2(&[]), Err(CTAP1_ERR_INVALID_COMMAND)));
+        assert!(matches!(
+            parse_ctap2(&[0x55]),
+            Err(CTAP1_ERR_INVALID_COMMAND)
+        ));
+        assert!(matches!(
+            parse_ctap2(&[CTAP2_MAKE_CREDENTIAL, 0xff]),
+            Err(CTAP2_ERR_INVALID_CBOR)
+        ));
+        // 参数不是 map
+        assert!(matches!(
+            parse_ctap2(&[CTAP2_GET_ASSERTION, 0x01]),
+            Err(CTAP2_ERR_CBOR_UNEXPECTED_TYPE)
+        ));
+        let mut params = make_credential_params(COSE_ALG_ES256);
+        params.remove(0);
+        assert!(matches!(
+            parse_ctap2(&ctap2(CTAP2_MAKE_CREDENTIAL, params)),
+            Err(CTAP2_ERR_MISSING_PARAMETER)
+        ));
+        assert!(matches!(
+            parse_ctap2(&ctap2(CTAP2_MAKE_CREDENTIAL, make_credential_params(-257))),
+            Err(CTAP2_ERR_UNSUPPORTED_ALGORITHM)
+        ));
+        let params = vec![
+            (1.into(), "example.com".into()),
+            (2.into(), Value::Bytes(vec![0; 32])),
+            (5.into(), Value::Map(vec![("uv".into(), true.into())])),
+        ];
+        assert!(matches!(
+            parse_ctap2(&ctap2(CTAP2_GET_ASSERTION, params)),
+            Err(CTAP2_ERR_INVALID_OPTION)
+        ));
+    }
+
+    #[test]
+    fn parse_apdu_register() {
+        let mut data = vec![0, U2F_REGISTER, 0, 0, 64];
+        data.extend_from_slice(&[1; 32]);
+        data.extend_from_slice(&[2; 32]);
+        let Ok(U2fRequest::Register {
+            challenge,
+            application,
+        }) = parse_apdu(&data)
+        else {
+            panic!("register expected");
+        };
+        assert_eq!(challenge, [1; 32]);
+        assert_eq!(application, [2; 32]);
+
+        // 扩展长度
+        let mut data = vec![0, U2F_REGISTER, 0, 0, 0, 0, 64];
+        data.extend_from_slice(&[0; 64]);
+        data.extend_from_slice(&[0, 0]);
+        assert!(matches!(parse_apdu(&data), Ok(U2fRequest::Register { .. })));
+    }
+
+    #[test]
+    fn parse_apdu_errors() {
+        assert_eq!(
+            parse_apdu(&[0, U2F_VERSION]).err(),
+            Some(U2F_SW_WRONG_LENGTH)
+        );
+        assert_eq!(
+            parse_apdu(&[1, U2F_VERSION, 0, 0]).err(),
+            Some(U2F_SW_CLA_NOT_SUPPORTED)
+        );
+        assert_eq!(
+            parse_apdu(&[0, 0x40, 0, 0]).err(),
+            Some(U2F_SW_INS_NOT_SUPPORTED)
+        );
+        // 声明的长度超过实际数据
+        assert_eq!(
+            parse_apdu(&[0, U2F_REGISTER, 0, 0, 64, 0, 0]).err(),
+            Some(U2F_SW_WRONG_LENGTH)
+        );
+        assert_eq!(
+            parse_apdu(&[0, U2F_REGISTER, 0, 0, 0, 0, 64, 0]).err(),
+            Some(U2F_SW_WRONG_LENGTH)
+        );
+        // key handle 长度和数据不一致
+        let mut data = vec![0, U2F_AUTHENTICATE, U2F_AUTH_ENFORCE, 0, 66];
+        data.extend_from_slice(&[0; 64]);
+        data.extend_from_slice(&[2, 0]);
+        assert_eq!(parse_apdu(&data).err(), Some(U2F_SW_WRONG_LENGTH));
+    }
+}
@@ -0,0 +1,149 @@
+use std::{net::SocketAddr, ops::ControlFlow, sync::Arc, time::Duration};
+
+use axum::{
+    extract::{
+        ws::{Message, WebSocket},
+        ConnectInfo, WebSocketUpgrade,
+    },
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Extension,
+};
+
+use axum_extra::{headers, TypedHeader};
+
+use futures::{SinkExt, StreamExt};
+use tokio::{sync::RwLock, task::JoinSet, time};
+
+use usb_otg::hid::gamepad::{Gamepad, GAMEPAD_REPORT_LENGTH};
+
+use crate::{held_input, hid_health, metrics::METRICS, DeviceCtx};
+
+pub async fn ws_handler(
+    ws: WebSocketUpgrade,
+    user_agent: Option<TypedHeader<headers::UserAgent>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(device_ctx): Extension<Arc<RwLock<DeviceCtx>>>,
+) -> Response {
+    // 没有启用 --gamepad
+    if device_ctx.read().await.gamepad_device.is_none() {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    let user_agent = if let Some(TypedHeader(user_agent)) = user_agent {
+        user_agent.to_string()
+    } else {
+        String::from("Unknown browser")
+    };
+    println!("`{user_agent}` at {addr} connected.");
+    ws.on_upgrade(move |socket| handle_socket(device_ctx, socket, addr))
+        .into_response()
+}
+
+async fn handle_socket(device_ctx: Arc<RwLock<DeviceCtx>>, socket: WebSocket, who: SocketAddr) {
+    let _client_guard = METRICS.websocket_client(device_ctx.read().await.id, "gamepad");
+    let (mut sender, mut receiver) = socket.split();
+
+    let mut join_set = JoinSet::new();
+
+    join_set.spawn(async move {
+        loop {
+            tokio::time::sleep(Duration::from_millis(1000)).await;
+            if sender.send(Message::Ping(vec![1, 2, 3])).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let session_id = held_input::new_session_id();
+    let device_ctx_recv = device_ctx.clone();
+    join_set.spawn(async move {
+        while let Ok(Some(Ok(msg))) =
+            time::timeout(held_input::SESSION_IDLE_TIMEOUT, receiver.next()).await
+        {
+            if process_message(device_ctx_recv.clone(), msg, who, session_id)
+                .await
+                .is_break()
+            {
+                break;
+            }
+        }
+    });
+
+    let _ = join_set.join_next().await;
+    join_set.shutdown().await;
+
+    // 浏览器异常退出时松开手柄按键，摇杆回到中间
+    held_input::release_session(&*device_ctx.read().await, session_id).await;
+
+    println!("Websocket context {} destroyed", who);
+}
+
+async fn process_message(
+    device_ctx: Arc<RwLock<DeviceCtx>>,
+    msg: Message,
+    who: SocketAddr,
+    session_id: u64,
+) -> ControlFlow<(), ()> {
+    match msg {
+        Message::Binary(d) => {
+            // 9 byte
+            // buttons -> 2
+            // hat -> 1
+            // left X, left Y, right X, right Y -> 4
+            // left trigger, right trigger -> 2
+
+            let Ok(payload) = <&[u8; GAMEPAD_REPORT_LENGTH]>::try_from(d.as_slice()) else {
+                return ControlFlow::Break(());
+            };
+            let gamepad = Gamepad::from_payload(payload);
+            let device_ctx_read = device_ctx.read().await;
+            let id = device_ctx_read.id;
+            device_ctx_read.held_inputs.set(
+                session_id,
+                held_input::Input::Gamepad,
+                !gamepad.is_neutral(),
+            );
+            device_ctx_read.jiggler.touch();
+            drop(device_ctx_read);
+            let mut join_set = JoinSet::new();
+            let device_ctx_send = device_ctx.clone();
+            join_set.spawn(async move {
+                let device_ctx_send = device_ctx_send.read().await;
+                let Some(gamepad_device) = &device_ctx_send.gamepad_device else {
+                    return ControlFlow::Break(());
+                };
+                let res = gamepad_device.send(gamepad).await;
+                METRICS.hid_report_sent(id, "gamepad", "send", res.is_ok());
+                if let Err(err) = res {
+                    log::error!("gamepad_device.send failed: {err}");
+                    device_ctx_send
+                        .hid_write_failed(hid_health::HID_DEV_GAMEPAD, &err)
+                        .await;
+                }
+                ControlFlow::Continue(())
+            });
+            join_set.spawn(async move {
+                time::sleep(Duration::from_secs(5)).await;
+                log::warn!("gamepad_device send timeout.");
+                METRICS.hid_send_timeout(id, "gamepad");
+                ControlFlow::Continue(())
+            });
+
+            let ret = join_set.join_next().await.unwrap().unwrap();
+            join_set.shutdown().await;
+            ret
+        }
+        Message::Close(c) => {
+            if let Some(cf) = c {
+                println!(
+                    ">>> {} sent close with code {} and reason `{}`",
+                    who, cf.code, cf.reason
+                );
+            } else {
+                println!(">>> {} somehow sent close message without CloseFrame", who);
+            }
+            ControlFlow::Break(())
+        }
+        _ => ControlFlow::Continue(()),
+    }
+}
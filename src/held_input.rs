@@ -0,0 +1,165 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use axum::Extension;
+use tokio::{sync::RwLock, time::Instant};
+
+use crate::{api_error, hid_health, keyboard, metrics::METRICS, DeviceCtx};
+
+// 连接在这段时间内没有收到任何消息 (包括 pong) 就认为已经断开
+pub const SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+pub const HELD_INPUT_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum Input {
+    Key(u16),
+    SysControlKey(u16),
+    AppleFn,
+    MouseButtons,
+    Gamepad,
+}
+
+struct HeldInput {
+    session_id: u64,
+    since: Instant,
+}
+
+// 记录每个 WebSocket 会话按下的按键，会话结束或按住太久时自动松开
+#[derive(Default)]
+pub struct HeldInputs {
+    inputs: Mutex<HashMap<Input, HeldInput>>,
+}
+
+static NEXT_SESSION_ID: AtomicU64 = AtomicU64::new(0);
+
+pub fn new_session_id() -> u64 {
+    NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+impl HeldInputs {
+    pub fn set(&self, session_id: u64, input: Input, pressed: bool) {
+        let mut inputs = self.inputs.lock().unwrap();
+        if pressed {
+            // 鼠标每次都会带上全部按键状态，已经按下的只更新所属会话，不重置按下的时间
+            inputs
+                .entry(input)
+                .and_modify(|held_input| held_input.session_id = session_id)
+                .or_insert(HeldInput {
+                    session_id,
+                    since: Instant::now(),
+                });
+        } else {
+            inputs.remove(&input);
+        }
+    }
+
+    fn take(&self, mut f: impl FnMut(&HeldInput) -> bool) -> Vec<Input> {
+        let mut inputs = self.inputs.lock().unwrap();
+        let taken: Vec<_> = inputs
+            .iter()
+            .filter(|(_, held_input)| f(held_input))
+            .map(|(input, _)| *input)
+            .collect();
+        for input in &taken {
+            inputs.remove(input);
+        }
+        taken
+    }
+
+    pub fn take_session(&self, session_id: u64) -> Vec<Input> {
+        self.take(|held_input| held_input.session_id == session_id)
+    }
+
+    pub fn take_expired(&self, max_hold: Duration) -> Vec<Input> {
+        self.take(|held_input| held_input.since.elapsed() > max_hold)
+    }
+
+    pub fn clear(&self) {
+        self.inputs.lock().unwrap().clear();
+    }
+}
+
+pub async fn release(device_ctx: &DeviceCtx, inputs: &[Input]) {
+    let keyboard_device = &device_ctx.keyboard_device;
+    let mut keyboard_changed = false;
+    let mut mouse_changed = false;
+    for input in inputs {
+        match *input {
+            // 正在输入文件时只从保存的按键中去掉
+            Input::Key(_) | Input::SysControlKey(_) | Input::AppleFn => {
+                keyboard_changed |= device_ctx
+                    .type_file
+                    .set_input(keyboard_device, *input, false)
+                    .await
+            }
+            Input::MouseButtons => mouse_changed = true,
+            Input::Gamepad => release_gamepad(device_ctx).await,
+        }
+    }
+    if keyboard_changed {
+        keyboard::send_keyboard(device_ctx).await;
+    }
+    if mouse_changed {
+        release_mouse(device_ctx).await;
+    }
+}
+
+async fn release_mouse(device_ctx: &DeviceCtx) {
+    let res = device_ctx.mouse_device.release_buttons().await;
+    METRICS.hid_report_sent(device_ctx.id, "mouse", "release_buttons", res.is_ok());
+    if let Err(err) = res {
+        log::error!("mouse_device.release_buttons failed: {err}");
+        device_ctx
+            .hid_write_failed(hid_health::HID_DEV_MOUSE_LEGACY, &err)
+            .await;
+    }
+}
+
+async fn release_gamepad(device_ctx: &DeviceCtx) {
+    if let Some(gamepad_device) = &device_ctx.gamepad_device {
+        let res = gamepad_device.release().await;
+        METRICS.hid_report_sent(device_ctx.id, "gamepad", "release", res.is_ok());
+        if let Err(err) = res {
+            log::error!("gamepad_device.release failed: {err}");
+            device_ctx
+                .hid_write_failed(hid_health::HID_DEV_GAMEPAD, &err)
+                .await;
+        }
+    }
+}
+
+pub async fn release_session(device_ctx: &DeviceCtx, session_id: u64) {
+    let inputs = device_ctx.held_inputs.take_session(session_id);
+    if !inputs.is_empty() {
+        log::info!("Session {session_id} closed, release {inputs:?}");
+        release(device_ctx, &inputs).await;
+    }
+}
+
+pub async fn release_all(device_ctx: &DeviceCtx) {
+    device_ctx.held_inputs.clear();
+    if device_ctx
+        .keyboard_device
+        .keyboard
+        .lock()
+        .await
+        .release_all()
+    {
+        keyboard::send_keyboard(device_ctx).await;
+    }
+    release_mouse(device_ctx).await;
+    release_gamepad(device_ctx).await;
+}
+
+pub async fn post_release_all(
+    Extension(device_ctx): Extension<Arc<RwLock<DeviceCtx>>>,
+) -> api_error::Result<String> {
+    release_all(&*device_ctx.read().await).await;
+    Ok("null".into())
+}
@@ -0,0 +1,117 @@
+use std::{collections::BTreeMap, sync::Mutex};
+
+use serde::Serialize;
+
+use usb_otg::hid::hidg;
+use util::error;
+
+pub const HID_DEV_HID_COMPOSITE: &str = "hid_composite";
+pub const HID_DEV_KEYBOARD_LEGACY: &str = "keyboard_legacy";
+pub const HID_DEV_MOUSE_LEGACY: &str = "mouse_legacy";
+pub const HID_DEV_GAMEPAD: &str = "gamepad";
+pub const HID_DEV_FIDO: &str = "fido";
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HidDevState {
+    Ok,
+    // 主机关机、拔线或挂起，等待主机重新枚举
+    HostDisconnected,
+    Recovering,
+    // 重新打开 fd 也失败了，之后会继续重试
+    Failed,
+}
+
+impl HidDevState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Ok => "ok",
+            Self::HostDisconnected => "host_disconnected",
+            Self::Recovering => "recovering",
+            Self::Failed => "failed",
+        }
+    }
+}
+
+#[derive(Clone, Serialize)]
+pub struct HidDevHealth {
+    pub state: HidDevState,
+    pub last_error: Option<String>,
+    pub errors: u64,
+    pub reopens: u64,
+}
+
+pub struct HidHealth {
+    devices: Mutex<BTreeMap<&'static str, HidDevHealth>>,
+}
+
+impl Default for HidHealth {
+    fn default() -> Self {
+        let health = Self {
+            devices: Mutex::new(BTreeMap::new()),
+        };
+        for name in [
+            HID_DEV_HID_COMPOSITE,
+            HID_DEV_KEYBOARD_LEGACY,
+            HID_DEV_MOUSE_LEGACY,
+        ] {
+            health.register(name);
+        }
+        health
+    }
+}
+
+impl HidHealth {
+    // 可选的设备启用后才加入
+    pub fn register(&self, name: &'static str) {
+        self.devices.lock().unwrap().insert(
+            name,
+            HidDevHealth {
+                state: HidDevState::Ok,
+                last_error: None,
+                errors: 0,
+                reopens: 0,
+            },
+        );
+    }
+
+    fn update(&self, name: &'static str, f: impl FnOnce(&mut HidDevHealth)) {
+        if let Some(health) = self.devices.lock().unwrap().get_mut(name) {
+            f(health);
+        }
+    }
+
+    pub fn set_ok(&self, name: &'static str) {
+        self.update(name, |health| health.state = HidDevState::Ok);
+    }
+
+    // 返回错误对应的状态
+    pub fn set_error(&self, name: &'static str, err: &error::Error) -> HidDevState {
+        let state = if hidg::is_host_disconnected(err) {
+            HidDevState::HostDisconnected
+        } else {
+            HidDevState::Recovering
+        };
+        self.update(name, |health| {
+            health.state = state;
+            health.last_error = Some(err.to_string());
+            health.errors += 1;
+        });
+        state
+    }
+
+    pub fn set_failed(&self, name: &'static str, err: &error::Error) {
+        self.update(name, |health| {
+            health.state = HidDevState::Failed;
+            health.last_error = Some(err.to_string());
+        });
+    }
+
+    pub fn reopened(&self, name: &'static str) {
+        self.update(name, |health| health.reopens += 1);
+    }
+
+    pub fn snapshot(&self) -> BTreeMap<&'static str, HidDevHealth> {
+        self.devices.lock().unwrap().clone()
+    }
+}
@@ -0,0 +1,866 @@
+use std::{
+    collections::BTreeMap,
+    fs::{File, OpenOptions},
+    io::{self, Read},
+    os::unix::fs::FileExt,
+    path::Path,
+    process::{Child, ChildStdout, Command, Stdio},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use anyhow::{anyhow, bail};
+use serde::Serialize;
+
+// 按这个大小检查全零，全零的块不写入，输出文件是稀疏的
+const SPARSE_BLOCK_SIZE: usize = 4096;
+const RAW_CHUNK_SIZE: usize = 1024 * 1024;
+const MAGIC_LENGTH: usize = 8;
+const SECTOR_SIZE: u64 = 512;
+// 损坏的镜像可能给出很大的表或者块，限制申请的内存
+const MAX_TABLE_SIZE: u64 = 32 * 1024 * 1024;
+const MAX_BLOCK_SIZE: u64 = 64 * 1024 * 1024;
+
+const QCOW2_MAGIC: &[u8] = b"QFI\xfb";
+const QCOW2_HEADER_LENGTH: usize = 104;
+const QCOW2_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+const QCOW2_COMPRESSED: u64 = 1 << 62;
+const QCOW2_ZERO: u64 = 1;
+// dirty 和压缩算法，其它不兼容特性都不支持
+const QCOW2_INCOMPAT_SUPPORTED: u64 = 0b1001;
+
+const VHD_COOKIE: &[u8] = b"conectix";
+const VHD_DYNAMIC_COOKIE: &[u8] = b"cxsparse";
+const VHD_FOOTER_LENGTH: usize = 512;
+const VHD_DYNAMIC_HEADER_LENGTH: usize = 1024;
+const VHD_TYPE_FIXED: u32 = 2;
+const VHD_TYPE_DYNAMIC: u32 = 3;
+const VHD_TYPE_DIFFERENCING: u32 = 4;
+const VHD_BAT_UNUSED: u32 = 0xffff_ffff;
+
+const VMDK_MAGIC: &[u8] = b"KDMV";
+const VMDK_HEADER_LENGTH: usize = 512;
+const VMDK_FLAG_COMPRESSED: u32 = 1 << 16;
+const VMDK_GD_AT_END: u64 = u64::MAX;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Gz,
+    Xz,
+    Zst,
+    Qcow2,
+    Vhd,
+    Vmdk,
+    Raw,
+}
+
+impl Format {
+    fn detect(magic: &[u8]) -> Self {
+        if magic.starts_with(&[0x1f, 0x8b]) {
+            Self::Gz
+        } else if magic.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0]) {
+            Self::Xz
+        } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Self::Zst
+        } else if magic.starts_with(QCOW2_MAGIC) {
+            Self::Qcow2
+        } else if magic.starts_with(VHD_COOKIE) {
+            // 固定大小的 VHD 只在末尾有 footer，按原始镜像处理
+            Self::Vhd
+        } else if magic.starts_with(VMDK_MAGIC) {
+            Self::Vmdk
+        } else {
+            Self::Raw
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Gz => "gz",
+            Self::Xz => "xz",
+            Self::Zst => "zst",
+            Self::Qcow2 => "qcow2",
+            Self::Vhd => "vhd",
+            Self::Vmdk => "vmdk",
+            Self::Raw => "raw",
+        }
+    }
+}
+
+// 转换过程中的状态，转换在单独的线程里进行
+#[derive(Default)]
+pub struct ConvertStatus {
+    formats: Mutex<Vec<&'static str>>,
+    // 0 表示还不知道
+    image_size: AtomicU64,
+    written_bytes: AtomicU64,
+}
+
+#[derive(Clone, Serialize)]
+pub struct ConvertStatusOutput {
+    // 从外到内检测到的格式，例如 ["xz", "qcow2"]
+    formats: Vec<&'static str>,
+    image_size: Option<u64>,
+    // 已经转换出的原始数据，包括没有实际写入的全零块
+    written_bytes: u64,
+}
+
+impl ConvertStatus {
+    pub fn output(&self) -> ConvertStatusOutput {
+        let image_size = self.image_size.load(Ordering::Relaxed);
+        ConvertStatusOutput {
+            formats: self.formats.lock().unwrap().clone(),
+            image_size: (image_size != 0).then_some(image_size),
+            written_bytes: self.written_bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// 写输出文件失败，和镜像本身的错误区分开
+#[derive(Debug)]
+pub struct OutputError(pub io::Error);
+
+impl std::fmt::Display for OutputError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl std::error::Error for OutputError {}
+
+struct SparseFile<'a> {
+    file: File,
+    size: u64,
+    status: &'a ConvertStatus,
+}
+
+impl<'a> SparseFile<'a> {
+    fn create(path: &Path, status: &'a ConvertStatus) -> Result<Self, OutputError> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .map_err(OutputError)?;
+        Ok(Self {
+            file,
+            size: 0,
+            status,
+        })
+    }
+
+    fn set_size(&mut self, size: u64) -> anyhow::Result<()> {
+        if size > i64::MAX as u64 {
+            bail!("image size {size} is too large");
+        }
+        self.size = size;
+        self.status.image_size.store(size, Ordering::Relaxed);
+        Ok(())
+    }
+
+    // 每个位置只写一次，跳过全零块不会留下旧数据
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> Result<(), OutputError> {
+        for (i, block) in data.chunks(SPARSE_BLOCK_SIZE).enumerate() {
+            if block.iter().any(|byte| *byte != 0) {
+                self.file
+                    .write_all_at(block, offset + (i * SPARSE_BLOCK_SIZE) as u64)
+                    .map_err(OutputError)?;
+            }
+        }
+        self.status
+            .written_bytes
+            .fetch_add(data.len() as u64, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn finish(self) -> Result<(), OutputError> {
+        self.file.set_len(self.size).map_err(OutputError)?;
+        self.file.sync_all().map_err(OutputError)
+    }
+}
+
+// 读满 buf，返回读到的长度，只有到了结尾才会小于 buf 的长度
+fn read_full(input: &mut dyn Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match input.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(len)
+}
+
+// 外部解压程序的输出，结束时检查退出状态
+struct ChildReader {
+    program: &'static str,
+    child: Child,
+    stdout: ChildStdout,
+}
+
+impl Read for ChildReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.stdout.read(buf)?;
+        if len == 0 && !buf.is_empty() {
+            let status = self.child.wait()?;
+            if !status.success() {
+                let mut stderr = String::new();
+                if let Some(mut child_stderr) = self.child.stderr.take() {
+                    let _ = child_stderr.read_to_string(&mut stderr);
+                }
+                return Err(io::Error::other(format!(
+                    "{} exited with {status}: {}",
+                    self.program,
+                    stderr.trim()
+                )));
+            }
+        }
+        Ok(len)
+    }
+}
+
+impl Drop for ChildReader {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+// 系统里的 gzip、xz、zstd 都支持 -dc，输入由单独的线程写入
+fn decompress(
+    mut input: Box<dyn Read + Send>,
+    program: &'static str,
+) -> anyhow::Result<Box<dyn Read + Send>> {
+    let mut child = Command::new(program)
+        .arg("-dc")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|err| anyhow!("run {program}: {err}"))?;
+    let mut stdin = child.stdin.take().unwrap();
+    let stdout = child.stdout.take().unwrap();
+    std::thread::spawn(move || {
+        // 解压程序提前退出时写入会失败
+        if let Err(err) = io::copy(&mut input, &mut stdin) {
+            log::debug!("{program} input closed: {err}");
+        }
+    });
+    Ok(Box::new(ChildReader {
+        program,
+        child,
+        stdout,
+    }))
+}
+
+// 检测格式，解压后继续检测，直到得到磁盘镜像格式
+pub fn convert(
+    mut input: Box<dyn Read + Send>,
+    path: &Path,
+    raw: bool,
+    status: &ConvertStatus,
+) -> anyhow::Result<()> {
+    let mut output = SparseFile::create(path, status)?;
+    loop {
+        let mut magic = [0_u8; MAGIC_LENGTH];
+        let len = read_full(&mut input, &mut magic)?;
+        input = Box::new(io::Cursor::new(magic[..len].to_vec()).chain(input));
+        let format = if raw {
+            Format::Raw
+        } else {
+            Format::detect(&magic[..len])
+        };
+        status.formats.lock().unwrap().push(format.name());
+        input = match format {
+            Format::Gz => decompress(input, "gzip")?,
+            Format::Xz => decompress(input, "xz")?,
+            Format::Zst => decompress(input, "zstd")?,
+            Format::Qcow2 => break convert_qcow2(StreamReader::new(input, format), &mut output)?,
+            Format::Vhd => break convert_vhd(StreamReader::new(input, format), &mut output)?,
+            Format::Vmdk => break convert_vmdk(StreamReader::new(input, format), &mut output)?,
+            Format::Raw => break convert_raw(input, &mut output)?,
+        };
+    }
+    output.finish()?;
+    Ok(())
+}
+
+fn convert_raw(mut input: Box<dyn Read + Send>, output: &mut SparseFile) -> anyhow::Result<()> {
+    let mut buf = vec![0_u8; RAW_CHUNK_SIZE];
+    let mut offset = 0;
+    // 末尾的全零块没有写入文件，footer 要从读到的数据里取
+    let mut footer = Vec::with_capacity(VHD_FOOTER_LENGTH * 2);
+    loop {
+        let len = read_full(&mut input, &mut buf)?;
+        if len == 0 {
+            break;
+        }
+        output.write_at(offset, &buf[..len])?;
+        offset += len as u64;
+        footer.extend_from_slice(&buf[len.saturating_sub(VHD_FOOTER_LENGTH)..len]);
+        footer.drain(..footer.len().saturating_sub(VHD_FOOTER_LENGTH));
+    }
+    output.set_size(offset)?;
+    // 固定大小的 VHD 是原始数据加上末尾的 footer
+    if offset > VHD_FOOTER_LENGTH as u64 {
+        let data_size = offset - VHD_FOOTER_LENGTH as u64;
+        if footer.starts_with(VHD_COOKIE)
+            && be32(&footer, 60) == VHD_TYPE_FIXED
+            && be64(&footer, 48) == data_size
+        {
+            output.status.formats.lock().unwrap().push("vhd");
+            output.set_size(data_size)?;
+        }
+    }
+    Ok(())
+}
+
+// 表的大小来自镜像文件，要检查溢出和上限
+fn table_size(entries: u64, entry_size: u64, format: Format) -> anyhow::Result<usize> {
+    match entries.checked_mul(entry_size) {
+        Some(size) if size <= MAX_TABLE_SIZE => Ok(size as usize),
+        _ => bail!(
+            "{} image table with {entries} entries is too large",
+            format.name()
+        ),
+    }
+}
+
+fn be32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn be64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_be_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+fn le32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn le64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+// 只能顺序读取，元数据必须出现在它描述的数据之前
+struct StreamReader {
+    input: Box<dyn Read + Send>,
+    format: Format,
+    pos: u64,
+}
+
+impl StreamReader {
+    fn new(input: Box<dyn Read + Send>, format: Format) -> Self {
+        Self {
+            input,
+            format,
+            pos: 0,
+        }
+    }
+
+    fn check_ahead(&self, offset: u64) -> anyhow::Result<()> {
+        if offset < self.pos {
+            bail!(
+                "{} image can not be converted while streaming, offset {offset} is referenced after {}",
+                self.format.name(),
+                self.pos
+            );
+        }
+        Ok(())
+    }
+
+    fn skip_to(&mut self, offset: u64) -> anyhow::Result<()> {
+        self.check_ahead(offset)?;
+        let len = offset - self.pos;
+        if io::copy(&mut (&mut self.input).take(len), &mut io::sink())? != len {
+            bail!("{} image is truncated", self.format.name());
+        }
+        self.pos = offset;
+        Ok(())
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> anyhow::Result<()> {
+        if read_full(&mut self.input, buf)? != buf.len() {
+            bail!("{} image is truncated", self.format.name());
+        }
+        self.pos += buf.len() as u64;
+        Ok(())
+    }
+
+    // 文件末尾的数据块可能不完整，不足的部分补零
+    fn read_padded(&mut self, buf: &mut [u8]) -> anyhow::Result<()> {
+        let len = read_full(&mut self.input, buf)?;
+        buf[len..].fill(0);
+        self.pos += len as u64;
+        Ok(())
+    }
+}
+
+// 等待读取的表和数据，key 是在镜像文件中的位置
+enum TableItem {
+    // 一级表
+    Directory,
+    // 二级表，覆盖从这个位置开始的虚拟磁盘
+    Table(u64),
+    // 数据块可能被多个位置共享
+    Data(Vec<u64>),
+}
+
+fn add_data(
+    items: &mut BTreeMap<u64, TableItem>,
+    reader: &StreamReader,
+    offset: u64,
+    guest_offset: u64,
+) -> anyhow::Result<()> {
+    reader.check_ahead(offset)?;
+    match items
+        .entry(offset)
+        .or_insert_with(|| TableItem::Data(Vec::new()))
+    {
+        TableItem::Data(guest_offsets) => guest_offsets.push(guest_offset),
+        _ => bail!(
+            "{} image is corrupted, offset {offset} is used as data and metadata",
+            reader.format.name()
+        ),
+    }
+    Ok(())
+}
+
+fn add_table(
+    items: &mut BTreeMap<u64, TableItem>,
+    reader: &StreamReader,
+    offset: u64,
+    guest_offset: u64,
+) -> anyhow::Result<()> {
+    reader.check_ahead(offset)?;
+    if items
+        .insert(offset, TableItem::Table(guest_offset))
+        .is_some()
+    {
+        bail!(
+            "{} image is corrupted, offset {offset} is used more than once",
+            reader.format.name()
+        );
+    }
+    Ok(())
+}
+
+// 写入数据块，超出虚拟磁盘大小的部分丢弃
+fn write_data(output: &mut SparseFile, guest_offsets: &[u64], data: &[u8]) -> anyhow::Result<()> {
+    for guest_offset in guest_offsets {
+        let len = output
+            .size
+            .saturating_sub(*guest_offset)
+            .min(data.len() as u64) as usize;
+        if len > 0 {
+            output.write_at(*guest_offset, &data[..len])?;
+        }
+    }
+    Ok(())
+}
+
+fn convert_qcow2(mut reader: StreamReader, output: &mut SparseFile) -> anyhow::Result<()> {
+    let mut header = [0_u8; QCOW2_HEADER_LENGTH];
+    reader.read_exact(&mut header)?;
+    let version = be32(&header, 4);
+    if !(2..=3).contains(&version) {
+        bail!("unsupported qcow2 version {version}");
+    }
+    if be64(&header, 8) != 0 {
+        bail!("qcow2 images with a backing file are not supported");
+    }
+    let cluster_bits = be32(&header, 20);
+    if !(9..=21).contains(&cluster_bits) {
+        bail!("invalid qcow2 cluster bits {cluster_bits}");
+    }
+    if be32(&header, 32) != 0 {
+        bail!("encrypted qcow2 images are not supported");
+    }
+    let incompatible_features = if version == 3 { be64(&header, 72) } else { 0 };
+    if incompatible_features & !QCOW2_INCOMPAT_SUPPORTED != 0 {
+        bail!("unsupported qcow2 features {incompatible_features:#x}");
+    }
+    output.set_size(be64(&header, 24))?;
+    let cluster_size = 1_u64 << cluster_bits;
+    let l1_size = table_size(be32(&header, 36) as u64, 8, Format::Qcow2)?;
+    // 一个二级表覆盖的虚拟磁盘大小
+    let l2_coverage = cluster_size / 8 * cluster_size;
+
+    let mut items = BTreeMap::new();
+    if l1_size > 0 {
+        reader.check_ahead(be64(&header, 40))?;
+        items.insert(be64(&header, 40), TableItem::Directory);
+    }
+    let mut buf = vec![0_u8; cluster_size as usize];
+    while let Some((offset, item)) = items.pop_first() {
+        reader.skip_to(offset)?;
+        match item {
+            TableItem::Directory => {
+                let mut l1 = vec![0_u8; l1_size];
+                reader.read_exact(&mut l1)?;
+                for (i, entry) in l1.chunks_exact(8).enumerate() {
+                    let l2_offset = be64(entry, 0) & QCOW2_OFFSET_MASK;
+                    if l2_offset != 0 {
+                        add_table(&mut items, &reader, l2_offset, i as u64 * l2_coverage)?;
+                    }
+                }
+            }
+            TableItem::Table(guest_offset) => {
+                reader.read_exact(&mut buf)?;
+                for (i, entry) in buf.chunks_exact(8).enumerate() {
+                    let entry = be64(entry, 0);
+                    if entry & QCOW2_COMPRESSED != 0 {
+                        bail!("compressed qcow2 clusters are not supported");
+                    }
+                    let data_offset = entry & QCOW2_OFFSET_MASK;
+                    let data_guest_offset = guest_offset + i as u64 * cluster_size;
+                    if entry & QCOW2_ZERO != 0
+                        || data_offset == 0
+                        || data_guest_offset >= output.size
+                    {
+                        continue;
+                    }
+                    add_data(&mut items, &reader, data_offset, data_guest_offset)?;
+                }
+            }
+            TableItem::Data(guest_offsets) => {
+                reader.read_padded(&mut buf)?;
+                write_data(output, &guest_offsets, &buf)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// 只支持动态 VHD，固定大小的在 convert_raw 中处理
+fn convert_vhd(mut reader: StreamReader, output: &mut SparseFile) -> anyhow::Result<()> {
+    let mut footer = [0_u8; VHD_FOOTER_LENGTH];
+    reader.read_exact(&mut footer)?;
+    match be32(&footer, 60) {
+        VHD_TYPE_DYNAMIC => {}
+        VHD_TYPE_DIFFERENCING => bail!("differencing vhd images are not supported"),
+        disk_type => bail!("unsupported vhd disk type {disk_type}"),
+    }
+    output.set_size(be64(&footer, 48))?;
+
+    reader.skip_to(be64(&footer, 16))?;
+    let mut header = [0_u8; VHD_DYNAMIC_HEADER_LENGTH];
+    reader.read_exact(&mut header)?;
+    if !header.starts_with(VHD_DYNAMIC_COOKIE) {
+        bail!("invalid vhd dynamic disk header");
+    }
+    let block_size = be32(&header, 32) as u64;
+    if block_size == 0 || block_size > MAX_BLOCK_SIZE || !block_size.is_multiple_of(SECTOR_SIZE) {
+        bail!("invalid vhd block size {block_size}");
+    }
+    let sectors_per_block = block_size / SECTOR_SIZE;
+    // 每个块前面是扇区位图，按扇区对齐
+    let bitmap_size = sectors_per_block.div_ceil(8).next_multiple_of(SECTOR_SIZE);
+
+    reader.skip_to(be64(&header, 16))?;
+    let mut bat = vec![0_u8; table_size(be32(&header, 28) as u64, 4, Format::Vhd)?];
+    reader.read_exact(&mut bat)?;
+    let mut blocks = BTreeMap::new();
+    for (i, entry) in bat.chunks_exact(4).enumerate() {
+        let sector = be32(entry, 0);
+        if sector != VHD_BAT_UNUSED {
+            reader.check_ahead(sector as u64 * SECTOR_SIZE)?;
+            blocks.insert(sector as u64 * SECTOR_SIZE, i as u64 * block_size);
+        }
+    }
+
+    let mut bitmap = vec![0_u8; bitmap_size as usize];
+    let mut buf = vec![0_u8; block_size as usize];
+    for (offset, guest_offset) in blocks {
+        reader.skip_to(offset)?;
+        reader.read_exact(&mut bitmap)?;
+        reader.read_padded(&mut buf)?;
+        if guest_offset >= output.size {
+            continue;
+        }
+        // 位图中为 0 的扇区是空的，高位在前
+        let mut sector = 0;
+        while sector < sectors_per_block {
+            let present = |sector: u64| bitmap[(sector / 8) as usize] & (0x80 >> (sector % 8)) != 0;
+            if !present(sector) {
+                sector += 1;
+                continue;
+            }
+            let start = sector;
+            while sector < sectors_per_block && present(sector) {
+                sector += 1;
+            }
+            write_data(
+                output,
+                &[guest_offset + start * SECTOR_SIZE],
+                &buf[(start * SECTOR_SIZE) as usize..(sector * SECTOR_SIZE) as usize],
+            )?;
+        }
+    }
+    Ok(())
+}
+
+// 只支持 monolithicSparse，streamOptimized 的数据块是压缩的
+fn convert_vmdk(mut reader: StreamReader, output: &mut SparseFile) -> anyhow::Result<()> {
+    let mut header = [0_u8; VMDK_HEADER_LENGTH];
+    reader.read_exact(&mut header)?;
+    let flags = le32(&header, 8);
+    let compress_algorithm = u16::from_le_bytes([header[77], header[78]]);
+    let gd_offset = le64(&header, 56);
+    if flags & VMDK_FLAG_COMPRESSED != 0 || compress_algorithm != 0 || gd_offset == VMDK_GD_AT_END {
+        bail!("stream optimized vmdk images are not supported");
+    }
+    let invalid_header = || anyhow!("invalid vmdk header");
+    let capacity = le64(&header, 12)
+        .checked_mul(SECTOR_SIZE)
+        .ok_or_else(invalid_header)?;
+    let grain_size = le64(&header, 20)
+        .checked_mul(SECTOR_SIZE)
+        .filter(|grain_size| (1..=MAX_BLOCK_SIZE).contains(grain_size))
+        .ok_or_else(invalid_header)?;
+    let gd_offset = gd_offset
+        .checked_mul(SECTOR_SIZE)
+        .ok_or_else(invalid_header)?;
+    let gtes_per_gt = le32(&header, 44) as u64;
+    if gtes_per_gt == 0 {
+        return Err(invalid_header());
+    }
+    let gt_size = table_size(gtes_per_gt, 4, Format::Vmdk)?;
+    output.set_size(capacity)?;
+    let gt_coverage = gtes_per_gt * grain_size;
+    let gd_entries = output.size.div_ceil(gt_coverage);
+
+    let mut items = BTreeMap::new();
+    reader.check_ahead(gd_offset)?;
+    items.insert(gd_offset, TableItem::Directory);
+    let mut gt = vec![0_u8; gt_size];
+    let mut buf = vec![0_u8; grain_size as usize];
+    while let Some((offset, item)) = items.pop_first() {
+        reader.skip_to(offset)?;
+        match item {
+            TableItem::Directory => {
+                let mut gd = vec![0_u8; table_size(gd_entries, 4, Format::Vmdk)?];
+                reader.read_exact(&mut gd)?;
+                for (i, entry) in gd.chunks_exact(4).enumerate() {
+                    let gt_sector = le32(entry, 0) as u64;
+                    if gt_sector != 0 {
+                        add_table(
+                            &mut items,
+                            &reader,
+                            gt_sector * SECTOR_SIZE,
+                            i as u64 * gt_coverage,
+                        )?;
+                    }
+                }
+            }
+            TableItem::Table(guest_offset) => {
+                reader.read_exact(&mut gt)?;
+                for (i, entry) in gt.chunks_exact(4).enumerate() {
+                    // 0 是未分配，1 是全零的块
+                    let grain_sector = le32(entry, 0) as u64;
+                    let grain_guest_offset = guest_offset + i as u64 * grain_size;
+                    if grain_sector <= 1 || grain_guest_offset >= output.size {
+                        continue;
+                    }
+                    add_data(
+                        &mut items,
+                        &reader,
+                        grain_sector * SECTOR_SIZE,
+                        grain_guest_offset,
+                    )?;
+                }
+            }
+            TableItem::Data(guest_offsets) => {
+                reader.read_padded(&mut buf)?;
+                write_data(output, &guest_offsets, &buf)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn convert_bytes(name: &str, data: Vec<u8>) -> anyhow::Result<(Vec<u8>, Vec<&'static str>)> {
+        let path =
+            std::env::temp_dir().join(format!("ip-kvm-convert-{}-{name}", std::process::id()));
+        let status = ConvertStatus::default();
+        let res = convert(Box::new(io::Cursor::new(data)), &path, false, &status)
+            .and_then(|()| Ok(std::fs::read(&path)?));
+        let _ = std::fs::remove_file(&path);
+        Ok((res?, status.output().formats))
+    }
+
+    fn put(buf: &mut Vec<u8>, offset: usize, data: &[u8]) {
+        if buf.len() < offset + data.len() {
+            buf.resize(offset + data.len(), 0);
+        }
+        buf[offset..offset + data.len()].copy_from_slice(data);
+    }
+
+    #[test]
+    fn raw_zero_tail() {
+        let mut image = vec![1_u8; 4096];
+        image.resize(4096 + RAW_CHUNK_SIZE + 1000, 0);
+        let (output, formats) = convert_bytes("raw", image.clone()).unwrap();
+        assert_eq!(output, image);
+        assert_eq!(formats, ["raw"]);
+    }
+
+    #[test]
+    fn fixed_vhd() {
+        let mut image = vec![1_u8; 4096];
+        let mut footer = [0_u8; VHD_FOOTER_LENGTH];
+        put_footer(&mut footer, 4096, VHD_TYPE_FIXED, u64::MAX);
+        image.extend_from_slice(&footer);
+        let (output, formats) = convert_bytes("fixed-vhd", image).unwrap();
+        assert_eq!(output, vec![1_u8; 4096]);
+        assert_eq!(formats, ["raw", "vhd"]);
+    }
+
+    fn put_footer(footer: &mut [u8], size: u64, disk_type: u32, data_offset: u64) {
+        footer[..8].copy_from_slice(VHD_COOKIE);
+        footer[16..24].copy_from_slice(&data_offset.to_be_bytes());
+        footer[48..56].copy_from_slice(&size.to_be_bytes());
+        footer[60..64].copy_from_slice(&disk_type.to_be_bytes());
+    }
+
+    #[test]
+    fn qcow2() {
+        // 512 字节的簇，一级表在 512，二级表在 1024，数据从 1536 开始
+        let mut image = QCOW2_MAGIC.to_vec();
+        put(&mut image, 4, &2_u32.to_be_bytes());
+        put(&mut image, 20, &9_u32.to_be_bytes());
+        put(&mut image, 24, &65536_u64.to_be_bytes());
+        put(&mut image, 36, &2_u32.to_be_bytes());
+        put(&mut image, 40, &512_u64.to_be_bytes());
+        put(&mut image, 512, &(1024_u64 | 1 << 63).to_be_bytes());
+        put(&mut image, 1024, &(1536_u64 | 1 << 63).to_be_bytes());
+        put(&mut image, 1024 + 8, &(2048_u64 | QCOW2_ZERO).to_be_bytes());
+        put(&mut image, 1024 + 5 * 8, &2048_u64.to_be_bytes());
+        put(&mut image, 1536, &[0xaa; 512]);
+        // 最后一个簇不完整
+        put(&mut image, 2048, &[0xbb; 100]);
+
+        let mut expected = vec![0_u8; 65536];
+        put(&mut expected, 0, &[0xaa; 512]);
+        put(&mut expected, 5 * 512, &[0xbb; 100]);
+        let (output, formats) = convert_bytes("qcow2", image).unwrap();
+        assert_eq!(output, expected);
+        assert_eq!(formats, ["qcow2"]);
+    }
+
+    #[test]
+    fn qcow2_table_too_large() {
+        let mut image = QCOW2_MAGIC.to_vec();
+        put(&mut image, 4, &2_u32.to_be_bytes());
+        put(&mut image, 20, &9_u32.to_be_bytes());
+        put(&mut image, 24, &65536_u64.to_be_bytes());
+        put(&mut image, 36, &u32::MAX.to_be_bytes());
+        put(&mut image, 40, &512_u64.to_be_bytes());
+        put(&mut image, 512, &[0; 512]);
+        assert!(convert_bytes("qcow2-large", image).is_err());
+    }
+
+    #[test]
+    fn dynamic_vhd() {
+        // 动态头在 512，BAT 在 1536，4096 字节的块，第二个块在 2048
+        let mut image = vec![0_u8; VHD_FOOTER_LENGTH];
+        put_footer(&mut image, 16384, VHD_TYPE_DYNAMIC, 512);
+        put(&mut image, 512, VHD_DYNAMIC_COOKIE);
+        put(&mut image, 512 + 16, &1536_u64.to_be_bytes());
+        put(&mut image, 512 + 28, &4_u32.to_be_bytes());
+        put(&mut image, 512 + 32, &4096_u32.to_be_bytes());
+        put(&mut image, 1536, &[0xff; 16]);
+        put(&mut image, 1536 + 4, &4_u32.to_be_bytes());
+        // 只有第 0 和第 2 个扇区有数据
+        put(&mut image, 2048, &[0b1010_0000]);
+        put(&mut image, 2560, &[0xcc; 4096]);
+
+        let mut expected = vec![0_u8; 16384];
+        put(&mut expected, 4096, &[0xcc; 512]);
+        put(&mut expected, 4096 + 1024, &[0xcc; 512]);
+        let (output, formats) = convert_bytes("vhd", image).unwrap();
+        assert_eq!(output, expected);
+        assert_eq!(formats, ["vhd"]);
+    }
+
+    #[test]
+    fn dynamic_vhd_block_too_large() {
+        let mut image = vec![0_u8; VHD_FOOTER_LENGTH];
+        put_footer(&mut image, 16384, VHD_TYPE_DYNAMIC, 512);
+        put(&mut image, 512, VHD_DYNAMIC_COOKIE);
+        put(&mut image, 512 + 16, &1536_u64.to_be_bytes());
+        put(&mut image, 512 + 28, &4_u32.to_be_bytes());
+        put(&mut image, 512 + 32, &0xffff_fe00_u32.to_be_bytes());
+        put(&mut image, 1536, &[0xff; 16]);
+        assert!(convert_bytes("vhd-large", image).is_err());
+    }
+
+    fn vmdk_header(capacity: u64, grain_size: u64, gd_offset: u64) -> Vec<u8> {
+        let mut image = VMDK_MAGIC.to_vec();
+        put(&mut image, 4, &1_u32.to_le_bytes());
+        put(&mut image, 12, &capacity.to_le_bytes());
+        put(&mut image, 20, &grain_size.to_le_bytes());
+        put(&mut image, 44, &4_u32.to_le_bytes());
+        put(&mut image, 56, &gd_offset.to_le_bytes());
+        put(&mut image, VMDK_HEADER_LENGTH - 1, &[0]);
+        image
+    }
+
+    #[test]
+    fn vmdk() {
+        // 512 字节的 grain，每个表 4 项，目录在扇区 1，表在扇区 2
+        let mut image = vmdk_header(16, 1, 1);
+        put(&mut image, 512 + 4, &2_u32.to_le_bytes());
+        for (i, grain_sector) in [0_u32, 1, 3, 0].iter().enumerate() {
+            put(&mut image, 1024 + i * 4, &grain_sector.to_le_bytes());
+        }
+        put(&mut image, 1536, &[0x5a; 512]);
+
+        let mut expected = vec![0_u8; 8192];
+        put(&mut expected, 2048 + 2 * 512, &[0x5a; 512]);
+        let (output, formats) = convert_bytes("vmdk", image).unwrap();
+        assert_eq!(output, expected);
+        assert_eq!(formats, ["vmdk"]);
+    }
+
+    #[test]
+    fn vmdk_sizes_overflow() {
+        for (i, (capacity, grain_size, gd_offset)) in
+            [(u64::MAX, 1, 1), (16, u64::MAX, 1), (16, 1, u64::MAX - 1)]
+                .into_iter()
+                .enumerate()
+        {
+            let image = vmdk_header(capacity, grain_size, gd_offset);
+            assert!(convert_bytes(&format!("vmdk-overflow-{i}"), image).is_err());
+        }
+    }
+
+    #[test]
+    fn output_errors() {
+        let path = std::env::temp_dir()
+            .join(format!("ip-kvm-convert-{}-missing", std::process::id()))
+            .join("image");
+        let status = ConvertStatus::default();
+        let err = convert(
+            Box::new(io::Cursor::new(vec![1; 10])),
+            &path,
+            false,
+            &status,
+        )
+        .unwrap_err();
+        assert!(err.is::<OutputError>());
+
+        let image = vmdk_header(u64::MAX, 1, 1);
+        let err = convert_bytes("vmdk-output-error", image).unwrap_err();
+        assert!(!err.is::<OutputError>());
+    }
+}
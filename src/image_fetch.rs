@@ -0,0 +1,893 @@
+use std::{
+    collections::BTreeMap,
+    io::SeekFrom,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use axum::{
+    body::Body,
+    extract::{self, State},
+    http::{header, HeaderMap, Request, Response, StatusCode, Uri},
+    Json,
+};
+use futures::StreamExt;
+use hyper::body::Incoming;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::{
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    task::AbortHandle,
+    time,
+};
+
+use crate::{
+    api_error::{self, ApiError},
+    mass_storage, AppState, Client,
+};
+
+const FETCH_RETRY_MAX: u32 = 5;
+const FETCH_RETRY_DELAY: Duration = Duration::from_secs(5);
+// 连接和每次读取的超时
+const FETCH_READ_TIMEOUT: Duration = Duration::from_secs(60);
+const FETCH_REDIRECT_MAX: usize = 5;
+const CHECKSUM_FILE_SIZE_MAX: usize = 1024 * 1024;
+const HASH_BUFFER_SIZE: usize = 1024 * 1024;
+// 保留的已结束任务数
+const FETCH_HISTORY_MAX: usize = 32;
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FetchState {
+    Running,
+    Done,
+    Failed,
+    Cancelled,
+}
+
+#[derive(Clone, Serialize)]
+pub struct FetchOutput {
+    id: u64,
+    url: String,
+    image_name: String,
+    state: FetchState,
+    // 包括之前下载过的部分
+    received_bytes: u64,
+    total_bytes: Option<u64>,
+    attempts: u32,
+    // 下载完成后计算出的 SHA-256
+    sha256: Option<String>,
+    error: Option<String>,
+}
+
+struct FetchJob {
+    output: Mutex<FetchOutput>,
+    abort_handle: Mutex<Option<AbortHandle>>,
+}
+
+impl FetchJob {
+    fn update(&self, f: impl FnOnce(&mut FetchOutput)) {
+        f(&mut self.output.lock().unwrap());
+    }
+
+    fn is_running(&self) -> bool {
+        self.output.lock().unwrap().state == FetchState::Running
+    }
+}
+
+#[derive(Default)]
+pub struct ImageFetches {
+    next_id: AtomicU64,
+    jobs: Mutex<BTreeMap<u64, Arc<FetchJob>>>,
+}
+
+impl ImageFetches {
+    pub fn is_fetching(&self, image_name: &str) -> bool {
+        is_fetching(&self.jobs.lock().unwrap(), image_name)
+    }
+}
+
+fn is_fetching(jobs: &BTreeMap<u64, Arc<FetchJob>>, image_name: &str) -> bool {
+    jobs.values()
+        .any(|job| job.is_running() && job.output.lock().unwrap().image_name == image_name)
+}
+
+// 下载失败时是否值得重试
+enum DownloadError {
+    Retry(anyhow::Error),
+    Fatal(anyhow::Error),
+}
+
+impl<E: Into<anyhow::Error>> From<E> for DownloadError {
+    fn from(err: E) -> Self {
+        Self::Retry(err.into())
+    }
+}
+
+fn fatal(err: anyhow::Error) -> DownloadError {
+    DownloadError::Fatal(err)
+}
+
+fn check_url(url: &Uri) -> anyhow::Result<()> {
+    match url.scheme_str() {
+        Some("http" | "https") => Ok(()),
+        _ => anyhow::bail!("invalid url: {url}"),
+    }
+}
+
+// Location 可以是相对地址
+fn resolve_location(base: &Uri, location: &str) -> anyhow::Result<Uri> {
+    if location.contains("://") {
+        return Ok(location.parse()?);
+    }
+    if location.starts_with("//") {
+        return Ok(format!("{}:{location}", base.scheme_str().unwrap_or("http")).parse()?);
+    }
+    let path = if location.starts_with('/') {
+        location.to_string()
+    } else {
+        let base_path = base.path();
+        format!(
+            "{}{location}",
+            &base_path[..=base_path.rfind('/').unwrap_or(0)]
+        )
+    };
+    Ok(Uri::builder()
+        .scheme(base.scheme_str().unwrap_or("http"))
+        .authority(base.authority().map_or("", |authority| authority.as_str()))
+        .path_and_query(path)
+        .build()?)
+}
+
+// GET 请求，跟随重定向，offset 不为 0 时从这个位置继续下载，
+// 文件和 if_range 不一致时服务器会返回整个文件
+async fn get(
+    client: &Client,
+    url: &str,
+    offset: u64,
+    if_range: Option<&str>,
+) -> Result<Response<Incoming>, DownloadError> {
+    let mut url: Uri = url
+        .parse()
+        .map_err(|err: axum::http::uri::InvalidUri| fatal(err.into()))?;
+    for _ in 0..=FETCH_REDIRECT_MAX {
+        check_url(&url).map_err(fatal)?;
+        let mut req = Request::get(&url);
+        if offset > 0 {
+            req = req.header(header::RANGE, format!("bytes={offset}-"));
+            if let Some(if_range) = if_range {
+                req = req.header(header::IF_RANGE, if_range);
+            }
+        }
+        let res =
+            time::timeout(FETCH_READ_TIMEOUT, client.request(req.body(Body::empty())?)).await??;
+        if !res.status().is_redirection() {
+            return Ok(res);
+        }
+        let location = res
+            .headers()
+            .get(header::LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .ok_or_else(|| {
+                fatal(anyhow::anyhow!(
+                    "{} redirect without Location",
+                    res.status()
+                ))
+            })?;
+        url = resolve_location(&url, location).map_err(fatal)?;
+    }
+    Err(fatal(anyhow::anyhow!("too many redirects")))
+}
+
+// URL 路径的最后一部分
+fn url_file_name(url: &str) -> Option<String> {
+    let url: Uri = url.parse().ok()?;
+    let name = url.path().rsplit('/').next()?;
+    (!name.is_empty()).then(|| name.to_string())
+}
+
+fn is_sha256(hash: &str) -> bool {
+    hash.len() == 64 && hash.chars().all(|ch| ch.is_ascii_hexdigit())
+}
+
+// 支持 sha256sum 的输出 `hash  name` 和 BSD 格式 `SHA256 (name) = hash`，
+// 只有 hash 没有文件名的行不检查文件名
+fn find_checksum(text: &str, file_name: &str) -> Option<String> {
+    let mut checksums = Vec::new();
+    for line in text.lines().map(str::trim) {
+        let (hash, name) = if let Some(rest) = line.strip_prefix("SHA256 (") {
+            let Some((name, hash)) = rest.split_once(") = ") else {
+                continue;
+            };
+            (hash.trim(), name)
+        } else {
+            let mut parts = line.splitn(2, char::is_whitespace);
+            let hash = parts.next().unwrap_or_default();
+            let name = parts
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .trim_start_matches('*');
+            (hash, name)
+        };
+        if is_sha256(hash) {
+            checksums.push((hash.to_ascii_lowercase(), name));
+        }
+    }
+    checksums
+        .into_iter()
+        .find(|(_, name)| {
+            name.is_empty() || *name == file_name || name.ends_with(&format!("/{file_name}"))
+        })
+        .map(|(hash, _)| hash)
+}
+
+async fn fetch_checksum(client: &Client, url: &str, file_name: &str) -> anyhow::Result<String> {
+    let res = get(client, url, 0, None).await.map_err(|err| match err {
+        DownloadError::Retry(err) | DownloadError::Fatal(err) => err,
+    })?;
+    if res.status() != StatusCode::OK {
+        anyhow::bail!("get {url} failed: {}", res.status());
+    }
+    let body = time::timeout(
+        FETCH_READ_TIMEOUT,
+        axum::body::to_bytes(Body::new(res.into_body()), CHECKSUM_FILE_SIZE_MAX),
+    )
+    .await??;
+    find_checksum(&String::from_utf8_lossy(&body), file_name)
+        .ok_or_else(|| anyhow::anyhow!("no sha256 for {file_name} in {url}"))
+}
+
+fn header_str(headers: &HeaderMap, name: header::HeaderName) -> Option<&str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+// 未完成的下载来自哪里，继续下载前用来确认远程文件没有变化
+#[derive(Serialize, Deserialize)]
+struct DownloadSource {
+    url: String,
+    // 强 ETag 或者 Last-Modified，用作 If-Range
+    validator: Option<String>,
+}
+
+impl DownloadSource {
+    async fn load(path: &Path) -> Option<Self> {
+        let data = tokio::fs::read(path).await.ok()?;
+        serde_json::from_slice(&data).ok()
+    }
+
+    async fn save(&self, path: &Path) -> anyhow::Result<()> {
+        tokio::fs::write(path, serde_json::to_vec(self)?).await?;
+        Ok(())
+    }
+}
+
+// 弱 ETag 不能用于 If-Range
+fn validator(headers: &HeaderMap) -> Option<String> {
+    header_str(headers, header::ETAG)
+        .filter(|etag| !etag.starts_with("W/"))
+        .or_else(|| header_str(headers, header::LAST_MODIFIED))
+        .map(str::to_string)
+}
+
+// 从已经下载的位置继续，写入文件的同时计算 SHA-256
+async fn download(
+    client: &Client,
+    job: &FetchJob,
+    source: &mut DownloadSource,
+    source_path: &Path,
+    file: &mut tokio::fs::File,
+    hasher: &mut Sha256,
+    downloaded: &mut u64,
+) -> Result<(), DownloadError> {
+    let url = source.url.as_str();
+    // 上次写了一半的部分没有计算过 SHA-256，丢弃
+    file.set_len(*downloaded).await?;
+    file.seek(SeekFrom::Start(*downloaded)).await?;
+    // 没有 validator 时无法确认文件没有变化，从头下载
+    let offset = if source.validator.is_some() {
+        *downloaded
+    } else {
+        0
+    };
+    let res = get(client, url, offset, source.validator.as_deref()).await?;
+    let total_bytes = match res.status() {
+        StatusCode::PARTIAL_CONTENT => {
+            // bytes start-end/total
+            let content_range =
+                header_str(res.headers(), header::CONTENT_RANGE).unwrap_or_default();
+            let (range, total) = content_range
+                .strip_prefix("bytes ")
+                .and_then(|range| range.split_once('/'))
+                .ok_or_else(|| fatal(anyhow::anyhow!("invalid Content-Range: {content_range}")))?;
+            if range.split('-').next().and_then(|start| start.parse().ok()) != Some(offset) {
+                return Err(fatal(anyhow::anyhow!(
+                    "unexpected Content-Range: {content_range}"
+                )));
+            }
+            total.parse().ok()
+        }
+        StatusCode::OK => {
+            // 服务器不支持 Range 或者文件已经变化，从头开始
+            if *downloaded > 0 {
+                log::warn!("{url} can not be resumed, restart from the beginning");
+                *downloaded = 0;
+                *hasher = Sha256::new();
+                file.set_len(0).await?;
+                file.seek(SeekFrom::Start(0)).await?;
+            }
+            // 写入数据前先记下新的 validator
+            source.validator = validator(res.headers());
+            source.save(source_path).await?;
+            header_str(res.headers(), header::CONTENT_LENGTH).and_then(|len| len.parse().ok())
+        }
+        // 之前已经下载完整了
+        StatusCode::RANGE_NOT_SATISFIABLE
+            if offset > 0
+                && header_str(res.headers(), header::CONTENT_RANGE)
+                    == Some(&format!("bytes */{downloaded}")) =>
+        {
+            return Ok(());
+        }
+        status if status.is_client_error() => {
+            return Err(fatal(anyhow::anyhow!("get {url} failed: {status}")));
+        }
+        status => return Err(anyhow::anyhow!("get {url} failed: {status}").into()),
+    };
+    job.update(|output| output.total_bytes = total_bytes);
+
+    let mut stream = Body::new(res.into_body()).into_data_stream();
+    loop {
+        let chunk = match time::timeout(FETCH_READ_TIMEOUT, stream.next()).await {
+            Ok(Some(chunk)) => chunk?,
+            Ok(None) => break,
+            Err(_) => return Err(anyhow::anyhow!("read {url} timed out").into()),
+        };
+        file.write_all(&chunk).await?;
+        hasher.update(&chunk);
+        *downloaded += chunk.len() as u64;
+        job.update(|output| output.received_bytes = *downloaded);
+    }
+    file.flush().await?;
+    if let Some(total_bytes) = total_bytes {
+        if *downloaded != total_bytes {
+            return Err(anyhow::anyhow!(
+                "connection closed at {downloaded} of {total_bytes} bytes"
+            )
+            .into());
+        }
+    }
+    Ok(())
+}
+
+// 返回下载文件的 SHA-256
+async fn fetch(
+    client: &Client,
+    job: &FetchJob,
+    input: &FetchInput,
+    download_path: &Path,
+    source_path: &Path,
+) -> anyhow::Result<String> {
+    let expected = match (&input.sha256, &input.sha256_url) {
+        (Some(sha256), _) => Some(sha256.to_ascii_lowercase()),
+        (None, Some(sha256_url)) => {
+            let file_name = url_file_name(&input.url).unwrap_or_default();
+            Some(fetch_checksum(client, sha256_url, &file_name).await?)
+        }
+        (None, None) => None,
+    };
+
+    // 只有同一个 URL 的下载才能继续
+    let mut source = match DownloadSource::load(source_path).await {
+        Some(source) if source.url == input.url => source,
+        _ => DownloadSource {
+            url: input.url.clone(),
+            validator: None,
+        },
+    };
+    let mut file = tokio::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(source.validator.is_none())
+        .open(download_path)
+        .await?;
+    // 之前下载过的部分也要计算 SHA-256
+    let mut hasher = Sha256::new();
+    let mut downloaded = 0;
+    let mut buf = vec![0_u8; HASH_BUFFER_SIZE];
+    loop {
+        let len = file.read(&mut buf).await?;
+        if len == 0 {
+            break;
+        }
+        hasher.update(&buf[..len]);
+        downloaded += len as u64;
+    }
+    if downloaded > 0 {
+        log::info!("Resume {} from {downloaded} bytes", input.url);
+    }
+    job.update(|output| output.received_bytes = downloaded);
+
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        job.update(|output| output.attempts = attempts);
+        match download(
+            client,
+            job,
+            &mut source,
+            source_path,
+            &mut file,
+            &mut hasher,
+            &mut downloaded,
+        )
+        .await
+        {
+            Ok(()) => break,
+            Err(DownloadError::Retry(err)) if attempts < FETCH_RETRY_MAX => {
+                log::warn!("Fetch {} failed, retry: {err:#}", input.url);
+                time::sleep(FETCH_RETRY_DELAY).await;
+            }
+            Err(DownloadError::Retry(err) | DownloadError::Fatal(err)) => return Err(err),
+        }
+    }
+    file.sync_all().await?;
+
+    let sha256 = hex::encode(hasher.finalize());
+    if let Some(expected) = expected {
+        if sha256 != expected {
+            // 内容不对，不能用来继续下载
+            let _ = tokio::fs::remove_file(download_path).await;
+            let _ = tokio::fs::remove_file(source_path).await;
+            anyhow::bail!("sha256 mismatch, expect {expected}, got {sha256}");
+        }
+    }
+    Ok(sha256)
+}
+
+// 下载期间可能上传了同名镜像，不能覆盖。失败时保留下载的文件
+async fn rename_no_replace(from: &Path, to: &Path) -> anyhow::Result<()> {
+    tokio::fs::hard_link(from, to).await.map_err(|err| {
+        if err.kind() == std::io::ErrorKind::AlreadyExists {
+            anyhow::anyhow!("{} already exists", to.display())
+        } else {
+            err.into()
+        }
+    })?;
+    tokio::fs::remove_file(from).await?;
+    Ok(())
+}
+
+async fn run_fetch(
+    client: Client,
+    job: Arc<FetchJob>,
+    input: FetchInput,
+    download_path: PathBuf,
+    source_path: PathBuf,
+    image_path: PathBuf,
+) {
+    let res = match fetch(&client, &job, &input, &download_path, &source_path).await {
+        Ok(sha256) => rename_no_replace(&download_path, &image_path)
+            .await
+            .map(|()| sha256),
+        Err(err) => Err(err),
+    };
+    if res.is_ok() {
+        let _ = tokio::fs::remove_file(&source_path).await;
+    }
+    match res {
+        Ok(sha256) => {
+            log::info!("Fetch {} done", input.url);
+            job.update(|output| {
+                output.state = FetchState::Done;
+                output.sha256 = Some(sha256);
+            });
+        }
+        Err(err) => {
+            log::error!("Fetch {} failed: {err:#}", input.url);
+            job.update(|output| {
+                output.state = FetchState::Failed;
+                output.error = Some(format!("{err:#}"));
+            });
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+pub struct FetchInput {
+    url: String,
+    // 默认用 URL 中的文件名
+    #[serde(default)]
+    image_name: Option<String>,
+    #[serde(default)]
+    sha256: Option<String>,
+    // sha256sum 格式的校验文件，例如 SHA256SUMS
+    #[serde(default)]
+    sha256_url: Option<String>,
+}
+
+// 在后台下载到 IP_KVM_IMAGES_PATH，同名的未完成下载会继续
+pub async fn post_fetch(
+    State(app_state): State<Arc<AppState>>,
+    Json(input): Json<FetchInput>,
+) -> api_error::Result<Json<FetchOutput>> {
+    let bad_request = |err: anyhow::Error| ApiError::new(StatusCode::BAD_REQUEST, err);
+    check_url(
+        &input
+            .url
+            .parse()
+            .map_err(|err: axum::http::uri::InvalidUri| bad_request(err.into()))?,
+    )
+    .map_err(bad_request)?;
+    if let Some(sha256_url) = &input.sha256_url {
+        check_url(
+            &sha256_url
+                .parse()
+                .map_err(|err: axum::http::uri::InvalidUri| bad_request(err.into()))?,
+        )
+        .map_err(bad_request)?;
+    }
+    if input.sha256.is_some() && input.sha256_url.is_some() {
+        return Err(bad_request(anyhow::anyhow!(
+            "sha256 and sha256_url can not be used together"
+        )));
+    }
+    if input
+        .sha256
+        .as_deref()
+        .is_some_and(|sha256| !is_sha256(sha256))
+    {
+        return Err(bad_request(anyhow::anyhow!(
+            "sha256 must be 64 hex characters"
+        )));
+    }
+    let image_name = match input
+        .image_name
+        .clone()
+        .or_else(|| url_file_name(&input.url))
+    {
+        Some(image_name) => image_name,
+        None => return Err(bad_request(anyhow::anyhow!("image_name is required"))),
+    };
+    let image_path = mass_storage::get_image_path(&image_name)?;
+    let (download_path, source_path) = mass_storage::get_download_paths(&image_name)?;
+    if tokio::fs::try_exists(&image_path).await? {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            anyhow::anyhow!("image {image_name} already exists"),
+        ));
+    }
+
+    let mut jobs = app_state.image_fetches.jobs.lock().unwrap();
+    if is_fetching(&jobs, &image_name) {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            anyhow::anyhow!("image {image_name} is being fetched"),
+        ));
+    }
+    // 删除最早结束的任务
+    while jobs.len() >= FETCH_HISTORY_MAX {
+        let Some(id) = jobs
+            .iter()
+            .find(|(_, job)| !job.is_running())
+            .map(|(id, _)| *id)
+        else {
+            break;
+        };
+        jobs.remove(&id);
+    }
+    let id = app_state
+        .image_fetches
+        .next_id
+        .fetch_add(1, Ordering::Relaxed);
+    let output = FetchOutput {
+        id,
+        url: input.url.clone(),
+        image_name,
+        state: FetchState::Running,
+        received_bytes: 0,
+        total_bytes: None,
+        attempts: 0,
+        sha256: None,
+        error: None,
+    };
+    let job = Arc::new(FetchJob {
+        output: Mutex::new(output.clone()),
+        abort_handle: Mutex::new(None),
+    });
+    jobs.insert(id, job.clone());
+    log::info!("Fetch {} into {}", input.url, image_path.display());
+    let task = tokio::spawn(run_fetch(
+        app_state.http_client.clone(),
+        job.clone(),
+        input,
+        download_path,
+        source_path,
+        image_path,
+    ));
+    *job.abort_handle.lock().unwrap() = Some(task.abort_handle());
+    Ok(Json(output))
+}
+
+pub async fn get_fetches(State(app_state): State<Arc<AppState>>) -> Json<Vec<FetchOutput>> {
+    let jobs = app_state.image_fetches.jobs.lock().unwrap();
+    Json(
+        jobs.values()
+            .map(|job| job.output.lock().unwrap().clone())
+            .collect(),
+    )
+}
+
+fn fetch_job(app_state: &AppState, id: u64) -> api_error::Result<Arc<FetchJob>> {
+    let jobs = app_state.image_fetches.jobs.lock().unwrap();
+    jobs.get(&id).cloned().ok_or_else(|| {
+        ApiError::new(
+            StatusCode::NOT_FOUND,
+            anyhow::anyhow!("fetch job {id} not found"),
+        )
+    })
+}
+
+pub async fn get_fetch(
+    State(app_state): State<Arc<AppState>>,
+    extract::Path(id): extract::Path<u64>,
+) -> api_error::Result<Json<FetchOutput>> {
+    let job = fetch_job(&app_state, id)?;
+    let output = job.output.lock().unwrap().clone();
+    Ok(Json(output))
+}
+
+// 取消后保留已经下载的部分，再次下载同名镜像时继续
+pub async fn delete_fetch(
+    State(app_state): State<Arc<AppState>>,
+    extract::Path(id): extract::Path<u64>,
+) -> api_error::Result<String> {
+    let job = fetch_job(&app_state, id)?;
+    let mut output = job.output.lock().unwrap();
+    if output.state == FetchState::Running {
+        if let Some(abort_handle) = job.abort_handle.lock().unwrap().take() {
+            abort_handle.abort();
+        }
+        output.state = FetchState::Cancelled;
+    }
+    Ok("null".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{response::IntoResponse, routing, Router};
+
+    use super::*;
+
+    const HASH_A: &str = "a1b2c3d4e5f60718293a4b5c6d7e8f90a1b2c3d4e5f60718293a4b5c6d7e8f90";
+    const HASH_B: &str = "0000000000000000000000000000000000000000000000000000000000000001";
+
+    #[test]
+    fn find_checksum_formats() {
+        let text = format!("{HASH_A}  disk.img\n{HASH_B} *other.iso\n");
+        assert_eq!(find_checksum(&text, "disk.img").as_deref(), Some(HASH_A));
+        assert_eq!(find_checksum(&text, "other.iso").as_deref(), Some(HASH_B));
+        assert_eq!(find_checksum(&text, "missing.img"), None);
+
+        let text = format!("SHA256 (disk.img) = {HASH_A}\nSHA256 (dir/other.iso) = {HASH_B}\n");
+        assert_eq!(find_checksum(&text, "disk.img").as_deref(), Some(HASH_A));
+        assert_eq!(find_checksum(&text, "other.iso").as_deref(), Some(HASH_B));
+    }
+
+    #[test]
+    fn find_checksum_single_hash() {
+        // 没有文件名时不检查文件名，大写转成小写
+        let text = format!("{}\n", HASH_A.to_ascii_uppercase());
+        assert_eq!(find_checksum(&text, "disk.img").as_deref(), Some(HASH_A));
+        // 不是 SHA-256 的行被忽略，唯一的 hash 属于别的文件时也不能用
+        let text =
+            format!("# comment\nd41d8cd98f00b204e9800998ecf8427e  disk.md5\n{HASH_A}  disk.img\n");
+        assert_eq!(find_checksum(&text, "disk.img").as_deref(), Some(HASH_A));
+        assert_eq!(find_checksum(&text, "other.img"), None);
+        assert_eq!(find_checksum("", "disk.img"), None);
+    }
+
+    #[test]
+    fn resolve_location_forms() {
+        let base: Uri = "http://example.com/a/b/disk.img?x=1".parse().unwrap();
+        let resolve = |location| resolve_location(&base, location).unwrap().to_string();
+        assert_eq!(
+            resolve("https://mirror.example.org/disk.img"),
+            "https://mirror.example.org/disk.img"
+        );
+        assert_eq!(
+            resolve("//mirror.example.org/disk.img"),
+            "http://mirror.example.org/disk.img"
+        );
+        assert_eq!(resolve("/c/disk.img"), "http://example.com/c/disk.img");
+        assert_eq!(resolve("new.img?y=2"), "http://example.com/a/b/new.img?y=2");
+        assert!(resolve_location(&base, "http://bad host/").is_err());
+    }
+
+    #[test]
+    fn validator_prefers_strong_etag() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(validator(&headers), None);
+        headers.insert(
+            header::LAST_MODIFIED,
+            "Wed, 21 Oct 2015 07:28:00 GMT".parse().unwrap(),
+        );
+        headers.insert(header::ETAG, "W/\"weak\"".parse().unwrap());
+        assert_eq!(
+            validator(&headers).as_deref(),
+            Some("Wed, 21 Oct 2015 07:28:00 GMT")
+        );
+        headers.insert(header::ETAG, "\"strong\"".parse().unwrap());
+        assert_eq!(validator(&headers).as_deref(), Some("\"strong\""));
+    }
+
+    // 本地的 HTTP 服务器，If-Range 和 ETag 一致时才返回请求的部分
+    async fn serve(data: Vec<u8>, etag: &'static str) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new().route(
+            "/disk.img",
+            routing::get(move |headers: HeaderMap| {
+                let data = data.clone();
+                async move {
+                    let start = header_str(&headers, header::RANGE)
+                        .and_then(|range| range.strip_prefix("bytes="))
+                        .and_then(|range| range.strip_suffix('-'))
+                        .and_then(|start| start.parse::<usize>().ok())
+                        .filter(|_| header_str(&headers, header::IF_RANGE) == Some(etag));
+                    match start {
+                        Some(start) if start >= data.len() => (
+                            StatusCode::RANGE_NOT_SATISFIABLE,
+                            [(header::CONTENT_RANGE, format!("bytes */{}", data.len()))],
+                        )
+                            .into_response(),
+                        Some(start) => (
+                            StatusCode::PARTIAL_CONTENT,
+                            [(
+                                header::CONTENT_RANGE,
+                                format!("bytes {start}-{}/{}", data.len() - 1, data.len()),
+                            )],
+                            data[start..].to_vec(),
+                        )
+                            .into_response(),
+                        None => ([(header::ETAG, etag)], data).into_response(),
+                    }
+                }
+            }),
+        );
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{addr}/disk.img")
+    }
+
+    // 文件中已经有 data 的前 downloaded 字节，返回下载后的文件内容、长度、
+    // 保存的来源和 total_bytes
+    async fn resume(
+        name: &str,
+        url: &str,
+        data: &[u8],
+        downloaded: u64,
+        validator: Option<&str>,
+    ) -> (Vec<u8>, u64, Option<DownloadSource>, Option<u64>) {
+        let dir = std::env::temp_dir().join(format!("ip-kvm-fetch-{}-{name}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let path = dir.join("disk.img");
+        let source_path = dir.join(".disk.img.json");
+        tokio::fs::write(&path, &data[..downloaded as usize])
+            .await
+            .unwrap();
+        let mut file = tokio::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .await
+            .unwrap();
+        let job = FetchJob {
+            output: Mutex::new(FetchOutput {
+                id: 0,
+                url: url.to_string(),
+                image_name: "disk.img".to_string(),
+                state: FetchState::Running,
+                received_bytes: 0,
+                total_bytes: None,
+                attempts: 1,
+                sha256: None,
+                error: None,
+            }),
+            abort_handle: Mutex::new(None),
+        };
+        let mut source = DownloadSource {
+            url: url.to_string(),
+            validator: validator.map(str::to_string),
+        };
+        let mut hasher = Sha256::new();
+        hasher.update(&data[..downloaded as usize]);
+        let mut downloaded = downloaded;
+        let res = download(
+            &crate::new_http_client(),
+            &job,
+            &mut source,
+            &source_path,
+            &mut file,
+            &mut hasher,
+            &mut downloaded,
+        )
+        .await;
+        assert!(res.is_ok());
+        let content = tokio::fs::read(&path).await.unwrap();
+        assert_eq!(
+            hex::encode(hasher.finalize()),
+            hex::encode(Sha256::digest(&content))
+        );
+        // 只有从头下载时才保存来源
+        let saved = DownloadSource::load(&source_path).await;
+        let total_bytes = job.output.lock().unwrap().total_bytes;
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+        (content, downloaded, saved, total_bytes)
+    }
+
+    #[tokio::test]
+    async fn download_resumes() {
+        let data: Vec<u8> = (0..10000).map(|i| (i % 251) as u8).collect();
+        let url = serve(data.clone(), "\"v1\"").await;
+
+        // 206，从 1000 继续
+        let (content, downloaded, saved, total_bytes) =
+            resume("partial", &url, &data, 1000, Some("\"v1\"")).await;
+        assert_eq!(content, data);
+        assert_eq!(downloaded, 10000);
+        assert!(saved.is_none());
+        assert_eq!(total_bytes, Some(10000));
+
+        // 416，之前已经下载完整
+        let (content, downloaded, saved, total_bytes) =
+            resume("complete", &url, &data, 10000, Some("\"v1\"")).await;
+        assert_eq!(content, data);
+        assert_eq!(downloaded, 10000);
+        assert!(saved.is_none());
+        assert_eq!(total_bytes, None);
+
+        // 200，If-Range 不一致，从头下载并记下新的 validator
+        let old = vec![7_u8; 10000];
+        let (content, downloaded, saved, total_bytes) =
+            resume("changed", &url, &old, 1000, Some("\"v0\"")).await;
+        assert_eq!(content, data);
+        assert_eq!(downloaded, 10000);
+        assert_eq!(
+            saved.and_then(|source| source.validator).as_deref(),
+            Some("\"v1\"")
+        );
+        assert_eq!(total_bytes, Some(10000));
+
+        // 没有 validator 时不发送 Range
+        let (content, downloaded, saved, _) = resume("unknown", &url, &old, 1000, None).await;
+        assert_eq!(content, data);
+        assert_eq!(downloaded, 10000);
+        assert_eq!(
+            saved.and_then(|source| source.validator).as_deref(),
+            Some("\"v1\"")
+        );
+    }
+
+    #[tokio::test]
+    async fn rename_keeps_existing_image() {
+        let dir = std::env::temp_dir().join(format!("ip-kvm-rename-{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let (from, to) = (dir.join("download"), dir.join("image"));
+        tokio::fs::write(&from, b"new").await.unwrap();
+        tokio::fs::write(&to, b"old").await.unwrap();
+        assert!(rename_no_replace(&from, &to).await.is_err());
+        assert_eq!(tokio::fs::read(&to).await.unwrap(), b"old");
+        assert_eq!(tokio::fs::read(&from).await.unwrap(), b"new");
+
+        tokio::fs::remove_file(&to).await.unwrap();
+        rename_no_replace(&from, &to).await.unwrap();
+        assert_eq!(tokio::fs::read(&to).await.unwrap(), b"new");
+        assert!(!tokio::fs::try_exists(&from).await.unwrap());
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
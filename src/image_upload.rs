@@ -0,0 +1,270 @@
+use std::{
+    collections::HashMap,
+    io::{self, Read},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{self, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    Json,
+};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+use crate::{
+    api_error::{self, ApiError},
+    image_convert::{self, ConvertStatus, ConvertStatusOutput, OutputError},
+    mass_storage,
+    metrics::METRICS,
+    AppState,
+};
+
+// 转换线程处理不过来时暂停接收
+const UPLOAD_CHANNEL_SIZE: usize = 16;
+
+// 把异步收到的请求体转成转换线程里的 Read
+struct BodyReader {
+    receiver: mpsc::Receiver<io::Result<Bytes>>,
+    chunk: Bytes,
+}
+
+impl Read for BodyReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.chunk.is_empty() {
+            match self.receiver.blocking_recv() {
+                Some(chunk) => self.chunk = chunk?,
+                None => return Ok(0),
+            }
+        }
+        let len = buf.len().min(self.chunk.len());
+        buf[..len].copy_from_slice(&self.chunk.split_to(len));
+        Ok(len)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UploadState {
+    Running,
+    Done,
+    Failed,
+}
+
+struct Upload {
+    state: Mutex<(UploadState, Option<String>)>,
+    received_bytes: AtomicU64,
+    total_bytes: Option<u64>,
+    convert: ConvertStatus,
+}
+
+#[derive(Serialize)]
+pub struct UploadOutput {
+    state: UploadState,
+    error: Option<String>,
+    received_bytes: u64,
+    // 请求的 Content-Length
+    total_bytes: Option<u64>,
+    #[serde(flatten)]
+    convert: ConvertStatusOutput,
+}
+
+impl Upload {
+    fn output(&self) -> UploadOutput {
+        let (state, error) = self.state.lock().unwrap().clone();
+        UploadOutput {
+            state,
+            error,
+            received_bytes: self.received_bytes.load(Ordering::Relaxed),
+            total_bytes: self.total_bytes,
+            convert: self.convert.output(),
+        }
+    }
+
+    fn set_state(&self, state: UploadState, error: Option<String>) {
+        *self.state.lock().unwrap() = (state, error);
+    }
+}
+
+// 要替换的镜像正在使用
+#[derive(Debug)]
+struct ImageBusy(String);
+
+impl std::fmt::Display for ImageBusy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl std::error::Error for ImageBusy {}
+
+// 上传的数据有问题是客户端的错误，写文件失败是服务器的错误
+fn error_status(err: &anyhow::Error) -> StatusCode {
+    if err.is::<ImageBusy>() {
+        StatusCode::CONFLICT
+    } else if err.is::<OutputError>() || err.is::<tokio::task::JoinError>() {
+        StatusCode::INTERNAL_SERVER_ERROR
+    } else {
+        StatusCode::BAD_REQUEST
+    }
+}
+
+// 每个镜像最近一次上传的进度
+#[derive(Default)]
+pub struct ImageUploads {
+    uploads: Mutex<HashMap<String, Arc<Upload>>>,
+}
+
+#[derive(Deserialize)]
+pub struct UploadQuery {
+    // 不检测格式，按原样保存
+    #[serde(default)]
+    raw: bool,
+}
+
+// 请求体是整个镜像文件，边接收边解压和转换成稀疏的原始镜像
+pub async fn post_upload(
+    State(app_state): State<Arc<AppState>>,
+    extract::Path(file_name): extract::Path<String>,
+    Query(query): Query<UploadQuery>,
+    headers: HeaderMap,
+    body: Body,
+) -> api_error::Result<Json<UploadOutput>> {
+    let image_path = mass_storage::get_image_path(&file_name)?;
+    let upload_path = mass_storage::get_upload_path(&file_name)?;
+    if let Some(reason) = mass_storage::image_busy(&app_state, &file_name).await {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            anyhow::anyhow!("image {file_name} is {reason}"),
+        ));
+    }
+    let upload = Arc::new(Upload {
+        state: Mutex::new((UploadState::Running, None)),
+        received_bytes: AtomicU64::new(0),
+        total_bytes: headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|len| len.to_str().ok())
+            .and_then(|len| len.parse().ok()),
+        convert: ConvertStatus::default(),
+    });
+    {
+        let mut uploads = app_state.image_uploads.uploads.lock().unwrap();
+        if uploads
+            .get(&file_name)
+            .is_some_and(|upload| upload.state.lock().unwrap().0 == UploadState::Running)
+        {
+            return Err(ApiError::new(
+                StatusCode::CONFLICT,
+                anyhow::anyhow!("{file_name} is being uploaded"),
+            ));
+        }
+        uploads.insert(file_name.clone(), upload.clone());
+    }
+
+    // 在单独的任务里接收和转换，客户端中途断开时也会删除临时文件并更新状态
+    let task_upload = upload.clone();
+    let res = tokio::spawn(async move {
+        let res = receive(
+            &app_state,
+            &task_upload,
+            body,
+            &file_name,
+            &upload_path,
+            &image_path,
+            query.raw,
+        )
+        .await;
+        match &res {
+            Ok(()) => task_upload.set_state(UploadState::Done, None),
+            Err(err) => {
+                log::error!("Upload {file_name} failed: {err:#}");
+                let _ = tokio::fs::remove_file(&upload_path).await;
+                task_upload.set_state(UploadState::Failed, Some(format!("{err:#}")));
+            }
+        }
+        res
+    })
+    .await;
+    match res {
+        Ok(Ok(())) => Ok(Json(upload.output())),
+        Ok(Err(err)) => Err(ApiError::new(error_status(&err), err)),
+        Err(err) => {
+            upload.set_state(UploadState::Failed, Some(err.to_string()));
+            Err(err.into())
+        }
+    }
+}
+
+async fn receive(
+    app_state: &AppState,
+    upload: &Arc<Upload>,
+    body: Body,
+    file_name: &str,
+    upload_path: &Path,
+    image_path: &Path,
+    raw: bool,
+) -> anyhow::Result<()> {
+    let (sender, receiver) = mpsc::channel(UPLOAD_CHANNEL_SIZE);
+    let reader = BodyReader {
+        receiver,
+        chunk: Bytes::new(),
+    };
+    let convert_upload = upload.clone();
+    let convert_path = upload_path.to_path_buf();
+    let convert = tokio::task::spawn_blocking(move || {
+        image_convert::convert(
+            Box::new(reader),
+            &convert_path,
+            raw,
+            &convert_upload.convert,
+        )
+    });
+    let mut stream = body.into_data_stream();
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(io::Error::other);
+        if let Ok(chunk) = &chunk {
+            upload
+                .received_bytes
+                .fetch_add(chunk.len() as u64, Ordering::Relaxed);
+            METRICS.image_upload_bytes.inc_by(chunk.len() as u64);
+        }
+        let failed = chunk.is_err();
+        // 转换失败时不再接收
+        if sender.send(chunk).await.is_err() || failed {
+            break;
+        }
+    }
+    drop(sender);
+
+    convert.await??;
+    // 上传期间镜像可能被插入或者开始下载
+    if let Some(reason) = mass_storage::image_busy(app_state, file_name).await {
+        return Err(ImageBusy(format!("image {file_name} is {reason}")).into());
+    }
+    // 旧镜像的覆盖层不能用在新镜像上
+    mass_storage::remove_overlays(file_name).map_err(OutputError)?;
+    tokio::fs::rename(upload_path, image_path)
+        .await
+        .map_err(OutputError)?;
+    Ok(())
+}
+
+pub async fn get_upload(
+    State(app_state): State<Arc<AppState>>,
+    extract::Path(file_name): extract::Path<String>,
+) -> api_error::Result<Json<UploadOutput>> {
+    let uploads = app_state.image_uploads.uploads.lock().unwrap();
+    let upload = uploads.get(&file_name).ok_or_else(|| {
+        ApiError::new(
+            StatusCode::NOT_FOUND,
+            anyhow::anyhow!("{file_name} has not been uploaded"),
+        )
+    })?;
+    Ok(Json(upload.output()))
+}
@@ -0,0 +1,162 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::{http::StatusCode, Extension, Json};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::{Notify, RwLock},
+    time::{self, Instant},
+};
+
+use usb_otg::hid::keyboard::usage_id;
+
+use crate::{
+    api_error::{self, ApiError},
+    keyboard,
+    metrics::METRICS,
+    DeviceCtx,
+};
+
+const JIGGLER_INTERVAL_MIN: Duration = Duration::from_secs(1);
+const JIGGLER_INTERVAL_MAX: Duration = Duration::from_secs(3600);
+// F15 在大多数系统上没有绑定任何功能
+const JIGGLER_KEY: u16 = usage_id::KEYBOARD_F15;
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JigglerMode {
+    // 移动 1 个单位再移回来，光标位置不变
+    Mouse,
+    Key,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct JigglerConfig {
+    enabled: bool,
+    interval_secs: u64,
+    mode: JigglerMode,
+}
+
+impl Default for JigglerConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval_secs: 60,
+            mode: JigglerMode::Mouse,
+        }
+    }
+}
+
+// 定时发送无害的输入防止目标机器锁屏，操作员有输入时暂停
+pub struct Jiggler {
+    config: Mutex<JigglerConfig>,
+    last_input: Mutex<Instant>,
+    config_notify: Notify,
+}
+
+impl Default for Jiggler {
+    fn default() -> Self {
+        Self {
+            config: Default::default(),
+            last_input: Mutex::new(Instant::now()),
+            config_notify: Notify::new(),
+        }
+    }
+}
+
+impl Jiggler {
+    pub fn config(&self) -> JigglerConfig {
+        *self.config.lock().unwrap()
+    }
+
+    pub fn set_config(&self, config: JigglerConfig) {
+        *self.config.lock().unwrap() = config;
+        self.config_notify.notify_waiters();
+    }
+
+    // 操作员发送了输入
+    pub fn touch(&self) {
+        *self.last_input.lock().unwrap() = Instant::now();
+    }
+
+    pub async fn run(&self, device_ctx: &DeviceCtx) {
+        loop {
+            // 先注册通知再读取配置，避免错过两者之间的修改
+            let config_changed = self.config_notify.notified();
+            tokio::pin!(config_changed);
+            config_changed.as_mut().enable();
+
+            let config = self.config();
+            if !config.enabled {
+                config_changed.await;
+                continue;
+            }
+            let interval = Duration::from_secs(config.interval_secs);
+            let deadline = *self.last_input.lock().unwrap() + interval;
+            if Instant::now() < deadline {
+                tokio::select! {
+                    _ = time::sleep_until(deadline) => {}
+                    _ = config_changed => {}
+                }
+                continue;
+            }
+            jiggle(device_ctx, config.mode).await;
+            // 自己发送的输入也算一次，下一次在 interval 之后
+            self.touch();
+        }
+    }
+}
+
+async fn jiggle(device_ctx: &DeviceCtx, mode: JigglerMode) {
+    log::debug!("Target {} jiggle", device_ctx.id);
+    match mode {
+        JigglerMode::Mouse => {
+            let mouse_device = &device_ctx.mouse_device;
+            for (x, y) in [(1, 0), (-1, 0)] {
+                let res = mouse_device.send_relative(x, y, 0).await;
+                METRICS.hid_report_sent(device_ctx.id, "mouse", "jiggle", res.is_ok());
+                if let Err(err) = res {
+                    log::error!("mouse_device.send_relative failed: {err}");
+                }
+            }
+        }
+        JigglerMode::Key => {
+            for pressed in [true, false] {
+                if device_ctx
+                    .keyboard_device
+                    .set_key(JIGGLER_KEY, pressed)
+                    .await
+                {
+                    keyboard::send_keyboard(device_ctx).await;
+                }
+            }
+        }
+    }
+}
+
+pub async fn get_jiggler(
+    Extension(device_ctx): Extension<Arc<RwLock<DeviceCtx>>>,
+) -> api_error::Result<Json<JigglerConfig>> {
+    Ok(Json(device_ctx.read().await.jiggler.config()))
+}
+
+pub async fn put_jiggler(
+    Extension(device_ctx): Extension<Arc<RwLock<DeviceCtx>>>,
+    Json(config): Json<JigglerConfig>,
+) -> api_error::Result<String> {
+    let interval = Duration::from_secs(config.interval_secs);
+    if !(JIGGLER_INTERVAL_MIN..=JIGGLER_INTERVAL_MAX).contains(&interval) {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            anyhow::anyhow!(
+                "interval_secs must be between {} and {}",
+                JIGGLER_INTERVAL_MIN.as_secs(),
+                JIGGLER_INTERVAL_MAX.as_secs()
+            ),
+        ));
+    }
+    device_ctx.read().await.jiggler.set_config(config);
+    Ok("null".into())
+}
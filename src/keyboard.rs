@@ -5,20 +5,29 @@ use axum::{
         ws::{Message, WebSocket},
         ConnectInfo, WebSocketUpgrade,
     },
+    http::StatusCode,
     response::IntoResponse,
-    Extension,
+    Extension, Json,
 };
 
 use axum_extra::{headers, TypedHeader};
 
 use futures::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
 use tokio::{
     sync::{Mutex, RwLock},
     task::JoinSet,
     time,
 };
 
-use crate::DeviceCtx;
+use usb_otg::hid::{apple::KeyboardProfile, keyboard::KeyboardProtocol};
+
+use crate::{
+    api_error::{self, ApiError},
+    held_input, hid_health,
+    metrics::{HID_SEND_TIMEOUT, METRICS},
+    DeviceCtx,
+};
 
 pub async fn ws_handler(
     ws: WebSocketUpgrade,
@@ -36,6 +45,7 @@ pub async fn ws_handler(
 }
 
 async fn handle_socket(device_ctx: Arc<RwLock<DeviceCtx>>, socket: WebSocket, who: SocketAddr) {
+    let _client_guard = METRICS.websocket_client(device_ctx.read().await.id, "keyboard");
     let (mut sender, mut receiver) = socket.split();
 
     let mut join_set = JoinSet::new();
@@ -106,10 +116,13 @@ async fn handle_socket(device_ctx: Arc<RwLock<DeviceCtx>>, socket: WebSocket, wh
         }
     });
 
+    let session_id = held_input::new_session_id();
     let device_ctx_recv = device_ctx.clone();
     join_set.spawn(async move {
-        while let Some(Ok(msg)) = receiver.next().await {
-            if process_message(device_ctx_recv.clone(), msg, who)
+        while let Ok(Some(Ok(msg))) =
+            time::timeout(held_input::SESSION_IDLE_TIMEOUT, receiver.next()).await
+        {
+            if process_message(device_ctx_recv.clone(), msg, who, session_id)
                 .await
                 .is_break()
             {
@@ -121,56 +134,258 @@ async fn handle_socket(device_ctx: Arc<RwLock<DeviceCtx>>, socket: WebSocket, wh
     let _ = join_set.join_next().await;
     join_set.shutdown().await;
 
+    // 浏览器异常退出时松开这个会话按下的按键
+    held_input::release_session(&*device_ctx.read().await, session_id).await;
+
     println!("Websocket context {} destroyed", who);
 }
 
-async fn send_keyboard_update(device_ctx: Arc<RwLock<DeviceCtx>>) -> ControlFlow<(), ()> {
-    let mut join_set = JoinSet::new();
-    join_set.spawn(async move {
-        let keyboard_device = &device_ctx.read().await.keyboard_device;
+// 只发送到主机正在使用的接口，不确定时两个接口都发送
+pub async fn send_keyboard(device_ctx: &DeviceCtx) {
+    let keyboard_device = &device_ctx.keyboard_device;
+    let protocol = keyboard_device.protocol();
+
+    let res = if protocol == KeyboardProtocol::Boot {
+        keyboard_device.release_composite().await
+    } else {
+        keyboard_device.send().await
+    };
+    METRICS.hid_report_sent(device_ctx.id, "keyboard", "send", res.is_ok());
+    if let Err(err) = res {
+        log::error!("keyboard_device.send failed: {err}");
+    }
+    // boot 键盘没有 Fn 键
+    if device_ctx.keyboard_profile == KeyboardProfile::Apple && protocol != KeyboardProtocol::Boot {
+        let res = keyboard_device.send_apple_fn().await;
+        METRICS.hid_report_sent(device_ctx.id, "keyboard", "send_apple_fn", res.is_ok());
+        if let Err(err) = res {
+            log::error!("keyboard_device.send_apple_fn failed: {err}");
+        }
+    }
+    let res = if protocol == KeyboardProtocol::Report {
+        keyboard_device.release_legacy().await
+    } else {
+        keyboard_device.send_legacy().await
+    };
+    METRICS.hid_report_sent(device_ctx.id, "keyboard_legacy", "send_legacy", res.is_ok());
+    if let Err(err) = res {
+        log::error!("keyboard_device.send_legacy failed: {err}");
+        device_ctx
+            .hid_write_failed(hid_health::HID_DEV_KEYBOARD_LEGACY, &err)
+            .await;
+    }
+}
+
+#[derive(Serialize)]
+pub struct KeyboardProtocolOutput {
+    // auto 表示根据 LED 输出报告推断
+    mode: &'static str,
+    detected: &'static str,
+    active: &'static str,
+}
+
+pub async fn get_protocol(
+    Extension(device_ctx): Extension<Arc<RwLock<DeviceCtx>>>,
+) -> api_error::Result<Json<KeyboardProtocolOutput>> {
+    let keyboard_device = &device_ctx.read().await.keyboard_device;
+    Ok(Json(KeyboardProtocolOutput {
+        mode: keyboard_device
+            .forced_protocol()
+            .map_or("auto", |protocol| protocol.as_str()),
+        detected: keyboard_device.detected_protocol().as_str(),
+        active: keyboard_device.protocol().as_str(),
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct KeyboardProtocolInput {
+    mode: String,
+}
+
+pub async fn put_protocol(
+    Extension(device_ctx): Extension<Arc<RwLock<DeviceCtx>>>,
+    Json(payload): Json<KeyboardProtocolInput>,
+) -> api_error::Result<String> {
+    let forced_protocol = match payload.mode.as_str() {
+        "auto" => None,
+        mode => match KeyboardProtocol::parse(mode) {
+            Some(protocol) => Some(protocol),
+            None => {
+                return Err(ApiError::new(
+                    StatusCode::BAD_REQUEST,
+                    anyhow::anyhow!("mode must be one of auto, boot, report, unknown"),
+                ))
+            }
+        },
+    };
+    let device_ctx = device_ctx.read().await;
+    device_ctx
+        .keyboard_device
+        .set_forced_protocol(forced_protocol);
+    // 立即把当前状态同步到新的接口，并松开旧接口上的按键
+    send_keyboard(&device_ctx).await;
+    Ok("null".into())
+}
 
-        if let Err(err) = keyboard_device.send().await {
-            log::error!("keyboard_device.send failed: {err}");
+const CHORD_HOLD_MAX: Duration = Duration::from_secs(120);
+// 开机时主机会重新枚举，按住期间需要不断重发，保证主机一定能收到
+const CHORD_RESEND_INTERVAL: Duration = Duration::from_millis(200);
+
+#[derive(Deserialize)]
+pub struct ChordInput {
+    #[serde(default)]
+    keys: Vec<u16>,
+    #[serde(default)]
+    apple_fn: bool,
+    hold_ms: u64,
+}
+
+// 按住组合键一段时间，例如 Mac 开机时的 Cmd+R 或 Option
+pub async fn post_chord(
+    Extension(device_ctx): Extension<Arc<RwLock<DeviceCtx>>>,
+    Json(payload): Json<ChordInput>,
+) -> api_error::Result<String> {
+    let hold = Duration::from_millis(payload.hold_ms);
+    // 超过 max_hold 的按键会被自动松开，组合键不能按得更久
+    let hold_max = device_ctx
+        .read()
+        .await
+        .max_hold
+        .map_or(CHORD_HOLD_MAX, |max_hold| max_hold.min(CHORD_HOLD_MAX));
+    if hold > hold_max {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            anyhow::anyhow!("hold_ms must not exceed {}", hold_max.as_millis()),
+        ));
+    }
+    if payload.apple_fn && device_ctx.read().await.keyboard_profile != KeyboardProfile::Apple {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            anyhow::anyhow!("apple_fn requires --keyboard-profile apple"),
+        ));
+    }
+    let Ok(chord_guard) = device_ctx.read().await.chord_lock.clone().try_lock_owned() else {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            anyhow::anyhow!("another chord is being held"),
+        ));
+    };
+
+    // 不等待按住结束，主机重启时请求方可能还要做别的事
+    tokio::spawn(async move {
+        let _chord_guard = chord_guard;
+        let device_ctx = device_ctx.read().await;
+        let keyboard_device = &device_ctx.keyboard_device;
+        // 和 WebSocket 会话一样记录按下的按键，超过 max_hold 或者重置时会被松开
+        let session_id = held_input::new_session_id();
+        let mut inputs: Vec<_> = payload
+            .keys
+            .iter()
+            .map(|key_id| held_input::Input::Key(*key_id))
+            .collect();
+        if payload.apple_fn {
+            inputs.push(held_input::Input::AppleFn);
         }
-        if let Err(err) = keyboard_device.send_legacy().await {
-            log::error!("keyboard_device.send_legacy failed: {err}");
+        for input in &inputs {
+            device_ctx.held_inputs.set(session_id, *input, true);
         }
-        ControlFlow::Continue(())
-    });
-    join_set.spawn(async {
-        time::sleep(Duration::from_secs(5)).await;
-        log::warn!("keyboard_device send timeout.");
-        ControlFlow::Continue(())
+        for key_id in &payload.keys {
+            keyboard_device.set_key(*key_id, true).await;
+        }
+        if payload.apple_fn {
+            keyboard_device.set_apple_fn(true).await;
+        }
+        let deadline = time::Instant::now() + hold;
+        while time::Instant::now() < deadline {
+            send_keyboard(&device_ctx).await;
+            time::sleep(CHORD_RESEND_INTERVAL.min(deadline - time::Instant::now())).await;
+        }
+        // 已经被 max_hold 或者其它会话接管的按键不再由这里松开
+        let inputs = device_ctx.held_inputs.take_session(session_id);
+        held_input::release(&device_ctx, &inputs).await;
+        log::info!("Target {} chord {:?} released", device_ctx.id, payload.keys);
     });
+    Ok("null".into())
+}
 
-    let ret = join_set.join_next().await.unwrap().unwrap();
-    join_set.shutdown().await;
-    ret
+async fn send_keyboard_update(device_ctx: Arc<RwLock<DeviceCtx>>) -> ControlFlow<(), ()> {
+    let id = device_ctx.read().await.id;
+    METRICS
+        .watch_hid_send(id, "keyboard", HID_SEND_TIMEOUT, async {
+            send_keyboard(&*device_ctx.read().await).await;
+        })
+        .await;
+    ControlFlow::Continue(())
 }
 
 async fn process_message(
     device_ctx: Arc<RwLock<DeviceCtx>>,
     msg: Message,
     who: SocketAddr,
+    session_id: u64,
 ) -> ControlFlow<(), ()> {
     match msg {
         Message::Binary(d) => {
             let keyboard_device = &device_ctx.read().await.keyboard_device;
-            if d.len() != 3 || (d[0] != 0 && d[0] != 1) {
+            // 0: 普通按键，1: 系统控制键，2: Apple Fn 键 (忽略 id)
+            if d.len() != 3 || d[0] > 2 {
                 return ControlFlow::Break(());
-            } else if d[0] == 0 {
+            }
+            // 正在输入文件时忽略操作员按下的按键，松开仍然要处理，否则输入结束后会恢复成按住
+            if d[2] == 1 && device_ctx.read().await.type_file.is_running() {
+                return ControlFlow::Continue(());
+            }
+            let type_file = &device_ctx.read().await.type_file;
+            device_ctx.read().await.jiggler.touch();
+            if d[0] == 0 {
+                if d[2] != 0 && d[2] != 1 {
+                    return ControlFlow::Break(());
+                }
+                device_ctx.read().await.held_inputs.set(
+                    session_id,
+                    held_input::Input::Key(d[1] as u16),
+                    d[2] == 1,
+                );
+                if type_file
+                    .set_input(
+                        keyboard_device,
+                        held_input::Input::Key(d[1] as u16),
+                        d[2] == 1,
+                    )
+                    .await
+                {
+                    return send_keyboard_update(device_ctx.clone()).await;
+                }
+            } else if d[0] == 2 {
                 if d[2] != 0 && d[2] != 1 {
                     return ControlFlow::Break(());
                 }
-                if keyboard_device.set_key(d[1] as u16, d[2] == 1).await {
+                device_ctx.read().await.held_inputs.set(
+                    session_id,
+                    held_input::Input::AppleFn,
+                    d[2] == 1,
+                );
+                if type_file
+                    .set_input(keyboard_device, held_input::Input::AppleFn, d[2] == 1)
+                    .await
+                {
                     return send_keyboard_update(device_ctx.clone()).await;
                 }
             } else {
                 if d[2] != 0 || d[2] != 1 {
                     return ControlFlow::Break(());
                 }
-                if keyboard_device
-                    .set_sys_control_key(d[1] as u16, d[2] == 1)
+                device_ctx.read().await.held_inputs.set(
+                    session_id,
+                    held_input::Input::SysControlKey(d[1] as u16),
+                    d[2] == 1,
+                );
+                if type_file
+                    .set_input(
+                        keyboard_device,
+                        held_input::Input::SysControlKey(d[1] as u16),
+                        d[2] == 1,
+                    )
                     .await
                 {
                     return send_keyboard_update(device_ctx.clone()).await;
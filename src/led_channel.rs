@@ -0,0 +1,340 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::{Extension, Json};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::{broadcast, RwLock},
+    time::Instant,
+};
+
+use usb_otg::hid::keyboard::{
+    KeyboardDevice, LOCK_LED_CAPS_LOCK, LOCK_LED_NUM_LOCK, LOCK_LED_SCROLL_LOCK,
+};
+
+use crate::{api_error, DeviceCtx};
+
+// 超过后丢弃最早收到的数据
+const LED_CHANNEL_DATA_MAX: usize = 1 << 20;
+// 一直没有收到 Scroll Lock 时不再继续接收
+const LED_CHANNEL_FRAME_MAX: usize = 4096;
+const LED_CHANNEL_RATE_WINDOW: Duration = Duration::from_secs(10);
+// 每帧最后两个字节是 CRC-16/CCITT-FALSE
+const LED_CHANNEL_CRC_LENGTH: usize = 2;
+
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xffff_u16;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+#[derive(Default)]
+struct LedChannelState {
+    enabled: bool,
+    prev_led: Option<u8>,
+    // 收到 Scroll Lock 之后才开始接收
+    in_frame: bool,
+    frame: Vec<u8>,
+    frame_bits: usize,
+    data: Vec<u8>,
+    bits: u64,
+    frames: u64,
+    bad_frames: u64,
+    errors: u64,
+    bit_times: VecDeque<Instant>,
+}
+
+impl LedChannelState {
+    fn push_bit(&mut self, bit: bool, now: Instant) {
+        self.bits += 1;
+        self.bit_times.push_back(now);
+        while self
+            .bit_times
+            .front()
+            .is_some_and(|time| now - *time > LED_CHANNEL_RATE_WINDOW)
+        {
+            self.bit_times.pop_front();
+        }
+        if !self.in_frame {
+            return;
+        }
+        if self.frame_bits.is_multiple_of(8) {
+            if self.frame.len() >= LED_CHANNEL_FRAME_MAX {
+                log::warn!("led channel frame too large");
+                self.bad_frames += 1;
+                self.in_frame = false;
+                self.frame.clear();
+                self.frame_bits = 0;
+                return;
+            }
+            self.frame.push(0);
+        }
+        if bit {
+            *self.frame.last_mut().unwrap() |= 0x80 >> (self.frame_bits % 8);
+        }
+        self.frame_bits += 1;
+    }
+
+    fn frame_boundary(&mut self) {
+        if self.in_frame && self.frame_bits != 0 {
+            let frame = std::mem::take(&mut self.frame);
+            let (payload, crc) = frame.split_at(frame.len().saturating_sub(LED_CHANNEL_CRC_LENGTH));
+            if !self.frame_bits.is_multiple_of(8)
+                || crc.len() != LED_CHANNEL_CRC_LENGTH
+                || crc16(payload) != u16::from_be_bytes([crc[0], crc[1]])
+            {
+                log::warn!("led channel bad frame: {} bits", self.frame_bits);
+                self.bad_frames += 1;
+            } else {
+                self.frames += 1;
+                self.data.extend_from_slice(payload);
+                if self.data.len() > LED_CHANNEL_DATA_MAX {
+                    let overflow = self.data.len() - LED_CHANNEL_DATA_MAX;
+                    self.data.drain(..overflow);
+                }
+            }
+        }
+        // 一个 Scroll Lock 同时结束上一帧并开始下一帧
+        self.in_frame = true;
+        self.frame.clear();
+        self.frame_bits = 0;
+    }
+
+    // 丢失了 LED 变化，当前帧已经不可信
+    fn drop_frame(&mut self) {
+        self.errors += 1;
+        self.in_frame = false;
+        self.frame.clear();
+        self.frame_bits = 0;
+    }
+
+    fn push(&mut self, led: u8, now: Instant) {
+        let Some(prev_led) = self.prev_led.replace(led) else {
+            return;
+        };
+        if !self.enabled {
+            return;
+        }
+        match prev_led ^ led {
+            0 => {}
+            LOCK_LED_NUM_LOCK => self.push_bit(false, now),
+            LOCK_LED_CAPS_LOCK => self.push_bit(true, now),
+            LOCK_LED_SCROLL_LOCK => self.frame_boundary(),
+            // 多个 LED 同时变化，无法判断顺序
+            _ => self.drop_frame(),
+        }
+    }
+
+    fn bits_per_sec(&self) -> f64 {
+        let (Some(first), Some(last)) = (self.bit_times.front(), self.bit_times.back()) else {
+            return 0.0;
+        };
+        if Instant::now() - *last > LED_CHANNEL_RATE_WINDOW || first == last {
+            return 0.0;
+        }
+        (self.bit_times.len() - 1) as f64 / (*last - *first).as_secs_f64()
+    }
+}
+
+// 目标机器上的脚本切换锁定键，通过键盘 LED 传回数据
+// Num Lock 表示 0，Caps Lock 表示 1，Scroll Lock 分隔帧
+#[derive(Default)]
+pub struct LedChannel {
+    state: Mutex<LedChannelState>,
+}
+
+impl LedChannel {
+    pub async fn run(&self, keyboard_device: &KeyboardDevice) {
+        let mut receiver = keyboard_device.subscribe_lock_led();
+        loop {
+            match receiver.recv().await {
+                Ok(led) => self.state.lock().unwrap().push(led, Instant::now()),
+                Err(broadcast::error::RecvError::Lagged(count)) => {
+                    log::warn!("led channel lagged {count} changes");
+                    let mut state = self.state.lock().unwrap();
+                    state.prev_led = None;
+                    state.drop_frame();
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct LedChannelOutput {
+    enabled: bool,
+    bits_per_sec: f64,
+    bits: u64,
+    frames: u64,
+    bad_frames: u64,
+    errors: u64,
+    // 当前帧已经收到的位数
+    frame_bits: usize,
+    received: usize,
+}
+
+pub async fn get_led_channel(
+    Extension(device_ctx): Extension<Arc<RwLock<DeviceCtx>>>,
+) -> api_error::Result<Json<LedChannelOutput>> {
+    let device_ctx = device_ctx.read().await;
+    let state = device_ctx.led_channel.state.lock().unwrap();
+    Ok(Json(LedChannelOutput {
+        enabled: state.enabled,
+        bits_per_sec: state.bits_per_sec(),
+        bits: state.bits,
+        frames: state.frames,
+        bad_frames: state.bad_frames,
+        errors: state.errors,
+        frame_bits: if state.in_frame { state.frame_bits } else { 0 },
+        received: state.data.len(),
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct LedChannelInput {
+    enabled: bool,
+}
+
+pub async fn put_led_channel(
+    Extension(device_ctx): Extension<Arc<RwLock<DeviceCtx>>>,
+    Json(input): Json<LedChannelInput>,
+) -> api_error::Result<String> {
+    let device_ctx = device_ctx.read().await;
+    let mut state = device_ctx.led_channel.state.lock().unwrap();
+    state.enabled = input.enabled;
+    // 重新开始时等待下一个 Scroll Lock
+    state.in_frame = false;
+    state.frame.clear();
+    state.frame_bits = 0;
+    Ok("null".into())
+}
+
+pub async fn get_led_channel_data(
+    Extension(device_ctx): Extension<Arc<RwLock<DeviceCtx>>>,
+) -> api_error::Result<Vec<u8>> {
+    let device_ctx = device_ctx.read().await;
+    let data = device_ctx.led_channel.state.lock().unwrap().data.clone();
+    Ok(data)
+}
+
+pub async fn delete_led_channel_data(
+    Extension(device_ctx): Extension<Arc<RwLock<DeviceCtx>>>,
+) -> api_error::Result<String> {
+    let device_ctx = device_ctx.read().await;
+    let mut state = device_ctx.led_channel.state.lock().unwrap();
+    let enabled = state.enabled;
+    let prev_led = state.prev_led;
+    *state = LedChannelState {
+        enabled,
+        prev_led,
+        ..Default::default()
+    };
+    Ok("null".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 按目标机器上脚本的方式切换锁定键
+    fn send_frame(state: &mut LedChannelState, led: &mut u8, frame: &[u8]) {
+        let now = Instant::now();
+        for byte in frame {
+            for i in 0..8 {
+                *led ^= if byte & (0x80 >> i) != 0 {
+                    LOCK_LED_CAPS_LOCK
+                } else {
+                    LOCK_LED_NUM_LOCK
+                };
+                state.push(*led, now);
+            }
+        }
+        *led ^= LOCK_LED_SCROLL_LOCK;
+        state.push(*led, now);
+    }
+
+    fn enabled_state(led: &mut u8) -> LedChannelState {
+        let mut state = LedChannelState {
+            enabled: true,
+            ..Default::default()
+        };
+        state.push(*led, Instant::now());
+        *led ^= LOCK_LED_SCROLL_LOCK;
+        state.push(*led, Instant::now());
+        state
+    }
+
+    #[test]
+    fn crc16_known_vector() {
+        assert_eq!(crc16(b"123456789"), 0x29b1);
+        assert_eq!(crc16(b""), 0xffff);
+    }
+
+    #[test]
+    fn decode_frames() {
+        let mut led = 0;
+        let mut state = enabled_state(&mut led);
+        let payload = b"hello";
+        let frame = [&payload[..], &crc16(payload).to_be_bytes()].concat();
+        send_frame(&mut state, &mut led, &frame);
+        assert_eq!(state.data, payload);
+        assert_eq!(state.frames, 1);
+
+        let mut frame = frame.clone();
+        frame[0] ^= 1;
+        send_frame(&mut state, &mut led, &frame);
+        assert_eq!(state.data, payload);
+        assert_eq!(state.bad_frames, 1);
+        assert_eq!(state.bits, 2 * 7 * 8);
+    }
+
+    #[test]
+    fn drop_frame_on_lost_led_change() {
+        let mut led = 0;
+        let mut state = enabled_state(&mut led);
+        led ^= LOCK_LED_NUM_LOCK | LOCK_LED_CAPS_LOCK;
+        state.push(led, Instant::now());
+        assert_eq!(state.errors, 1);
+        // 下一个 Scroll Lock 之前的数据都被丢弃
+        let payload = b"x";
+        let frame = [&payload[..], &crc16(payload).to_be_bytes()].concat();
+        send_frame(&mut state, &mut led, &frame);
+        assert!(state.data.is_empty());
+        assert_eq!(state.bad_frames, 0);
+        send_frame(&mut state, &mut led, &frame);
+        assert_eq!(state.data, payload);
+    }
+
+    #[test]
+    fn drop_frame_too_large() {
+        let mut led = 0;
+        let mut state = enabled_state(&mut led);
+        let now = Instant::now();
+        for _ in 0..(LED_CHANNEL_FRAME_MAX + 1) * 8 {
+            led ^= LOCK_LED_NUM_LOCK;
+            state.push(led, now);
+        }
+        assert_eq!(state.bad_frames, 1);
+        assert!(!state.in_frame);
+        assert!(state.frame.is_empty());
+        // 下一个 Scroll Lock 之后恢复接收
+        let payload = b"x";
+        let frame = [&payload[..], &crc16(payload).to_be_bytes()].concat();
+        send_frame(&mut state, &mut led, &frame);
+        send_frame(&mut state, &mut led, &frame);
+        assert_eq!(state.data, payload);
+    }
+}
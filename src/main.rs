@@ -1,18 +1,12 @@
 use std::{any::Any, net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
 
-use axum::{
-    body::Body,
-    extract::{Extension, State},
-    http::{uri::Uri, Request},
-    response::{IntoResponse, Response},
-    routing, Router,
-};
+use axum::{body::Body, extract::Extension, routing, Router};
 
-use hyper::StatusCode;
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
 use hyper_util::{client::legacy::connect::HttpConnector, rt::TokioExecutor};
 use tokio::{
     main, signal,
-    sync::{Mutex, RwLock},
+    sync::{watch, Mutex, RwLock},
     task::JoinSet,
     time,
 };
@@ -23,34 +17,103 @@ use tower_http::{
 
 use clap::Parser;
 
-use usb_otg::{hid, Configurable, GadgetInfo, UsbConfiguration};
+use usb_otg::{
+    ffs::{msg::FfsMsg, FunctionFsOpts},
+    hid::{self, apple::KeyboardProfile, hid_composite::HidCompositeOutput, hidg},
+    udc::{self, UdcState},
+    Configurable, GadgetInfo, UsbConfiguration,
+};
 use util::error;
 
+mod agent;
 mod api_error;
+mod custom_hid;
+mod ffs_msg;
+mod fido;
+mod fido_authenticator;
+mod gamepad;
+mod held_input;
+mod hid_health;
+mod image_convert;
+mod image_fetch;
+mod image_upload;
+mod jiggler;
 mod keyboard;
+mod led_channel;
 mod mass_storage;
+mod metrics;
 mod mouse;
 mod mouse_legacy;
+mod mouse_relative;
+mod ocr;
+mod screen;
+mod stream;
+mod type_file;
+mod udc_state;
 
 const CONFIGFS_BASE: &str = "/sys/kernel/config/usb_gadget";
 
 pub struct DeviceCtx {
+    id: usize,
     usb_gadget_path: String,
+    udc_name: String,
+    udc_state_sender: watch::Sender<UdcState>,
     hid_composite_device: hid::hid_composite::HidCompositeDevice,
     keyboard_device: hid::keyboard::KeyboardDevice,
     mouse_device: hid::mouse::MouseDevice,
+    gamepad_device: Option<hid::gamepad::GamepadDevice>,
+    fido: Option<fido::Fido>,
+    custom_hids: Vec<custom_hid::CustomHid>,
+    ffs_msg: Option<ffs_msg::FfsMassStorage>,
+    keyboard_profile: KeyboardProfile,
+    max_hold: Option<Duration>,
+    // 同一时间只允许一个组合键任务
+    chord_lock: Arc<Mutex<()>>,
     join_set: Mutex<JoinSet<()>>,
+    reconnect_lock: Mutex<()>,
+    hid_health: hid_health::HidHealth,
+    held_inputs: held_input::HeldInputs,
+    jiggler: jiggler::Jiggler,
+    agent: agent::Agent,
+    led_channel: led_channel::LedChannel,
+    type_file: type_file::TypeFile,
 }
 
-const UDC_PATH: &str = "/sys/class/udc";
 const CONFIGURE_NAME: &str = "c.1";
 const FUNCTION_NAME_KEYBOARD_LEGACY: &str = "hid.keyboard_legacy";
 const FUNCTION_NAME_MOUSE_LEGACY: &str = "hid.mouse_legacy";
 const FUNCTION_NAME_HID_COMPOSITE: &str = "hid.hid_composite";
+const FUNCTION_NAME_GAMEPAD: &str = "hid.gamepad";
+const FUNCTION_NAME_FIDO: &str = "hid.fido";
 const FUNCTION_NAME_MSG: &str = "mass_storage.msg";
 const LUN_COUNT: u8 = 8;
+const FFS_MOUNT_BASE: &str = "/run/ip-kvm/ffs";
+const UDC_STATE_POLL_INTERVAL: Duration = Duration::from_millis(500);
+const HID_RECOVER_BACKOFF_MIN: Duration = Duration::from_millis(100);
+const HID_RECOVER_BACKOFF_MAX: Duration = Duration::from_secs(10);
+
+// 每个 gadget 的可选功能
+#[derive(Clone)]
+pub struct DeviceOptions {
+    // 按键按住超过这个时间后自动松开
+    max_hold: Option<Duration>,
+    gamepad: bool,
+    // 启用虚拟安全密钥，凭据保存在这个文件里
+    fido_store: Option<PathBuf>,
+    keyboard_profile: KeyboardProfile,
+    // 配置文件中描述的额外 hid 设备
+    custom_hids: Vec<custom_hid::CustomHidConfig>,
+    // 用 FunctionFS 在用户态实现 U 盘，替代内核的 mass_storage
+    ffs_mass_storage: bool,
+}
+
 impl DeviceCtx {
-    pub async fn new(configfs_base: &str) -> error::Result<Arc<RwLock<Self>>> {
+    pub async fn new(
+        configfs_base: &str,
+        id: usize,
+        udc_name: String,
+        options: DeviceOptions,
+    ) -> error::Result<Arc<RwLock<Self>>> {
         let mut gadget_info: GadgetInfo = Default::default();
         gadget_info.functions.insert(
             FUNCTION_NAME_KEYBOARD_LEGACY.into(),
@@ -62,25 +125,58 @@ impl DeviceCtx {
         );
         gadget_info.functions.insert(
             FUNCTION_NAME_HID_COMPOSITE.into(),
-            Box::new(hid::hid_composite::HID_COMPOSITE_FHO.clone()),
+            Box::new(options.keyboard_profile.hid_composite_fho()),
         );
-
-        let mut function_msg_opt = usb_otg::mass_storage::FunctionMsgOpts::default();
-
-        for i in 1..LUN_COUNT {
-            function_msg_opt.luns.insert(
-                usb_otg::mass_storage::FunctionMsgOpts::lun_name(i as u8),
-                usb_otg::mass_storage::MsgLun::default(),
+        // 会多出一个设备，需要时才启用
+        if options.gamepad {
+            gadget_info.functions.insert(
+                FUNCTION_NAME_GAMEPAD.into(),
+                Box::new(hid::gamepad::GAMEPAD_FHO.clone()),
             );
         }
-
-        for entry in function_msg_opt.luns.iter_mut() {
-            entry.1.removable = true;
+        // 先打开凭据文件，出错时不用再清理 gadget
+        let fido_authenticator = match &options.fido_store {
+            Some(fido_store) => {
+                gadget_info.functions.insert(
+                    FUNCTION_NAME_FIDO.into(),
+                    Box::new(hid::fido::FIDO_FHO.clone()),
+                );
+                Some(fido_authenticator::Authenticator::open(fido_store)?)
+            }
+            None => None,
+        };
+        for config in &options.custom_hids {
+            gadget_info
+                .functions
+                .insert(config.function_name(), Box::new(config.fho()));
         }
 
-        gadget_info
-            .functions
-            .insert(FUNCTION_NAME_MSG.into(), Box::new(function_msg_opt));
+        // functionfs 的实例名在所有 gadget 之间不能重复
+        let ffs_msg_instance = format!("ip_kvm_msg{id}");
+        if options.ffs_mass_storage {
+            gadget_info.functions.insert(
+                format!("ffs.{ffs_msg_instance}"),
+                Box::new(FunctionFsOpts::default()),
+            );
+        } else {
+            // 内核的 mass_storage 直接读写镜像文件，没有覆盖层，覆盖层接口返回 404
+            let mut function_msg_opt = usb_otg::mass_storage::FunctionMsgOpts::default();
+
+            for i in 1..LUN_COUNT {
+                function_msg_opt.luns.insert(
+                    usb_otg::mass_storage::FunctionMsgOpts::lun_name(i),
+                    usb_otg::mass_storage::MsgLun::default(),
+                );
+            }
+
+            for entry in function_msg_opt.luns.iter_mut() {
+                entry.1.removable = true;
+            }
+
+            gadget_info
+                .functions
+                .insert(FUNCTION_NAME_MSG.into(), Box::new(function_msg_opt));
+        }
 
         let mut usb_config: UsbConfiguration = Default::default();
         usb_config
@@ -96,32 +192,46 @@ impl DeviceCtx {
         gadget_info
             .strings
             .insert(usb_otg::LANGUAGE_CODE_ENGLISH, Default::default());
+        options.keyboard_profile.apply(&mut gadget_info);
 
-        let mut udc_name = None;
-        for entry in util::fs::read_dir(UDC_PATH)? {
-            let entry = entry.map_err(|err| error::ErrorKind::io(err, UDC_PATH))?;
-            let path = entry.path();
-            if let Some(path_file_name) = path.file_name() {
-                if let Some(path_file_name) = path_file_name.to_str() {
-                    udc_name = Some(path_file_name.to_string());
-                    break;
-                }
-            }
-        }
-
-        if let Some(udc_name) = udc_name {
-            gadget_info.udc = udc_name;
+        log::info!("Target {id} UDC name: {udc_name}");
+        // functionfs 写入描述符后才能绑定 UDC
+        gadget_info.udc = if options.ffs_mass_storage {
+            "\n".into()
         } else {
-            Err(error::ErrorKind::custom("Can not found udc".into()))?;
-        }
-
-        log::info!("UDC name: {}", gadget_info.udc);
+            udc_name.clone()
+        };
 
         gadget_info.bcd_usb = 0x210; // USB 2.1
 
-        let usb_gadget_path = format!("{configfs_base}/ip-kvm");
+        // 第一个 gadget 保持原来的路径
+        let usb_gadget_path = if id == 0 {
+            format!("{configfs_base}/ip-kvm")
+        } else {
+            format!("{configfs_base}/ip-kvm-{id}")
+        };
         GadgetInfo::cleanup(&usb_gadget_path)?;
         gadget_info.apply_config(&usb_gadget_path)?;
+        let ffs_msg = if options.ffs_mass_storage {
+            let mount_dir = std::path::Path::new(FFS_MOUNT_BASE).join(&ffs_msg_instance);
+            let msg = match FfsMsg::new(&ffs_msg_instance, &mount_dir) {
+                Ok(msg) => msg,
+                Err(err) => {
+                    let _ = GadgetInfo::cleanup(&usb_gadget_path);
+                    return Err(err);
+                }
+            };
+            // 先绑定，失败时还没有启动线程，只需要卸载 functionfs
+            if let Err(err) = GadgetInfo::bind_udc(&usb_gadget_path, &udc_name) {
+                msg.close();
+                let _ = GadgetInfo::cleanup(&usb_gadget_path);
+                return Err(err);
+            }
+            msg.spawn();
+            Some(ffs_msg::FfsMassStorage::new(msg))
+        } else {
+            None
+        };
 
         let keyboard_legacy_minor = (gadget_info
             .functions
@@ -154,12 +264,50 @@ impl DeviceCtx {
             "keyboard_legacy_minor: {keyboard_legacy_minor} mouse_legacy_minor: {mouse_legacy_minor} hid_composite_minor: {hid_composite_minor}"
         );
 
+        let gamepad_minor = gadget_info
+            .functions
+            .get(FUNCTION_NAME_GAMEPAD)
+            .map(|function| {
+                (function.as_ref() as &dyn Any)
+                    .downcast_ref::<hid::FunctionHidOpts>()
+                    .unwrap()
+                    .minor
+            });
+
+        let fido_minor = gadget_info
+            .functions
+            .get(FUNCTION_NAME_FIDO)
+            .map(|function| {
+                (function.as_ref() as &dyn Any)
+                    .downcast_ref::<hid::FunctionHidOpts>()
+                    .unwrap()
+                    .minor
+            });
+
+        let custom_hid_minors: Vec<_> = options
+            .custom_hids
+            .iter()
+            .map(|config| {
+                (gadget_info
+                    .functions
+                    .get(&config.function_name())
+                    .unwrap()
+                    .as_ref() as &dyn Any)
+                    .downcast_ref::<hid::FunctionHidOpts>()
+                    .unwrap()
+                    .minor
+            })
+            .collect();
+
         let hid_path_list: Vec<_> = [
             keyboard_legacy_minor,
             mouse_legacy_minor,
             hid_composite_minor,
         ]
         .iter()
+        .chain(gamepad_minor.iter())
+        .chain(fido_minor.iter())
+        .chain(custom_hid_minors.iter())
         .map(|hid_id| std::path::Path::new(&format!("/dev/hidg{hid_id}")).to_path_buf())
         .collect();
 
@@ -171,20 +319,62 @@ impl DeviceCtx {
             hid::hid_composite::HidCompositeDevice::new(hid_composite_minor).await?;
         let keyboard_device = hid::keyboard::KeyboardDevice::new(
             keyboard_legacy_minor,
-            hid_composite_device.hid_composite_dev_send_sender.clone(),
+            hid_composite_device.hid_composite_report_queue.clone(),
         )
         .await?;
         let mouse_device = hid::mouse::MouseDevice::new(
             mouse_legacy_minor,
-            hid_composite_device.hid_composite_dev_send_sender.clone(),
+            hid_composite_device.hid_composite_report_queue.clone(),
         )
         .await?;
+        let gamepad_device = match gamepad_minor {
+            Some(gamepad_minor) => Some(hid::gamepad::GamepadDevice::new(gamepad_minor).await?),
+            None => None,
+        };
+        let fido = match (fido_minor, fido_authenticator) {
+            (Some(fido_minor), Some(fido_authenticator)) => Some(fido::Fido::new(
+                hid::fido::FidoDevice::new(fido_minor).await?,
+                fido_authenticator,
+            )),
+            _ => None,
+        };
+        let mut custom_hids = Vec::new();
+        for (config, custom_hid_minor) in options.custom_hids.iter().zip(custom_hid_minors) {
+            custom_hids.push(custom_hid::CustomHid::new(config, custom_hid_minor).await?);
+        }
+        let hid_health = hid_health::HidHealth::default();
+        if gamepad_device.is_some() {
+            hid_health.register(hid_health::HID_DEV_GAMEPAD);
+        }
+        if fido.is_some() {
+            hid_health.register(hid_health::HID_DEV_FIDO);
+        }
+        for custom_hid in &custom_hids {
+            hid_health.register(custom_hid.health_name);
+        }
         let ret = Arc::new(RwLock::new(Self {
             join_set: Mutex::new(JoinSet::new()),
+            reconnect_lock: Mutex::new(()),
+            hid_health,
+            held_inputs: Default::default(),
+            jiggler: Default::default(),
+            agent: Default::default(),
+            led_channel: Default::default(),
+            type_file: Default::default(),
             hid_composite_device,
             keyboard_device,
             mouse_device,
+            gamepad_device,
+            fido,
+            custom_hids,
+            ffs_msg,
+            keyboard_profile: options.keyboard_profile,
+            max_hold: options.max_hold,
+            chord_lock: Default::default(),
+            id,
             usb_gadget_path,
+            udc_name,
+            udc_state_sender: watch::channel(UdcState::Unknown).0,
         }));
         let device_ctx = ret.write().await;
         let join_set = &device_ctx.join_set;
@@ -195,50 +385,338 @@ impl DeviceCtx {
             let device_ctx = ret_recv.read().await;
             let hid_composite_device = &device_ctx.hid_composite_device;
             let keyboard_device = &device_ctx.keyboard_device;
+            let mut backoff = HID_RECOVER_BACKOFF_MIN;
             loop {
-                let res = hid_composite_device.recv().await;
-
-                if let Err(error::Error(err)) = &res {
-                    if let error::ErrorKind::Ignore = err.as_ref() {
+                let payload = match hid_composite_device.recv().await {
+                    Ok(payload) => payload,
+                    Err(err) if hidg::is_ignore(&err) => continue,
+                    Err(err) => {
+                        device_ctx
+                            .recover_hid_dev(hid_health::HID_DEV_HID_COMPOSITE, &err, &mut backoff)
+                            .await;
                         continue;
                     }
+                };
+                backoff = HID_RECOVER_BACKOFF_MIN;
+                device_ctx
+                    .hid_health
+                    .set_ok(hid_health::HID_DEV_HID_COMPOSITE);
+                let res = match payload {
+                    HidCompositeOutput::BootLed(led) => keyboard_device.recv_boot_led(led).await,
+                    HidCompositeOutput::Report(payload) => match payload[0] {
+                        hid::hid_composite::HID_REPORT_ID_KEYBOARD => {
+                            keyboard_device.recv(&payload).await
+                        }
+                        hid::hid_composite::HID_REPORT_ID_VENDOR => {
+                            device_ctx.agent.recv(device_ctx.id, &payload);
+                            continue;
+                        }
+                        report_id => {
+                            log::warn!("hid_composite_dev unknown report id: {report_id}");
+                            continue;
+                        }
+                    },
+                };
+                if let Err(err) = res {
+                    log::error!("keyboard_device.recv failed: {err}");
                 }
-                let payload = res.unwrap();
-                assert!(payload[0] == hid::hid_composite::HID_REPORT_ID_KEYBOARD);
-                keyboard_device.recv(&payload).await.unwrap();
             }
         });
 
-        // 复合 hid 设备接受来自其它设备的请求，并发送给 /dev/
+        // 复合 hid 设备按顺序发送队列中的报告
         let hid_composite_ret = ret.clone();
         join_set.lock().await.spawn(async move {
             let device_ctx = hid_composite_ret.read().await;
             let hid_composite_device = &device_ctx.hid_composite_device;
-            let mut hid_composite_dev_receiver = hid_composite_device
-                .hid_composite_dev_send_sender
-                .subscribe();
+            let hid_composite_report_queue = &hid_composite_device.hid_composite_report_queue;
+            let mut backoff = HID_RECOVER_BACKOFF_MIN;
 
+            while let Some(hid_composite_send_data) = hid_composite_report_queue.pop().await {
+                let res = hid_composite_device.send(&hid_composite_send_data).await;
+                metrics::METRICS.hid_report_sent(
+                    device_ctx.id,
+                    "hid_composite",
+                    "send",
+                    res.is_ok(),
+                );
+                match res {
+                    Ok(()) => {
+                        backoff = HID_RECOVER_BACKOFF_MIN;
+                        device_ctx
+                            .hid_health
+                            .set_ok(hid_health::HID_DEV_HID_COMPOSITE);
+                    }
+                    Err(err) if hidg::is_ignore(&err) => {}
+                    // 电脑关机后 send 会失败，直接丢弃报告，避免队列阻塞输入
+                    Err(err) if hidg::is_host_disconnected(&err) => {
+                        device_ctx
+                            .hid_health
+                            .set_error(hid_health::HID_DEV_HID_COMPOSITE, &err);
+                    }
+                    Err(err) => {
+                        device_ctx
+                            .recover_hid_dev(hid_health::HID_DEV_HID_COMPOSITE, &err, &mut backoff)
+                            .await;
+                    }
+                }
+            }
+        });
+
+        // 监视 UDC 状态，判断主机是否已经枚举了 gadget
+        let udc_state_ret = ret.clone();
+        join_set.lock().await.spawn(async move {
+            let device_ctx = udc_state_ret.read().await;
             loop {
-                if hid_composite_dev_receiver.changed().await.is_ok() {
-                    let hid_composite_send_data =
-                        hid_composite_dev_receiver.borrow_and_update().to_vec();
-                    // 电脑关机后 send 会失败
-                    let _ = hid_composite_device.send(&hid_composite_send_data).await;
+                let udc_state = match udc::read_state(&device_ctx.udc_name) {
+                    Ok(udc_state) => udc_state,
+                    Err(err) => {
+                        log::warn!("Read udc state failed: {err}");
+                        UdcState::Unknown
+                    }
+                };
+                let changed = device_ctx.udc_state_sender.send_if_modified(|prev| {
+                    if *prev != udc_state {
+                        log::info!("UDC {} state: {prev} -> {udc_state}", device_ctx.udc_name);
+                        *prev = udc_state;
+                        true
+                    } else {
+                        false
+                    }
+                });
+                if changed {
+                    // 主机复位或者重新配置后可能换成 BIOS 或者另一个系统，重新推断键盘协议
+                    device_ctx.keyboard_device.reset_protocol();
+                    if udc_state.is_configured() {
+                        device_ctx.update_hid_report_interval();
+                    }
                 }
+                time::sleep(UDC_STATE_POLL_INTERVAL).await;
             }
         });
 
+        // 按住超过 max_hold 的按键自动松开，避免目标机器一直认为按键被按下
+        if let Some(max_hold) = options.max_hold {
+            let held_input_ret = ret.clone();
+            join_set.lock().await.spawn(async move {
+                let device_ctx = held_input_ret.read().await;
+                loop {
+                    time::sleep(held_input::HELD_INPUT_CHECK_INTERVAL).await;
+                    let inputs = device_ctx.held_inputs.take_expired(max_hold);
+                    if !inputs.is_empty() {
+                        log::warn!(
+                            "Target {} release {inputs:?} held longer than {max_hold:?}",
+                            device_ctx.id
+                        );
+                        held_input::release(&device_ctx, &inputs).await;
+                    }
+                }
+            });
+        }
+
+        let led_channel_ret = ret.clone();
+        join_set.lock().await.spawn(async move {
+            let device_ctx = led_channel_ret.read().await;
+            device_ctx
+                .led_channel
+                .run(&device_ctx.keyboard_device)
+                .await;
+        });
+
+        let jiggler_ret = ret.clone();
+        join_set.lock().await.spawn(async move {
+            let device_ctx = jiggler_ret.read().await;
+            device_ctx.jiggler.run(&device_ctx).await;
+        });
+
+        if device_ctx.fido.is_some() {
+            let (fido_sender, fido_receiver) = tokio::sync::mpsc::channel(1);
+            let fido_reader_ret = ret.clone();
+            join_set.lock().await.spawn(async move {
+                let device_ctx = fido_reader_ret.read().await;
+                let fido = device_ctx.fido.as_ref().unwrap();
+                fido.run_reader(&device_ctx, fido_sender).await;
+            });
+            let fido_processor_ret = ret.clone();
+            join_set.lock().await.spawn(async move {
+                let device_ctx = fido_processor_ret.read().await;
+                let fido = device_ctx.fido.as_ref().unwrap();
+                fido.run_processor(&device_ctx, fido_receiver).await;
+            });
+        }
+
+        for i in 0..device_ctx.custom_hids.len() {
+            let custom_hid_ret = ret.clone();
+            join_set.lock().await.spawn(async move {
+                let device_ctx = custom_hid_ret.read().await;
+                device_ctx.custom_hids[i].run_reader(&device_ctx).await;
+            });
+        }
+
         let recv_legacy = ret.clone();
         join_set.lock().await.spawn(async move {
             let device_ctx = recv_legacy.read().await;
             let keyboard_device = &device_ctx.keyboard_device;
+            let mut backoff = HID_RECOVER_BACKOFF_MIN;
             loop {
-                keyboard_device.recv_legacy().await.unwrap();
+                match keyboard_device.recv_legacy().await {
+                    Ok(()) => {
+                        backoff = HID_RECOVER_BACKOFF_MIN;
+                        device_ctx
+                            .hid_health
+                            .set_ok(hid_health::HID_DEV_KEYBOARD_LEGACY);
+                    }
+                    Err(err) if hidg::is_ignore(&err) => {}
+                    Err(err) => {
+                        device_ctx
+                            .recover_hid_dev(
+                                hid_health::HID_DEV_KEYBOARD_LEGACY,
+                                &err,
+                                &mut backoff,
+                            )
+                            .await;
+                    }
+                }
             }
         });
         drop(device_ctx);
         Ok(ret)
     }
+    // 读写 hidg 失败后由对应的任务调用，主机断开时等待重新枚举，其它错误重新打开 fd
+    async fn recover_hid_dev(
+        &self,
+        name: &'static str,
+        err: &error::Error,
+        backoff: &mut Duration,
+    ) {
+        match self.hid_health.set_error(name, err) {
+            hid_health::HidDevState::HostDisconnected => {
+                log::debug!("Target {} {name} host disconnected: {err}", self.id);
+                let mut udc_state_receiver = self.udc_state_sender.subscribe();
+                let _ = udc_state_receiver
+                    .wait_for(|udc_state| udc_state.is_configured())
+                    .await;
+                time::sleep(*backoff).await;
+            }
+            _ => {
+                log::warn!(
+                    "Target {} {name} failed, reopen after {backoff:?}: {err}",
+                    self.id
+                );
+                time::sleep(*backoff).await;
+                if let Err(err) = self.reopen_hid_dev(name).await {
+                    log::error!("Target {} {name} reopen failed: {err}", self.id);
+                    self.hid_health.set_failed(name, &err);
+                }
+            }
+        }
+        *backoff = (*backoff * 2).min(HID_RECOVER_BACKOFF_MAX);
+    }
+
+    // 主机枚举后根据实际速度调整报告的发送间隔
+    fn update_hid_report_interval(&self) {
+        let speed = match udc::read_speed(&self.udc_name) {
+            Ok(speed) => speed,
+            Err(err) => {
+                log::warn!("Read udc speed failed: {err}");
+                return;
+            }
+        };
+        let interval = hidg::report_interval(&speed);
+        log::info!("Target {} hid report interval: {interval:?}", self.id);
+        self.hid_composite_device.set_report_interval(interval);
+        self.keyboard_device.set_report_interval(interval);
+        self.mouse_device.set_report_interval(interval);
+        if let Some(gamepad_device) = &self.gamepad_device {
+            gamepad_device.set_report_interval(interval);
+        }
+        if let Some(fido) = &self.fido {
+            fido.device.set_report_interval(interval);
+        }
+        for custom_hid in &self.custom_hids {
+            custom_hid.device.set_report_interval(interval);
+        }
+    }
+
+    fn custom_hid(&self, name: &str) -> Option<&custom_hid::CustomHid> {
+        self.custom_hids
+            .iter()
+            .find(|custom_hid| custom_hid.name == name)
+    }
+
+    pub async fn reopen_hid_dev(&self, name: &'static str) -> error::Result<()> {
+        match name {
+            hid_health::HID_DEV_HID_COMPOSITE => self.hid_composite_device.reopen().await?,
+            hid_health::HID_DEV_KEYBOARD_LEGACY => self.keyboard_device.reopen().await?,
+            hid_health::HID_DEV_MOUSE_LEGACY => self.mouse_device.reopen().await?,
+            hid_health::HID_DEV_GAMEPAD => {
+                if let Some(gamepad_device) = &self.gamepad_device {
+                    gamepad_device.reopen().await?
+                }
+            }
+            hid_health::HID_DEV_FIDO => {
+                if let Some(fido) = &self.fido {
+                    fido.device.reopen().await?
+                }
+            }
+            _ => match self
+                .custom_hids
+                .iter()
+                .find(|custom_hid| custom_hid.health_name == name)
+            {
+                Some(custom_hid) => custom_hid.device.reopen().await?,
+                None => unreachable!(),
+            },
+        }
+        self.hid_health.reopened(name);
+        metrics::METRICS.hid_device_reopened(self.id, name);
+        Ok(())
+    }
+
+    // WebSocket 处理中直接写入 legacy 设备失败时调用，不等待，避免阻塞输入
+    pub async fn hid_write_failed(&self, name: &'static str, err: &error::Error) {
+        if hidg::is_ignore(err) {
+            return;
+        }
+        if self.hid_health.set_error(name, err) == hid_health::HidDevState::Recovering {
+            if let Err(err) = self.reopen_hid_dev(name).await {
+                log::error!("Target {} {name} reopen failed: {err}", self.id);
+                self.hid_health.set_failed(name, &err);
+            }
+        }
+    }
+
+    // 模拟拔插 USB，让主机重新枚举，web 服务和 WebSocket 连接不受影响
+    pub async fn reconnect(&self, delay: Duration) -> error::Result<()> {
+        let _reconnect_guard = self.reconnect_lock.lock().await;
+        log::info!("Target {} unbind UDC {}.", self.id, self.udc_name);
+        GadgetInfo::unbind_udc(&self.usb_gadget_path)?;
+        time::sleep(delay).await;
+
+        let res = async {
+            self.reopen_hid_dev(hid_health::HID_DEV_HID_COMPOSITE)
+                .await?;
+            self.reopen_hid_dev(hid_health::HID_DEV_KEYBOARD_LEGACY)
+                .await?;
+            self.reopen_hid_dev(hid_health::HID_DEV_MOUSE_LEGACY)
+                .await?;
+            if self.gamepad_device.is_some() {
+                self.reopen_hid_dev(hid_health::HID_DEV_GAMEPAD).await?;
+            }
+            if self.fido.is_some() {
+                self.reopen_hid_dev(hid_health::HID_DEV_FIDO).await?;
+            }
+            for custom_hid in &self.custom_hids {
+                self.reopen_hid_dev(custom_hid.health_name).await?;
+            }
+            Ok(())
+        }
+        .await;
+        // reopen 失败也要重新绑定，否则主机就一直看不到设备了
+        log::info!("Target {} bind UDC {}.", self.id, self.udc_name);
+        GadgetInfo::bind_udc(&self.usb_gadget_path, &self.udc_name)?;
+        res
+    }
+
     pub async fn abort_join_set(&self) {
         log::info!("DeviceCtx start shutdown.");
         self.join_set.lock().await.shutdown().await;
@@ -248,6 +726,9 @@ impl DeviceCtx {
 
 impl Drop for DeviceCtx {
     fn drop(&mut self) {
+        if let Some(ffs_msg) = &self.ffs_msg {
+            ffs_msg.msg.close();
+        }
         if let Err(err) = GadgetInfo::cleanup(&self.usb_gadget_path) {
             log::error!("GadgetInfo cleanup failed: {err}");
         } else {
@@ -266,53 +747,260 @@ struct Args {
     ustreamer_url: String,
     #[arg(long, default_value = "images")]
     image_dir: String,
+    // 可以重复指定，每个 UDC 对应一个独立的 gadget，默认使用第一个 UDC
+    #[arg(long)]
+    udc: Vec<String>,
+    #[arg(long)]
+    list_udc: bool,
+    // 按键按住超过这个时间后自动松开，0 表示不限制
+    #[arg(long, default_value_t = 60)]
+    max_hold_secs: u64,
+    // 额外提供一个游戏手柄设备
+    #[arg(long)]
+    gamepad: bool,
+    // 虚拟 FIDO2/U2F 安全密钥的凭据文件，指定后启用，多个 target 时会加上 .<id> 后缀
+    #[arg(long)]
+    fido_store: Option<PathBuf>,
+    // 额外 hid 设备的 JSON 配置文件，每个 target 都会添加这些设备
+    #[arg(long)]
+    custom_hid: Option<PathBuf>,
+    // 用 FunctionFS 在用户态实现 U 盘，可以使用内存、覆盖层和 HTTP 存储
+    #[arg(long)]
+    ffs_mass_storage: bool,
+    // generic 或 apple，apple 会模拟 Apple 键盘并提供 Fn 键
+    #[arg(long, value_enum, default_value = "generic")]
+    keyboard_profile: KeyboardProfile,
+    #[arg(long, requires = "ocr_recognition_model")]
+    ocr_detection_model: Option<String>,
+    #[arg(long, requires = "ocr_detection_model")]
+    ocr_recognition_model: Option<String>,
 }
 
-type Client = hyper_util::client::legacy::Client<HttpConnector, Body>;
+type Client = hyper_util::client::legacy::Client<HttpsConnector<HttpConnector>, Body>;
+
+// 远程镜像可能是 https 的，证书用内置的根证书验证
+fn new_http_client() -> Client {
+    let https_connector = HttpsConnectorBuilder::new()
+        .with_webpki_roots()
+        .https_or_http()
+        .enable_http1()
+        .build();
+    hyper_util::client::legacy::Client::<(), ()>::builder(TokioExecutor::new())
+        .build(https_connector)
+}
 
 struct AppState {
     args: Args,
+    targets: Vec<Arc<RwLock<DeviceCtx>>>,
+    mjpeg_stream: Arc<stream::MjpegStream>,
+    ocr: Option<ocr::Ocr>,
     http_client: Client,
+    image_uploads: image_upload::ImageUploads,
+    image_fetches: image_fetch::ImageFetches,
+}
+
+// 与具体 gadget 相关的路由
+fn target_router(device_ctx: Arc<RwLock<DeviceCtx>>) -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/ws/keyboard", routing::get(keyboard::ws_handler))
+        .route("/ws/mouse", routing::get(mouse::ws_handler))
+        .route("/ws/mouse_legacy", routing::get(mouse_legacy::ws_handler))
+        .route(
+            "/ws/mouse_relative",
+            routing::get(mouse_relative::ws_handler),
+        )
+        .route("/ws/gamepad", routing::get(gamepad::ws_handler))
+        .route("/ws/hid/custom/:name", routing::get(custom_hid::ws_handler))
+        .route("/hid/custom", routing::get(custom_hid::get_custom_hids))
+        .route(
+            "/hid/custom/:name/report",
+            routing::post(custom_hid::post_report),
+        )
+        .route("/usb/state", routing::get(udc_state::get_state))
+        .route("/ws/udc_state", routing::get(udc_state::ws_handler))
+        .route("/usb/reconnect", routing::post(udc_state::post_reconnect))
+        .route("/usb/health", routing::get(udc_state::get_health))
+        .route(
+            "/keyboard/protocol",
+            routing::get(keyboard::get_protocol).put(keyboard::put_protocol),
+        )
+        .route("/keyboard/chord", routing::post(keyboard::post_chord))
+        .route(
+            "/keyboard/led-channel",
+            routing::get(led_channel::get_led_channel).put(led_channel::put_led_channel),
+        )
+        .route(
+            "/keyboard/led-channel/data",
+            routing::get(led_channel::get_led_channel_data)
+                .delete(led_channel::delete_led_channel_data),
+        )
+        .route(
+            "/keyboard/type-file",
+            routing::get(type_file::get_type_file)
+                .post(type_file::post_type_file)
+                .delete(type_file::delete_type_file),
+        )
+        .route(
+            "/keyboard/type-file/pause",
+            routing::post(type_file::post_pause),
+        )
+        .route(
+            "/keyboard/type-file/resume",
+            routing::post(type_file::post_resume),
+        )
+        .route("/mass-storage", routing::get(ffs_msg::get_mass_storage))
+        .route(
+            "/mass-storage/medium",
+            routing::put(ffs_msg::put_medium).delete(ffs_msg::delete_medium),
+        )
+        .route(
+            "/mass-storage/written-sectors",
+            routing::get(ffs_msg::get_written_sectors),
+        )
+        .route(
+            "/mass-storage/overlay",
+            routing::post(ffs_msg::post_overlay),
+        )
+        .route("/agent", routing::get(agent::get_agent))
+        .route("/agent/clipboard", routing::put(agent::put_clipboard))
+        .route("/agent/query", routing::post(agent::post_query))
+        .route("/fido/presence", routing::get(fido::get_presence))
+        .route("/fido/touch", routing::post(fido::post_touch))
+        .route("/fido/deny", routing::post(fido::post_deny))
+        .route("/fido/credentials", routing::get(fido::get_credentials))
+        .route(
+            "/jiggler",
+            routing::get(jiggler::get_jiggler).put(jiggler::put_jiggler),
+        )
+        .route(
+            "/input/release-all",
+            routing::post(held_input::post_release_all),
+        )
+        .route(
+            "/current-image",
+            routing::put(mass_storage::put_current_image),
+        )
+        .layer(Extension(device_ctx))
 }
 
 #[main]
 async fn main() -> error::Result<()> {
-    let http_client: Client =
-        hyper_util::client::legacy::Client::<(), ()>::builder(TokioExecutor::new())
-            .build(HttpConnector::new());
+    let args = Args::parse();
+
+    let http_client = new_http_client();
 
     pretty_env_logger::formatted_builder()
         .filter_level(log::LevelFilter::Info)
         .parse_default_env()
         .init();
 
+    let udc_names = udc::list()?;
+    if args.list_udc {
+        for udc_name in &udc_names {
+            let udc_state = udc::read_state(udc_name).unwrap_or(UdcState::Unknown);
+            println!("{udc_name}\t{udc_state}");
+        }
+        return Ok(());
+    }
+
+    let target_udc_names = if args.udc.is_empty() {
+        let Some(udc_name) = udc_names.first() else {
+            Err(error::ErrorKind::custom("Can not found udc".into()))?
+        };
+        vec![udc_name.clone()]
+    } else {
+        for (i, udc_name) in args.udc.iter().enumerate() {
+            if !udc_names.contains(udc_name) {
+                Err(error::ErrorKind::custom(format!(
+                    "Can not found udc {udc_name}, available: {udc_names:?}"
+                )))?;
+            }
+            if args.udc[..i].contains(udc_name) {
+                Err(error::ErrorKind::custom(format!(
+                    "udc {udc_name} specified more than once"
+                )))?;
+            }
+        }
+        args.udc.clone()
+    };
+
+    let custom_hids = match &args.custom_hid {
+        Some(custom_hid) => custom_hid::CustomHidConfig::load(custom_hid)?,
+        None => Vec::new(),
+    };
+
     let mut join_set = JoinSet::new();
 
-    let device_ctx = DeviceCtx::new(CONFIGFS_BASE).await?;
-    let device_ctx_recv = device_ctx.clone();
-    join_set.spawn(async move {
-        let device_ctx_recv = device_ctx_recv.read().await;
-        let mut join_set = device_ctx_recv.join_set.lock().await;
-        let _ = join_set.join_next().await;
-    });
+    let mut targets = Vec::new();
+    for (id, udc_name) in target_udc_names.into_iter().enumerate() {
+        let options = DeviceOptions {
+            max_hold: (args.max_hold_secs != 0).then(|| Duration::from_secs(args.max_hold_secs)),
+            gamepad: args.gamepad,
+            // 第一个 target 保持原来的文件名
+            fido_store: args.fido_store.as_ref().map(|fido_store| {
+                if id == 0 {
+                    fido_store.clone()
+                } else {
+                    PathBuf::from(format!("{}.{id}", fido_store.display()))
+                }
+            }),
+            keyboard_profile: args.keyboard_profile,
+            custom_hids: custom_hids.clone(),
+            ffs_mass_storage: args.ffs_mass_storage,
+        };
+        let device_ctx = DeviceCtx::new(CONFIGFS_BASE, id, udc_name, options).await?;
+        let device_ctx_recv = device_ctx.clone();
+        join_set.spawn(async move {
+            let device_ctx_recv = device_ctx_recv.read().await;
+            let mut join_set = device_ctx_recv.join_set.lock().await;
+            let _ = join_set.join_next().await;
+        });
+        targets.push(device_ctx);
+    }
+
+    let mjpeg_stream = stream::MjpegStream::new();
+    join_set.spawn(mjpeg_stream.clone().run(
+        http_client.clone(),
+        format!("{}/stream", args.ustreamer_url),
+    ));
 
     let assets_dir = PathBuf::from("ip-kvm-assets");
 
+    let ocr = if let (Some(detection_model), Some(recognition_model)) =
+        (&args.ocr_detection_model, &args.ocr_recognition_model)
+    {
+        let ocr = ocr::Ocr::load(detection_model, recognition_model)
+            .map_err(|err| error::ErrorKind::custom(format!("Load OCR models failed: {err}")))?;
+        log::info!("OCR models loaded.");
+        Some(ocr)
+    } else {
+        None
+    };
+
     let app_state = Arc::new(AppState {
-        args: Args::parse(),
+        args,
+        targets,
+        mjpeg_stream,
+        ocr,
         http_client,
+        image_uploads: Default::default(),
+        image_fetches: Default::default(),
     });
 
-    let app = Router::new()
+    let mut app = Router::new()
         .fallback_service(ServeDir::new(assets_dir).append_index_html_on_directories(true))
-        .route("/stream", routing::get(stream_handler))
-        .route("/v1/ws/keyboard", routing::get(keyboard::ws_handler))
-        .route("/v1/ws/mouse", routing::get(mouse::ws_handler))
+        .route("/stream", routing::get(stream::stream_handler))
+        .route("/metrics", routing::get(metrics::metrics_handler))
+        .route("/v1/targets", routing::get(udc_state::get_targets))
+        .route("/v1/usb-images", routing::get(mass_storage::get_images))
         .route(
-            "/v1/ws/mouse_legacy",
-            routing::get(mouse_legacy::ws_handler),
+            "/v1/usb-images/fetch",
+            routing::get(image_fetch::get_fetches).post(image_fetch::post_fetch),
+        )
+        .route(
+            "/v1/usb-images/fetch/:id",
+            routing::get(image_fetch::get_fetch).delete(image_fetch::delete_fetch),
         )
-        .route("/v1/usb-images", routing::get(mass_storage::get_images))
         .route(
             "/v1/usb-image/:file_name",
             routing::get(mass_storage::get_image).delete(mass_storage::delete_image),
@@ -322,15 +1010,33 @@ async fn main() -> error::Result<()> {
             routing::put(mass_storage::put_image_block),
         )
         .route(
-            "/v1/current-image",
-            routing::put(mass_storage::put_current_image),
+            "/v1/usb-image/:file_name/upload",
+            routing::get(image_upload::get_upload).post(image_upload::post_upload),
         )
-        .with_state(app_state.clone())
-        .layer(
-            TraceLayer::new_for_http()
-                .make_span_with(DefaultMakeSpan::default().include_headers(true)),
+        .route(
+            "/v1/wait-for-screen",
+            routing::post(screen::wait_for_screen),
+        )
+        .route("/v1/ocr", routing::post(ocr::post_ocr))
+        .route(
+            "/v1/screen-references",
+            routing::get(screen::get_references),
+        )
+        .route(
+            "/v1/screen-references/:name",
+            routing::put(screen::put_reference).delete(screen::delete_reference),
         )
-        .layer(Extension(device_ctx.clone()));
+        // 第一个 target 同时挂在 /v1 下，兼容只有一个 UDC 的情况
+        .nest("/v1", target_router(app_state.targets[0].clone()));
+    for (id, device_ctx) in app_state.targets.iter().enumerate() {
+        app = app.nest(
+            &format!("/v1/targets/{id}"),
+            target_router(device_ctx.clone()),
+        );
+    }
+    let app = app.with_state(app_state.clone()).layer(
+        TraceLayer::new_for_http().make_span_with(DefaultMakeSpan::default().include_headers(true)),
+    );
 
     let listener = tokio::net::TcpListener::bind(&app_state.args.server_listen_addr)
         .await
@@ -363,31 +1069,10 @@ async fn main() -> error::Result<()> {
 
     log::info!("Now shutdown IP-KVM...");
     join_set.shutdown().await;
-    device_ctx.read().await.abort_join_set().await;
+    for device_ctx in &app_state.targets {
+        device_ctx.read().await.abort_join_set().await;
+    }
     log::info!("IP-KVM join_set shutdown.");
 
     Ok(())
 }
-
-async fn stream_handler(
-    State(app_state): State<Arc<AppState>>,
-    mut req: Request<Body>,
-) -> Result<Response, StatusCode> {
-    let path = req.uri().path();
-    let path_query = req
-        .uri()
-        .path_and_query()
-        .map(|v| v.as_str())
-        .unwrap_or(path);
-
-    let uri = format!("{}{path_query}", app_state.args.ustreamer_url);
-
-    *req.uri_mut() = Uri::try_from(uri).unwrap();
-
-    Ok(app_state
-        .http_client
-        .request(req)
-        .await
-        .map_err(|_| StatusCode::BAD_REQUEST)?
-        .into_response())
-}
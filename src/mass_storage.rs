@@ -1,9 +1,21 @@
-use std::path::PathBuf;
+use std::{io::SeekFrom, path::PathBuf, sync::Arc};
 
-use axum::{extract, Json};
+use axum::{
+    body::Bytes,
+    extract::{self, State},
+    http::StatusCode,
+    Json,
+};
+use md5::{Digest, Md5};
 use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
 
-use crate::api_error;
+use crate::{
+    api_error::{self, ApiError},
+    ffs_msg,
+    metrics::METRICS,
+    AppState,
+};
 
 #[derive(Serialize)]
 pub struct ImageBlock {
@@ -19,22 +31,100 @@ pub struct Image {
 }
 
 const IP_KVM_IMAGES_PATH: &str = "ip-kvm-images";
+const IP_KVM_OVERLAYS_DIR: &str = ".overlay";
+const IP_KVM_UPLOADS_DIR: &str = ".upload";
+const IP_KVM_DOWNLOADS_DIR: &str = ".download";
 
-fn get_image_path(file_name: &String) -> api_error::Result<PathBuf> {
-    let file_path = if let Ok(file_path) = std::fs::canonicalize(file_name) {
-        file_path
-    } else {
-        return Err(anyhow::anyhow!("Canonicalize {file_name:?} failed!"))?;
+pub fn get_image_path(file_name: &String) -> api_error::Result<PathBuf> {
+    // 镜像文件可能还不存在，无法 canonicalize，只允许单层文件名
+    if file_name.is_empty() || file_name.starts_with('.') || file_name.contains(['/', '\\']) {
+        Err(anyhow::anyhow!("Invalid file_name:{file_name:?}."))?;
+    }
+    Ok(PathBuf::from(IP_KVM_IMAGES_PATH).join(file_name))
+}
+
+// 覆盖层放在镜像目录的隐藏子目录中，每个目标机各一份
+pub fn get_overlay_paths(
+    image_name: &String,
+    target_id: usize,
+) -> api_error::Result<(PathBuf, PathBuf)> {
+    get_image_path(image_name)?;
+    let dir = PathBuf::from(IP_KVM_IMAGES_PATH).join(IP_KVM_OVERLAYS_DIR);
+    std::fs::create_dir_all(&dir)?;
+    Ok((
+        dir.join(format!("{image_name}.{target_id}")),
+        dir.join(format!("{image_name}.{target_id}.bitmap")),
+    ))
+}
+
+// {image}.{target_id}、{image}.{target_id}.bitmap 和保存位图时的临时文件
+fn is_overlay_of(file_name: &str, image_name: &str) -> bool {
+    let Some(rest) = file_name
+        .strip_prefix(image_name)
+        .and_then(|rest| rest.strip_prefix('.'))
+    else {
+        return false;
     };
-    let images_path = if let Ok(images_path) = std::fs::canonicalize(IP_KVM_IMAGES_PATH) {
-        images_path
-    } else {
-        return Err(anyhow::anyhow!("Canonicalize {IP_KVM_IMAGES_PATH:?} failed!"))?;
+    let target_id = rest
+        .strip_suffix(".bitmap")
+        .or_else(|| rest.strip_suffix(".tmp"))
+        .unwrap_or(rest);
+    !target_id.is_empty() && target_id.bytes().all(|b| b.is_ascii_digit())
+}
+
+// 镜像被删除或替换后，它的覆盖层不再有用
+pub fn remove_overlays(image_name: &str) -> std::io::Result<()> {
+    let dir = PathBuf::from(IP_KVM_IMAGES_PATH).join(IP_KVM_OVERLAYS_DIR);
+    let entries = match std::fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err),
     };
-    if !file_path.starts_with(images_path) {
-        return Err(anyhow::anyhow!("Invalid file_name:{file_name:?}."))?;
+    for entry in entries {
+        let entry = entry?;
+        if !entry
+            .file_name()
+            .to_str()
+            .is_some_and(|name| is_overlay_of(name, image_name))
+        {
+            continue;
+        }
+        match std::fs::remove_file(entry.path()) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
     }
-    Ok(file_path)
+    Ok(())
+}
+
+// 作为 U 盘插入或者正在下载的镜像不能替换和删除，返回原因
+pub async fn image_busy(app_state: &AppState, image_name: &str) -> Option<&'static str> {
+    if ffs_msg::image_in_use(app_state, image_name).await {
+        Some("in use")
+    } else if app_state.image_fetches.is_fetching(image_name) {
+        Some("being fetched")
+    } else {
+        None
+    }
+}
+
+fn get_hidden_path(dir: &str, file_name: &String) -> api_error::Result<PathBuf> {
+    get_image_path(file_name)?;
+    let dir = PathBuf::from(IP_KVM_IMAGES_PATH).join(dir);
+    std::fs::create_dir_all(&dir)?;
+    Ok(dir.join(file_name))
+}
+
+// 上传完成前写在隐藏目录中，完成后再改名
+pub fn get_upload_path(file_name: &String) -> api_error::Result<PathBuf> {
+    get_hidden_path(IP_KVM_UPLOADS_DIR, file_name)
+}
+
+// 未完成的下载和它的来源，可以继续。镜像名不以 . 开头，来源文件不会和其它下载重名
+pub fn get_download_paths(file_name: &String) -> api_error::Result<(PathBuf, PathBuf)> {
+    let path = get_hidden_path(IP_KVM_DOWNLOADS_DIR, file_name)?;
+    let source_path = path.with_file_name(format!(".{file_name}.json"));
+    Ok((path, source_path))
 }
 
 #[derive(Deserialize)]
@@ -56,12 +146,80 @@ pub async fn get_image(extract::Path(file_name): extract::Path<String>) -> api_e
     todo!()
 }
 
-pub async fn delete_image(extract::Path(file_name): extract::Path<String>) -> api_error::Result<String> {
+pub async fn delete_image(
+    State(app_state): State<Arc<AppState>>,
+    extract::Path(file_name): extract::Path<String>,
+) -> api_error::Result<String> {
     let file_path = get_image_path(&file_name)?;
+    if let Some(reason) = image_busy(&app_state, &file_name).await {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            anyhow::anyhow!("image {file_name} is {reason}"),
+        ));
+    }
+    match tokio::fs::remove_file(&file_path).await {
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            return Err(ApiError::new(StatusCode::NOT_FOUND, err));
+        }
+        result => result?,
+    }
+    remove_overlays(&file_name)?;
     Ok("null".into())
 }
 
-pub async fn put_image_block(extract::Path((file_name, offset)): extract::Path<(String, usize)>) -> api_error::Result<Json<ImageBlock>> {
+pub async fn put_image_block(
+    extract::Path((file_name, offset)): extract::Path<(String, usize)>,
+    body: Bytes,
+) -> api_error::Result<Json<ImageBlock>> {
     let file_path = get_image_path(&file_name)?;
-    todo!()
-}
\ No newline at end of file
+    tokio::fs::create_dir_all(IP_KVM_IMAGES_PATH).await?;
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&file_path)
+        .await?;
+    file.seek(SeekFrom::Start(offset as u64)).await?;
+    file.write_all(&body).await?;
+    file.flush().await?;
+    METRICS.image_upload_bytes.inc_by(body.len() as u64);
+    Ok(Json(ImageBlock {
+        offset,
+        size: body.len(),
+        checksum: Md5::digest(&body).into(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn image_names() {
+        assert_eq!(
+            get_image_path(&"disk.img".to_string()).ok(),
+            Some(PathBuf::from(IP_KVM_IMAGES_PATH).join("disk.img"))
+        );
+        for name in ["", ".overlay", "..", "../disk.img", "a/b", "a\\b", "/etc/passwd"] {
+            assert!(get_image_path(&name.to_string()).is_err(), "{name}");
+        }
+    }
+
+    #[test]
+    fn overlay_names() {
+        for name in ["disk.img.0", "disk.img.12.bitmap", "disk.img.1.tmp"] {
+            assert!(is_overlay_of(name, "disk.img"), "{name}");
+        }
+        for name in [
+            "disk.img",
+            "disk.img.",
+            "disk.img.bitmap",
+            "disk.img.a",
+            "disk.img.old.0",
+            "disk.img2.0",
+            "disk.0",
+        ] {
+            assert!(!is_overlay_of(name, "disk.img"), "{name}");
+        }
+    }
+}
@@ -0,0 +1,280 @@
+use std::{future::Future, sync::Arc, time::Duration};
+
+use axum::{extract::State, http::header, response::IntoResponse};
+use once_cell::sync::Lazy;
+use prometheus::{IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder};
+use tokio::time;
+
+use crate::{api_error, hid_health, AppState};
+
+pub const HID_SEND_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct Metrics {
+    registry: Registry,
+    hid_reports: IntCounterVec,
+    hid_report_errors: IntCounterVec,
+    hid_send_timeouts: IntCounterVec,
+    websocket_clients: IntGaugeVec,
+    stream_upstream_up: IntGauge,
+    stream_clients: IntGauge,
+    pub stream_frames: IntCounter,
+    pub image_upload_bytes: IntCounter,
+    udc_state: IntGaugeVec,
+    hid_device_state: IntGaugeVec,
+    hid_device_reopens: IntCounterVec,
+    hid_queue_length: IntGaugeVec,
+}
+
+pub static METRICS: Lazy<Metrics> = Lazy::new(|| Metrics::new().unwrap());
+
+impl Metrics {
+    fn new() -> prometheus::Result<Self> {
+        let registry = Registry::new_custom(Some("ip_kvm".into()), None)?;
+        let metrics = Self {
+            hid_reports: IntCounterVec::new(
+                Opts::new("hid_reports_total", "HID reports sent."),
+                &["target", "device", "method"],
+            )?,
+            hid_report_errors: IntCounterVec::new(
+                Opts::new("hid_report_errors_total", "HID reports failed to send."),
+                &["target", "device", "method"],
+            )?,
+            hid_send_timeouts: IntCounterVec::new(
+                Opts::new("hid_send_timeouts_total", "HID sends that timed out."),
+                &["target", "device"],
+            )?,
+            websocket_clients: IntGaugeVec::new(
+                Opts::new("websocket_clients", "Connected WebSocket clients."),
+                &["target", "endpoint"],
+            )?,
+            stream_upstream_up: IntGauge::new(
+                "stream_upstream_up",
+                "Whether the MJPEG upstream is delivering frames.",
+            )?,
+            stream_clients: IntGauge::new("stream_clients", "Connected MJPEG stream clients.")?,
+            stream_frames: IntCounter::new(
+                "stream_frames_total",
+                "Frames received from the MJPEG upstream.",
+            )?,
+            image_upload_bytes: IntCounter::new(
+                "image_upload_bytes_total",
+                "Bytes uploaded to USB images.",
+            )?,
+            udc_state: IntGaugeVec::new(
+                Opts::new("udc_state", "Current UDC state, 1 for the active state."),
+                &["target", "udc", "state"],
+            )?,
+            hid_device_state: IntGaugeVec::new(
+                Opts::new(
+                    "hid_device_state",
+                    "Current hidg device health, 1 for the active state.",
+                ),
+                &["target", "device", "state"],
+            )?,
+            hid_device_reopens: IntCounterVec::new(
+                Opts::new("hid_device_reopens_total", "hidg device fds reopened."),
+                &["target", "device"],
+            )?,
+            hid_queue_length: IntGaugeVec::new(
+                Opts::new("hid_queue_length", "HID reports waiting to be sent."),
+                &["target", "device"],
+            )?,
+            registry,
+        };
+        metrics
+            .registry
+            .register(Box::new(metrics.hid_reports.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.hid_report_errors.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.hid_send_timeouts.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.websocket_clients.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.stream_upstream_up.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.stream_clients.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.stream_frames.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.image_upload_bytes.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.udc_state.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.hid_device_state.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.hid_device_reopens.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.hid_queue_length.clone()))?;
+        Ok(metrics)
+    }
+
+    pub fn hid_report_sent(&self, target: usize, device: &str, method: &str, ok: bool) {
+        let target = target.to_string();
+        if ok {
+            self.hid_reports
+                .with_label_values(&[&target, device, method])
+                .inc();
+        } else {
+            self.hid_report_errors
+                .with_label_values(&[&target, device, method])
+                .inc();
+        }
+    }
+
+    pub fn hid_send_timeout(&self, target: usize, device: &str) {
+        self.hid_send_timeouts
+            .with_label_values(&[&target.to_string(), device])
+            .inc();
+    }
+
+    // 超时只记录，不取消发送。发送时已经读取了状态，取消后这次状态变化就丢失了
+    pub async fn watch_hid_send<F: Future>(
+        &self,
+        target: usize,
+        device: &str,
+        timeout: Duration,
+        send: F,
+    ) -> F::Output {
+        tokio::pin!(send);
+        match time::timeout(timeout, &mut send).await {
+            Ok(ret) => ret,
+            Err(_) => {
+                log::warn!("{device} send timeout.");
+                self.hid_send_timeout(target, device);
+                send.await
+            }
+        }
+    }
+
+    pub fn hid_device_reopened(&self, target: usize, device: &str) {
+        self.hid_device_reopens
+            .with_label_values(&[&target.to_string(), device])
+            .inc();
+    }
+
+    pub fn websocket_client(
+        &'static self,
+        target: usize,
+        endpoint: &'static str,
+    ) -> WebsocketClientGuard {
+        let target = target.to_string();
+        self.websocket_clients
+            .with_label_values(&[&target, endpoint])
+            .inc();
+        WebsocketClientGuard {
+            metrics: self,
+            target,
+            endpoint,
+        }
+    }
+}
+
+// WebSocket 连接结束时自动减少计数
+pub struct WebsocketClientGuard {
+    metrics: &'static Metrics,
+    target: String,
+    endpoint: &'static str,
+}
+
+impl Drop for WebsocketClientGuard {
+    fn drop(&mut self) {
+        self.metrics
+            .websocket_clients
+            .with_label_values(&[&self.target, self.endpoint])
+            .dec();
+    }
+}
+
+pub async fn metrics_handler(
+    State(app_state): State<Arc<AppState>>,
+) -> api_error::Result<impl IntoResponse> {
+    let metrics = &*METRICS;
+
+    // 这些状态在抓取时才读取
+    let mjpeg_stream = &app_state.mjpeg_stream;
+    metrics
+        .stream_upstream_up
+        .set(*mjpeg_stream.upstream_online.borrow() as i64);
+    metrics
+        .stream_clients
+        .set(mjpeg_stream.receiver_count() as i64);
+
+    metrics.udc_state.reset();
+    metrics.hid_device_state.reset();
+    for device_ctx in &app_state.targets {
+        let device_ctx = device_ctx.read().await;
+        let target = device_ctx.id.to_string();
+        metrics
+            .hid_queue_length
+            .with_label_values(&[&target, hid_health::HID_DEV_HID_COMPOSITE])
+            .set(
+                device_ctx
+                    .hid_composite_device
+                    .hid_composite_report_queue
+                    .len() as i64,
+            );
+        for (device, health) in device_ctx.hid_health.snapshot() {
+            metrics
+                .hid_device_state
+                .with_label_values(&[&target, device, health.state.as_str()])
+                .set(1);
+        }
+        let udc_state = device_ctx.udc_state_sender.borrow().as_str();
+        metrics
+            .udc_state
+            .with_label_values(&[&target, &device_ctx.udc_name, udc_state])
+            .set(1);
+    }
+
+    let body = TextEncoder::new().encode_to_string(&metrics.registry.gather())?;
+    Ok((
+        [(
+            header::CONTENT_TYPE,
+            "text/plain; version=0.0.4; charset=utf-8",
+        )],
+        body,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use usb_otg::hid::report_queue::ReportQueue;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn send_timeout_keeps_report() {
+        let queue = Arc::new(ReportQueue::<1>::new(1));
+        queue.push([1]).await.unwrap();
+        // 队列已满，超时之后这个报告仍然要按顺序送达
+        let push_queue = queue.clone();
+        let push = tokio::spawn(async move {
+            METRICS
+                .watch_hid_send(0, "test", Duration::from_millis(10), push_queue.push([2]))
+                .await
+        });
+        time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(queue.pop().await, Some([1]));
+        push.await.unwrap().unwrap();
+        assert_eq!(queue.pop().await, Some([2]));
+        assert_eq!(
+            METRICS
+                .hid_send_timeouts
+                .with_label_values(&["0", "test"])
+                .get(),
+            1
+        );
+    }
+}
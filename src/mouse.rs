@@ -16,7 +16,11 @@ use tokio::{sync::RwLock, task::JoinSet, time};
 
 use usb_otg::hid::mouse::Mouse;
 
-use crate::DeviceCtx;
+use crate::{
+    held_input,
+    metrics::{HID_SEND_TIMEOUT, METRICS},
+    DeviceCtx,
+};
 
 pub async fn ws_handler(
     ws: WebSocketUpgrade,
@@ -34,6 +38,7 @@ pub async fn ws_handler(
 }
 
 async fn handle_socket(device_ctx: Arc<RwLock<DeviceCtx>>, socket: WebSocket, who: SocketAddr) {
+    let _client_guard = METRICS.websocket_client(device_ctx.read().await.id, "mouse");
     let (mut sender, mut receiver) = socket.split();
 
     let mut join_set = JoinSet::new();
@@ -47,9 +52,13 @@ async fn handle_socket(device_ctx: Arc<RwLock<DeviceCtx>>, socket: WebSocket, wh
         }
     });
 
+    let session_id = held_input::new_session_id();
+    let device_ctx_recv = device_ctx.clone();
     join_set.spawn(async move {
-        while let Some(Ok(msg)) = receiver.next().await {
-            if process_message(device_ctx.clone(), msg, who)
+        while let Ok(Some(Ok(msg))) =
+            time::timeout(held_input::SESSION_IDLE_TIMEOUT, receiver.next()).await
+        {
+            if process_message(device_ctx_recv.clone(), msg, who, session_id)
                 .await
                 .is_break()
             {
@@ -61,6 +70,9 @@ async fn handle_socket(device_ctx: Arc<RwLock<DeviceCtx>>, socket: WebSocket, wh
     let _ = join_set.join_next().await;
     join_set.shutdown().await;
 
+    // 浏览器异常退出时松开这个会话按下的鼠标按键
+    held_input::release_session(&*device_ctx.read().await, session_id).await;
+
     println!("Websocket context {} destroyed", who);
 }
 
@@ -68,6 +80,7 @@ async fn process_message(
     device_ctx: Arc<RwLock<DeviceCtx>>,
     msg: Message,
     who: SocketAddr,
+    session_id: u64,
 ) -> ControlFlow<(), ()> {
     match msg {
         Message::Binary(d) => {
@@ -95,26 +108,25 @@ async fn process_message(
             if wheel < Mouse::WHEEL_MIN {
                 return ControlFlow::Break(());
             }
-            let mouse_device = &device_ctx.read().await.mouse_device;
-            mouse_device.mouse.lock().await.button = d[0];
-            let mut join_set = JoinSet::new();
-            let device_ctx_send = device_ctx.clone();
-            join_set.spawn(async move {
-                let mouse_device = &device_ctx_send.read().await.mouse_device;
-                if let Err(err) = mouse_device.send(x, y, wheel).await {
-                    log::error!("mouse_device.send failed: {err}");
-                }
-                ControlFlow::Continue(())
-            });
-            join_set.spawn(async {
-                time::sleep(Duration::from_secs(5)).await;
-                log::warn!("mouse_device send timeout.");
-                ControlFlow::Continue(())
-            });
-
-            let ret = join_set.join_next().await.unwrap().unwrap();
-            join_set.shutdown().await;
-            ret
+            let device_ctx_read = device_ctx.read().await;
+            let id = device_ctx_read.id;
+            device_ctx_read.mouse_device.mouse.lock().await.button = d[0];
+            device_ctx_read
+                .held_inputs
+                .set(session_id, held_input::Input::MouseButtons, d[0] != 0);
+            device_ctx_read.jiggler.touch();
+            drop(device_ctx_read);
+            METRICS
+                .watch_hid_send(id, "mouse", HID_SEND_TIMEOUT, async {
+                    let mouse_device = &device_ctx.read().await.mouse_device;
+                    let res = mouse_device.send(x, y, wheel).await;
+                    METRICS.hid_report_sent(id, "mouse", "send", res.is_ok());
+                    if let Err(err) = res {
+                        log::error!("mouse_device.send failed: {err}");
+                    }
+                })
+                .await;
+            ControlFlow::Continue(())
         }
         Message::Close(c) => {
             if let Some(cf) = c {
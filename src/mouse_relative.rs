@@ -0,0 +1,135 @@
+use std::{net::SocketAddr, ops::ControlFlow, sync::Arc, time::Duration};
+
+use axum::{
+    extract::{
+        ws::{Message, WebSocket},
+        ConnectInfo, WebSocketUpgrade,
+    },
+    response::IntoResponse,
+    Extension,
+};
+
+use axum_extra::{headers, TypedHeader};
+
+use futures::{SinkExt, StreamExt};
+use tokio::{sync::RwLock, task::JoinSet, time};
+
+use usb_otg::hid::mouse::Mouse;
+
+use crate::{
+    held_input,
+    metrics::{HID_SEND_TIMEOUT, METRICS},
+    DeviceCtx,
+};
+
+pub async fn ws_handler(
+    ws: WebSocketUpgrade,
+    user_agent: Option<TypedHeader<headers::UserAgent>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(device_ctx): Extension<Arc<RwLock<DeviceCtx>>>,
+) -> impl IntoResponse {
+    let user_agent = if let Some(TypedHeader(user_agent)) = user_agent {
+        user_agent.to_string()
+    } else {
+        String::from("Unknown browser")
+    };
+    println!("`{user_agent}` at {addr} connected.");
+    ws.on_upgrade(move |socket| handle_socket(device_ctx, socket, addr))
+}
+
+async fn handle_socket(device_ctx: Arc<RwLock<DeviceCtx>>, socket: WebSocket, who: SocketAddr) {
+    let _client_guard = METRICS.websocket_client(device_ctx.read().await.id, "mouse_relative");
+    let (mut sender, mut receiver) = socket.split();
+
+    let mut join_set = JoinSet::new();
+
+    join_set.spawn(async move {
+        loop {
+            tokio::time::sleep(Duration::from_millis(1000)).await;
+            if sender.send(Message::Ping(vec![1, 2, 3])).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let session_id = held_input::new_session_id();
+    let device_ctx_recv = device_ctx.clone();
+    join_set.spawn(async move {
+        while let Ok(Some(Ok(msg))) =
+            time::timeout(held_input::SESSION_IDLE_TIMEOUT, receiver.next()).await
+        {
+            if process_message(device_ctx_recv.clone(), msg, who, session_id)
+                .await
+                .is_break()
+            {
+                break;
+            }
+        }
+    });
+
+    let _ = join_set.join_next().await;
+    join_set.shutdown().await;
+
+    // 浏览器异常退出时松开这个会话按下的鼠标按键
+    held_input::release_session(&*device_ctx.read().await, session_id).await;
+
+    println!("Websocket context {} destroyed", who);
+}
+
+async fn process_message(
+    device_ctx: Arc<RwLock<DeviceCtx>>,
+    msg: Message,
+    who: SocketAddr,
+    session_id: u64,
+) -> ControlFlow<(), ()> {
+    match msg {
+        Message::Binary(d) => {
+            // 6 byte
+            // button -> 1
+            // X -> 2
+            // Y -> 2
+            // wheel -> 1
+
+            if d.len() != 6 {
+                return ControlFlow::Break(());
+            }
+            let x = i16::from_le_bytes([d[1], d[2]]);
+            let y = i16::from_le_bytes([d[3], d[4]]);
+            let wheel = d[5] as i8;
+            if x < Mouse::REL16_MIN || y < Mouse::REL16_MIN || wheel < Mouse::WHEEL_MIN {
+                return ControlFlow::Break(());
+            }
+            let device_ctx_read = device_ctx.read().await;
+            let id = device_ctx_read.id;
+            device_ctx_read.mouse_device.mouse.lock().await.button = d[0];
+            device_ctx_read
+                .held_inputs
+                .set(session_id, held_input::Input::MouseButtons, d[0] != 0);
+            device_ctx_read.jiggler.touch();
+            drop(device_ctx_read);
+            METRICS
+                .watch_hid_send(id, "mouse", HID_SEND_TIMEOUT, async {
+                    let mouse_device = &device_ctx.read().await.mouse_device;
+                    let res = mouse_device.send_relative(x, y, wheel).await;
+                    METRICS.hid_report_sent(id, "mouse", "send_relative", res.is_ok());
+                    if let Err(err) = res {
+                        log::error!("mouse_device.send_relative failed: {err}");
+                    }
+                })
+                .await;
+            ControlFlow::Continue(())
+        }
+        Message::Close(c) => {
+            if let Some(cf) = c {
+                println!(
+                    ">>> {} sent close with code {} and reason `{}`",
+                    who, cf.code, cf.reason
+                );
+            } else {
+                println!(">>> {} somehow sent close message without CloseFrame", who);
+            }
+            ControlFlow::Break(())
+        }
+        _ => ControlFlow::Continue(()),
+    }
+}
@@ -0,0 +1,139 @@
+use std::sync::Arc;
+
+use axum::{extract::State, http::StatusCode, Json};
+use ocrs::{ImageSource, OcrEngine, OcrEngineParams, TextItem};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::{
+    api_error::{self, ApiError},
+    screen::{self, GrayImage, Region},
+    AppState,
+};
+
+pub struct Ocr {
+    // 识别很吃 CPU，同一时间只跑一个
+    engine: Arc<Mutex<OcrEngine>>,
+}
+
+impl Ocr {
+    pub fn load(detection_model_path: &str, recognition_model_path: &str) -> anyhow::Result<Self> {
+        let detection_model = rten::Model::load_file(detection_model_path)?;
+        let recognition_model = rten::Model::load_file(recognition_model_path)?;
+        let engine = OcrEngine::new(OcrEngineParams {
+            detection_model: Some(detection_model),
+            recognition_model: Some(recognition_model),
+            ..Default::default()
+        })?;
+        Ok(Self {
+            engine: Arc::new(Mutex::new(engine)),
+        })
+    }
+}
+
+#[derive(Serialize)]
+pub struct BoundingBox {
+    x: i32,
+    y: i32,
+    width: i32,
+    height: i32,
+}
+
+impl BoundingBox {
+    fn new(item: &impl TextItem, (offset_x, offset_y): (i32, i32)) -> Self {
+        let rect = item.bounding_rect();
+        Self {
+            x: rect.left() + offset_x,
+            y: rect.top() + offset_y,
+            width: rect.width(),
+            height: rect.height(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct OcrWord {
+    text: String,
+    bounding_box: BoundingBox,
+}
+
+#[derive(Serialize)]
+pub struct OcrLine {
+    text: String,
+    bounding_box: BoundingBox,
+    words: Vec<OcrWord>,
+}
+
+#[derive(Serialize)]
+pub struct OcrOutput {
+    text: String,
+    lines: Vec<OcrLine>,
+}
+
+#[derive(Deserialize)]
+pub struct OcrInput {
+    // 只识别画面的一部分，坐标为当前画面的像素
+    region: Option<Region>,
+}
+
+fn recognize(
+    engine: &OcrEngine,
+    jpeg: &[u8],
+    region: Option<&Region>,
+) -> api_error::Result<OcrOutput> {
+    let mut image = GrayImage::decode_jpeg(jpeg, u16::MAX, u16::MAX)?;
+    let mut offset = (0, 0);
+    if let Some(region) = region {
+        image = image
+            .crop(region)
+            .map_err(|err| ApiError::new(StatusCode::BAD_REQUEST, err))?;
+        offset = (region.x as i32, region.y as i32);
+    }
+
+    let image_source =
+        ImageSource::from_bytes(&image.pixels, (image.width as u32, image.height as u32))?;
+    let ocr_input = engine.prepare_input(image_source)?;
+    let word_rects = engine.detect_words(&ocr_input)?;
+    let line_rects = engine.find_text_lines(&ocr_input, &word_rects);
+
+    let lines: Vec<_> = engine
+        .recognize_text(&ocr_input, &line_rects)?
+        .into_iter()
+        .flatten()
+        .map(|line| OcrLine {
+            text: line.to_string(),
+            bounding_box: BoundingBox::new(&line, offset),
+            words: line
+                .words()
+                .map(|word| OcrWord {
+                    text: word.to_string(),
+                    bounding_box: BoundingBox::new(&word, offset),
+                })
+                .collect(),
+        })
+        .collect();
+    let text = lines
+        .iter()
+        .map(|line| line.text.as_str())
+        .collect::<Vec<_>>()
+        .join("\n");
+    Ok(OcrOutput { text, lines })
+}
+
+pub async fn post_ocr(
+    State(app_state): State<Arc<AppState>>,
+    Json(payload): Json<OcrInput>,
+) -> api_error::Result<Json<OcrOutput>> {
+    let Some(ocr) = &app_state.ocr else {
+        return Err(ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            anyhow::anyhow!("OCR models are not configured."),
+        ));
+    };
+    let jpeg = screen::current_frame(&app_state)?;
+    let engine = ocr.engine.clone().lock_owned().await;
+    let output =
+        tokio::task::spawn_blocking(move || recognize(&engine, &jpeg, payload.region.as_ref()))
+            .await??;
+    Ok(Json(output))
+}
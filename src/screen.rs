@@ -0,0 +1,553 @@
+use std::{path::PathBuf, sync::Arc, time::Duration};
+
+use axum::{
+    body::Bytes,
+    extract::{self, State},
+    http::StatusCode,
+    Json,
+};
+use jpeg_decoder::PixelFormat;
+use serde::{Deserialize, Serialize};
+use tokio::time::{self, Instant};
+
+use crate::{
+    api_error::{self, ApiError},
+    AppState,
+};
+
+const IP_KVM_SCREENS_PATH: &str = "ip-kvm-screens";
+const REFERENCE_EXTENSION: &str = "jpg";
+// 限制解码频率，板子的 CPU 解不动每一帧
+const COMPARE_INTERVAL: Duration = Duration::from_millis(200);
+const MAX_WAIT_TIMEOUT: Duration = Duration::from_secs(3600);
+const PHASH_SAMPLE_SIZE: usize = 32;
+const PHASH_SIZE: usize = 8;
+const PIXEL_DIFF_SAMPLE_SIZE: usize = 64;
+
+pub struct GrayImage {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u8>,
+}
+
+impl GrayImage {
+    // 解码时按 DCT 缩放，只保证输出尺寸不小于 min_width x min_height
+    pub fn decode_jpeg(jpeg: &[u8], min_width: u16, min_height: u16) -> anyhow::Result<Self> {
+        let mut decoder = jpeg_decoder::Decoder::new(jpeg);
+        decoder.scale(min_width, min_height)?;
+        let pixels = decoder.decode()?;
+        let info = decoder
+            .info()
+            .ok_or_else(|| anyhow::anyhow!("Can not read jpeg info"))?;
+        let pixels = match info.pixel_format {
+            PixelFormat::L8 => pixels,
+            PixelFormat::RGB24 => pixels
+                .chunks_exact(3)
+                .map(|rgb| {
+                    ((rgb[0] as u32 * 299 + rgb[1] as u32 * 587 + rgb[2] as u32 * 114) / 1000) as u8
+                })
+                .collect(),
+            pixel_format => anyhow::bail!("Unsupported pixel format {pixel_format:?}"),
+        };
+        Ok(Self {
+            width: info.width as usize,
+            height: info.height as usize,
+            pixels,
+        })
+    }
+
+    pub fn crop(&self, region: &Region) -> anyhow::Result<Self> {
+        let (x, y) = (region.x as usize, region.y as usize);
+        let (width, height) = (region.width as usize, region.height as usize);
+        if width == 0
+            || height == 0
+            || x.checked_add(width).is_none_or(|right| right > self.width)
+            || y.checked_add(height)
+                .is_none_or(|bottom| bottom > self.height)
+        {
+            anyhow::bail!("Region is out of {}x{} image.", self.width, self.height);
+        }
+        let mut pixels = Vec::with_capacity(width * height);
+        for row in self.pixels.chunks_exact(self.width).skip(y).take(height) {
+            pixels.extend_from_slice(&row[x..x + width]);
+        }
+        Ok(Self {
+            width,
+            height,
+            pixels,
+        })
+    }
+
+    // 把 region 区域按面积平均缩放到 width x height
+    fn sample(&self, region: &NormRegion, width: usize, height: usize) -> Vec<f32> {
+        let region_x = region.x * self.width as f32;
+        let region_y = region.y * self.height as f32;
+        let cell_width = region.width * self.width as f32 / width as f32;
+        let cell_height = region.height * self.height as f32 / height as f32;
+
+        let mut ret = Vec::with_capacity(width * height);
+        for cell_y in 0..height {
+            let y0 = (region_y + cell_y as f32 * cell_height) as usize;
+            let y1 = ((region_y + (cell_y + 1) as f32 * cell_height) as usize)
+                .clamp(y0 + 1, self.height.max(y0 + 1));
+            for cell_x in 0..width {
+                let x0 = (region_x + cell_x as f32 * cell_width) as usize;
+                let x1 = ((region_x + (cell_x + 1) as f32 * cell_width) as usize)
+                    .clamp(x0 + 1, self.width.max(x0 + 1));
+                let mut sum = 0_u32;
+                let mut count = 0_u32;
+                for y in y0..y1.min(self.height) {
+                    let row = &self.pixels[y * self.width..(y + 1) * self.width];
+                    for pixel in &row[x0.min(self.width)..x1.min(self.width)] {
+                        sum += *pixel as u32;
+                        count += 1;
+                    }
+                }
+                ret.push(if count == 0 {
+                    0.0
+                } else {
+                    sum as f32 / count as f32
+                });
+            }
+        }
+        ret
+    }
+}
+
+// 以参考图像的像素为单位
+#[derive(Deserialize, Clone, Copy)]
+pub struct Region {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+// 相对于整幅图像的比例，参考图像和当前画面分辨率不同时也能比较
+#[derive(Clone, Copy)]
+struct NormRegion {
+    x: f32,
+    y: f32,
+    width: f32,
+    height: f32,
+}
+
+impl NormRegion {
+    const FULL: Self = Self {
+        x: 0.0,
+        y: 0.0,
+        width: 1.0,
+        height: 1.0,
+    };
+
+    fn new(region: &Region, (width, height): (u16, u16)) -> anyhow::Result<Self> {
+        if region.width == 0
+            || region.height == 0
+            || region
+                .x
+                .checked_add(region.width)
+                .is_none_or(|right| right > width as u32)
+            || region
+                .y
+                .checked_add(region.height)
+                .is_none_or(|bottom| bottom > height as u32)
+        {
+            anyhow::bail!("Region is out of {width}x{height} image.");
+        }
+        Ok(Self {
+            x: region.x as f32 / width as f32,
+            y: region.y as f32 / height as f32,
+            width: region.width as f32 / width as f32,
+            height: region.height as f32 / height as f32,
+        })
+    }
+
+    // 保证区域解码后至少有 sample_size 个像素
+    fn min_decode_size(&self, sample_size: usize) -> (u16, u16) {
+        let min_width = (sample_size as f32 / self.width).ceil();
+        let min_height = (sample_size as f32 / self.height).ceil();
+        (
+            min_width.min(u16::MAX as f32) as u16,
+            min_height.min(u16::MAX as f32) as u16,
+        )
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum CompareMethod {
+    // 感知哈希，对 jpeg 噪声和轻微变化不敏感
+    #[default]
+    Phash,
+    // 逐像素比较灰度差
+    Pixel,
+}
+
+enum Signature {
+    Hash(u64),
+    Pixels(Vec<f32>),
+}
+
+impl CompareMethod {
+    fn default_threshold(self) -> f32 {
+        match self {
+            Self::Phash => 0.1,
+            Self::Pixel => 0.05,
+        }
+    }
+
+    fn sample_size(self) -> usize {
+        match self {
+            Self::Phash => PHASH_SAMPLE_SIZE,
+            Self::Pixel => PIXEL_DIFF_SAMPLE_SIZE,
+        }
+    }
+
+    fn signature(self, jpeg: &[u8], region: &NormRegion) -> anyhow::Result<Signature> {
+        let sample_size = self.sample_size();
+        let (min_width, min_height) = region.min_decode_size(sample_size);
+        let image = GrayImage::decode_jpeg(jpeg, min_width, min_height)?;
+        let samples = image.sample(region, sample_size, sample_size);
+        Ok(match self {
+            Self::Phash => Signature::Hash(phash(&samples)),
+            Self::Pixel => Signature::Pixels(samples),
+        })
+    }
+}
+
+impl Signature {
+    // 返回 0~1 的距离，0 表示完全相同
+    fn distance(&self, other: &Self) -> f32 {
+        match (self, other) {
+            (Self::Hash(a), Self::Hash(b)) => {
+                (a ^ b).count_ones() as f32 / (PHASH_SIZE * PHASH_SIZE) as f32
+            }
+            (Self::Pixels(a), Self::Pixels(b)) => {
+                let sum: f32 = a.iter().zip(b).map(|(a, b)| (a - b).abs()).sum();
+                sum / a.len() as f32 / 255.0
+            }
+            _ => 1.0,
+        }
+    }
+}
+
+fn dct_1d(input: &[f32], output: &mut [f32]) {
+    let n = input.len();
+    for (k, out) in output.iter_mut().enumerate() {
+        *out = input
+            .iter()
+            .enumerate()
+            .map(|(i, v)| v * (std::f32::consts::PI / n as f32 * (i as f32 + 0.5) * k as f32).cos())
+            .sum();
+    }
+}
+
+fn phash(samples: &[f32]) -> u64 {
+    let n = PHASH_SAMPLE_SIZE;
+    let mut rows = vec![0_f32; n * n];
+    for y in 0..n {
+        dct_1d(&samples[y * n..(y + 1) * n], &mut rows[y * n..(y + 1) * n]);
+    }
+    // 只需要左上角的低频部分
+    let mut low_freq = [0_f32; PHASH_SIZE * PHASH_SIZE];
+    let mut column = vec![0_f32; n];
+    let mut column_dct = vec![0_f32; n];
+    for x in 0..PHASH_SIZE {
+        for y in 0..n {
+            column[y] = rows[y * n + x];
+        }
+        dct_1d(&column, &mut column_dct);
+        for y in 0..PHASH_SIZE {
+            low_freq[y * PHASH_SIZE + x] = column_dct[y];
+        }
+    }
+    // 直流分量不参与计算中位数
+    let mut sorted = low_freq[1..].to_vec();
+    sorted.sort_by(f32::total_cmp);
+    let median = sorted[sorted.len() / 2];
+    low_freq.iter().enumerate().fold(
+        0_u64,
+        |hash, (i, v)| if *v > median { hash | 1 << i } else { hash },
+    )
+}
+
+fn jpeg_size(jpeg: &[u8]) -> anyhow::Result<(u16, u16)> {
+    let mut decoder = jpeg_decoder::Decoder::new(jpeg);
+    decoder.read_info()?;
+    let info = decoder
+        .info()
+        .ok_or_else(|| anyhow::anyhow!("Can not read jpeg info"))?;
+    Ok((info.width, info.height))
+}
+
+fn get_reference_path(name: &str) -> api_error::Result<PathBuf> {
+    if name.is_empty()
+        || name.starts_with('.')
+        || !name
+            .chars()
+            .all(|ch| ch.is_ascii_alphanumeric() || ch == '-' || ch == '_' || ch == '.')
+    {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            anyhow::anyhow!("Invalid reference name: {name:?}."),
+        ));
+    }
+    Ok(PathBuf::from(IP_KVM_SCREENS_PATH).join(format!("{name}.{REFERENCE_EXTENSION}")))
+}
+
+pub fn current_frame(app_state: &AppState) -> api_error::Result<Bytes> {
+    if !*app_state.mjpeg_stream.upstream_online.borrow() {
+        return Err(ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            anyhow::anyhow!("Stream upstream is offline."),
+        ));
+    }
+    Ok(app_state.mjpeg_stream.latest_frame.borrow().jpeg.clone())
+}
+
+pub async fn get_references() -> api_error::Result<Json<Vec<String>>> {
+    let mut ret = Vec::new();
+    let mut read_dir = match tokio::fs::read_dir(IP_KVM_SCREENS_PATH).await {
+        Ok(read_dir) => read_dir,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Json(ret)),
+        Err(err) => Err(err)?,
+    };
+    while let Some(entry) = read_dir.next_entry().await? {
+        let path = entry.path();
+        if path.extension().and_then(|ext| ext.to_str()) == Some(REFERENCE_EXTENSION) {
+            if let Some(name) = path.file_stem().and_then(|name| name.to_str()) {
+                ret.push(name.to_string());
+            }
+        }
+    }
+    ret.sort();
+    Ok(Json(ret))
+}
+
+// body 为空时保存当前画面，否则保存上传的 jpeg
+pub async fn put_reference(
+    State(app_state): State<Arc<AppState>>,
+    extract::Path(name): extract::Path<String>,
+    body: Bytes,
+) -> api_error::Result<String> {
+    let path = get_reference_path(&name)?;
+    let jpeg = if body.is_empty() {
+        current_frame(&app_state)?
+    } else {
+        jpeg_size(&body).map_err(|err| ApiError::new(StatusCode::BAD_REQUEST, err))?;
+        body
+    };
+    tokio::fs::create_dir_all(IP_KVM_SCREENS_PATH).await?;
+    tokio::fs::write(path, jpeg).await?;
+    Ok("null".into())
+}
+
+pub async fn delete_reference(
+    extract::Path(name): extract::Path<String>,
+) -> api_error::Result<String> {
+    let path = get_reference_path(&name)?;
+    tokio::fs::remove_file(path).await?;
+    Ok("null".into())
+}
+
+#[derive(Deserialize)]
+pub struct WaitForScreenInput {
+    // 为空时以当前画面为基准，等待画面发生变化
+    reference: Option<String>,
+    region: Option<Region>,
+    #[serde(default)]
+    method: CompareMethod,
+    threshold: Option<f32>,
+    timeout_ms: u64,
+}
+
+#[derive(Serialize)]
+pub struct WaitForScreenOutput {
+    distance: f32,
+    elapsed_ms: u64,
+}
+
+// 等待变化时距离超过阈值结束，等待参考图像时距离不超过阈值结束
+fn screen_matched(distance: f32, threshold: f32, wait_for_change: bool) -> bool {
+    (distance > threshold) == wait_for_change
+}
+
+pub async fn wait_for_screen(
+    State(app_state): State<Arc<AppState>>,
+    Json(payload): Json<WaitForScreenInput>,
+) -> api_error::Result<Json<WaitForScreenOutput>> {
+    let start = Instant::now();
+    let deadline = start + Duration::from_millis(payload.timeout_ms).min(MAX_WAIT_TIMEOUT);
+    let method = payload.method;
+    let threshold = payload
+        .threshold
+        .unwrap_or_else(|| method.default_threshold());
+
+    let mut frame_receiver = app_state.mjpeg_stream.latest_frame.subscribe();
+    let wait_for_change = payload.reference.is_none();
+    // 参考图像是请求指定的，解码失败是请求的问题；当前画面解码失败是上游的问题
+    let decode_failed = |err: anyhow::Error| {
+        let status_code = if wait_for_change {
+            StatusCode::BAD_GATEWAY
+        } else {
+            StatusCode::BAD_REQUEST
+        };
+        ApiError::new(status_code, err.context("Decode reference failed"))
+    };
+    let reference_jpeg = if let Some(reference) = &payload.reference {
+        let path = get_reference_path(reference)?;
+        Bytes::from(tokio::fs::read(&path).await.map_err(|err| {
+            ApiError::new(
+                StatusCode::NOT_FOUND,
+                anyhow::anyhow!("Read reference {path:?} failed: {err}"),
+            )
+        })?)
+    } else {
+        current_frame(&app_state)?
+    };
+    // 有参考图像时当前画面也需要比较
+    if !wait_for_change {
+        frame_receiver.mark_changed();
+    }
+
+    let region = if let Some(region) = &payload.region {
+        NormRegion::new(region, jpeg_size(&reference_jpeg).map_err(decode_failed)?)
+            .map_err(|err| ApiError::new(StatusCode::BAD_REQUEST, err))?
+    } else {
+        NormRegion::FULL
+    };
+    let reference = tokio::task::spawn_blocking(move || method.signature(&reference_jpeg, &region))
+        .await?
+        .map_err(decode_failed)?;
+    let reference = Arc::new(reference);
+
+    let mut last_distance = None;
+    while let Ok(Ok(())) = time::timeout_at(deadline, frame_receiver.changed()).await {
+        if !*app_state.mjpeg_stream.upstream_online.borrow() {
+            continue;
+        }
+        let jpeg = frame_receiver.borrow_and_update().jpeg.clone();
+        let frame_reference = reference.clone();
+        let distance = match tokio::task::spawn_blocking(move || {
+            method
+                .signature(&jpeg, &region)
+                .map(|signature| signature.distance(&frame_reference))
+        })
+        .await?
+        {
+            Ok(distance) => distance,
+            // 个别帧解码失败时跳过，继续等待下一帧
+            Err(err) => {
+                log::warn!("Skip a frame that can not be decoded: {err}");
+                continue;
+            }
+        };
+        last_distance = Some(distance);
+
+        if screen_matched(distance, threshold, wait_for_change) {
+            return Ok(Json(WaitForScreenOutput {
+                distance,
+                elapsed_ms: start.elapsed().as_millis() as u64,
+            }));
+        }
+        time::sleep_until((Instant::now() + COMPARE_INTERVAL).min(deadline)).await;
+    }
+
+    Err(ApiError::new(
+        StatusCode::REQUEST_TIMEOUT,
+        anyhow::anyhow!("Wait for screen timeout, last distance: {last_distance:?}."),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NO_SIGNAL_JPEG: &[u8] = include_bytes!("../ip-kvm-assets/no_signal.jpg");
+
+    fn region(x: u32, y: u32, width: u32, height: u32) -> Region {
+        Region {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    #[test]
+    fn same_frame_matches() {
+        let (width, height) = jpeg_size(NO_SIGNAL_JPEG).unwrap();
+        let regions = [
+            NormRegion::FULL,
+            NormRegion::new(
+                &region(0, 0, width as u32 / 2, height as u32 / 2),
+                (width, height),
+            )
+            .unwrap(),
+        ];
+        for method in [CompareMethod::Phash, CompareMethod::Pixel] {
+            for region in &regions {
+                let reference = method.signature(NO_SIGNAL_JPEG, region).unwrap();
+                let frame = method.signature(NO_SIGNAL_JPEG, region).unwrap();
+                let distance = frame.distance(&reference);
+                assert_eq!(distance, 0.0);
+                assert!(screen_matched(distance, method.default_threshold(), false));
+                assert!(!screen_matched(distance, method.default_threshold(), true));
+            }
+        }
+    }
+
+    #[test]
+    fn different_frames_do_not_match() {
+        let n = PHASH_SAMPLE_SIZE;
+        let gradient: Vec<f32> = (0..n * n).map(|i| (i % n * 8) as f32).collect();
+        let inverted: Vec<f32> = gradient.iter().map(|v| 255.0 - v).collect();
+        let distance =
+            Signature::Hash(phash(&gradient)).distance(&Signature::Hash(phash(&inverted)));
+        assert!(distance > CompareMethod::Phash.default_threshold());
+        assert!(screen_matched(
+            distance,
+            CompareMethod::Phash.default_threshold(),
+            true
+        ));
+
+        let distance =
+            Signature::Pixels(vec![0.0; 16]).distance(&Signature::Pixels(vec![255.0; 16]));
+        assert_eq!(distance, 1.0);
+        assert!(!screen_matched(
+            distance,
+            CompareMethod::Pixel.default_threshold(),
+            false
+        ));
+    }
+
+    #[test]
+    fn invalid_reference() {
+        assert!(jpeg_size(b"not a jpeg").is_err());
+        assert!(CompareMethod::Phash
+            .signature(b"not a jpeg", &NormRegion::FULL)
+            .is_err());
+    }
+
+    #[test]
+    fn crop_bounds() {
+        let image = GrayImage {
+            width: 4,
+            height: 3,
+            pixels: (0..12).collect(),
+        };
+        let cropped = image.crop(&region(1, 1, 3, 2)).unwrap();
+        assert_eq!((cropped.width, cropped.height), (3, 2));
+        assert_eq!(cropped.pixels, [5, 6, 7, 9, 10, 11]);
+        assert!(image.crop(&region(1, 1, 4, 2)).is_err());
+        assert!(image.crop(&region(0, u32::MAX, 1, u32::MAX)).is_err());
+    }
+
+    #[test]
+    fn region_bounds() {
+        assert!(NormRegion::new(&region(10, 10, 90, 90), (100, 100)).is_ok());
+        assert!(NormRegion::new(&region(10, 10, 91, 90), (100, 100)).is_err());
+        assert!(NormRegion::new(&region(0, 0, 0, 10), (100, 100)).is_err());
+        assert!(NormRegion::new(&region(u32::MAX, 0, 1, 10), (100, 100)).is_err());
+    }
+}
@@ -0,0 +1,325 @@
+use std::{convert::Infallible, sync::Arc, time::Duration};
+
+use axum::{
+    body::{Body, Bytes},
+    extract::State,
+    http::{header, Request, StatusCode},
+    response::{IntoResponse, Response},
+};
+use bytes::{Buf, BytesMut};
+use futures::StreamExt;
+use tokio::{
+    sync::{broadcast, watch},
+    time::{self, Instant},
+};
+
+use crate::{metrics::METRICS, AppState, Client};
+
+const NO_SIGNAL_JPEG: &[u8] = include_bytes!("../ip-kvm-assets/no_signal.jpg");
+const FRAME_BOUNDARY: &str = "ip-kvm-frame";
+// 客户端落后超过这个数量的帧时直接丢弃，只发送最新的帧
+const FRAME_CHANNEL_CAPACITY: usize = 2;
+const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+const UPSTREAM_READ_TIMEOUT: Duration = Duration::from_secs(5);
+const RECONNECT_BACKOFF_MIN: Duration = Duration::from_millis(500);
+const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(10);
+const NO_SIGNAL_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone)]
+pub struct Frame {
+    pub jpeg: Bytes,
+}
+
+impl Frame {
+    fn no_signal() -> Self {
+        Self {
+            jpeg: Bytes::from_static(NO_SIGNAL_JPEG),
+        }
+    }
+
+    fn part_header(&self) -> Bytes {
+        Bytes::from(format!(
+            "--{FRAME_BOUNDARY}\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n",
+            self.jpeg.len()
+        ))
+    }
+}
+
+pub struct MjpegStream {
+    frame_sender: broadcast::Sender<Frame>,
+    pub latest_frame: watch::Sender<Frame>,
+    pub upstream_online: watch::Sender<bool>,
+}
+
+impl MjpegStream {
+    pub fn new() -> Arc<Self> {
+        let (frame_sender, _) = broadcast::channel(FRAME_CHANNEL_CAPACITY);
+        let (latest_frame, _) = watch::channel(Frame::no_signal());
+        let (upstream_online, _) = watch::channel(false);
+        Arc::new(Self {
+            frame_sender,
+            latest_frame,
+            upstream_online,
+        })
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Frame> {
+        self.frame_sender.subscribe()
+    }
+
+    pub fn receiver_count(&self) -> usize {
+        self.frame_sender.receiver_count()
+    }
+
+    fn publish(&self, frame: Frame) {
+        // 没有客户端时 send 会失败，忽略即可
+        let _ = self.frame_sender.send(frame.clone());
+        self.latest_frame.send_replace(frame);
+    }
+
+    fn set_upstream_online(&self, online: bool) {
+        self.upstream_online.send_if_modified(|prev| {
+            if *prev != online {
+                *prev = online;
+                true
+            } else {
+                false
+            }
+        });
+    }
+
+    // 只维护一个上游连接，断开后按指数退避重连
+    pub async fn run(self: Arc<Self>, http_client: Client, upstream_url: String) {
+        let mut backoff = RECONNECT_BACKOFF_MIN;
+        loop {
+            let mut received = false;
+            match self
+                .read_upstream(&http_client, &upstream_url, &mut received)
+                .await
+            {
+                Ok(()) => log::warn!("MJPEG upstream {upstream_url} closed."),
+                Err(err) => log::warn!("MJPEG upstream {upstream_url} failed: {err}"),
+            }
+            self.set_upstream_online(false);
+            if received {
+                backoff = RECONNECT_BACKOFF_MIN;
+            }
+
+            // 等待重连期间向客户端推送无信号画面
+            let deadline = Instant::now() + backoff;
+            while Instant::now() < deadline {
+                self.publish(Frame::no_signal());
+                time::sleep(NO_SIGNAL_INTERVAL.min(deadline - Instant::now())).await;
+            }
+            backoff = (backoff * 2).min(RECONNECT_BACKOFF_MAX);
+        }
+    }
+
+    async fn read_upstream(
+        &self,
+        http_client: &Client,
+        upstream_url: &str,
+        received: &mut bool,
+    ) -> anyhow::Result<()> {
+        let req = Request::get(upstream_url).body(Body::empty())?;
+        let res = http_client.request(req).await?;
+        if !res.status().is_success() {
+            anyhow::bail!("Unexpected status {}", res.status());
+        }
+        let boundary = res
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .and_then(parse_boundary)
+            .ok_or_else(|| anyhow::anyhow!("Upstream is not a multipart stream"))?;
+
+        let mut parser = MultipartParser::new(&boundary);
+        let mut body = Body::new(res.into_body()).into_data_stream();
+        loop {
+            let chunk = match time::timeout(UPSTREAM_READ_TIMEOUT, body.next()).await {
+                Ok(Some(chunk)) => chunk?,
+                Ok(None) => return Ok(()),
+                Err(_) => anyhow::bail!("Read timeout"),
+            };
+            parser.push(&chunk);
+            while let Some(jpeg) = parser.next_part()? {
+                if !*received {
+                    *received = true;
+                    log::info!("MJPEG upstream {upstream_url} connected.");
+                }
+                self.set_upstream_online(true);
+                METRICS.stream_frames.inc();
+                self.publish(Frame { jpeg });
+            }
+        }
+    }
+}
+
+fn parse_boundary(content_type: &str) -> Option<String> {
+    let mut params = content_type.split(';');
+    if !params
+        .next()?
+        .trim()
+        .eq_ignore_ascii_case("multipart/x-mixed-replace")
+    {
+        return None;
+    }
+    params.find_map(|param| {
+        let (key, value) = param.split_once('=')?;
+        if key.trim().eq_ignore_ascii_case("boundary") {
+            Some(value.trim().trim_matches('"').to_string())
+        } else {
+            None
+        }
+    })
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+struct MultipartParser {
+    delimiter: Vec<u8>,
+    buf: BytesMut,
+}
+
+impl MultipartParser {
+    fn new(boundary: &str) -> Self {
+        // 部分服务器的 boundary 参数本身就带有 "--" 前缀
+        let delimiter = if boundary.starts_with("--") {
+            boundary.to_string()
+        } else {
+            format!("--{boundary}")
+        };
+        Self {
+            delimiter: delimiter.into_bytes(),
+            buf: BytesMut::new(),
+        }
+    }
+
+    fn push(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    fn next_part(&mut self) -> anyhow::Result<Option<Bytes>> {
+        let Some(delimiter_pos) = find(&self.buf, &self.delimiter) else {
+            if self.buf.len() > MAX_FRAME_SIZE {
+                anyhow::bail!("Can not find boundary in {} bytes", self.buf.len());
+            }
+            return Ok(None);
+        };
+        let Some(headers_end) = find(&self.buf[delimiter_pos..], b"\r\n\r\n") else {
+            if self.buf.len() - delimiter_pos > MAX_FRAME_SIZE {
+                anyhow::bail!("Can not find part headers in {} bytes", self.buf.len());
+            }
+            return Ok(None);
+        };
+        let headers_end = delimiter_pos + headers_end + 4;
+        let headers = std::str::from_utf8(&self.buf[delimiter_pos..headers_end])?;
+        let content_length = headers.lines().find_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            if key.trim().eq_ignore_ascii_case("content-length") {
+                value.trim().parse::<usize>().ok()
+            } else {
+                None
+            }
+        });
+
+        let body_len = if let Some(content_length) = content_length {
+            if content_length > MAX_FRAME_SIZE {
+                anyhow::bail!("Frame too large: {content_length}");
+            }
+            if self.buf.len() < headers_end + content_length {
+                return Ok(None);
+            }
+            content_length
+        } else {
+            // 没有 Content-Length 时只能以下一个 boundary 作为结束
+            let mut next_delimiter = b"\r\n".to_vec();
+            next_delimiter.extend_from_slice(&self.delimiter);
+            match find(&self.buf[headers_end..], &next_delimiter) {
+                Some(body_len) => body_len,
+                None if self.buf.len() - headers_end > MAX_FRAME_SIZE => {
+                    anyhow::bail!("Frame too large");
+                }
+                None => return Ok(None),
+            }
+        };
+
+        self.buf.advance(headers_end);
+        Ok(Some(self.buf.split_to(body_len).freeze()))
+    }
+}
+
+// 所有客户端共用一个上游连接，请求参数被忽略
+pub async fn stream_handler(State(app_state): State<Arc<AppState>>) -> Response {
+    let mjpeg_stream = &app_state.mjpeg_stream;
+    // 先发送最近的一帧，避免客户端在上游出帧前一直黑屏
+    let first_frame = mjpeg_stream.latest_frame.borrow().clone();
+    let receiver = mjpeg_stream.subscribe();
+
+    let frames = futures::stream::unfold(
+        (Some(first_frame), receiver),
+        |(first_frame, mut receiver)| async move {
+            if let Some(frame) = first_frame {
+                return Some((frame, (None, receiver)));
+            }
+            loop {
+                match receiver.recv().await {
+                    Ok(frame) => return Some((frame, (None, receiver))),
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        log::debug!("Stream client lagged, {skipped} frames dropped.");
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        },
+    );
+    let body = frames.flat_map(|frame| {
+        futures::stream::iter([
+            Ok::<_, Infallible>(frame.part_header()),
+            Ok(frame.jpeg),
+            Ok(Bytes::from_static(b"\r\n")),
+        ])
+    });
+
+    (
+        StatusCode::OK,
+        [
+            (
+                header::CONTENT_TYPE,
+                format!("multipart/x-mixed-replace;boundary={FRAME_BOUNDARY}"),
+            ),
+            (header::CACHE_CONTROL, "no-store".to_string()),
+        ],
+        Body::from_stream(body),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_parts() {
+        let mut parser = MultipartParser::new("frame");
+        parser.push(b"--frame\r\nContent-Type: image/jpeg\r\nContent-Length: 3\r\n\r\nabc\r\n");
+        parser.push(b"--frame\r\nContent-Type: image/jpeg\r\n\r\nde");
+        assert_eq!(parser.next_part().unwrap().as_deref(), Some(&b"abc"[..]));
+        // 没有 Content-Length 的部分要等到下一个 boundary
+        assert_eq!(parser.next_part().unwrap(), None);
+        parser.push(b"f\r\n--frame\r\n");
+        assert_eq!(parser.next_part().unwrap().as_deref(), Some(&b"def"[..]));
+    }
+
+    #[test]
+    fn part_headers_too_large() {
+        let mut parser = MultipartParser::new("--frame");
+        parser.push(b"--frame\r\n");
+        parser.push(&vec![b'a'; MAX_FRAME_SIZE]);
+        assert!(parser.next_part().is_err());
+    }
+}
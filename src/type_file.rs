@@ -0,0 +1,730 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::{body::Bytes, extract::Query, http::StatusCode, Extension, Json};
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::{Mutex as AsyncMutex, OwnedMutexGuard, RwLock},
+    time,
+};
+
+use usb_otg::hid::keyboard::{
+    ascii_to_usage, usage_id, Keyboard, KeyboardDevice, LOCK_LED_CAPS_LOCK,
+};
+
+use crate::{
+    api_error::{self, ApiError},
+    held_input::Input,
+    keyboard, DeviceCtx,
+};
+
+// 按 100 字符每秒计算，256 KiB 的 base64 也要将近一个小时
+const TYPE_FILE_SIZE_MAX: usize = 256 * 1024;
+const LINE_LENGTH_MIN: usize = 4;
+const LINE_LENGTH_MAX: usize = 1024;
+const DELAY_MAX: Duration = Duration::from_secs(10);
+// 按下 Caps Lock 后等待主机更新 LED 的时间
+const CAPS_LOCK_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Encoding {
+    #[default]
+    Base64,
+    Hex,
+}
+
+// 在目标机器上把编码后的内容还原成文件的命令
+#[derive(Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Decoder {
+    // 只输入编码后的内容，例如输入到已经打开的编辑器里
+    #[default]
+    None,
+    Bash,
+    Powershell,
+    Cmd,
+}
+
+fn default_line_length() -> usize {
+    76
+}
+
+fn default_char_delay_ms() -> u64 {
+    5
+}
+
+fn default_line_delay_ms() -> u64 {
+    50
+}
+
+#[derive(Deserialize)]
+pub struct TypeFileParams {
+    #[serde(default)]
+    encoding: Encoding,
+    #[serde(default)]
+    decoder: Decoder,
+    file_name: Option<String>,
+    #[serde(default = "default_line_length")]
+    line_length: usize,
+    #[serde(default = "default_char_delay_ms")]
+    char_delay_ms: u64,
+    #[serde(default = "default_line_delay_ms")]
+    line_delay_ms: u64,
+}
+
+// 每一行都是独立的追加命令，可以从任意一行继续
+fn build_lines(params: &TypeFileParams, data: &[u8]) -> Vec<String> {
+    let (encoded, ext) = match params.encoding {
+        Encoding::Base64 => (
+            base64::engine::general_purpose::STANDARD.encode(data),
+            "b64",
+        ),
+        Encoding::Hex => (hex::encode(data), "hex"),
+    };
+    let chunks = encoded
+        .as_bytes()
+        .chunks(params.line_length)
+        .map(|chunk| String::from_utf8(chunk.to_vec()).unwrap());
+    let file_name = params.file_name.as_deref().unwrap_or_default();
+    let tmp_name = format!("{file_name}.{ext}");
+
+    let mut lines = Vec::new();
+    match params.decoder {
+        Decoder::None => lines.extend(chunks),
+        Decoder::Bash => {
+            lines.push(format!(": > '{tmp_name}'"));
+            lines.extend(chunks.map(|chunk| format!("echo '{chunk}' >> '{tmp_name}'")));
+            lines.push(match params.encoding {
+                Encoding::Base64 => format!("base64 -d '{tmp_name}' > '{file_name}'"),
+                Encoding::Hex => format!("xxd -r -p '{tmp_name}' > '{file_name}'"),
+            });
+            lines.push(format!("rm '{tmp_name}'"));
+        }
+        Decoder::Powershell => {
+            lines.push(format!("Set-Content -Path '{tmp_name}' -Value $null"));
+            lines.extend(
+                chunks.map(|chunk| format!("Add-Content -Path '{tmp_name}' -Value '{chunk}'")),
+            );
+            lines.push(format!("$data = (Get-Content -Path '{tmp_name}') -join ''"));
+            lines.push(match params.encoding {
+                Encoding::Base64 => format!(
+                    "[IO.File]::WriteAllBytes(\"$PWD\\{file_name}\", [Convert]::FromBase64String($data))"
+                ),
+                Encoding::Hex => format!(
+                    "[IO.File]::WriteAllBytes(\"$PWD\\{file_name}\", [byte[]](-split ($data -replace '..', '0x$& ')))"
+                ),
+            });
+            lines.push(format!("Remove-Item -Path '{tmp_name}'"));
+        }
+        Decoder::Cmd => {
+            lines.push(format!("type nul > {tmp_name}"));
+            // 重定向放在前面，避免行尾的数字被当成文件句柄
+            lines.extend(chunks.map(|chunk| format!(">> {tmp_name} echo {chunk}")));
+            lines.push(match params.encoding {
+                Encoding::Base64 => format!("certutil -f -decode {tmp_name} {file_name}"),
+                Encoding::Hex => format!("certutil -f -decodehex {tmp_name} {file_name} 12"),
+            });
+            lines.push(format!("del {tmp_name}"));
+        }
+    }
+    lines
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TypeFileState {
+    Running,
+    // 暂停、主机断开后从 next_line 继续
+    Paused,
+    Done,
+    Cancelled,
+}
+
+struct TypeFileJob {
+    lines: Vec<String>,
+    next_line: usize,
+    state: TypeFileState,
+    // 任务是否还在运行，暂停后会在当前行结束时退出
+    running: bool,
+    error: Option<String>,
+    bytes: usize,
+    decoder: Decoder,
+    // 主机断开时 next_line 已经输入的字符数，继续前要先清除
+    partial_chars: usize,
+    char_delay: Duration,
+    line_delay: Duration,
+}
+
+// 把文件编码后通过键盘输入到目标机器
+#[derive(Default)]
+pub struct TypeFile {
+    job: Mutex<Option<TypeFileJob>>,
+    // 开始输入前操作员按住的按键，输入结束后恢复
+    saved: AsyncMutex<Option<Keyboard>>,
+}
+
+fn set_input(keyboard: &mut Keyboard, input: Input, pressed: bool) -> bool {
+    match input {
+        Input::Key(key_id) => keyboard.set_key(key_id, pressed),
+        Input::SysControlKey(sys_control_key_id) => {
+            keyboard.set_sys_control_key(sys_control_key_id, pressed)
+        }
+        Input::AppleFn => std::mem::replace(&mut keyboard.apple_fn, pressed) != pressed,
+        Input::MouseButtons | Input::Gamepad => false,
+    }
+}
+
+impl TypeFile {
+    // 输入文件期间不接受操作员的按键，避免混进输入的内容里
+    pub fn is_running(&self) -> bool {
+        self.job
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|job| job.running)
+    }
+
+    // 输入文件期间只修改保存的状态，返回当前的状态是否有变化
+    pub async fn set_input(
+        &self,
+        keyboard_device: &KeyboardDevice,
+        input: Input,
+        pressed: bool,
+    ) -> bool {
+        let mut saved = self.saved.lock().await;
+        if let Some(saved) = saved.as_mut() {
+            set_input(saved, input, pressed);
+            return false;
+        }
+        set_input(&mut *keyboard_device.keyboard.lock().await, input, pressed)
+    }
+
+    async fn save_keys(&self, keyboard_device: &KeyboardDevice) -> bool {
+        let mut saved = self.saved.lock().await;
+        let mut keyboard = keyboard_device.keyboard.lock().await;
+        *saved = Some(keyboard.clone());
+        keyboard.release_all()
+    }
+
+    // 输入期间松开的按键已经从保存的状态中去掉了
+    async fn restore_keys(&self, keyboard_device: &KeyboardDevice) -> bool {
+        let mut saved = self.saved.lock().await;
+        let Some(saved) = saved.take() else {
+            return false;
+        };
+        keyboard_device.keyboard.lock().await.press_from(&saved)
+    }
+}
+
+async fn type_key(device_ctx: &DeviceCtx, modifier: Option<u16>, usage: u16) {
+    let keyboard_device = &device_ctx.keyboard_device;
+    if let Some(modifier) = modifier {
+        keyboard_device.set_key(modifier, true).await;
+    }
+    keyboard_device.set_key(usage, true).await;
+    keyboard::send_keyboard(device_ctx).await;
+    keyboard_device.set_key(usage, false).await;
+    if let Some(modifier) = modifier {
+        keyboard_device.set_key(modifier, false).await;
+    }
+    keyboard::send_keyboard(device_ctx).await;
+}
+
+// Caps Lock 会改变字母的大小写，输入前先关掉
+async fn caps_lock_off(device_ctx: &DeviceCtx) -> bool {
+    let keyboard_device = &device_ctx.keyboard_device;
+    if keyboard_device.lock_led() & LOCK_LED_CAPS_LOCK == 0 {
+        return true;
+    }
+    log::info!("Target {} type file turn off Caps Lock", device_ctx.id);
+    let mut receiver = keyboard_device.subscribe_lock_led();
+    type_key(device_ctx, None, usage_id::KEYBOARD_CAPS_LOCK).await;
+    let _ = time::timeout(CAPS_LOCK_TIMEOUT, async {
+        while keyboard_device.lock_led() & LOCK_LED_CAPS_LOCK != 0 {
+            if receiver.recv().await.is_err() {
+                break;
+            }
+        }
+    })
+    .await;
+    keyboard_device.lock_led() & LOCK_LED_CAPS_LOCK == 0
+}
+
+// 清除主机断开前输入了一半的行
+async fn clear_line(device_ctx: &DeviceCtx, decoder: Decoder, chars: usize, delay: Duration) {
+    match decoder {
+        Decoder::Bash => {
+            type_key(
+                device_ctx,
+                Some(usage_id::KEYBOARD_LEFT_CONTROL),
+                usage_id::KEYBOARD_U,
+            )
+            .await
+        }
+        Decoder::Powershell | Decoder::Cmd => {
+            type_key(device_ctx, None, usage_id::KEYBOARD_ESCAPE).await
+        }
+        // 编辑器里只能逐个删除
+        Decoder::None => {
+            for _ in 0..chars {
+                type_key(device_ctx, None, usage_id::KEYBOARD_BACKSPACE).await;
+                time::sleep(delay).await;
+            }
+        }
+    }
+}
+
+// 停在当前行，之后从这一行继续
+fn interrupt(type_file: &TypeFile, partial_chars: usize, error: &str) {
+    let mut job = type_file.job.lock().unwrap();
+    let job = job.as_mut().unwrap();
+    if job.state == TypeFileState::Running {
+        job.state = TypeFileState::Paused;
+        job.error = Some(error.into());
+    }
+    job.partial_chars = partial_chars;
+    job.running = false;
+}
+
+async fn run(device_ctx: Arc<RwLock<DeviceCtx>>, _chord_guard: OwnedMutexGuard<()>) {
+    let device_ctx = device_ctx.read().await;
+    let type_file = &device_ctx.type_file;
+    // 操作员按住的按键会混进输入的内容里
+    if type_file.save_keys(&device_ctx.keyboard_device).await {
+        keyboard::send_keyboard(&device_ctx).await;
+    }
+    type_lines(&device_ctx).await;
+    if type_file.restore_keys(&device_ctx.keyboard_device).await {
+        keyboard::send_keyboard(&device_ctx).await;
+    }
+}
+
+async fn type_lines(device_ctx: &DeviceCtx) {
+    let type_file = &device_ctx.type_file;
+    loop {
+        let (line, decoder, partial_chars, char_delay, line_delay) = {
+            let mut job = type_file.job.lock().unwrap();
+            let job = job.as_mut().unwrap();
+            if job.state == TypeFileState::Running
+                && !device_ctx.udc_state_sender.borrow().is_configured()
+            {
+                job.state = TypeFileState::Paused;
+                job.error = Some("host is not connected".into());
+            }
+            if job.state != TypeFileState::Running {
+                job.running = false;
+                return;
+            }
+            let Some(line) = job.lines.get(job.next_line).cloned() else {
+                job.state = TypeFileState::Done;
+                job.running = false;
+                log::info!("Target {} type file done", device_ctx.id);
+                return;
+            };
+            (
+                line,
+                job.decoder,
+                job.partial_chars,
+                job.char_delay,
+                job.line_delay,
+            )
+        };
+        if partial_chars != 0 {
+            clear_line(device_ctx, decoder, partial_chars, char_delay).await;
+            type_file
+                .job
+                .lock()
+                .unwrap()
+                .as_mut()
+                .unwrap()
+                .partial_chars = 0;
+        }
+        for (i, ch) in line.chars().chain(['\n']).enumerate() {
+            if !device_ctx.udc_state_sender.borrow().is_configured() {
+                interrupt(type_file, i, "host is not connected");
+                return;
+            }
+            if !caps_lock_off(device_ctx).await {
+                interrupt(type_file, i, "Caps Lock can not be turned off");
+                return;
+            }
+            // build_lines 只会生成可以输入的字符
+            let (usage, shift) = ascii_to_usage(ch).unwrap();
+            type_key(
+                device_ctx,
+                shift.then_some(usage_id::KEYBOARD_LEFT_SHIFT),
+                usage,
+            )
+            .await;
+            time::sleep(char_delay).await;
+        }
+        device_ctx.jiggler.touch();
+        type_file.job.lock().unwrap().as_mut().unwrap().next_line += 1;
+        time::sleep(line_delay).await;
+    }
+}
+
+fn start(device_ctx: Arc<RwLock<DeviceCtx>>, chord_guard: OwnedMutexGuard<()>) {
+    tokio::spawn(run(device_ctx, chord_guard));
+}
+
+fn bad_request(msg: String) -> ApiError {
+    ApiError::new(StatusCode::BAD_REQUEST, anyhow::anyhow!(msg))
+}
+
+async fn lock_keyboard(
+    device_ctx: &Arc<RwLock<DeviceCtx>>,
+) -> api_error::Result<OwnedMutexGuard<()>> {
+    device_ctx
+        .read()
+        .await
+        .chord_lock
+        .clone()
+        .try_lock_owned()
+        .map_err(|_| {
+            ApiError::new(
+                StatusCode::CONFLICT,
+                anyhow::anyhow!("a chord or another file is being typed"),
+            )
+        })
+}
+
+pub async fn post_type_file(
+    Extension(device_ctx): Extension<Arc<RwLock<DeviceCtx>>>,
+    Query(params): Query<TypeFileParams>,
+    body: Bytes,
+) -> api_error::Result<String> {
+    if body.len() > TYPE_FILE_SIZE_MAX {
+        return Err(ApiError::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            anyhow::anyhow!("file must not exceed {TYPE_FILE_SIZE_MAX} bytes"),
+        ));
+    }
+    if !(LINE_LENGTH_MIN..=LINE_LENGTH_MAX).contains(&params.line_length) {
+        return Err(bad_request(format!(
+            "line_length must be between {LINE_LENGTH_MIN} and {LINE_LENGTH_MAX}"
+        )));
+    }
+    let char_delay = Duration::from_millis(params.char_delay_ms);
+    let line_delay = Duration::from_millis(params.line_delay_ms);
+    if char_delay > DELAY_MAX || line_delay > DELAY_MAX {
+        return Err(bad_request(format!(
+            "delay must not exceed {}",
+            DELAY_MAX.as_millis()
+        )));
+    }
+    if params.decoder != Decoder::None {
+        // 文件名会直接拼进命令里
+        let valid = params.file_name.as_ref().is_some_and(|file_name| {
+            !file_name.is_empty()
+                && !file_name.starts_with('-')
+                && file_name
+                    .chars()
+                    .all(|ch| ch.is_ascii_alphanumeric() || ch == '-' || ch == '_' || ch == '.')
+        });
+        if !valid {
+            return Err(bad_request(
+                "file_name is required by the decoder and may only contain [A-Za-z0-9._-]".into(),
+            ));
+        }
+    }
+
+    let chord_guard = lock_keyboard(&device_ctx).await?;
+    let lines = build_lines(&params, &body);
+    log::info!(
+        "Target {} type file: {} bytes, {} lines",
+        device_ctx.read().await.id,
+        body.len(),
+        lines.len()
+    );
+    *device_ctx.read().await.type_file.job.lock().unwrap() = Some(TypeFileJob {
+        lines,
+        next_line: 0,
+        state: TypeFileState::Running,
+        running: true,
+        error: None,
+        bytes: body.len(),
+        decoder: params.decoder,
+        partial_chars: 0,
+        char_delay,
+        line_delay,
+    });
+    start(device_ctx, chord_guard);
+    Ok("null".into())
+}
+
+#[derive(Serialize)]
+pub struct TypeFileOutput {
+    state: TypeFileState,
+    bytes: usize,
+    lines: usize,
+    next_line: usize,
+    error: Option<String>,
+}
+
+pub async fn get_type_file(
+    Extension(device_ctx): Extension<Arc<RwLock<DeviceCtx>>>,
+) -> api_error::Result<Json<Option<TypeFileOutput>>> {
+    let device_ctx = device_ctx.read().await;
+    let job = device_ctx.type_file.job.lock().unwrap();
+    Ok(Json(job.as_ref().map(|job| TypeFileOutput {
+        state: job.state,
+        bytes: job.bytes,
+        lines: job.lines.len(),
+        next_line: job.next_line,
+        error: job.error.clone(),
+    })))
+}
+
+pub async fn post_pause(
+    Extension(device_ctx): Extension<Arc<RwLock<DeviceCtx>>>,
+) -> api_error::Result<String> {
+    let device_ctx = device_ctx.read().await;
+    let mut job = device_ctx.type_file.job.lock().unwrap();
+    match job.as_mut() {
+        Some(job) if job.state == TypeFileState::Running => {
+            job.state = TypeFileState::Paused;
+            Ok("null".into())
+        }
+        _ => Err(ApiError::new(
+            StatusCode::CONFLICT,
+            anyhow::anyhow!("no running job"),
+        )),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ResumeInput {
+    // 目标机器漏掉了某些行时可以从更早的行重新输入
+    // 已经执行过的追加命令会再追加一次，所以只能从漏掉的第一行或者第 0 行 (重新创建临时文件) 开始
+    from_line: Option<usize>,
+}
+
+fn resume_job(job: &mut TypeFileJob, from_line: Option<usize>) -> api_error::Result<()> {
+    if job.state == TypeFileState::Cancelled {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            anyhow::anyhow!("job is cancelled"),
+        ));
+    }
+    if let Some(from_line) = from_line {
+        // 任务输入完当前行后会把 next_line 加一
+        if job.running {
+            return Err(ApiError::new(
+                StatusCode::CONFLICT,
+                anyhow::anyhow!("pause the job and wait for it to stop before setting from_line"),
+            ));
+        }
+        if from_line > job.lines.len() {
+            return Err(bad_request(format!(
+                "from_line must not exceed {}",
+                job.lines.len()
+            )));
+        }
+        job.next_line = from_line;
+    }
+    job.state = TypeFileState::Running;
+    job.error = None;
+    Ok(())
+}
+
+pub async fn post_resume(
+    Extension(device_ctx): Extension<Arc<RwLock<DeviceCtx>>>,
+    input: Option<Json<ResumeInput>>,
+) -> api_error::Result<String> {
+    let from_line = input.and_then(|Json(input)| input.from_line);
+    let resume = |job: &mut TypeFileJob| resume_job(job, from_line);
+    {
+        let device_ctx = device_ctx.read().await;
+        let mut job = device_ctx.type_file.job.lock().unwrap();
+        let Some(job) = job.as_mut() else {
+            return Err(ApiError::new(
+                StatusCode::NOT_FOUND,
+                anyhow::anyhow!("no job"),
+            ));
+        };
+        // 任务还没有退出，修改状态后它会继续
+        if job.running {
+            resume(job)?;
+            return Ok("null".into());
+        }
+    }
+    let chord_guard = lock_keyboard(&device_ctx).await?;
+    {
+        let device_ctx = device_ctx.read().await;
+        let mut job = device_ctx.type_file.job.lock().unwrap();
+        let job = job.as_mut().unwrap();
+        resume(job)?;
+        job.running = true;
+    }
+    start(device_ctx, chord_guard);
+    Ok("null".into())
+}
+
+pub async fn delete_type_file(
+    Extension(device_ctx): Extension<Arc<RwLock<DeviceCtx>>>,
+) -> api_error::Result<String> {
+    let device_ctx = device_ctx.read().await;
+    let mut job = device_ctx.type_file.job.lock().unwrap();
+    if let Some(job) = job.as_mut() {
+        if job.state != TypeFileState::Done {
+            job.state = TypeFileState::Cancelled;
+        }
+    }
+    Ok("null".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::response::IntoResponse;
+
+    use super::*;
+
+    fn params(encoding: Encoding, decoder: Decoder, line_length: usize) -> TypeFileParams {
+        TypeFileParams {
+            encoding,
+            decoder,
+            file_name: Some("a.txt".into()),
+            line_length,
+            char_delay_ms: default_char_delay_ms(),
+            line_delay_ms: default_line_delay_ms(),
+        }
+    }
+
+    fn job(state: TypeFileState, running: bool) -> TypeFileJob {
+        TypeFileJob {
+            lines: vec!["a".into(), "b".into(), "c".into()],
+            next_line: 2,
+            state,
+            running,
+            error: Some("host is not connected".into()),
+            bytes: 3,
+            decoder: Decoder::None,
+            partial_chars: 0,
+            char_delay: Duration::ZERO,
+            line_delay: Duration::ZERO,
+        }
+    }
+
+    #[test]
+    fn build_bash_lines() {
+        let lines = build_lines(&params(Encoding::Base64, Decoder::Bash, 8), b"hello world");
+        assert_eq!(
+            lines,
+            [
+                ": > 'a.txt.b64'",
+                "echo 'aGVsbG8g' >> 'a.txt.b64'",
+                "echo 'd29ybGQ=' >> 'a.txt.b64'",
+                "base64 -d 'a.txt.b64' > 'a.txt'",
+                "rm 'a.txt.b64'",
+            ]
+        );
+    }
+
+    #[test]
+    fn build_hex_lines() {
+        let lines = build_lines(
+            &params(Encoding::Hex, Decoder::None, 4),
+            &[0xab, 0xcd, 0xef],
+        );
+        assert_eq!(lines, ["abcd", "ef"]);
+
+        let lines = build_lines(&params(Encoding::Hex, Decoder::Cmd, 4), &[0xab, 0xcd, 0xef]);
+        assert_eq!(
+            lines,
+            [
+                "type nul > a.txt.hex",
+                ">> a.txt.hex echo abcd",
+                ">> a.txt.hex echo ef",
+                "certutil -f -decodehex a.txt.hex a.txt 12",
+                "del a.txt.hex",
+            ]
+        );
+    }
+
+    #[test]
+    fn build_lines_are_typeable() {
+        let data: Vec<u8> = (0..=255).collect();
+        for encoding in [Encoding::Base64, Encoding::Hex] {
+            for decoder in [
+                Decoder::None,
+                Decoder::Bash,
+                Decoder::Powershell,
+                Decoder::Cmd,
+            ] {
+                let lines = build_lines(&params(encoding, decoder, 76), &data);
+                assert!(lines
+                    .iter()
+                    .all(|line| line.chars().all(|ch| ascii_to_usage(ch).is_some())));
+            }
+        }
+    }
+
+    #[test]
+    fn resume_from_line() {
+        let mut paused = job(TypeFileState::Paused, false);
+        assert!(resume_job(&mut paused, Some(1)).is_ok());
+        assert_eq!(paused.next_line, 1);
+        assert!(paused.state == TypeFileState::Running);
+        assert!(paused.error.is_none());
+
+        // 不指定时从停下的行继续
+        let mut paused = job(TypeFileState::Paused, false);
+        assert!(resume_job(&mut paused, None).is_ok());
+        assert_eq!(paused.next_line, 2);
+
+        let mut done = job(TypeFileState::Done, false);
+        assert!(resume_job(&mut done, Some(0)).is_ok());
+        assert_eq!(done.next_line, 0);
+
+        let mut paused = job(TypeFileState::Paused, false);
+        let Err(err) = resume_job(&mut paused, Some(4)) else {
+            panic!("error expected");
+        };
+        assert_eq!(err.into_response().status(), StatusCode::BAD_REQUEST);
+        assert_eq!(paused.next_line, 2);
+    }
+
+    #[test]
+    fn resume_rejects_running_and_cancelled() {
+        // 任务还在输入当前行时不能修改 next_line
+        let mut running = job(TypeFileState::Paused, true);
+        let Err(err) = resume_job(&mut running, Some(0)) else {
+            panic!("error expected");
+        };
+        assert_eq!(err.into_response().status(), StatusCode::CONFLICT);
+        assert_eq!(running.next_line, 2);
+        assert!(resume_job(&mut running, None).is_ok());
+        assert!(running.state == TypeFileState::Running);
+
+        let mut cancelled = job(TypeFileState::Cancelled, false);
+        let Err(err) = resume_job(&mut cancelled, None) else {
+            panic!("error expected");
+        };
+        assert_eq!(err.into_response().status(), StatusCode::CONFLICT);
+    }
+
+    #[test]
+    fn input_changes_saved_keys() {
+        let mut keyboard = Keyboard::default();
+        assert!(set_input(
+            &mut keyboard,
+            Input::Key(usage_id::KEYBOARD_A),
+            true
+        ));
+        assert!(set_input(&mut keyboard, Input::AppleFn, true));
+        assert!(!set_input(&mut keyboard, Input::AppleFn, true));
+        assert!(!set_input(&mut keyboard, Input::Gamepad, true));
+        assert!(set_input(
+            &mut keyboard,
+            Input::Key(usage_id::KEYBOARD_A),
+            false
+        ));
+        assert!(set_input(&mut keyboard, Input::AppleFn, false));
+        assert!(!keyboard.release_all());
+    }
+}
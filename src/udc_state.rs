@@ -0,0 +1,168 @@
+use std::{collections::BTreeMap, net::SocketAddr, sync::Arc, time::Duration};
+
+use axum::{
+    extract::{
+        ws::{Message, WebSocket},
+        ConnectInfo, State, WebSocketUpgrade,
+    },
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+use futures::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::{sync::RwLock, time};
+
+use usb_otg::udc::UdcState;
+
+use crate::{
+    api_error::{self, ApiError},
+    hid_health::HidDevHealth,
+    metrics::METRICS,
+    AppState, DeviceCtx,
+};
+
+#[derive(Serialize)]
+pub struct UdcStateOutput {
+    udc: String,
+    state: &'static str,
+    configured: bool,
+}
+
+impl UdcStateOutput {
+    fn new(udc: &str, state: UdcState) -> Self {
+        Self {
+            udc: udc.to_string(),
+            state: state.as_str(),
+            configured: state.is_configured(),
+        }
+    }
+}
+
+pub async fn get_state(
+    Extension(device_ctx): Extension<Arc<RwLock<DeviceCtx>>>,
+) -> api_error::Result<Json<UdcStateOutput>> {
+    let device_ctx = device_ctx.read().await;
+    let udc_state = *device_ctx.udc_state_sender.borrow();
+    Ok(Json(UdcStateOutput::new(&device_ctx.udc_name, udc_state)))
+}
+
+const RECONNECT_DELAY_MAX: Duration = Duration::from_secs(60);
+
+#[derive(Deserialize)]
+pub struct ReconnectInput {
+    // UDC 解绑后等待多久再重新绑定
+    #[serde(default = "default_reconnect_delay_ms")]
+    delay_ms: u64,
+}
+
+fn default_reconnect_delay_ms() -> u64 {
+    1000
+}
+
+pub async fn post_reconnect(
+    Extension(device_ctx): Extension<Arc<RwLock<DeviceCtx>>>,
+    payload: Option<Json<ReconnectInput>>,
+) -> api_error::Result<String> {
+    let delay_ms =
+        payload.map_or_else(default_reconnect_delay_ms, |Json(payload)| payload.delay_ms);
+    let delay = Duration::from_millis(delay_ms);
+    if delay > RECONNECT_DELAY_MAX {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            anyhow::anyhow!(
+                "delay_ms must not exceed {}",
+                RECONNECT_DELAY_MAX.as_millis()
+            ),
+        ));
+    }
+    device_ctx.read().await.reconnect(delay).await?;
+    Ok("null".into())
+}
+
+pub async fn ws_handler(
+    ws: WebSocketUpgrade,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(device_ctx): Extension<Arc<RwLock<DeviceCtx>>>,
+) -> impl IntoResponse {
+    println!("udc state client at {addr} connected.");
+    ws.on_upgrade(move |socket| handle_socket(device_ctx, socket, addr))
+}
+
+async fn handle_socket(device_ctx: Arc<RwLock<DeviceCtx>>, socket: WebSocket, who: SocketAddr) {
+    let _client_guard = METRICS.websocket_client(device_ctx.read().await.id, "udc_state");
+    let (mut sender, mut receiver) = socket.split();
+
+    let (udc_name, mut udc_state_receiver) = {
+        let device_ctx = device_ctx.read().await;
+        (
+            device_ctx.udc_name.clone(),
+            device_ctx.udc_state_sender.subscribe(),
+        )
+    };
+    let mut ping_interval = time::interval(Duration::from_millis(1000));
+
+    // 连接后先推送一次当前状态，之后只在状态变化时推送
+    let mut changed = true;
+    loop {
+        if changed {
+            let udc_state = *udc_state_receiver.borrow_and_update();
+            let msg = serde_json::to_string(&UdcStateOutput::new(&udc_name, udc_state)).unwrap();
+            if sender.send(Message::Text(msg)).await.is_err() {
+                break;
+            }
+        }
+        changed = tokio::select! {
+            res = udc_state_receiver.changed() => {
+                if res.is_err() {
+                    break;
+                }
+                true
+            }
+            _ = ping_interval.tick() => {
+                if sender.send(Message::Ping(vec![1, 2, 3])).await.is_err() {
+                    break;
+                }
+                false
+            }
+            msg = receiver.next() => {
+                match msg {
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    _ => false,
+                }
+            }
+        };
+    }
+
+    println!("Websocket context {} destroyed", who);
+}
+
+#[derive(Serialize)]
+pub struct TargetOutput {
+    id: usize,
+    usb_gadget_path: String,
+    #[serde(flatten)]
+    udc_state: UdcStateOutput,
+}
+
+pub async fn get_targets(
+    State(app_state): State<Arc<AppState>>,
+) -> api_error::Result<Json<Vec<TargetOutput>>> {
+    let mut targets = Vec::new();
+    for device_ctx in &app_state.targets {
+        let device_ctx = device_ctx.read().await;
+        let udc_state = *device_ctx.udc_state_sender.borrow();
+        targets.push(TargetOutput {
+            id: device_ctx.id,
+            usb_gadget_path: device_ctx.usb_gadget_path.clone(),
+            udc_state: UdcStateOutput::new(&device_ctx.udc_name, udc_state),
+        });
+    }
+    Ok(Json(targets))
+}
+
+pub async fn get_health(
+    Extension(device_ctx): Extension<Arc<RwLock<DeviceCtx>>>,
+) -> api_error::Result<Json<BTreeMap<&'static str, HidDevHealth>>> {
+    Ok(Json(device_ctx.read().await.hid_health.snapshot()))
+}
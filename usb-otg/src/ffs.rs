@@ -0,0 +1,263 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use nix::mount::{self, MntFlags, MsFlags};
+use util::{error, fs};
+
+use crate::{Configurable, UsbFunctionOpts};
+
+pub mod block_backend;
+pub mod msg;
+
+// configfs 中的 ffs.<instance> 目录没有属性，描述符由用户态写入 ep0
+#[derive(Clone, Default)]
+pub struct FunctionFsOpts {}
+
+impl Configurable for FunctionFsOpts {
+    fn apply_config(&mut self, base_dir: &dyn AsRef<Path>) -> error::Result<()> {
+        fs::create_dir(base_dir.as_ref())?;
+        Ok(())
+    }
+
+    fn from_config(&mut self, _base_dir: &dyn AsRef<Path>) -> error::Result<()> {
+        Ok(())
+    }
+}
+
+impl UsbFunctionOpts for FunctionFsOpts {}
+
+const FUNCTIONFS_DESCRIPTORS_MAGIC_V2: u32 = 3;
+const FUNCTIONFS_STRINGS_MAGIC: u32 = 2;
+const FUNCTIONFS_HAS_FS_DESC: u32 = 1;
+const FUNCTIONFS_HAS_HS_DESC: u32 = 2;
+const FUNCTIONFS_HAS_SS_DESC: u32 = 4;
+
+// struct usb_functionfs_event
+pub const FUNCTIONFS_EVENT_LENGTH: usize = 12;
+pub const FUNCTIONFS_BIND: u8 = 0;
+pub const FUNCTIONFS_UNBIND: u8 = 1;
+pub const FUNCTIONFS_ENABLE: u8 = 2;
+pub const FUNCTIONFS_DISABLE: u8 = 3;
+pub const FUNCTIONFS_SETUP: u8 = 4;
+pub const FUNCTIONFS_SUSPEND: u8 = 5;
+pub const FUNCTIONFS_RESUME: u8 = 6;
+
+pub const USB_DIR_IN: u8 = 0x80;
+
+// 一个接口，一个 bulk IN 端点和一个 bulk OUT 端点
+pub struct BulkInterface {
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+    pub name: String,
+}
+
+impl BulkInterface {
+    fn descriptors(&self, max_packet_size: u16, super_speed: bool) -> Vec<u8> {
+        let mut ret = vec![
+            9,
+            0x04, // INTERFACE
+            0,
+            0,
+            2,
+            self.class,
+            self.subclass,
+            self.protocol,
+            1,
+        ];
+        for address in [USB_DIR_IN | 1, 2] {
+            ret.extend_from_slice(&[7, 0x05, address, 0x02]); // ENDPOINT, bulk
+            ret.extend_from_slice(&max_packet_size.to_le_bytes());
+            ret.push(0);
+            if super_speed {
+                ret.extend_from_slice(&[6, 0x30, 0, 0, 0, 0]); // SS_ENDPOINT_COMP
+            }
+        }
+        ret
+    }
+
+    fn descriptors_v2(&self) -> Vec<u8> {
+        let fs_descs = self.descriptors(64, false);
+        let hs_descs = self.descriptors(512, false);
+        let ss_descs = self.descriptors(1024, true);
+        let mut ret = Vec::new();
+        ret.extend_from_slice(&FUNCTIONFS_DESCRIPTORS_MAGIC_V2.to_le_bytes());
+        ret.extend_from_slice(&0_u32.to_le_bytes());
+        ret.extend_from_slice(
+            &(FUNCTIONFS_HAS_FS_DESC | FUNCTIONFS_HAS_HS_DESC | FUNCTIONFS_HAS_SS_DESC)
+                .to_le_bytes(),
+        );
+        ret.extend_from_slice(&3_u32.to_le_bytes());
+        ret.extend_from_slice(&3_u32.to_le_bytes());
+        ret.extend_from_slice(&5_u32.to_le_bytes());
+        ret.extend_from_slice(&fs_descs);
+        ret.extend_from_slice(&hs_descs);
+        ret.extend_from_slice(&ss_descs);
+        let length = ret.len() as u32;
+        ret[4..8].copy_from_slice(&length.to_le_bytes());
+        ret
+    }
+
+    fn strings(&self) -> Vec<u8> {
+        let mut ret = Vec::new();
+        ret.extend_from_slice(&FUNCTIONFS_STRINGS_MAGIC.to_le_bytes());
+        ret.extend_from_slice(&0_u32.to_le_bytes());
+        ret.extend_from_slice(&1_u32.to_le_bytes());
+        ret.extend_from_slice(&1_u32.to_le_bytes());
+        ret.extend_from_slice(&crate::LANGUAGE_CODE_ENGLISH.to_le_bytes());
+        ret.extend_from_slice(self.name.as_bytes());
+        ret.push(0);
+        let length = ret.len() as u32;
+        ret[4..8].copy_from_slice(&length.to_le_bytes());
+        ret
+    }
+}
+
+// 控制请求，对应 struct usb_ctrlrequest
+pub struct CtrlRequest {
+    pub request_type: u8,
+    pub request: u8,
+    pub value: u16,
+    pub index: u16,
+    pub length: u16,
+}
+
+pub enum FunctionFsEvent {
+    Bind,
+    Unbind,
+    Enable,
+    Disable,
+    Setup(CtrlRequest),
+    Suspend,
+    Resume,
+}
+
+// 挂载好的 functionfs 实例，端点文件都是阻塞的，需要在单独的线程里读写
+pub struct FunctionFs {
+    mount_dir: PathBuf,
+    ep0: File,
+    pub ep_in: File,
+    pub ep_out: File,
+}
+
+impl FunctionFs {
+    // 写入描述符后内核才会创建端点文件，UDC 也要在这之后才能绑定
+    pub fn mount(
+        instance: &str,
+        mount_dir: &Path,
+        interface: &BulkInterface,
+    ) -> error::Result<Self> {
+        // 上次异常退出时可能还挂载着
+        let _ = mount::umount2(mount_dir, MntFlags::MNT_DETACH);
+        std::fs::create_dir_all(mount_dir).map_err(|err| error::ErrorKind::io(err, mount_dir))?;
+        mount::mount(
+            Some(instance),
+            mount_dir,
+            Some("functionfs"),
+            MsFlags::empty(),
+            None::<&str>,
+        )
+        .map_err(|err| error::ErrorKind::io(err.into(), mount_dir))?;
+
+        let ep0_path = mount_dir.join("ep0");
+        let mut ep0 = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&ep0_path)
+            .map_err(|err| error::ErrorKind::io(err, &ep0_path))?;
+        ep0.write_all(&interface.descriptors_v2())
+            .map_err(|err| error::ErrorKind::io(err, &ep0_path))?;
+        ep0.write_all(&interface.strings())
+            .map_err(|err| error::ErrorKind::io(err, &ep0_path))?;
+
+        // 反方向读写时需要，见 halt_bulk
+        let ep_in_path = mount_dir.join("ep1");
+        let ep_in = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&ep_in_path)
+            .map_err(|err| error::ErrorKind::io(err, &ep_in_path))?;
+        let ep_out_path = mount_dir.join("ep2");
+        let ep_out = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&ep_out_path)
+            .map_err(|err| error::ErrorKind::io(err, &ep_out_path))?;
+        Ok(Self {
+            mount_dir: mount_dir.to_path_buf(),
+            ep0,
+            ep_in,
+            ep_out,
+        })
+    }
+
+    pub fn read_events(&self) -> std::io::Result<Vec<FunctionFsEvent>> {
+        let mut buf = [0_u8; FUNCTIONFS_EVENT_LENGTH * 4];
+        let read_len = (&self.ep0).read(&mut buf)?;
+        let mut ret = Vec::new();
+        for event in buf[..read_len].chunks_exact(FUNCTIONFS_EVENT_LENGTH) {
+            ret.push(match event[8] {
+                FUNCTIONFS_BIND => FunctionFsEvent::Bind,
+                FUNCTIONFS_UNBIND => FunctionFsEvent::Unbind,
+                FUNCTIONFS_ENABLE => FunctionFsEvent::Enable,
+                FUNCTIONFS_DISABLE => FunctionFsEvent::Disable,
+                FUNCTIONFS_SETUP => FunctionFsEvent::Setup(CtrlRequest {
+                    request_type: event[0],
+                    request: event[1],
+                    value: u16::from_le_bytes([event[2], event[3]]),
+                    index: u16::from_le_bytes([event[4], event[5]]),
+                    length: u16::from_le_bytes([event[6], event[7]]),
+                }),
+                FUNCTIONFS_SUSPEND => FunctionFsEvent::Suspend,
+                FUNCTIONFS_RESUME => FunctionFsEvent::Resume,
+                event_type => {
+                    log::warn!("functionfs unknown event: {event_type}");
+                    continue;
+                }
+            });
+        }
+        Ok(ret)
+    }
+
+    // 回应控制请求的数据阶段，OUT 请求读取 0 字节表示确认
+    pub fn reply_setup(&self, request: &CtrlRequest, data: &[u8]) -> std::io::Result<()> {
+        if request.request_type & USB_DIR_IN != 0 {
+            (&self.ep0).write_all(data)
+        } else {
+            (&self.ep0).read(&mut []).map(|_| ())
+        }
+    }
+
+    // 反方向读写 ep0 会让内核 stall 这个请求
+    pub fn stall_setup(&self, request: &CtrlRequest) {
+        let _ = if request.request_type & USB_DIR_IN != 0 {
+            (&self.ep0).read(&mut []).map(|_| ())
+        } else {
+            (&self.ep0).write(&[]).map(|_| ())
+        };
+    }
+
+    // 和 stall_setup 一样，反方向读写批量端点会让内核 stall 这个端点
+    pub fn halt_bulk(&self) {
+        let _ = (&self.ep_in).read(&mut [0_u8; 1]);
+        let _ = (&self.ep_out).write(&[0_u8; 1]);
+    }
+
+    #[cfg(test)]
+    pub(crate) fn from_files(ep0: File, ep_in: File, ep_out: File) -> Self {
+        Self {
+            mount_dir: PathBuf::new(),
+            ep0,
+            ep_in,
+            ep_out,
+        }
+    }
+
+    // 延迟卸载，端点文件还在其它线程里打开着
+    pub fn umount(&self) {
+        if let Err(err) = mount::umount2(&self.mount_dir, MntFlags::MNT_DETACH) {
+            log::warn!("umount {} failed: {err}", self.mount_dir.display());
+        }
+    }
+}
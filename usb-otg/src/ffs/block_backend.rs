@@ -0,0 +1,675 @@
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::ErrorKind;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+
+use util::error;
+
+// 覆盖层按扇区记录写入
+pub const OVERLAY_SECTOR_SIZE: u64 = 512;
+
+// U 盘的存储，由 U 盘线程同步调用，offset 和长度都是扇区对齐的
+pub trait BlockBackend: Send + Sync {
+    fn size(&self) -> u64;
+    fn read_only(&self) -> bool;
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> error::Result<()>;
+    fn write_at(&self, offset: u64, data: &[u8]) -> error::Result<()>;
+    fn flush(&self) -> error::Result<()> {
+        Ok(())
+    }
+    // 保存的覆盖层只能用在同一个底层存储上，存储被替换或修改后标识会变化
+    fn identity(&self) -> Vec<u8> {
+        self.size().to_le_bytes().to_vec()
+    }
+}
+
+// 写时复制的覆盖层，底层存储保持不变
+pub trait Overlay: BlockBackend {
+    // 被写过的扇区，合并成 (起始扇区, 扇区数)
+    fn written_ranges(&self) -> Vec<(u64, u64)>;
+    fn written_sectors(&self) -> u64;
+    // 拔出后是否还保留着写入的内容
+    fn persistent(&self) -> bool;
+    // 丢弃所有写入，删除保存写入的文件
+    fn remove(&self) -> error::Result<()>;
+}
+
+// 导出镜像时每次读取的大小
+const EXPORT_CHUNK_SIZE: usize = 1024 * 1024;
+
+fn read_only_error() -> error::Error {
+    error::ErrorKind::custom("backend is read only".into()).into()
+}
+
+fn check_range(backend: &dyn BlockBackend, offset: u64, len: usize) -> error::Result<()> {
+    if offset
+        .checked_add(len as u64)
+        .is_none_or(|end| end > backend.size())
+    {
+        Err(error::ErrorKind::custom(format!(
+            "range {offset}+{len} out of size {}",
+            backend.size()
+        )))?;
+    }
+    Ok(())
+}
+
+pub struct FileBackend {
+    path: String,
+    file: File,
+    size: u64,
+    read_only: bool,
+}
+
+impl FileBackend {
+    pub fn open(path: &Path, read_only: bool) -> error::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(!read_only)
+            .open(path)
+            .map_err(|err| error::ErrorKind::io(err, path))?;
+        let size = file
+            .metadata()
+            .map_err(|err| error::ErrorKind::io(err, path))?
+            .len();
+        Ok(Self {
+            path: path.display().to_string(),
+            file,
+            size,
+            read_only,
+        })
+    }
+}
+
+impl BlockBackend for FileBackend {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> error::Result<()> {
+        check_range(self, offset, buf.len())?;
+        self.file
+            .read_exact_at(buf, offset)
+            .map_err(|err| error::ErrorKind::io(err, &self.path))?;
+        Ok(())
+    }
+
+    fn write_at(&self, offset: u64, data: &[u8]) -> error::Result<()> {
+        if self.read_only {
+            return Err(read_only_error());
+        }
+        check_range(self, offset, data.len())?;
+        self.file
+            .write_all_at(data, offset)
+            .map_err(|err| error::ErrorKind::io(err, &self.path))?;
+        Ok(())
+    }
+
+    fn flush(&self) -> error::Result<()> {
+        self.file
+            .sync_data()
+            .map_err(|err| error::ErrorKind::io(err, &self.path))?;
+        Ok(())
+    }
+
+    fn identity(&self) -> Vec<u8> {
+        let Ok(metadata) = self.file.metadata() else {
+            return self.size.to_le_bytes().to_vec();
+        };
+        [
+            metadata.dev(),
+            metadata.ino(),
+            metadata.size(),
+            metadata.mtime() as u64,
+            metadata.mtime_nsec() as u64,
+        ]
+        .iter()
+        .flat_map(|value| value.to_le_bytes())
+        .collect()
+    }
+}
+
+// 内容只在内存中，拔出后丢失
+pub struct MemoryBackend {
+    data: RwLock<Vec<u8>>,
+}
+
+impl MemoryBackend {
+    pub fn new(size: usize) -> Self {
+        Self {
+            data: RwLock::new(vec![0; size]),
+        }
+    }
+}
+
+impl BlockBackend for MemoryBackend {
+    fn size(&self) -> u64 {
+        self.data.read().unwrap().len() as u64
+    }
+
+    fn read_only(&self) -> bool {
+        false
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> error::Result<()> {
+        check_range(self, offset, buf.len())?;
+        let offset = offset as usize;
+        buf.copy_from_slice(&self.data.read().unwrap()[offset..offset + buf.len()]);
+        Ok(())
+    }
+
+    fn write_at(&self, offset: u64, data: &[u8]) -> error::Result<()> {
+        check_range(self, offset, data.len())?;
+        let offset = offset as usize;
+        self.data.write().unwrap()[offset..offset + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+}
+
+// 写入的扇区保存在内存中
+pub struct OverlayBackend {
+    base: Arc<dyn BlockBackend>,
+    sectors: RwLock<BTreeMap<u64, Box<[u8]>>>,
+    // 写入超过这个大小后返回错误
+    max_bytes: u64,
+}
+
+impl OverlayBackend {
+    pub fn new(base: Arc<dyn BlockBackend>, max_bytes: u64) -> Self {
+        Self {
+            base,
+            sectors: Default::default(),
+            max_bytes,
+        }
+    }
+}
+
+impl Overlay for OverlayBackend {
+    fn written_ranges(&self) -> Vec<(u64, u64)> {
+        let mut ret: Vec<(u64, u64)> = Vec::new();
+        for sector in self.sectors.read().unwrap().keys() {
+            match ret.last_mut() {
+                Some((start, count)) if *start + *count == *sector => *count += 1,
+                _ => ret.push((*sector, 1)),
+            }
+        }
+        ret
+    }
+
+    fn written_sectors(&self) -> u64 {
+        self.sectors.read().unwrap().len() as u64
+    }
+
+    fn persistent(&self) -> bool {
+        false
+    }
+
+    fn remove(&self) -> error::Result<()> {
+        self.sectors.write().unwrap().clear();
+        Ok(())
+    }
+}
+
+impl BlockBackend for OverlayBackend {
+    fn size(&self) -> u64 {
+        self.base.size()
+    }
+
+    fn read_only(&self) -> bool {
+        false
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> error::Result<()> {
+        check_range(self, offset, buf.len())?;
+        self.base.read_at(offset, buf)?;
+        let end = offset + buf.len() as u64;
+        let sectors = self.sectors.read().unwrap();
+        for (sector, data) in
+            sectors.range(offset / OVERLAY_SECTOR_SIZE..end.div_ceil(OVERLAY_SECTOR_SIZE))
+        {
+            let sector_offset = sector * OVERLAY_SECTOR_SIZE;
+            // 扇区与读取范围的交集
+            let start = sector_offset.max(offset);
+            let stop = (sector_offset + OVERLAY_SECTOR_SIZE).min(end);
+            buf[(start - offset) as usize..(stop - offset) as usize].copy_from_slice(
+                &data[(start - sector_offset) as usize..(stop - sector_offset) as usize],
+            );
+        }
+        Ok(())
+    }
+
+    fn write_at(&self, offset: u64, data: &[u8]) -> error::Result<()> {
+        check_range(self, offset, data.len())?;
+        let end = offset + data.len() as u64;
+        for sector in offset / OVERLAY_SECTOR_SIZE..end.div_ceil(OVERLAY_SECTOR_SIZE) {
+            let sector_offset = sector * OVERLAY_SECTOR_SIZE;
+            let start = sector_offset.max(offset);
+            let stop = (sector_offset + OVERLAY_SECTOR_SIZE).min(end);
+            let chunk = &data[(start - offset) as usize..(stop - offset) as usize];
+            let mut sectors = self.sectors.write().unwrap();
+            if let Some(sector_data) = sectors.get_mut(&sector) {
+                sector_data[(start - sector_offset) as usize..(stop - sector_offset) as usize]
+                    .copy_from_slice(chunk);
+                continue;
+            }
+            if (sectors.len() as u64 + 1) * OVERLAY_SECTOR_SIZE > self.max_bytes {
+                Err(error::ErrorKind::custom("overlay is full".into()))?;
+            }
+            let mut sector_data = vec![0_u8; OVERLAY_SECTOR_SIZE as usize].into_boxed_slice();
+            // 只写了部分扇区时先读出原来的内容
+            if chunk.len() as u64 != OVERLAY_SECTOR_SIZE {
+                self.base.read_at(sector_offset, &mut sector_data)?;
+            }
+            sector_data[(start - sector_offset) as usize..(stop - sector_offset) as usize]
+                .copy_from_slice(chunk);
+            sectors.insert(sector, sector_data);
+        }
+        Ok(())
+    }
+}
+
+// 位图文件的开头，后面是底层存储的标识
+const OVERLAY_BITMAP_MAGIC: &[u8; 8] = b"IPKVMOV1";
+
+fn bitmap_header(base: &dyn BlockBackend) -> Vec<u8> {
+    let identity = base.identity();
+    let mut header = OVERLAY_BITMAP_MAGIC.to_vec();
+    header.extend_from_slice(&(identity.len() as u32).to_le_bytes());
+    header.extend_from_slice(&identity);
+    header
+}
+
+fn bitmap_get(bitmap: &[u8], sector: u64) -> bool {
+    bitmap[(sector / 8) as usize] & (1 << (sector % 8)) != 0
+}
+
+fn bitmap_set(bitmap: &mut [u8], sector: u64) {
+    bitmap[(sector / 8) as usize] |= 1 << (sector % 8);
+}
+
+// 写入的扇区保存在和底层存储一样大的稀疏文件中的相同位置，
+// 另一个文件按位记录哪些扇区被写过，重新打开时可以继续使用
+pub struct FileOverlayBackend {
+    base: Arc<dyn BlockBackend>,
+    data_path: PathBuf,
+    data: File,
+    bitmap_path: PathBuf,
+    bitmap_header: Vec<u8>,
+    bitmap: RwLock<Vec<u8>>,
+    // 位图还没有保存到文件
+    dirty: AtomicBool,
+}
+
+impl FileOverlayBackend {
+    pub fn open(
+        base: Arc<dyn BlockBackend>,
+        data_path: &Path,
+        bitmap_path: &Path,
+    ) -> error::Result<Self> {
+        let size = base.size();
+        let bitmap_len = size.div_ceil(OVERLAY_SECTOR_SIZE).div_ceil(8) as usize;
+        let data = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(data_path)
+            .map_err(|err| error::ErrorKind::io(err, data_path))?;
+        let data_len = data
+            .metadata()
+            .map_err(|err| error::ErrorKind::io(err, data_path))?
+            .len();
+        let bitmap_header = bitmap_header(base.as_ref());
+        let bitmap = match std::fs::read(bitmap_path) {
+            Ok(bitmap)
+                if bitmap.len() == bitmap_header.len() + bitmap_len
+                    && bitmap.starts_with(&bitmap_header)
+                    && data_len == size =>
+            {
+                Some(bitmap[bitmap_header.len()..].to_vec())
+            }
+            Ok(_) => {
+                log::warn!(
+                    "overlay {} does not match its base, reset it",
+                    data_path.display()
+                );
+                None
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => None,
+            Err(err) => Err(error::ErrorKind::io(err, bitmap_path))?,
+        };
+        let reset = bitmap.is_none();
+        let backend = Self {
+            base,
+            data_path: data_path.to_path_buf(),
+            data,
+            bitmap_path: bitmap_path.to_path_buf(),
+            bitmap_header,
+            dirty: AtomicBool::new(reset),
+            bitmap: RwLock::new(bitmap.unwrap_or_else(|| vec![0; bitmap_len])),
+        };
+        if reset {
+            backend.reset_data()?;
+            backend.flush()?;
+        }
+        Ok(backend)
+    }
+
+    // 截断后重新扩展，释放已经分配的块
+    fn reset_data(&self) -> error::Result<()> {
+        for len in [0, self.base.size()] {
+            self.data
+                .set_len(len)
+                .map_err(|err| error::ErrorKind::io(err, &self.data_path))?;
+        }
+        Ok(())
+    }
+
+    // 先写临时文件再改名，避免留下写了一半的位图
+    fn save_bitmap(&self, bitmap: &[u8]) -> error::Result<()> {
+        let tmp_path = self.bitmap_path.with_extension("tmp");
+        std::fs::write(&tmp_path, [&self.bitmap_header[..], bitmap].concat())
+            .map_err(|err| error::ErrorKind::io(err, &tmp_path))?;
+        std::fs::rename(&tmp_path, &self.bitmap_path)
+            .map_err(|err| error::ErrorKind::io(err, &self.bitmap_path))?;
+        Ok(())
+    }
+}
+
+impl BlockBackend for FileOverlayBackend {
+    fn size(&self) -> u64 {
+        self.base.size()
+    }
+
+    fn read_only(&self) -> bool {
+        false
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> error::Result<()> {
+        check_range(self, offset, buf.len())?;
+        self.base.read_at(offset, buf)?;
+        let end = offset + buf.len() as u64;
+        let bitmap = self.bitmap.read().unwrap();
+        let mut sector = offset / OVERLAY_SECTOR_SIZE;
+        let last = end.div_ceil(OVERLAY_SECTOR_SIZE);
+        while sector < last {
+            if !bitmap_get(&bitmap, sector) {
+                sector += 1;
+                continue;
+            }
+            // 连续被写过的扇区一次读出
+            let run_start = sector;
+            while sector < last && bitmap_get(&bitmap, sector) {
+                sector += 1;
+            }
+            let start = (run_start * OVERLAY_SECTOR_SIZE).max(offset);
+            let stop = (sector * OVERLAY_SECTOR_SIZE).min(end);
+            self.data
+                .read_exact_at(
+                    &mut buf[(start - offset) as usize..(stop - offset) as usize],
+                    start,
+                )
+                .map_err(|err| error::ErrorKind::io(err, &self.data_path))?;
+        }
+        Ok(())
+    }
+
+    fn write_at(&self, offset: u64, data: &[u8]) -> error::Result<()> {
+        check_range(self, offset, data.len())?;
+        if data.is_empty() {
+            return Ok(());
+        }
+        let end = offset + data.len() as u64;
+        let first = offset / OVERLAY_SECTOR_SIZE;
+        let last = (end - 1) / OVERLAY_SECTOR_SIZE;
+        let mut bitmap = self.bitmap.write().unwrap();
+        // 首尾扇区只写了一部分且还没写过时，先复制原来的内容
+        for sector in [first, last] {
+            let sector_offset = sector * OVERLAY_SECTOR_SIZE;
+            let sector_end = (sector_offset + OVERLAY_SECTOR_SIZE).min(self.size());
+            if bitmap_get(&bitmap, sector) || (offset <= sector_offset && end >= sector_end) {
+                continue;
+            }
+            let mut sector_data = vec![0_u8; (sector_end - sector_offset) as usize];
+            self.base.read_at(sector_offset, &mut sector_data)?;
+            self.data
+                .write_all_at(&sector_data, sector_offset)
+                .map_err(|err| error::ErrorKind::io(err, &self.data_path))?;
+            bitmap_set(&mut bitmap, sector);
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+        self.data
+            .write_all_at(data, offset)
+            .map_err(|err| error::ErrorKind::io(err, &self.data_path))?;
+        // 只有新写入的扇区才需要重新保存位图
+        for sector in first..=last {
+            if !bitmap_get(&bitmap, sector) {
+                bitmap_set(&mut bitmap, sector);
+                self.dirty.store(true, Ordering::Relaxed);
+            }
+        }
+        Ok(())
+    }
+
+    // 数据落盘后才保存位图，位图中的扇区一定是完整的
+    fn flush(&self) -> error::Result<()> {
+        self.data
+            .sync_data()
+            .map_err(|err| error::ErrorKind::io(err, &self.data_path))?;
+        let bitmap = self.bitmap.read().unwrap();
+        if self.dirty.swap(false, Ordering::Relaxed) {
+            if let Err(err) = self.save_bitmap(&bitmap) {
+                self.dirty.store(true, Ordering::Relaxed);
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Overlay for FileOverlayBackend {
+    fn written_ranges(&self) -> Vec<(u64, u64)> {
+        let bitmap = self.bitmap.read().unwrap();
+        let mut ret: Vec<(u64, u64)> = Vec::new();
+        for sector in 0..self.size().div_ceil(OVERLAY_SECTOR_SIZE) {
+            if !bitmap_get(&bitmap, sector) {
+                continue;
+            }
+            match ret.last_mut() {
+                Some((start, count)) if *start + *count == sector => *count += 1,
+                _ => ret.push((sector, 1)),
+            }
+        }
+        ret
+    }
+
+    fn written_sectors(&self) -> u64 {
+        let bitmap = self.bitmap.read().unwrap();
+        bitmap.iter().map(|byte| byte.count_ones() as u64).sum()
+    }
+
+    fn persistent(&self) -> bool {
+        true
+    }
+
+    fn remove(&self) -> error::Result<()> {
+        let mut bitmap = self.bitmap.write().unwrap();
+        bitmap.fill(0);
+        self.dirty.store(false, Ordering::Relaxed);
+        for path in [&self.data_path, &self.bitmap_path] {
+            match std::fs::remove_file(path) {
+                Err(err) if err.kind() != ErrorKind::NotFound => {
+                    Err(error::ErrorKind::io(err, path))?
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+// 把存储的内容导出成新的镜像文件，全零的块不写入，导出的文件是稀疏的
+pub fn export_image(backend: &dyn BlockBackend, path: &Path) -> error::Result<()> {
+    let file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .map_err(|err| error::ErrorKind::io(err, path))?;
+    let res = (|| {
+        let size = backend.size();
+        file.set_len(size)
+            .map_err(|err| error::ErrorKind::io(err, path))?;
+        let mut buf = vec![0_u8; EXPORT_CHUNK_SIZE];
+        let mut offset = 0;
+        while offset < size {
+            let len = (size - offset).min(EXPORT_CHUNK_SIZE as u64) as usize;
+            backend.read_at(offset, &mut buf[..len])?;
+            if buf[..len].iter().any(|byte| *byte != 0) {
+                file.write_all_at(&buf[..len], offset)
+                    .map_err(|err| error::ErrorKind::io(err, path))?;
+            }
+            offset += len as u64;
+        }
+        file.sync_all()
+            .map_err(|err| error::ErrorKind::io(err, path))?;
+        Ok(())
+    })();
+    // 不留下导出了一半的文件
+    if res.is_err() {
+        let _ = std::fs::remove_file(path);
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(backend: &dyn BlockBackend) -> Vec<u8> {
+        let mut buf = vec![0_u8; backend.size() as usize];
+        backend.read_at(0, &mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn overlay_matches_memory_backend() {
+        let size = 64 * OVERLAY_SECTOR_SIZE as usize;
+        let base = Arc::new(MemoryBackend::new(size));
+        let init: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+        base.write_at(0, &init).unwrap();
+        let expected = MemoryBackend::new(size);
+        expected.write_at(0, &init).unwrap();
+        let overlay = OverlayBackend::new(base.clone(), size as u64);
+
+        // 固定的伪随机序列，包括不对齐扇区的写入
+        let mut seed = 1_u64;
+        for i in 0..200 {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            let offset = (seed >> 33) % (size as u64 - 1);
+            let len = ((seed >> 20) % 1500 + 1).min(size as u64 - offset) as usize;
+            let data = vec![i as u8; len];
+            overlay.write_at(offset, &data).unwrap();
+            expected.write_at(offset, &data).unwrap();
+
+            let mut buf = vec![0_u8; len + 7];
+            let offset = offset.saturating_sub(3).min(size as u64 - buf.len() as u64);
+            let mut expected_buf = buf.clone();
+            overlay.read_at(offset, &mut buf).unwrap();
+            expected.read_at(offset, &mut expected_buf).unwrap();
+            assert_eq!(buf, expected_buf);
+        }
+        assert_eq!(read(&overlay), read(&expected));
+        // 写入不会改变底层的镜像
+        assert_eq!(read(base.as_ref()), init);
+
+        let written: u64 = overlay
+            .written_ranges()
+            .iter()
+            .map(|(_, count)| count)
+            .sum();
+        assert_eq!(written, overlay.written_sectors());
+        overlay.remove().unwrap();
+        assert_eq!(read(&overlay), init);
+    }
+
+    #[test]
+    fn overlay_written_ranges_and_limit() {
+        let base = Arc::new(MemoryBackend::new(16 * OVERLAY_SECTOR_SIZE as usize));
+        let overlay = OverlayBackend::new(base, 3 * OVERLAY_SECTOR_SIZE);
+        overlay
+            .write_at(OVERLAY_SECTOR_SIZE, &[1; 2 * OVERLAY_SECTOR_SIZE as usize])
+            .unwrap();
+        overlay.write_at(5 * OVERLAY_SECTOR_SIZE, &[1; 10]).unwrap();
+        assert_eq!(overlay.written_ranges(), [(1, 2), (5, 1)]);
+        // 已经写过的扇区不占用新的空间
+        overlay.write_at(OVERLAY_SECTOR_SIZE, &[2; 10]).unwrap();
+        assert!(overlay.write_at(7 * OVERLAY_SECTOR_SIZE, &[1; 10]).is_err());
+        assert!(overlay
+            .write_at(
+                15 * OVERLAY_SECTOR_SIZE + 1,
+                &[1; OVERLAY_SECTOR_SIZE as usize]
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn file_overlay_resets_on_base_change() {
+        let dir = std::env::temp_dir().join(format!("ip-kvm-overlay-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let base_path = dir.join("base.img");
+        let data_path = dir.join("base.img.0");
+        let bitmap_path = dir.join("base.img.0.bitmap");
+        let size = 8 * OVERLAY_SECTOR_SIZE as usize;
+        std::fs::write(&base_path, vec![1_u8; size]).unwrap();
+        let open = || {
+            let base = Arc::new(FileBackend::open(&base_path, true).unwrap());
+            FileOverlayBackend::open(base, &data_path, &bitmap_path).unwrap()
+        };
+
+        let overlay = open();
+        overlay.write_at(OVERLAY_SECTOR_SIZE, &[2; 10]).unwrap();
+        overlay.flush().unwrap();
+        drop(overlay);
+        // 同一个镜像重新打开时继续使用
+        let overlay = open();
+        assert_eq!(overlay.written_ranges(), [(1, 1)]);
+        assert_eq!(read(&overlay)[OVERLAY_SECTOR_SIZE as usize], 2);
+        drop(overlay);
+
+        // 修改时间变化
+        let file = File::options().write(true).open(&base_path).unwrap();
+        file.set_modified(std::time::SystemTime::UNIX_EPOCH)
+            .unwrap();
+        drop(file);
+        let overlay = open();
+        assert_eq!(overlay.written_sectors(), 0);
+        assert_eq!(read(&overlay), vec![1_u8; size]);
+        overlay.write_at(0, &[3; 10]).unwrap();
+        overlay.flush().unwrap();
+        drop(overlay);
+
+        // 大小相同的新文件替换了镜像
+        let new_path = dir.join("new.img");
+        std::fs::write(&new_path, vec![4_u8; size]).unwrap();
+        std::fs::rename(&new_path, &base_path).unwrap();
+        let overlay = open();
+        assert_eq!(overlay.written_sectors(), 0);
+        assert_eq!(read(&overlay), vec![4_u8; size]);
+
+        // 旧格式的位图也会重置
+        overlay.write_at(0, &[5; 10]).unwrap();
+        drop(overlay);
+        std::fs::write(&bitmap_path, [1_u8]).unwrap();
+        assert_eq!(open().written_sectors(), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
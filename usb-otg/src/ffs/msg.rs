@@ -0,0 +1,805 @@
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+use tokio::runtime::Handle;
+use tokio::sync::watch;
+use util::error;
+
+use crate::ffs::block_backend::BlockBackend;
+use crate::ffs::{BulkInterface, FunctionFs, FunctionFsEvent};
+
+// Bulk-Only Transport
+const CBW_SIGNATURE: u32 = 0x4342_5355;
+const CSW_SIGNATURE: u32 = 0x5342_5355;
+const CBW_LENGTH: usize = 31;
+const CSW_STATUS_PASSED: u8 = 0;
+const CSW_STATUS_FAILED: u8 = 1;
+const BOT_GET_MAX_LUN: u8 = 0xfe;
+const BOT_RESET: u8 = 0xff;
+
+// 每次读写端点的最大长度，是所有速度下包大小的整数倍
+const MSG_TRANSFER_CHUNK: usize = 64 * 1024;
+pub const MSG_SECTOR_SIZE: u32 = 512;
+pub const MSG_CDROM_SECTOR_SIZE: u32 = 2048;
+
+const SCSI_TEST_UNIT_READY: u8 = 0x00;
+const SCSI_REQUEST_SENSE: u8 = 0x03;
+const SCSI_READ_6: u8 = 0x08;
+const SCSI_WRITE_6: u8 = 0x0a;
+const SCSI_INQUIRY: u8 = 0x12;
+const SCSI_MODE_SENSE_6: u8 = 0x1a;
+const SCSI_START_STOP_UNIT: u8 = 0x1b;
+const SCSI_PREVENT_ALLOW_MEDIUM_REMOVAL: u8 = 0x1e;
+const SCSI_READ_FORMAT_CAPACITIES: u8 = 0x23;
+const SCSI_READ_CAPACITY_10: u8 = 0x25;
+const SCSI_READ_10: u8 = 0x28;
+const SCSI_WRITE_10: u8 = 0x2a;
+const SCSI_VERIFY_10: u8 = 0x2f;
+const SCSI_SYNCHRONIZE_CACHE_10: u8 = 0x35;
+const SCSI_READ_TOC: u8 = 0x43;
+const SCSI_MODE_SENSE_10: u8 = 0x5a;
+const SCSI_READ_16: u8 = 0x88;
+const SCSI_WRITE_16: u8 = 0x8a;
+const SCSI_SYNCHRONIZE_CACHE_16: u8 = 0x91;
+const SCSI_SERVICE_ACTION_IN_16: u8 = 0x9e;
+const SCSI_READ_12: u8 = 0xa8;
+const SCSI_WRITE_12: u8 = 0xaa;
+const SCSI_SAI_READ_CAPACITY_16: u8 = 0x10;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct Sense {
+    key: u8,
+    asc: u8,
+    ascq: u8,
+}
+
+impl Sense {
+    const NOT_READY: u8 = 0x02;
+    const MEDIUM_ERROR: u8 = 0x03;
+    const ILLEGAL_REQUEST: u8 = 0x05;
+    const UNIT_ATTENTION: u8 = 0x06;
+    const DATA_PROTECT: u8 = 0x07;
+
+    const MEDIUM_NOT_PRESENT: Self = Self::new(Self::NOT_READY, 0x3a, 0);
+    const UNRECOVERED_READ_ERROR: Self = Self::new(Self::MEDIUM_ERROR, 0x11, 0);
+    const WRITE_ERROR: Self = Self::new(Self::MEDIUM_ERROR, 0x0c, 0);
+    const INVALID_OPCODE: Self = Self::new(Self::ILLEGAL_REQUEST, 0x20, 0);
+    const LBA_OUT_OF_RANGE: Self = Self::new(Self::ILLEGAL_REQUEST, 0x21, 0);
+    const INVALID_FIELD_IN_CDB: Self = Self::new(Self::ILLEGAL_REQUEST, 0x24, 0);
+    const MEDIUM_CHANGED: Self = Self::new(Self::UNIT_ATTENTION, 0x28, 0);
+    const WRITE_PROTECTED: Self = Self::new(Self::DATA_PROTECT, 0x27, 0);
+
+    const fn new(key: u8, asc: u8, ascq: u8) -> Self {
+        Self { key, asc, ascq }
+    }
+}
+
+struct Cbw {
+    tag: u32,
+    data_length: u32,
+    data_in: bool,
+    cb: [u8; 16],
+}
+
+impl Cbw {
+    fn parse(buf: &[u8]) -> Option<Self> {
+        if buf.len() != CBW_LENGTH
+            || u32::from_le_bytes(buf[0..4].try_into().unwrap()) != CBW_SIGNATURE
+        {
+            return None;
+        }
+        Some(Self {
+            tag: u32::from_le_bytes(buf[4..8].try_into().unwrap()),
+            data_length: u32::from_le_bytes(buf[8..12].try_into().unwrap()),
+            data_in: buf[12] & 0x80 != 0,
+            cb: buf[15..31].try_into().unwrap(),
+        })
+    }
+}
+
+#[derive(Clone)]
+struct Medium {
+    backend: Arc<dyn BlockBackend>,
+    block_size: u32,
+    blocks: u64,
+    cdrom: bool,
+    read_only: bool,
+}
+
+#[derive(Default)]
+struct MsgState {
+    medium: Option<Medium>,
+    sense: Sense,
+    // 换盘后第一个命令返回 UNIT ATTENTION，让主机重新读取容量
+    unit_attention: bool,
+}
+
+// 在用户态实现 U 盘，一个 LUN
+pub struct FfsMsg {
+    ffs: FunctionFs,
+    state: Mutex<MsgState>,
+    enabled: watch::Sender<bool>,
+    closed: AtomicBool,
+    // 收到无效的 CBW 后等待主机的 Reset Recovery
+    reset_wait: Mutex<bool>,
+    reset_cond: Condvar,
+    handle: Handle,
+}
+
+impl FfsMsg {
+    // 需要在 tokio 运行时中调用
+    pub fn new(instance: &str, mount_dir: &Path) -> error::Result<Arc<Self>> {
+        let interface = BulkInterface {
+            class: 0x08,    // Mass Storage
+            subclass: 0x06, // SCSI transparent
+            protocol: 0x50, // Bulk-Only
+            name: "Mass Storage".into(),
+        };
+        Ok(Arc::new(Self {
+            ffs: FunctionFs::mount(instance, mount_dir, &interface)?,
+            state: Default::default(),
+            enabled: watch::channel(false).0,
+            closed: AtomicBool::new(false),
+            reset_wait: Mutex::new(false),
+            reset_cond: Condvar::new(),
+            handle: Handle::current(),
+        }))
+    }
+
+    // 端点的读写都是阻塞的，各用一个线程
+    pub fn spawn(self: &Arc<Self>) {
+        let ep0 = self.clone();
+        std::thread::spawn(move || ep0.run_ep0());
+        let bot = self.clone();
+        std::thread::spawn(move || bot.run_bot());
+    }
+
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        self.reset_recovery();
+        // 文件覆盖层的位图在 flush 时才保存
+        if let Some(medium) = &self.state.lock().unwrap().medium {
+            if let Err(err) = medium.backend.flush() {
+                log::error!("ffs msg flush failed: {err}");
+            }
+        }
+        self.ffs.umount();
+    }
+
+    pub fn insert(&self, backend: Arc<dyn BlockBackend>, cdrom: bool, read_only: bool) {
+        let block_size = if cdrom {
+            MSG_CDROM_SECTOR_SIZE
+        } else {
+            MSG_SECTOR_SIZE
+        };
+        let mut state = self.state.lock().unwrap();
+        state.medium = Some(Medium {
+            read_only: read_only || backend.read_only(),
+            blocks: backend.size() / block_size as u64,
+            backend,
+            block_size,
+            cdrom,
+        });
+        state.unit_attention = true;
+    }
+
+    pub fn eject(&self) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(medium) = state.medium.take() {
+            if let Err(err) = medium.backend.flush() {
+                log::error!("ffs msg flush failed: {err}");
+            }
+        }
+        state.unit_attention = true;
+    }
+
+    // 主机也可以弹出
+    pub fn has_medium(&self) -> bool {
+        self.state.lock().unwrap().medium.is_some()
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        *self.enabled.borrow()
+    }
+
+    fn run_ep0(&self) {
+        while !self.closed.load(Ordering::Relaxed) {
+            let events = match self.ffs.read_events() {
+                Ok(events) => events,
+                Err(err) => {
+                    log::error!("ffs msg read ep0 failed: {err}");
+                    std::thread::sleep(Duration::from_secs(1));
+                    continue;
+                }
+            };
+            for event in events {
+                match event {
+                    FunctionFsEvent::Enable => {
+                        log::info!("ffs msg enabled");
+                        self.enabled.send_replace(true);
+                    }
+                    FunctionFsEvent::Disable | FunctionFsEvent::Unbind => {
+                        log::info!("ffs msg disabled");
+                        self.enabled.send_replace(false);
+                        self.reset_recovery();
+                    }
+                    FunctionFsEvent::Setup(request) => {
+                        let res = match request.request {
+                            BOT_GET_MAX_LUN => self.ffs.reply_setup(&request, &[0]),
+                            // 命令之间没有状态，直接确认
+                            BOT_RESET => {
+                                self.reset_recovery();
+                                self.ffs.reply_setup(&request, &[])
+                            }
+                            _ => {
+                                log::debug!("ffs msg stall request {:#x}", request.request);
+                                self.ffs.stall_setup(&request);
+                                Ok(())
+                            }
+                        };
+                        if let Err(err) = res {
+                            log::warn!("ffs msg reply setup failed: {err}");
+                        }
+                    }
+                    FunctionFsEvent::Bind | FunctionFsEvent::Suspend | FunctionFsEvent::Resume => {}
+                }
+            }
+        }
+    }
+
+    fn run_bot(&self) {
+        let mut enabled_receiver = self.enabled.subscribe();
+        while !self.closed.load(Ordering::Relaxed) {
+            if let Err(err) = self.process_command() {
+                if self.closed.load(Ordering::Relaxed) {
+                    break;
+                }
+                log::debug!("ffs msg transfer failed: {err}");
+                // 主机断开后等待重新启用
+                std::thread::sleep(Duration::from_millis(100));
+                let _ = self
+                    .handle
+                    .block_on(enabled_receiver.wait_for(|enabled| *enabled));
+            }
+        }
+    }
+
+    fn reset_recovery(&self) {
+        *self.reset_wait.lock().unwrap() = false;
+        self.reset_cond.notify_all();
+    }
+
+    fn wait_reset(&self) {
+        let reset_wait = self.reset_wait.lock().unwrap();
+        let _reset_wait = self
+            .reset_cond
+            .wait_while(reset_wait, |reset_wait| {
+                *reset_wait && !self.closed.load(Ordering::Relaxed)
+            })
+            .unwrap();
+    }
+
+    fn process_command(&self) -> io::Result<()> {
+        let mut buf = [0_u8; MSG_TRANSFER_CHUNK];
+        let read_len = (&self.ffs.ep_out).read(&mut buf)?;
+        let Some(cbw) = Cbw::parse(&buf[..read_len]) else {
+            // BOT 要求 stall 两个批量端点并且不回复 CSW，直到主机执行 Reset Recovery
+            log::warn!("ffs msg invalid cbw, length {read_len}");
+            *self.reset_wait.lock().unwrap() = true;
+            self.ffs.halt_bulk();
+            self.wait_reset();
+            return Ok(());
+        };
+        let (status, residue) = match cbw.cb[0] {
+            SCSI_READ_6 | SCSI_READ_10 | SCSI_READ_12 | SCSI_READ_16 => self.read_blocks(&cbw)?,
+            SCSI_WRITE_6 | SCSI_WRITE_10 | SCSI_WRITE_12 | SCSI_WRITE_16 => {
+                self.write_blocks(&cbw)?
+            }
+            _ => match self.execute(&cbw.cb) {
+                Ok(data) => {
+                    let sent = if cbw.data_in {
+                        self.send_in(&data, cbw.data_length)?
+                    } else {
+                        self.discard_out(cbw.data_length)?;
+                        0
+                    };
+                    (CSW_STATUS_PASSED, cbw.data_length - sent)
+                }
+                Err(sense) => self.fail(&cbw, sense, 0)?,
+            },
+        };
+        let mut csw = [0_u8; 13];
+        csw[0..4].copy_from_slice(&CSW_SIGNATURE.to_le_bytes());
+        csw[4..8].copy_from_slice(&cbw.tag.to_le_bytes());
+        csw[8..12].copy_from_slice(&residue.to_le_bytes());
+        csw[12] = status;
+        (&self.ffs.ep_in).write_all(&csw)
+    }
+
+    // 已经传输了 done 字节后失败，剩下的数据阶段用 0 填充或丢弃
+    fn fail(&self, cbw: &Cbw, sense: Sense, done: u32) -> io::Result<(u8, u32)> {
+        self.state.lock().unwrap().sense = sense;
+        let remain = cbw.data_length - done;
+        if cbw.data_in {
+            self.send_in(&[], remain)?;
+        } else {
+            self.discard_out(remain)?;
+        }
+        Ok((CSW_STATUS_FAILED, remain))
+    }
+
+    // 数据不够时补 0，返回有效数据的长度
+    fn send_in(&self, data: &[u8], length: u32) -> io::Result<u32> {
+        let length = length as usize;
+        let sent = data.len().min(length);
+        // 长度由主机指定，按块填充，不能一次分配
+        let mut buf = vec![0_u8; length.min(MSG_TRANSFER_CHUNK)];
+        let mut done = 0;
+        while done < length {
+            let chunk = &mut buf[..(length - done).min(MSG_TRANSFER_CHUNK)];
+            chunk.fill(0);
+            if done < sent {
+                let data_len = (sent - done).min(chunk.len());
+                chunk[..data_len].copy_from_slice(&data[done..done + data_len]);
+            }
+            (&self.ffs.ep_in).write_all(chunk)?;
+            done += chunk.len();
+        }
+        Ok(sent as u32)
+    }
+
+    fn discard_out(&self, mut length: u32) -> io::Result<()> {
+        let mut buf = vec![0_u8; MSG_TRANSFER_CHUNK];
+        while length > 0 {
+            let chunk_len = (length as usize).min(MSG_TRANSFER_CHUNK);
+            let read_len = (&self.ffs.ep_out).read(&mut buf[..chunk_len])?;
+            if read_len == 0 {
+                break;
+            }
+            length -= read_len as u32;
+        }
+        Ok(())
+    }
+
+    // 检查是否有盘，换盘后先报告一次 UNIT ATTENTION
+    fn medium(&self, check_attention: bool) -> Result<Medium, Sense> {
+        let mut state = self.state.lock().unwrap();
+        if check_attention && state.unit_attention {
+            state.unit_attention = false;
+            return Err(Sense::MEDIUM_CHANGED);
+        }
+        state.medium.clone().ok_or(Sense::MEDIUM_NOT_PRESENT)
+    }
+
+    fn block_range(cb: &[u8; 16], medium: &Medium) -> Result<(u64, u64), Sense> {
+        let (lba, count) = match cb[0] {
+            SCSI_READ_6 | SCSI_WRITE_6 => {
+                let lba = u32::from_be_bytes([0, cb[1] & 0x1f, cb[2], cb[3]]) as u64;
+                // 0 表示 256 块
+                let count = if cb[4] == 0 { 256 } else { cb[4] as u64 };
+                (lba, count)
+            }
+            SCSI_READ_10 | SCSI_WRITE_10 => (
+                u32::from_be_bytes(cb[2..6].try_into().unwrap()) as u64,
+                u16::from_be_bytes(cb[7..9].try_into().unwrap()) as u64,
+            ),
+            SCSI_READ_12 | SCSI_WRITE_12 => (
+                u32::from_be_bytes(cb[2..6].try_into().unwrap()) as u64,
+                u32::from_be_bytes(cb[6..10].try_into().unwrap()) as u64,
+            ),
+            _ => (
+                u64::from_be_bytes(cb[2..10].try_into().unwrap()),
+                u32::from_be_bytes(cb[10..14].try_into().unwrap()) as u64,
+            ),
+        };
+        if lba.checked_add(count).is_none_or(|end| end > medium.blocks) {
+            return Err(Sense::LBA_OUT_OF_RANGE);
+        }
+        Ok((lba, count))
+    }
+
+    fn read_blocks(&self, cbw: &Cbw) -> io::Result<(u8, u32)> {
+        let (medium, lba, count) = match self
+            .medium(true)
+            .and_then(|medium| Ok((Self::block_range(&cbw.cb, &medium)?, medium)))
+        {
+            Ok(((lba, count), medium)) => (medium, lba, count),
+            Err(sense) => return self.fail(cbw, sense, 0),
+        };
+        if !cbw.data_in {
+            return self.fail(cbw, Sense::INVALID_FIELD_IN_CDB, 0);
+        }
+        let length = (count * medium.block_size as u64).min(cbw.data_length as u64) as u32;
+        let mut buf = vec![0_u8; MSG_TRANSFER_CHUNK];
+        let mut done = 0;
+        while done < length {
+            let chunk_len = ((length - done) as usize).min(MSG_TRANSFER_CHUNK);
+            let offset = lba * medium.block_size as u64 + done as u64;
+            if let Err(err) = medium.backend.read_at(offset, &mut buf[..chunk_len]) {
+                log::error!("ffs msg read {offset}+{chunk_len} failed: {err}");
+                return self.fail(cbw, Sense::UNRECOVERED_READ_ERROR, done);
+            }
+            (&self.ffs.ep_in).write_all(&buf[..chunk_len])?;
+            done += chunk_len as u32;
+        }
+        let residue = cbw.data_length - done;
+        self.send_in(&[], residue)?;
+        Ok((CSW_STATUS_PASSED, residue))
+    }
+
+    fn write_blocks(&self, cbw: &Cbw) -> io::Result<(u8, u32)> {
+        let (medium, lba, count) = match self
+            .medium(true)
+            .and_then(|medium| Ok((Self::block_range(&cbw.cb, &medium)?, medium)))
+        {
+            Ok(((lba, count), medium)) => (medium, lba, count),
+            Err(sense) => return self.fail(cbw, sense, 0),
+        };
+        if medium.read_only {
+            return self.fail(cbw, Sense::WRITE_PROTECTED, 0);
+        }
+        if cbw.data_in {
+            return self.fail(cbw, Sense::INVALID_FIELD_IN_CDB, 0);
+        }
+        let length = (count * medium.block_size as u64).min(cbw.data_length as u64) as u32;
+        let mut buf = vec![0_u8; MSG_TRANSFER_CHUNK];
+        let mut done = 0;
+        while done < length {
+            let chunk_len = ((length - done) as usize).min(MSG_TRANSFER_CHUNK);
+            (&self.ffs.ep_out).read_exact(&mut buf[..chunk_len])?;
+            let offset = lba * medium.block_size as u64 + done as u64;
+            if let Err(err) = medium.backend.write_at(offset, &buf[..chunk_len]) {
+                log::error!("ffs msg write {offset}+{chunk_len} failed: {err}");
+                return self.fail(cbw, Sense::WRITE_ERROR, done + chunk_len as u32);
+            }
+            done += chunk_len as u32;
+        }
+        let residue = cbw.data_length - done;
+        self.discard_out(residue)?;
+        Ok((CSW_STATUS_PASSED, residue))
+    }
+
+    // 除读写外的命令，返回 IN 数据
+    fn execute(&self, cb: &[u8; 16]) -> Result<Vec<u8>, Sense> {
+        let opcode = cb[0];
+        // INQUIRY 和 REQUEST SENSE 不受 UNIT ATTENTION 影响
+        let check_attention = opcode != SCSI_INQUIRY && opcode != SCSI_REQUEST_SENSE;
+        if check_attention {
+            let mut state = self.state.lock().unwrap();
+            if state.unit_attention {
+                state.unit_attention = false;
+                return Err(Sense::MEDIUM_CHANGED);
+            }
+        }
+        let data = match opcode {
+            SCSI_TEST_UNIT_READY | SCSI_VERIFY_10 => {
+                self.medium(false)?;
+                Vec::new()
+            }
+            SCSI_REQUEST_SENSE => {
+                let sense = std::mem::take(&mut self.state.lock().unwrap().sense);
+                let mut data = vec![0_u8; 18];
+                data[0] = 0x70; // current errors, fixed format
+                data[2] = sense.key;
+                data[7] = 10;
+                data[12] = sense.asc;
+                data[13] = sense.ascq;
+                data.truncate(cb[4] as usize);
+                data
+            }
+            SCSI_INQUIRY => {
+                // 不支持 VPD
+                if cb[1] & 0x01 != 0 {
+                    return Err(Sense::INVALID_FIELD_IN_CDB);
+                }
+                let cdrom = self
+                    .state
+                    .lock()
+                    .unwrap()
+                    .medium
+                    .as_ref()
+                    .is_some_and(|medium| medium.cdrom);
+                let mut data = vec![0_u8; 36];
+                data[0] = if cdrom { 0x05 } else { 0x00 };
+                data[1] = 0x80; // removable
+                data[2] = 0x04; // SPC-2
+                data[3] = 0x02;
+                data[4] = 31;
+                data[8..36].copy_from_slice(b"ip-kvm  Virtual Storage 1.00");
+                data.truncate(u16::from_be_bytes([cb[3], cb[4]]) as usize);
+                data
+            }
+            SCSI_MODE_SENSE_6 | SCSI_MODE_SENSE_10 => {
+                let medium = self.medium(false)?;
+                let write_protect = if medium.read_only { 0x80 } else { 0 };
+                // 只返回头部，没有模式页
+                if opcode == SCSI_MODE_SENSE_6 {
+                    let mut data = vec![3, 0, write_protect, 0];
+                    data.truncate(cb[4] as usize);
+                    data
+                } else {
+                    let mut data = vec![0, 6, 0, write_protect, 0, 0, 0, 0];
+                    data.truncate(u16::from_be_bytes([cb[7], cb[8]]) as usize);
+                    data
+                }
+            }
+            SCSI_START_STOP_UNIT => {
+                // LoEj 且不是 Start 时主机要求弹出
+                if cb[4] & 0x03 == 0x02 {
+                    log::info!("ffs msg ejected by host");
+                    let mut state = self.state.lock().unwrap();
+                    if let Some(medium) = state.medium.take() {
+                        let _ = medium.backend.flush();
+                    }
+                }
+                Vec::new()
+            }
+            SCSI_PREVENT_ALLOW_MEDIUM_REMOVAL => Vec::new(),
+            SCSI_SYNCHRONIZE_CACHE_10 | SCSI_SYNCHRONIZE_CACHE_16 => {
+                let medium = self.medium(false)?;
+                if let Err(err) = medium.backend.flush() {
+                    log::error!("ffs msg flush failed: {err}");
+                    return Err(Sense::WRITE_ERROR);
+                }
+                Vec::new()
+            }
+            SCSI_READ_FORMAT_CAPACITIES => {
+                let mut data = vec![0_u8; 12];
+                data[3] = 8;
+                match self.medium(false) {
+                    Ok(medium) => {
+                        let blocks = medium.blocks.min(u32::MAX as u64) as u32;
+                        data[4..8].copy_from_slice(&blocks.to_be_bytes());
+                        data[8] = 0x02; // formatted media
+                        data[9..12].copy_from_slice(&medium.block_size.to_be_bytes()[1..]);
+                    }
+                    Err(_) => {
+                        data[4..8].copy_from_slice(&u32::MAX.to_be_bytes());
+                        data[8] = 0x03; // no media
+                        data[9..12].copy_from_slice(&MSG_SECTOR_SIZE.to_be_bytes()[1..]);
+                    }
+                }
+                data.truncate(u16::from_be_bytes([cb[7], cb[8]]) as usize);
+                data
+            }
+            SCSI_READ_CAPACITY_10 => {
+                let medium = self.medium(false)?;
+                // 超过 2TiB 时主机会改用 READ CAPACITY(16)
+                let last_lba = medium.blocks.saturating_sub(1).min(u32::MAX as u64) as u32;
+                [last_lba.to_be_bytes(), medium.block_size.to_be_bytes()].concat()
+            }
+            SCSI_SERVICE_ACTION_IN_16 if cb[1] & 0x1f == SCSI_SAI_READ_CAPACITY_16 => {
+                let medium = self.medium(false)?;
+                let mut data = vec![0_u8; 32];
+                data[0..8].copy_from_slice(&medium.blocks.saturating_sub(1).to_be_bytes());
+                data[8..12].copy_from_slice(&medium.block_size.to_be_bytes());
+                data.truncate(u32::from_be_bytes(cb[10..14].try_into().unwrap()) as usize);
+                data
+            }
+            SCSI_READ_TOC => {
+                let medium = self.medium(false)?;
+                if !medium.cdrom {
+                    return Err(Sense::INVALID_OPCODE);
+                }
+                let mut data = Self::read_toc(cb, medium.blocks)?;
+                data.truncate(u16::from_be_bytes([cb[7], cb[8]]) as usize);
+                data
+            }
+            _ => {
+                log::debug!("ffs msg unsupported scsi command {opcode:#x}");
+                return Err(Sense::INVALID_OPCODE);
+            }
+        };
+        Ok(data)
+    }
+
+    // 只有一个数据轨道
+    fn read_toc(cb: &[u8; 16], blocks: u64) -> Result<Vec<u8>, Sense> {
+        let msf = cb[1] & 0x02 != 0;
+        let address = |lba: u64| -> [u8; 4] {
+            if msf {
+                let frames = lba + 150;
+                [
+                    0,
+                    (frames / 75 / 60) as u8,
+                    (frames / 75 % 60) as u8,
+                    (frames % 75) as u8,
+                ]
+            } else {
+                (lba as u32).to_be_bytes()
+            }
+        };
+        let mut data = Vec::new();
+        match cb[2] & 0x0f {
+            // TOC
+            0 => {
+                data.extend_from_slice(&[0, 18, 1, 1]);
+                data.extend_from_slice(&[0, 0x14, 1, 0]);
+                data.extend_from_slice(&address(0));
+                // lead-out
+                data.extend_from_slice(&[0, 0x14, 0xaa, 0]);
+                data.extend_from_slice(&address(blocks));
+            }
+            // 会话信息
+            1 => {
+                data.extend_from_slice(&[0, 10, 1, 1]);
+                data.extend_from_slice(&[0, 0x14, 1, 0]);
+                data.extend_from_slice(&address(0));
+            }
+            _ => return Err(Sense::INVALID_FIELD_IN_CDB),
+        }
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::os::fd::OwnedFd;
+    use std::os::unix::net::UnixDatagram;
+
+    use super::*;
+    use crate::ffs::block_backend::MemoryBackend;
+
+    fn cbw(data_length: u32, flags: u8, cb: &[u8]) -> Vec<u8> {
+        let mut buf = vec![0_u8; CBW_LENGTH];
+        buf[0..4].copy_from_slice(&CBW_SIGNATURE.to_le_bytes());
+        buf[4..8].copy_from_slice(&0x1234_u32.to_le_bytes());
+        buf[8..12].copy_from_slice(&data_length.to_le_bytes());
+        buf[12] = flags;
+        buf[14] = cb.len() as u8;
+        buf[15..15 + cb.len()].copy_from_slice(cb);
+        buf
+    }
+
+    fn medium(blocks: u64) -> Medium {
+        Medium {
+            backend: Arc::new(MemoryBackend::new(
+                blocks as usize * MSG_SECTOR_SIZE as usize,
+            )),
+            block_size: MSG_SECTOR_SIZE,
+            blocks,
+            cdrom: false,
+            read_only: false,
+        }
+    }
+
+    fn cb(bytes: &[u8]) -> [u8; 16] {
+        let mut cb = [0_u8; 16];
+        cb[..bytes.len()].copy_from_slice(bytes);
+        cb
+    }
+
+    #[test]
+    fn parse_cbw() {
+        let cbw = Cbw::parse(&cbw(512, 0x80, &[SCSI_READ_10, 0, 0, 0, 0, 1, 0, 0, 1])).unwrap();
+        assert_eq!(cbw.tag, 0x1234);
+        assert_eq!(cbw.data_length, 512);
+        assert!(cbw.data_in);
+        assert_eq!(cbw.cb[0], SCSI_READ_10);
+        assert_eq!(cbw.cb[5], 1);
+
+        let buf = self::cbw(0, 0, &[SCSI_TEST_UNIT_READY]);
+        assert!(!Cbw::parse(&buf).unwrap().data_in);
+        assert!(Cbw::parse(&buf[..CBW_LENGTH - 1]).is_none());
+        let mut buf = buf;
+        buf[0] = 0;
+        assert!(Cbw::parse(&buf).is_none());
+    }
+
+    #[test]
+    fn block_range_commands() {
+        let medium = medium(1000);
+        // READ(6) 的 LBA 只有 21 位，长度 0 表示 256 块
+        assert_eq!(
+            FfsMsg::block_range(&cb(&[SCSI_READ_6, 0xe0, 0x01, 0x02, 0]), &medium),
+            Ok((0x0102, 256))
+        );
+        assert_eq!(
+            FfsMsg::block_range(&cb(&[SCSI_WRITE_10, 0, 0, 0, 0x03, 0xe7, 0, 0, 1]), &medium),
+            Ok((999, 1))
+        );
+        assert_eq!(
+            FfsMsg::block_range(&cb(&[SCSI_READ_12, 0, 0, 0, 0, 10, 0, 0, 0, 20]), &medium),
+            Ok((10, 20))
+        );
+        let mut cb16 = cb(&[SCSI_READ_16]);
+        cb16[2..10].copy_from_slice(&990_u64.to_be_bytes());
+        cb16[10..14].copy_from_slice(&10_u32.to_be_bytes());
+        assert_eq!(FfsMsg::block_range(&cb16, &medium), Ok((990, 10)));
+    }
+
+    #[test]
+    fn block_range_out_of_range() {
+        let medium = medium(1000);
+        assert_eq!(
+            FfsMsg::block_range(&cb(&[SCSI_READ_10, 0, 0, 0, 0x03, 0xe7, 0, 0, 2]), &medium),
+            Err(Sense::LBA_OUT_OF_RANGE)
+        );
+        // LBA + 长度溢出
+        let mut cb16 = cb(&[SCSI_WRITE_16]);
+        cb16[2..10].copy_from_slice(&u64::MAX.to_be_bytes());
+        cb16[10..14].copy_from_slice(&2_u32.to_be_bytes());
+        assert_eq!(
+            FfsMsg::block_range(&cb16, &medium),
+            Err(Sense::LBA_OUT_OF_RANGE)
+        );
+    }
+
+    // 用 datagram socket 模拟批量端点，每次读写是一个传输
+    fn socket_msg(runtime: &tokio::runtime::Runtime) -> (Arc<FfsMsg>, UnixDatagram, UnixDatagram) {
+        let (ep_in, host_in) = UnixDatagram::pair().unwrap();
+        let (ep_out, host_out) = UnixDatagram::pair().unwrap();
+        // 没有数据时 halt_bulk 读 ep_in 不能阻塞
+        ep_in.set_nonblocking(true).unwrap();
+        let ep0 = File::open("/dev/null").unwrap();
+        let msg = Arc::new(FfsMsg {
+            ffs: FunctionFs::from_files(
+                ep0,
+                File::from(OwnedFd::from(ep_in)),
+                File::from(OwnedFd::from(ep_out)),
+            ),
+            state: Default::default(),
+            enabled: watch::channel(false).0,
+            closed: AtomicBool::new(false),
+            reset_wait: Mutex::new(false),
+            reset_cond: Condvar::new(),
+            handle: runtime.handle().clone(),
+        });
+        (msg, host_in, host_out)
+    }
+
+    #[test]
+    fn invalid_cbw_stalls_until_reset() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let (msg, host_in, host_out) = socket_msg(&runtime);
+        let bot = msg.clone();
+        let thread = std::thread::spawn(move || bot.process_command());
+        let mut buf = cbw(0, 0, &[SCSI_TEST_UNIT_READY]);
+        buf[0] = 0;
+        host_out.send(&buf).unwrap();
+
+        // 反方向写 OUT 端点表示 stall
+        let mut halt = [0_u8; 64];
+        assert_eq!(host_out.recv(&mut halt).unwrap(), 1);
+        host_in.set_nonblocking(true).unwrap();
+        assert!(host_in.recv(&mut [0_u8; 64]).is_err());
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(!thread.is_finished());
+
+        msg.reset_recovery();
+        thread.join().unwrap().unwrap();
+        // 没有回复 CSW
+        assert!(host_in.recv(&mut [0_u8; 64]).is_err());
+
+        // Reset Recovery 之后继续处理命令
+        host_out.send(&cbw(0, 0, &[SCSI_TEST_UNIT_READY])).unwrap();
+        msg.process_command().unwrap();
+        let mut csw = [0_u8; 64];
+        assert_eq!(host_in.recv(&mut csw).unwrap(), 13);
+        assert_eq!(csw[0..4], CSW_SIGNATURE.to_le_bytes());
+        assert_eq!(csw[4..8], 0x1234_u32.to_le_bytes());
+        assert_eq!(csw[12], CSW_STATUS_FAILED);
+        assert_eq!(msg.state.lock().unwrap().sense, Sense::MEDIUM_NOT_PRESENT);
+    }
+
+    #[test]
+    fn close_wakes_stalled_bot() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let (msg, _host_in, host_out) = socket_msg(&runtime);
+        let bot = msg.clone();
+        let thread = std::thread::spawn(move || bot.process_command());
+        host_out.send(&[0_u8; CBW_LENGTH - 1]).unwrap();
+        assert_eq!(host_out.recv(&mut [0_u8; 64]).unwrap(), 1);
+        msg.closed.store(true, Ordering::Relaxed);
+        msg.reset_recovery();
+        thread.join().unwrap().unwrap();
+    }
+}
@@ -7,10 +7,17 @@ use util::fs;
 
 use crate::{error, Configurable, UsbFunctionOpts};
 
+pub mod apple;
+pub mod custom;
+pub mod fido;
+pub mod gamepad;
 pub mod generic_desktop;
 pub mod hid_composite;
+pub mod hidg;
 pub mod keyboard;
 pub mod mouse;
+pub mod report_queue;
+pub mod vendor;
 
 #[derive(Clone)]
 pub struct FunctionHidOpts {
@@ -0,0 +1,76 @@
+use crate::hid;
+use crate::hid::hid_composite;
+use crate::{GadgetInfo, LANGUAGE_CODE_ENGLISH};
+
+pub const APPLE_VENDOR_ID: u16 = 0x05ac;
+// Aluminium Keyboard (ANSI)，macOS 认识这个型号，不会弹出键盘设置助理
+pub const APPLE_KEYBOARD_PRODUCT_ID: u16 = 0x024f;
+pub const APPLE_MANUFACTURER: &str = "Apple Inc.";
+pub const APPLE_PRODUCT: &str = "Apple Keyboard";
+
+// Apple 键盘的 Fn/Globe 键在厂商自定义的 top case 页中
+pub const USAGE_PAGE_APPLE_VENDOR_TOP_CASE: u8 = 0xff;
+pub const APPLE_TOP_CASE_KEYBOARD_FN: u8 = 0x03;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+pub enum KeyboardProfile {
+    Generic,
+    Apple,
+}
+
+impl KeyboardProfile {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Generic => "generic",
+            Self::Apple => "apple",
+        }
+    }
+
+    // 修改 VID/PID 和字符串描述符，需要在 strings 插入后调用
+    pub fn apply(&self, gadget_info: &mut GadgetInfo) {
+        if *self != Self::Apple {
+            return;
+        }
+        gadget_info.id_vendor = APPLE_VENDOR_ID;
+        gadget_info.id_product = APPLE_KEYBOARD_PRODUCT_ID;
+        let strings = gadget_info
+            .strings
+            .entry(LANGUAGE_CODE_ENGLISH)
+            .or_default();
+        strings.manufacturer = APPLE_MANUFACTURER.to_string();
+        strings.product = APPLE_PRODUCT.to_string();
+    }
+
+    pub fn hid_composite_fho(&self) -> hid::FunctionHidOpts {
+        let mut fho = hid_composite::HID_COMPOSITE_FHO.clone();
+        if *self == Self::Apple {
+            fho.report_desc.extend_from_slice(&[
+                // Apple Fn
+                0x05, 0x01,     /* USAGE_PAGE (Generic Desktop)           */
+                0x09, 0x06,     /* USAGE (Keyboard)                       */
+                0xa1, 0x01,     /* COLLECTION (Application)               */
+                0x85, hid_composite::HID_REPORT_ID_APPLE_FN,     /* Report ID (HID_REPORT_ID_APPLE_FN)                 */
+
+                0x05, USAGE_PAGE_APPLE_VENDOR_TOP_CASE,     /*   USAGE_PAGE (Apple Vendor Top Case)                */
+                0x09, APPLE_TOP_CASE_KEYBOARD_FN,     /*   USAGE (Keyboard Fn)                */
+                0x15, 0x00,     /*   LOGICAL_MINIMUM (0)                  */
+                0x25, 0x01,     /*   LOGICAL_MAXIMUM (1)                  */
+                0x75, 0x01,     /*   REPORT_SIZE (1)                      */
+                0x95, 0x01,     /*   REPORT_COUNT (1)                     */
+                0x81, 0x02,     /*   INPUT (Data,Var,Abs)                 */
+
+                // Padding
+                0x75, 0x07,     /*   REPORT_SIZE (7)                      */
+                0x95, 0x01,     /*   REPORT_COUNT (1)                     */
+                0x81, 0x03,     /*   INPUT (Cnst,Var,Abs)                 */
+                0x75, 0x08,     /*   REPORT_SIZE (8)                      */
+                0x95, 0x21,     /*   REPORT_COUNT (0x21)                     */ // 0x21+0x1 = 0x22
+                0x81, 0x03,     /*   INPUT (Cnst,Var,Abs)                 */
+
+                0xc0            /* END_COLLECTION                         */
+            ]);
+        }
+        fho
+    }
+}
@@ -0,0 +1,74 @@
+use std::time::Duration;
+
+use nix::fcntl;
+use tokio::sync::broadcast;
+use util::error;
+
+use crate::hid::hidg::Hidg;
+
+// 订阅者太慢时丢弃最早的报告
+const CUSTOM_HID_OUT_CHANNEL_CAPACITY: usize = 256;
+
+// 由配置文件描述的任意 hid 设备，只负责收发原始报告
+pub struct CustomHidDevice {
+    report_length: usize,
+    custom_dev_read: Hidg,
+    custom_dev_write: Hidg,
+    out_sender: broadcast::Sender<Vec<u8>>,
+}
+
+impl CustomHidDevice {
+    // AsyncFd::new must call in tokio async runtime
+    pub async fn new(custom_minor: i32, report_length: u16) -> error::Result<Self> {
+        let custom_dev_read = Hidg::open(custom_minor, fcntl::OFlag::O_RDONLY)?;
+        let custom_dev_write = Hidg::open(custom_minor, fcntl::OFlag::O_WRONLY)?;
+
+        Ok(Self {
+            report_length: report_length as usize,
+            custom_dev_read,
+            custom_dev_write,
+            out_sender: broadcast::channel(CUSTOM_HID_OUT_CHANNEL_CAPACITY).0,
+        })
+    }
+
+    pub async fn reopen(&self) -> error::Result<()> {
+        self.custom_dev_read.reopen().await?;
+        self.custom_dev_write.reopen().await?;
+        Ok(())
+    }
+
+    pub fn set_report_interval(&self, interval: Duration) {
+        self.custom_dev_write.set_write_interval(interval);
+    }
+
+    pub fn report_length(&self) -> usize {
+        self.report_length
+    }
+
+    // 带 report id 的描述符可以发送比 report_length 短的报告
+    pub async fn send(&self, report: &[u8]) -> error::Result<()> {
+        if report.is_empty() || report.len() > self.report_length {
+            Err(error::ErrorKind::custom(format!(
+                "report length must be between 1 and {}, got {}",
+                self.report_length,
+                report.len()
+            )))?;
+        }
+        log::debug!("custom_dev send {report:?}");
+        self.custom_dev_write.write_all(report).await
+    }
+
+    // 读取主机发来的 OUT 报告并广播给订阅者
+    pub async fn recv(&self) -> error::Result<()> {
+        let mut buf = vec![0_u8; self.report_length];
+        let read_len = self.custom_dev_read.read(&mut buf).await?;
+        buf.truncate(read_len);
+        log::debug!("custom_dev recv {buf:?}");
+        let _ = self.out_sender.send(buf);
+        Ok(())
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Vec<u8>> {
+        self.out_sender.subscribe()
+    }
+}
@@ -0,0 +1,433 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use lazy_static::lazy_static;
+use nix::fcntl;
+use tokio::sync::Mutex;
+use util::error;
+
+use crate::hid;
+use crate::hid::hidg::Hidg;
+
+pub const CTAPHID_PACKET_SIZE: usize = 64;
+const CTAPHID_INIT_DATA_SIZE: usize = CTAPHID_PACKET_SIZE - 7;
+const CTAPHID_CONT_DATA_SIZE: usize = CTAPHID_PACKET_SIZE - 5;
+// 128 个后续包能携带的最大数据
+pub const CTAPHID_MAX_MESSAGE_SIZE: usize = CTAPHID_INIT_DATA_SIZE + 128 * CTAPHID_CONT_DATA_SIZE;
+// 后续包应该连续到达，超时就丢弃没收完的消息
+pub const CTAPHID_TRANSACTION_TIMEOUT: Duration = Duration::from_secs(1);
+// 同时拼接的通道数上限
+const CTAPHID_MAX_PARTIAL: usize = 4;
+
+pub const CTAPHID_BROADCAST_CID: u32 = 0xffffffff;
+
+pub const CTAPHID_PING: u8 = 0x01;
+pub const CTAPHID_MSG: u8 = 0x03;
+pub const CTAPHID_INIT: u8 = 0x06;
+pub const CTAPHID_WINK: u8 = 0x08;
+pub const CTAPHID_CBOR: u8 = 0x10;
+pub const CTAPHID_CANCEL: u8 = 0x11;
+pub const CTAPHID_KEEPALIVE: u8 = 0x3b;
+pub const CTAPHID_ERROR: u8 = 0x3f;
+
+pub const CTAPHID_ERR_INVALID_CMD: u8 = 0x01;
+pub const CTAPHID_ERR_INVALID_LEN: u8 = 0x03;
+pub const CTAPHID_ERR_INVALID_SEQ: u8 = 0x04;
+pub const CTAPHID_ERR_MSG_TIMEOUT: u8 = 0x05;
+pub const CTAPHID_ERR_CHANNEL_BUSY: u8 = 0x06;
+pub const CTAPHID_ERR_INVALID_CHANNEL: u8 = 0x0b;
+
+pub const CTAPHID_STATUS_PROCESSING: u8 = 1;
+pub const CTAPHID_STATUS_UPNEEDED: u8 = 2;
+
+pub const CTAPHID_CAPABILITY_WINK: u8 = 0x01;
+pub const CTAPHID_CAPABILITY_CBOR: u8 = 0x04;
+
+lazy_static! {
+    pub static ref FIDO_FHO: hid::FunctionHidOpts = hid::FunctionHidOpts {
+        major: 0,
+        minor: 0,
+        // CTAPHID 要求有中断 OUT 端点
+        no_out_endpoint: 0,
+        subclass: 0, /* No Subclass */
+        protocol: 0,  /* None */
+        report_length: CTAPHID_PACKET_SIZE as u16,
+        report_desc: vec![
+            0x06, 0xd0, 0xf1,     /* USAGE_PAGE (FIDO Alliance)           */
+            0x09, 0x01,     /* USAGE (CTAPHID)                       */
+            0xa1, 0x01,     /* COLLECTION (Application)               */
+
+            0x09, 0x20,     /*   USAGE (Input Report Data)                */
+            0x15, 0x00,     /*   LOGICAL_MINIMUM (0)                  */
+            0x26, 0xff, 0x00,     /*   LOGICAL_MAXIMUM (255)                  */
+            0x75, 0x08,     /*   REPORT_SIZE (8)                      */
+            0x95, 0x40,     /*   REPORT_COUNT (64)                     */
+            0x81, 0x02,     /*   INPUT (Data,Var,Abs)                 */
+
+            0x09, 0x21,     /*   USAGE (Output Report Data)                */
+            0x15, 0x00,     /*   LOGICAL_MINIMUM (0)                  */
+            0x26, 0xff, 0x00,     /*   LOGICAL_MAXIMUM (255)                  */
+            0x75, 0x08,     /*   REPORT_SIZE (8)                      */
+            0x95, 0x40,     /*   REPORT_COUNT (64)                     */
+            0x91, 0x02,     /*   OUTPUT (Data,Var,Abs)                */
+
+            0xc0            /* END_COLLECTION                         */
+        ],
+    };
+}
+
+pub struct CtaphidMessage {
+    pub cid: u32,
+    pub cmd: u8,
+    pub data: Vec<u8>,
+}
+
+pub enum CtaphidPacket {
+    Message(CtaphidMessage),
+    // 消息还没有接收完
+    Pending,
+    Error { cid: u32, code: u8 },
+}
+
+struct PartialMessage {
+    cmd: u8,
+    len: usize,
+    data: Vec<u8>,
+    seq: u8,
+    started: Instant,
+}
+
+// 把 64 字节的包拼成完整的消息，每个通道独立
+#[derive(Default)]
+pub struct CtaphidAssembler {
+    partial: HashMap<u32, PartialMessage>,
+}
+
+impl CtaphidAssembler {
+    pub fn push(&mut self, packet: &[u8; CTAPHID_PACKET_SIZE], now: Instant) -> CtaphidPacket {
+        let cid = u32::from_be_bytes([packet[0], packet[1], packet[2], packet[3]]);
+        if packet[4] & 0x80 != 0 {
+            let cmd = packet[4] & 0x7f;
+            let len = u16::from_be_bytes([packet[5], packet[6]]) as usize;
+            if len > CTAPHID_MAX_MESSAGE_SIZE {
+                self.partial.remove(&cid);
+                return CtaphidPacket::Error {
+                    cid,
+                    code: CTAPHID_ERR_INVALID_LEN,
+                };
+            }
+            let data = packet[7..7 + len.min(CTAPHID_INIT_DATA_SIZE)].to_vec();
+            // 新的初始化包会丢弃这个通道上没收完的消息
+            self.partial.remove(&cid);
+            if data.len() == len {
+                return CtaphidPacket::Message(CtaphidMessage { cid, cmd, data });
+            }
+            if self.partial.len() >= CTAPHID_MAX_PARTIAL {
+                return CtaphidPacket::Error {
+                    cid,
+                    code: CTAPHID_ERR_CHANNEL_BUSY,
+                };
+            }
+            self.partial.insert(
+                cid,
+                PartialMessage {
+                    cmd,
+                    len,
+                    data,
+                    seq: 0,
+                    started: now,
+                },
+            );
+            return CtaphidPacket::Pending;
+        }
+
+        let seq = packet[4];
+        let Some(partial) = self.partial.get_mut(&cid) else {
+            // 没有对应的初始化包，忽略
+            return CtaphidPacket::Pending;
+        };
+        if seq != partial.seq {
+            self.partial.remove(&cid);
+            return CtaphidPacket::Error {
+                cid,
+                code: CTAPHID_ERR_INVALID_SEQ,
+            };
+        }
+        partial.seq += 1;
+        let remain = partial.len - partial.data.len();
+        partial
+            .data
+            .extend_from_slice(&packet[5..5 + remain.min(CTAPHID_CONT_DATA_SIZE)]);
+        if partial.data.len() < partial.len {
+            return CtaphidPacket::Pending;
+        }
+        let partial = self.partial.remove(&cid).unwrap();
+        CtaphidPacket::Message(CtaphidMessage {
+            cid,
+            cmd: partial.cmd,
+            data: partial.data,
+        })
+    }
+
+    // 最早超时的时间，没有正在拼接的消息时返回 None
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.partial
+            .values()
+            .map(|partial| partial.started + CTAPHID_TRANSACTION_TIMEOUT)
+            .min()
+    }
+
+    // 丢弃超时的消息，返回这些消息的通道
+    pub fn expire(&mut self, now: Instant) -> Vec<u32> {
+        let expired: Vec<u32> = self
+            .partial
+            .iter()
+            .filter(|(_, partial)| now >= partial.started + CTAPHID_TRANSACTION_TIMEOUT)
+            .map(|(cid, _)| *cid)
+            .collect();
+        for cid in &expired {
+            self.partial.remove(cid);
+        }
+        expired
+    }
+}
+
+pub struct FidoDevice {
+    fido_dev_read: Hidg,
+    fido_dev_write: Hidg,
+    // 一条消息的所有包必须连续发送
+    write_lock: Mutex<()>,
+}
+
+impl FidoDevice {
+    // AsyncFd::new must call in tokio async runtime
+    pub async fn new(fido_minor: i32) -> error::Result<Self> {
+        let fido_dev_read = Hidg::open(fido_minor, fcntl::OFlag::O_RDONLY)?;
+        let fido_dev_write = Hidg::open(fido_minor, fcntl::OFlag::O_WRONLY)?;
+
+        Ok(Self {
+            fido_dev_read,
+            fido_dev_write,
+            write_lock: Mutex::new(()),
+        })
+    }
+
+    pub async fn reopen(&self) -> error::Result<()> {
+        self.fido_dev_read.reopen().await?;
+        self.fido_dev_write.reopen().await?;
+        Ok(())
+    }
+
+    pub fn set_report_interval(&self, interval: Duration) {
+        self.fido_dev_write.set_write_interval(interval);
+    }
+
+    pub async fn recv_packet(&self) -> error::Result<[u8; CTAPHID_PACKET_SIZE]> {
+        let mut packet = [0_u8; CTAPHID_PACKET_SIZE];
+        let read_len = self.fido_dev_read.read(&mut packet).await?;
+        if read_len != CTAPHID_PACKET_SIZE {
+            log::warn!("fido_dev ignore: {:?}", &packet[..read_len]);
+            Err(error::ErrorKind::Ignore)?;
+        }
+        log::debug!("fido_dev recv {packet:?}");
+        Ok(packet)
+    }
+
+    pub async fn send(&self, cid: u32, cmd: u8, data: &[u8]) -> error::Result<()> {
+        let _write_guard = self.write_lock.lock().await;
+        let mut packet = [0_u8; CTAPHID_PACKET_SIZE];
+        packet[0..4].copy_from_slice(&cid.to_be_bytes());
+        packet[4] = 0x80 | cmd;
+        packet[5..7].copy_from_slice(&(data.len() as u16).to_be_bytes());
+        let (init_data, mut remain) = data.split_at(data.len().min(CTAPHID_INIT_DATA_SIZE));
+        packet[7..7 + init_data.len()].copy_from_slice(init_data);
+        self.fido_dev_write.write_all(&packet).await?;
+
+        let mut seq = 0_u8;
+        while !remain.is_empty() {
+            let (cont_data, next) = remain.split_at(remain.len().min(CTAPHID_CONT_DATA_SIZE));
+            let mut packet = [0_u8; CTAPHID_PACKET_SIZE];
+            packet[0..4].copy_from_slice(&cid.to_be_bytes());
+            packet[4] = seq;
+            packet[5..5 + cont_data.len()].copy_from_slice(cont_data);
+            self.fido_dev_write.write_all(&packet).await?;
+            seq += 1;
+            remain = next;
+        }
+        Ok(())
+    }
+
+    pub async fn send_error(&self, cid: u32, code: u8) -> error::Result<()> {
+        self.send(cid, CTAPHID_ERROR, &[code]).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn init_packet(cid: u32, cmd: u8, len: u16, data: &[u8]) -> [u8; CTAPHID_PACKET_SIZE] {
+        let mut packet = [0_u8; CTAPHID_PACKET_SIZE];
+        packet[0..4].copy_from_slice(&cid.to_be_bytes());
+        packet[4] = 0x80 | cmd;
+        packet[5..7].copy_from_slice(&len.to_be_bytes());
+        packet[7..7 + data.len()].copy_from_slice(data);
+        packet
+    }
+
+    fn cont_packet(cid: u32, seq: u8, data: &[u8]) -> [u8; CTAPHID_PACKET_SIZE] {
+        let mut packet = [0_u8; CTAPHID_PACKET_SIZE];
+        packet[0..4].copy_from_slice(&cid.to_be_bytes());
+        packet[4] = seq;
+        packet[5..5 + data.len()].copy_from_slice(data);
+        packet
+    }
+
+    #[test]
+    fn assembler_single_packet() {
+        let mut assembler = CtaphidAssembler::default();
+        let now = Instant::now();
+        let CtaphidPacket::Message(message) =
+            assembler.push(&init_packet(1, CTAPHID_PING, 3, &[1, 2, 3]), now)
+        else {
+            panic!("message expected");
+        };
+        assert_eq!(message.cid, 1);
+        assert_eq!(message.cmd, CTAPHID_PING);
+        assert_eq!(message.data, [1, 2, 3]);
+    }
+
+    #[test]
+    fn assembler_fragments() {
+        let data: Vec<u8> = (0..150).collect();
+        let mut assembler = CtaphidAssembler::default();
+        let now = Instant::now();
+        let packet = init_packet(1, CTAPHID_CBOR, 150, &data[..CTAPHID_INIT_DATA_SIZE]);
+        assert!(matches!(
+            assembler.push(&packet, now),
+            CtaphidPacket::Pending
+        ));
+        // 其它通道的包不影响正在拼接的消息
+        let packet = init_packet(2, CTAPHID_PING, 1, &[9]);
+        assert!(matches!(
+            assembler.push(&packet, now),
+            CtaphidPacket::Message(_)
+        ));
+        let packet = cont_packet(1, 0, &data[57..116]);
+        assert!(matches!(
+            assembler.push(&packet, now),
+            CtaphidPacket::Pending
+        ));
+        let CtaphidPacket::Message(message) = assembler.push(&cont_packet(1, 1, &data[116..]), now)
+        else {
+            panic!("message expected");
+        };
+        assert_eq!(message.cmd, CTAPHID_CBOR);
+        assert_eq!(message.data, data);
+    }
+
+    #[test]
+    fn assembler_rejects_bad_seq() {
+        let mut assembler = CtaphidAssembler::default();
+        let now = Instant::now();
+        let packet = init_packet(1, CTAPHID_MSG, 100, &[0; CTAPHID_INIT_DATA_SIZE]);
+        assert!(matches!(
+            assembler.push(&packet, now),
+            CtaphidPacket::Pending
+        ));
+        assert!(matches!(
+            assembler.push(&cont_packet(1, 1, &[0; 43]), now),
+            CtaphidPacket::Error {
+                cid: 1,
+                code: CTAPHID_ERR_INVALID_SEQ
+            }
+        ));
+        // 出错后丢弃没收完的消息
+        assert!(matches!(
+            assembler.push(&cont_packet(1, 0, &[0; 43]), now),
+            CtaphidPacket::Pending
+        ));
+    }
+
+    #[test]
+    fn assembler_rejects_bad_len() {
+        let mut assembler = CtaphidAssembler::default();
+        let now = Instant::now();
+        let packet = init_packet(1, CTAPHID_MSG, CTAPHID_MAX_MESSAGE_SIZE as u16 + 1, &[]);
+        assert!(matches!(
+            assembler.push(&packet, now),
+            CtaphidPacket::Error {
+                cid: 1,
+                code: CTAPHID_ERR_INVALID_LEN
+            }
+        ));
+    }
+
+    #[test]
+    fn assembler_ignores_orphan_cont() {
+        let mut assembler = CtaphidAssembler::default();
+        let now = Instant::now();
+        assert!(matches!(
+            assembler.push(&cont_packet(1, 0, &[0; 10]), now),
+            CtaphidPacket::Pending
+        ));
+    }
+
+    #[test]
+    fn assembler_expires_stalled_message() {
+        let mut assembler = CtaphidAssembler::default();
+        let now = Instant::now();
+        assert_eq!(assembler.next_deadline(), None);
+        let packet = init_packet(1, CTAPHID_CBOR, 100, &[0; CTAPHID_INIT_DATA_SIZE]);
+        assert!(matches!(
+            assembler.push(&packet, now),
+            CtaphidPacket::Pending
+        ));
+        let deadline = now + CTAPHID_TRANSACTION_TIMEOUT;
+        assert_eq!(assembler.next_deadline(), Some(deadline));
+        assert!(assembler
+            .expire(deadline - Duration::from_millis(1))
+            .is_empty());
+        assert_eq!(assembler.expire(deadline), [1]);
+        assert_eq!(assembler.next_deadline(), None);
+        // 超时后的后续包被忽略
+        assert!(matches!(
+            assembler.push(&cont_packet(1, 0, &[0; 43]), deadline),
+            CtaphidPacket::Pending
+        ));
+    }
+
+    #[test]
+    fn assembler_limits_partial_messages() {
+        let mut assembler = CtaphidAssembler::default();
+        let now = Instant::now();
+        for cid in 1..=CTAPHID_MAX_PARTIAL as u32 {
+            let packet = init_packet(cid, CTAPHID_CBOR, 100, &[0; CTAPHID_INIT_DATA_SIZE]);
+            assert!(matches!(
+                assembler.push(&packet, now),
+                CtaphidPacket::Pending
+            ));
+        }
+        let cid = CTAPHID_MAX_PARTIAL as u32 + 1;
+        let packet = init_packet(cid, CTAPHID_CBOR, 100, &[0; CTAPHID_INIT_DATA_SIZE]);
+        assert!(matches!(
+            assembler.push(&packet, now),
+            CtaphidPacket::Error {
+                code: CTAPHID_ERR_CHANNEL_BUSY,
+                ..
+            }
+        ));
+        // 单包消息不占用拼接的位置
+        let packet = init_packet(cid, CTAPHID_PING, 1, &[9]);
+        assert!(matches!(
+            assembler.push(&packet, now),
+            CtaphidPacket::Message(_)
+        ));
+        // 已经在拼接的通道可以重新开始
+        let packet = init_packet(1, CTAPHID_CBOR, 100, &[0; CTAPHID_INIT_DATA_SIZE]);
+        assert!(matches!(
+            assembler.push(&packet, now),
+            CtaphidPacket::Pending
+        ));
+    }
+}
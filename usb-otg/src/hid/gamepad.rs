@@ -0,0 +1,255 @@
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+use nix::fcntl;
+use tokio::sync::Mutex;
+use util::error;
+
+use crate::hid;
+use crate::hid::hidg::Hidg;
+
+pub const GAMEPAD_REPORT_LENGTH: usize = 9;
+
+lazy_static! {
+    pub static ref GAMEPAD_FHO: hid::FunctionHidOpts = hid::FunctionHidOpts {
+        major: 0,
+        minor: 0,
+        no_out_endpoint: 1,
+        subclass: 0, /* No Subclass */
+        protocol: 0,  /* None */
+        report_length: GAMEPAD_REPORT_LENGTH as u16,
+        report_desc: vec![
+            0x05, 0x01,     /* USAGE_PAGE (Generic Desktop)           */
+            0x09, 0x05,     /* USAGE (Game Pad)                       */
+            0xa1, 0x01,     /* COLLECTION (Application)               */
+
+            // 16 Buttons
+            0x05, 0x09,     /*   USAGE_PAGE (Button)                */
+            0x19, 0x01,     /*   USAGE_MINIMUM (Button 1) */
+            0x29, 0x10,     /*   USAGE_MAXIMUM (Button 16)   */
+            0x15, 0x00,     /*   LOGICAL_MINIMUM (0)                  */
+            0x25, 0x01,     /*   LOGICAL_MAXIMUM (1)                  */
+            0x75, 0x01,     /*   REPORT_SIZE (1)                      */
+            0x95, 0x10,     /*   REPORT_COUNT (16)                     */
+            0x81, 0x02,     /*   INPUT (Data,Var,Abs)                 */
+
+            // Hat Switch
+            0x05, 0x01,     /*   USAGE_PAGE (Generic Desktop)           */
+            0x09, 0x39,     /*   USAGE (Hat switch)                       */
+            0x15, 0x00,     /*   LOGICAL_MINIMUM (0)                  */
+            0x25, 0x07,     /*   LOGICAL_MAXIMUM (7)                  */
+            0x35, 0x00,     /*   PHYSICAL_MINIMUM (0)                  */
+            0x46, 0x3b, 0x01,     /*   PHYSICAL_MAXIMUM (315)                  */
+            0x65, 0x14,     /*   UNIT (Eng Rot:Angular Pos)                  */
+            0x75, 0x04,     /*   REPORT_SIZE (4)                      */
+            0x95, 0x01,     /*   REPORT_COUNT (1)                     */
+            0x81, 0x42,     /*   INPUT (Data,Var,Abs,Null)                 */
+
+            // Padding
+            0x65, 0x00,     /*   UNIT (None)                  */
+            0x45, 0x00,     /*   PHYSICAL_MAXIMUM (0)                  */
+            0x75, 0x04,     /*   REPORT_SIZE (4)                      */
+            0x95, 0x01,     /*   REPORT_COUNT (1)                     */
+            0x81, 0x03,     /*   INPUT (Cnst,Var,Abs)                 */
+
+            // Left Stick X, Y, Right Stick Rx, Ry
+            0x09, 0x30,     /*   USAGE (X)                       */
+            0x09, 0x31,     /*   USAGE (Y)                       */
+            0x09, 0x33,     /*   USAGE (Rx)                       */
+            0x09, 0x34,     /*   USAGE (Ry)                       */
+            0x15, 0x81,     /*   LOGICAL_MINIMUM (-127) */
+            0x25, 0x7f,     /*   LOGICAL_MAXIMUM (127) */
+            0x75, 0x08,     /*   REPORT_SIZE (8) */
+            0x95, 0x04,     /*   REPORT_COUNT (4) */
+            0x81, 0x02,     /*   INPUT (Data,Var,Abs) */
+
+            // Left Trigger Z, Right Trigger Rz
+            0x09, 0x32,     /*   USAGE (Z)                       */
+            0x09, 0x35,     /*   USAGE (Rz)                       */
+            0x15, 0x00,     /*   LOGICAL_MINIMUM (0) */
+            0x26, 0xff, 0x00,     /*   LOGICAL_MAXIMUM (255) */
+            0x75, 0x08,     /*   REPORT_SIZE (8) */
+            0x95, 0x02,     /*   REPORT_COUNT (2) */
+            0x81, 0x02,     /*   INPUT (Data,Var,Abs) */
+
+            0xc0            /* END_COLLECTION                         */
+        ],
+    };
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Gamepad {
+    pub buttons: u16,
+    // 0 为上，顺时针每 45 度加 1，HAT_NULL 表示没有按下
+    pub hat: u8,
+    pub left_x: i8,
+    pub left_y: i8,
+    pub right_x: i8,
+    pub right_y: i8,
+    pub left_trigger: u8,
+    pub right_trigger: u8,
+}
+
+impl Default for Gamepad {
+    fn default() -> Self {
+        Self {
+            buttons: 0,
+            hat: Self::HAT_NULL,
+            left_x: 0,
+            left_y: 0,
+            right_x: 0,
+            right_y: 0,
+            left_trigger: 0,
+            right_trigger: 0,
+        }
+    }
+}
+
+impl Gamepad {
+    pub const HAT_NULL: u8 = 8;
+    pub const AXIS_MIN: i8 = -127;
+
+    // 没有按键按下，摇杆和扳机都在原位
+    pub fn is_neutral(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn from_payload(payload: &[u8; GAMEPAD_REPORT_LENGTH]) -> Self {
+        Self {
+            buttons: u16::from_le_bytes([payload[0], payload[1]]),
+            hat: (payload[2] & 0xf).min(Self::HAT_NULL),
+            left_x: (payload[3] as i8).max(Self::AXIS_MIN),
+            left_y: (payload[4] as i8).max(Self::AXIS_MIN),
+            right_x: (payload[5] as i8).max(Self::AXIS_MIN),
+            right_y: (payload[6] as i8).max(Self::AXIS_MIN),
+            left_trigger: payload[7],
+            right_trigger: payload[8],
+        }
+    }
+
+    pub fn get_payload(&self) -> [u8; GAMEPAD_REPORT_LENGTH] {
+        let mut ret = [0; GAMEPAD_REPORT_LENGTH];
+        ret[0..2].copy_from_slice(&self.buttons.to_le_bytes());
+        ret[2] = self.hat;
+        ret[3] = self.left_x as u8;
+        ret[4] = self.left_y as u8;
+        ret[5] = self.right_x as u8;
+        ret[6] = self.right_y as u8;
+        ret[7] = self.left_trigger;
+        ret[8] = self.right_trigger;
+        ret
+    }
+}
+
+pub struct GamepadDevice {
+    pub gamepad: Mutex<Gamepad>,
+    gamepad_dev_write: Hidg,
+}
+
+impl GamepadDevice {
+    // AsyncFd::new must call in tokio async runtime
+    pub async fn new(gamepad_minor: i32) -> error::Result<Self> {
+        let gamepad_dev_write = Hidg::open(gamepad_minor, fcntl::OFlag::O_WRONLY)?;
+
+        Ok(Self {
+            gamepad: Default::default(),
+            gamepad_dev_write,
+        })
+    }
+
+    pub async fn reopen(&self) -> error::Result<()> {
+        self.gamepad_dev_write.reopen().await
+    }
+
+    pub fn set_report_interval(&self, interval: Duration) {
+        self.gamepad_dev_write.set_write_interval(interval);
+    }
+
+    pub async fn send(&self, gamepad: Gamepad) -> error::Result<()> {
+        let mut current = self.gamepad.lock().await;
+        *current = gamepad;
+        let payload = current.get_payload();
+        log::debug!("gamepad send {payload:?}");
+        self.gamepad_dev_write.write_all(&payload).await
+    }
+
+    // 松开所有按键，摇杆回到中间
+    pub async fn release(&self) -> error::Result<()> {
+        self.send(Gamepad::default()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn payload_round_trip() {
+        let gamepad = Gamepad {
+            buttons: 0x8001,
+            hat: 3,
+            left_x: -127,
+            left_y: 127,
+            right_x: -1,
+            right_y: 1,
+            left_trigger: 0,
+            right_trigger: 255,
+        };
+        let payload = gamepad.get_payload();
+        assert_eq!(payload, [0x01, 0x80, 3, 0x81, 0x7f, 0xff, 0x01, 0, 0xff]);
+        assert!(Gamepad::from_payload(&payload) == gamepad);
+    }
+
+    #[test]
+    fn neutral_payload() {
+        let gamepad = Gamepad::default();
+        assert!(gamepad.is_neutral());
+        assert_eq!(
+            gamepad.get_payload(),
+            [0, 0, Gamepad::HAT_NULL, 0, 0, 0, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn from_payload_clamps() {
+        // -128 超出逻辑范围，大于 8 的方向当作没有按下
+        let gamepad = Gamepad::from_payload(&[0, 0, 0xf, 0x80, 0x80, 0x80, 0x80, 0, 0]);
+        assert_eq!(gamepad.hat, Gamepad::HAT_NULL);
+        assert_eq!(
+            [
+                gamepad.left_x,
+                gamepad.left_y,
+                gamepad.right_x,
+                gamepad.right_y
+            ],
+            [Gamepad::AXIS_MIN; 4]
+        );
+    }
+
+    #[test]
+    fn descriptor_matches_report_length() {
+        let desc = &GAMEPAD_FHO.report_desc;
+        let (mut i, mut report_size, mut report_count, mut input_bits) = (0, 0, 0, 0);
+        while i < desc.len() {
+            let prefix = desc[i];
+            let len = match prefix & 0x3 {
+                3 => 4,
+                len => len as usize,
+            };
+            let data = desc[i + 1..i + 1 + len]
+                .iter()
+                .rev()
+                .fold(0_u32, |value, byte| value << 8 | *byte as u32);
+            match prefix & 0xfc {
+                0x74 => report_size = data,
+                0x94 => report_count = data,
+                0x80 => input_bits += report_size * report_count,
+                _ => {}
+            }
+            i += 1 + len;
+        }
+        assert_eq!(i, desc.len());
+        assert_eq!(input_bits, GAMEPAD_REPORT_LENGTH as u32 * 8);
+        assert_eq!(GAMEPAD_FHO.report_length as usize, GAMEPAD_REPORT_LENGTH);
+    }
+}
@@ -1,21 +1,28 @@
 use std::sync::Arc;
+use std::time::Duration;
 
 use lazy_static::lazy_static;
 
 use nix::fcntl;
-use nix::sys::stat::Mode;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::sync::watch::Sender;
-use tokio::sync::Mutex;
 
-use crate::async_fd::AsyncFd;
 use crate::hid;
+use crate::hid::hidg::Hidg;
+use crate::hid::report_queue::ReportQueue;
 use util::error;
 
 pub const HID_COMPOSITE_RECV_LENGTH: usize = 0x21;
 pub const HID_COMPOSITE_SEND_LENGTH: usize = 0x23;
 pub const HID_REPORT_ID_MOUSE: u8 = 1;
 pub const HID_REPORT_ID_KEYBOARD: u8 = 2;
+pub const HID_REPORT_ID_MOUSE_RELATIVE: u8 = 3;
+// 只在 Apple 键盘配置中存在
+pub const HID_REPORT_ID_APPLE_FN: u8 = 4;
+// 与目标机器上的代理程序通信
+pub const HID_REPORT_ID_VENDOR: u8 = 5;
+// 粘贴等快速输入时最多缓存的报告数量，超过后发送方需要等待
+const HID_COMPOSITE_QUEUE_CAPACITY: usize = 64;
+
+pub type HidCompositeReportQueue = ReportQueue<HID_COMPOSITE_SEND_LENGTH>;
 
 lazy_static! {
 
@@ -113,62 +120,124 @@ lazy_static! {
                 0x96, 0x00, 0x01,     /*   REPORT_COUNT (8)                     */
                 0x91, 0x02,     /*   OUTPUT (Data,Var,Abs)                */
 
+                0xc0,            /* END_COLLECTION                         */
+
+                // Relative Mouse
+                0x05, 0x01,     /* USAGE_PAGE (Generic Desktop)           */
+                0x09, 0x02,     /* USAGE (Mouse)                       */
+                0xa1, 0x01,     /* COLLECTION (Application)               */
+                0x85, HID_REPORT_ID_MOUSE_RELATIVE,     /* Report ID (HID_REPORT_ID_MOUSE_RELATIVE)                 */
+
+                0x09, 0x01,                      /*   USAGE (Pointer) */
+                0xa1, 0x00,                      /*   COLLECTION (Physical) */
+
+                // 8 Buttons
+                0x05, 0x09,     /*   USAGE_PAGE (Button)                */
+                0x19, 0x01,     /*   USAGE_MINIMUM (Button 1) */
+                0x29, 0x08,     /*   USAGE_MAXIMUM (Button 8)   */
+                0x15, 0x00,     /*   LOGICAL_MINIMUM (0)                  */
+                0x25, 0x01,     /*   LOGICAL_MAXIMUM (1)                  */
+                0x75, 0x01,     /*   REPORT_SIZE (1)                      */
+                0x95, 0x08,     /*   REPORT_COUNT (8)                     */
+                0x81, 0x02,     /*   INPUT (Data,Var,Abs)                 */
+
+                // X, Y
+                0x05, 0x01,     /* USAGE_PAGE (Generic Desktop)           */
+                0x09, 0x30,     /* USAGE (X)                       */
+                0x09, 0x31,     /* USAGE (Y)                       */
+                0x16, 0x01, 0x80,				 /* 	Logical Minimum (-32767) */
+                0x26, 0xFF, 0x7F,				 /* 	Logical Maximum (32767) */
+                0x75, 0x10,						 /* 	Report Size (16), */
+                0x95, 0x02,						 /* 	Report Count (2), */
+                0x81, 0x06,						 /* 	Input (Data, Variable, Relative) */
+
+                /* Wheel */
+                0x09, 0x38,                      /*     USAGE (Wheel) */
+                0x15, 0x81,                      /*     LOGICAL_MINIMUM (-127) */
+                0x25, 0x7f,                      /*     LOGICAL_MAXIMUM (127) */
+                0x75, 0x08,                      /*     REPORT_SIZE (8) */
+                0x95, 0x01,                      /*     REPORT_COUNT (1) */
+                0x81, 0x06,                      /*     INPUT (Data,Var,Rel) */
+
+                // Padding
+                0x75, 0x08,     /*   REPORT_SIZE (8)                      */
+                0x95, 0x1c,     /*   REPORT_COUNT (0x1c)                     */ // 0x1c+0x6 = 0x22
+                0x81, 0x03,     /*   INPUT (Cnst,Var,Abs)                 */
+
+                0xc0,                           /* END_COLLECTION (Physical) */
+                0xc0,            /* END_COLLECTION (Application)           */
+
+                // Vendor
+                0x06, 0x00, 0xff,     /* USAGE_PAGE (Vendor Defined 0xFF00)  */
+                0x09, 0x01,     /* USAGE (Vendor Usage 1)                 */
+                0xa1, 0x01,     /* COLLECTION (Application)               */
+                0x85, HID_REPORT_ID_VENDOR,     /* Report ID (HID_REPORT_ID_VENDOR)                 */
+
+                0x09, 0x02,     /*   USAGE (Vendor Usage 2)               */
+                0x15, 0x00,     /*   LOGICAL_MINIMUM (0)                  */
+                0x26, 0xff, 0x00,     /*   LOGICAL_MAXIMUM (255)                  */
+                0x75, 0x08,     /*   REPORT_SIZE (8)                      */
+                0x95, 0x22,     /*   REPORT_COUNT (0x22)                     */ // 0x22+0x1 = 0x23
+                0x81, 0x02,     /*   INPUT (Data,Var,Abs)                 */
+
+                0x09, 0x03,     /*   USAGE (Vendor Usage 3)               */
+                0x15, 0x00,     /*   LOGICAL_MINIMUM (0)                  */
+                0x26, 0xff, 0x00,     /*   LOGICAL_MAXIMUM (255)                  */
+                0x75, 0x08,     /*   REPORT_SIZE (8)                      */
+                0x95, 0x20,     /*   REPORT_COUNT (0x20)                     */ // 0x20+0x1 = 0x21
+                0x91, 0x02,     /*   OUTPUT (Data,Var,Abs)                */
+
                 0xc0            /* END_COLLECTION                         */
             ],
         };
 }
 
+// 主机切换到 boot 协议后，输出报告只有 1 字节的 LED，没有 report id
+pub enum HidCompositeOutput {
+    Report([u8; HID_COMPOSITE_RECV_LENGTH]),
+    BootLed(u8),
+}
+
 pub struct HidCompositeDevice {
-    hid_composite_dev_read: Mutex<AsyncFd>,
-    hid_composite_dev_write: Mutex<AsyncFd>,
-    pub hid_composite_dev_send_sender: Arc<Sender<[u8; HID_COMPOSITE_SEND_LENGTH]>>,
+    hid_composite_dev_read: Hidg,
+    hid_composite_dev_write: Hidg,
+    pub hid_composite_report_queue: Arc<HidCompositeReportQueue>,
 }
 
 impl HidCompositeDevice {
     // AsyncFd::new must call in tokio async runtime
     pub async fn new(hid_composite_minor: i32) -> error::Result<Self> {
-        let hid_composite_dev_name = format!("/dev/hidg{hid_composite_minor}");
-
-        let hid_composite_dev_read = AsyncFd::try_from(
-            fcntl::open(
-                hid_composite_dev_name.as_str(),
-                fcntl::OFlag::O_RDONLY,
-                Mode::empty(),
-            )
-            .map_err(|err| error::ErrorKind::io(err.into(), &hid_composite_dev_name))?,
-        )
-        .unwrap();
-
-        let hid_composite_dev_write = AsyncFd::try_from(
-            fcntl::open(
-                hid_composite_dev_name.as_str(),
-                fcntl::OFlag::O_WRONLY,
-                Mode::empty(),
-            )
-            .map_err(|err| error::ErrorKind::io(err.into(), &hid_composite_dev_name))?,
-        )
-        .unwrap();
-
-        let (hid_composite_dev_send_sender, _) =
-            tokio::sync::watch::channel([0; HID_COMPOSITE_SEND_LENGTH]);
+        let hid_composite_dev_read = Hidg::open(hid_composite_minor, fcntl::OFlag::O_RDONLY)?;
+        let hid_composite_dev_write = Hidg::open(hid_composite_minor, fcntl::OFlag::O_WRONLY)?;
 
         let ret = Self {
-            hid_composite_dev_read: Mutex::new(hid_composite_dev_read),
-            hid_composite_dev_write: Mutex::new(hid_composite_dev_write),
-            hid_composite_dev_send_sender: Arc::new(hid_composite_dev_send_sender),
+            hid_composite_dev_read,
+            hid_composite_dev_write,
+            hid_composite_report_queue: Arc::new(ReportQueue::new(HID_COMPOSITE_QUEUE_CAPACITY)),
         };
 
         Ok(ret)
     }
 
-    pub async fn recv(&self) -> error::Result<[u8; HID_COMPOSITE_RECV_LENGTH]> {
+    pub async fn reopen(&self) -> error::Result<()> {
+        self.hid_composite_dev_read.reopen().await?;
+        self.hid_composite_dev_write.reopen().await?;
+        Ok(())
+    }
+
+    pub fn set_report_interval(&self, interval: Duration) {
+        self.hid_composite_dev_write.set_write_interval(interval);
+    }
+
+    pub async fn recv(&self) -> error::Result<HidCompositeOutput> {
         let mut hid_composite_recv_data = [0_u8; HID_COMPOSITE_RECV_LENGTH];
-        let mut hid_composite_dev_read: tokio::sync::MutexGuard<'_, AsyncFd> =
-            self.hid_composite_dev_read.lock().await;
-        let read_len = hid_composite_dev_read
+        let read_len = self
+            .hid_composite_dev_read
             .read(&mut hid_composite_recv_data)
-            .await
-            .map_err(|err| error::ErrorKind::io(err, "hid_composite_dev"))?;
+            .await?;
+        if read_len == 1 {
+            return Ok(HidCompositeOutput::BootLed(hid_composite_recv_data[0]));
+        }
         if read_len != HID_COMPOSITE_RECV_LENGTH {
             log::warn!(
                 "hid_composite_dev ignore: {:?}",
@@ -176,16 +245,14 @@ impl HidCompositeDevice {
             );
             Err(error::ErrorKind::Ignore)?;
         }
-        return Ok(hid_composite_recv_data);
+        return Ok(HidCompositeOutput::Report(hid_composite_recv_data));
     }
 
     pub async fn send(&self, hid_composite_send_data: &[u8]) -> error::Result<()> {
         log::debug!("hid_composite_dev send {hid_composite_send_data:?}");
-        let mut hid_composite_dev = self.hid_composite_dev_write.lock().await;
-        hid_composite_dev
+        self.hid_composite_dev_write
             .write_all(&hid_composite_send_data)
-            .await
-            .map_err(|err| error::ErrorKind::io(err, "hid_composite_dev"))?;
+            .await?;
 
         Ok(())
     }
@@ -0,0 +1,131 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use nix::fcntl;
+use nix::sys::stat::Mode;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::{Mutex, MutexGuard, Notify};
+use tokio::time::{self, Instant};
+use util::error;
+
+use crate::async_fd::AsyncFd;
+use crate::UsbDeviceSpeed;
+
+// /dev/hidgN 的一个 fd，可以在 UDC 重新绑定后重新打开
+pub struct Hidg {
+    dev_name: String,
+    flags: fcntl::OFlag,
+    fd: Mutex<AsyncFd>,
+    // 两次写入之间的最小间隔，与端点的轮询间隔一致
+    write_interval_us: AtomicU64,
+    last_write: Mutex<Option<Instant>>,
+    // 读操作会一直持有锁，reopen 时需要先打断它
+    interrupt_notify: Notify,
+}
+
+impl Hidg {
+    // AsyncFd::new must call in tokio async runtime
+    pub fn open(minor: i32, flags: fcntl::OFlag) -> error::Result<Self> {
+        let dev_name = format!("/dev/hidg{minor}");
+        let fd = Self::open_fd(&dev_name, flags)?;
+        Ok(Self {
+            dev_name,
+            flags,
+            fd: Mutex::new(fd),
+            write_interval_us: AtomicU64::new(0),
+            last_write: Mutex::new(None),
+            interrupt_notify: Notify::new(),
+        })
+    }
+
+    fn open_fd(dev_name: &str, flags: fcntl::OFlag) -> error::Result<AsyncFd> {
+        let fd = fcntl::open(dev_name, flags, Mode::empty())
+            .map_err(|err| error::ErrorKind::io(err.into(), dev_name))?;
+        Ok(AsyncFd::try_from(fd).map_err(|err| error::ErrorKind::io(err, dev_name))?)
+    }
+
+    pub fn dev_name(&self) -> &str {
+        &self.dev_name
+    }
+
+    // 被 reopen 打断时返回 ErrorKind::Ignore
+    pub async fn read(&self, buf: &mut [u8]) -> error::Result<usize> {
+        let mut fd = self.fd.lock().await;
+        tokio::select! {
+            res = fd.read(buf) => Ok(res.map_err(|err| error::ErrorKind::io(err, &self.dev_name))?),
+            _ = self.interrupt_notify.notified() => Err(error::ErrorKind::Ignore)?,
+        }
+    }
+
+    pub async fn read_exact(&self, buf: &mut [u8]) -> error::Result<()> {
+        let mut fd = self.fd.lock().await;
+        tokio::select! {
+            res = fd.read_exact(buf) => {
+                res.map_err(|err| error::ErrorKind::io(err, &self.dev_name))?;
+                Ok(())
+            }
+            _ = self.interrupt_notify.notified() => Err(error::ErrorKind::Ignore)?,
+        }
+    }
+
+    pub fn set_write_interval(&self, interval: Duration) {
+        self.write_interval_us
+            .store(interval.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub async fn write_all(&self, buf: &[u8]) -> error::Result<()> {
+        let mut fd = self.fd.lock().await;
+        let mut last_write = self.last_write.lock().await;
+        let write_interval = Duration::from_micros(self.write_interval_us.load(Ordering::Relaxed));
+        if let Some(last_write) = *last_write {
+            time::sleep_until(last_write + write_interval).await;
+        }
+        *last_write = Some(Instant::now());
+        tokio::select! {
+            res = fd.write_all(buf) => {
+                res.map_err(|err| error::ErrorKind::io(err, &self.dev_name))?;
+                Ok(())
+            }
+            _ = self.interrupt_notify.notified() => Err(error::ErrorKind::Ignore)?,
+        }
+    }
+
+    async fn lock_interrupted(&self) -> MutexGuard<'_, AsyncFd> {
+        loop {
+            self.interrupt_notify.notify_waiters();
+            // tokio 的 Mutex 是公平的，等待中的读任务释放锁后会先轮到这里
+            if let Ok(fd) = time::timeout(Duration::from_millis(10), self.fd.lock()).await {
+                return fd;
+            }
+        }
+    }
+
+    pub async fn reopen(&self) -> error::Result<()> {
+        let mut fd = self.lock_interrupted().await;
+        *fd = Self::open_fd(&self.dev_name, self.flags)?;
+        log::info!("{} reopened.", self.dev_name);
+        Ok(())
+    }
+}
+
+// 主机断开或挂起时 f_hid 返回 ESHUTDOWN，这时 fd 本身没有问题，不需要重新打开
+pub fn is_host_disconnected(err: &error::Error) -> bool {
+    match err.0.as_ref() {
+        error::ErrorKind::Io { source, .. } => {
+            source.raw_os_error() == Some(nix::errno::Errno::ESHUTDOWN as i32)
+        }
+        _ => false,
+    }
+}
+
+pub fn is_ignore(err: &error::Error) -> bool {
+    matches!(err.0.as_ref(), error::ErrorKind::Ignore)
+}
+
+// f_hid 的中断端点 bInterval 高速为 4 (2^(4-1) * 125us)，全速为 10 (10ms)
+pub fn report_interval(speed: &UsbDeviceSpeed) -> Duration {
+    match speed {
+        UsbDeviceSpeed::UsbSpeedLow | UsbDeviceSpeed::UsbSpeedFull => Duration::from_millis(10),
+        _ => Duration::from_millis(1),
+    }
+}
@@ -1,17 +1,27 @@
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use lazy_static::lazy_static;
 use nix::fcntl;
-use nix::sys::stat::Mode;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::broadcast;
 use tokio::sync::watch::Sender;
 use tokio::sync::Mutex;
 use util::error;
 
-use crate::async_fd::AsyncFd;
 use crate::hid;
+use crate::hid::hidg::Hidg;
 use crate::hid::{generic_desktop, hid_composite};
 
+// 按 boot 协议的位置排列的锁定键 LED
+pub const LOCK_LED_NUM_LOCK: u8 = 0x01;
+pub const LOCK_LED_CAPS_LOCK: u8 = 0x02;
+pub const LOCK_LED_SCROLL_LOCK: u8 = 0x04;
+const LOCK_LED_MASK: u8 = 0x07;
+const LOCK_LED_CHANNEL_CAPACITY: usize = 1024;
+// report 协议的主机会同时给两个键盘发送 LED，间隔不会超过这个时间
+const LED_PAIR_WINDOW: Duration = Duration::from_millis(200);
+
 pub mod usage_id {
     pub const KEYBOARD_ERROR_ROLL_OVER: u16 = 0x1;
     pub const KEYBOARD_POST_FAIL: u16 = 0x2;
@@ -233,6 +243,54 @@ pub mod usage_id {
     pub const KEYBOARD_RIGHT_GUI: u16 = 0xe7;
 }
 
+// 按美式键盘布局把可打印的 ASCII 字符转换成按键，第二个值表示是否需要 Shift
+pub fn ascii_to_usage(ch: char) -> Option<(u16, bool)> {
+    use usage_id::*;
+    let ret = match ch {
+        'a'..='z' => (KEYBOARD_A + (ch as u16 - 'a' as u16), false),
+        'A'..='Z' => (KEYBOARD_A + (ch as u16 - 'A' as u16), true),
+        '1'..='9' => (KEYBOARD_1 + (ch as u16 - '1' as u16), false),
+        '0' => (KEYBOARD_0, false),
+        '\n' => (KEYBOARD_ENTER, false),
+        '\t' => (KEYBOARD_TAB, false),
+        ' ' => (KEYBOARD_SPACEBAR, false),
+        '-' => (KEYBOARD_MINUS, false),
+        '=' => (KEYBOARD_EQUAL, false),
+        '[' => (KEYBOARD_LEFT_BRACKET, false),
+        ']' => (KEYBOARD_RIGHT_BRACKED, false),
+        '\\' => (KEYBOARD_REVERSE_SOLIDUS, false),
+        ';' => (KEYBOARD_SEMICOLON, false),
+        '\'' => (KEYBOARD_SINGLE_QUOTE, false),
+        '`' => (KEYBOARD_GRAVE_ACCENT, false),
+        ',' => (KEYBOARD_COMMA, false),
+        '.' => (KEYBOARD_DOT, false),
+        '/' => (KEYBOARD_SOLIDUS, false),
+        '!' => (KEYBOARD_1, true),
+        '@' => (KEYBOARD_2, true),
+        '#' => (KEYBOARD_3, true),
+        '$' => (KEYBOARD_4, true),
+        '%' => (KEYBOARD_5, true),
+        '^' => (KEYBOARD_6, true),
+        '&' => (KEYBOARD_7, true),
+        '*' => (KEYBOARD_8, true),
+        '(' => (KEYBOARD_9, true),
+        ')' => (KEYBOARD_0, true),
+        '_' => (KEYBOARD_MINUS, true),
+        '+' => (KEYBOARD_EQUAL, true),
+        '{' => (KEYBOARD_LEFT_BRACKET, true),
+        '}' => (KEYBOARD_RIGHT_BRACKED, true),
+        '|' => (KEYBOARD_REVERSE_SOLIDUS, true),
+        ':' => (KEYBOARD_SEMICOLON, true),
+        '"' => (KEYBOARD_SINGLE_QUOTE, true),
+        '~' => (KEYBOARD_GRAVE_ACCENT, true),
+        '<' => (KEYBOARD_COMMA, true),
+        '>' => (KEYBOARD_DOT, true),
+        '?' => (KEYBOARD_SOLIDUS, true),
+        _ => return None,
+    };
+    Some(ret)
+}
+
 lazy_static! {
     // 对于 BIOS 而言，会忽略 report_desc
     // 对于标准操作系统而言，会读取 report_desc
@@ -275,11 +333,122 @@ lazy_static! {
     };
 }
 
+// 主机通过 SET_PROTOCOL 选择的协议，f_hid 不会把它暴露给用户态，只能根据 LED 输出报告推断
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum KeyboardProtocol {
+    #[default]
+    Unknown,
+    Boot,
+    Report,
+}
+
+impl KeyboardProtocol {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Unknown => "unknown",
+            Self::Boot => "boot",
+            Self::Report => "report",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "unknown" => Some(Self::Unknown),
+            "boot" => Some(Self::Boot),
+            "report" => Some(Self::Report),
+            _ => None,
+        }
+    }
+}
+
+// 操作系统会同时给两个键盘发送 LED，只有 boot 键盘收到时才认为是 boot 协议
+#[derive(Default)]
+struct ProtocolDetector {
+    protocol: KeyboardProtocol,
+    report_led_at: Option<Instant>,
+    // boot 键盘收到 LED 的时间，等待复合设备也收到的期间仍然使用原来的协议
+    boot_led_at: Option<Instant>,
+}
+
+impl ProtocolDetector {
+    fn protocol(&mut self, now: Instant) -> KeyboardProtocol {
+        if self
+            .boot_led_at
+            .is_some_and(|boot_led_at| now >= boot_led_at + LED_PAIR_WINDOW)
+        {
+            self.boot_led_at = None;
+            self.protocol = KeyboardProtocol::Boot;
+        }
+        self.protocol
+    }
+
+    fn recv_legacy_led(&mut self, now: Instant) {
+        let paired = self
+            .report_led_at
+            .is_some_and(|report_led_at| report_led_at + LED_PAIR_WINDOW >= now);
+        if !paired && self.boot_led_at.is_none() {
+            self.boot_led_at = Some(now);
+        }
+    }
+
+    fn recv_report_led(&mut self, now: Instant) {
+        self.report_led_at = Some(now);
+        self.boot_led_at = None;
+        self.protocol = KeyboardProtocol::Report;
+    }
+
+    // 复合设备收到 boot 协议的 LED，说明主机发送了 SET_PROTOCOL
+    fn recv_boot_led(&mut self) {
+        self.boot_led_at = None;
+        self.protocol = KeyboardProtocol::Boot;
+    }
+}
+
+// 两个接口会收到同样的 LED，交替处理会产生多余的变化，所以只使用一个接口
+// 操作系统会给所有键盘发送 LED，BIOS 只使用 boot 键盘，收到过 boot 键盘的 LED 后就只看它
 #[derive(Default)]
+struct LockLedState {
+    legacy: Option<u8>,
+    composite: Option<u8>,
+    // 最后通知的状态
+    current: Option<u8>,
+}
+
+impl LockLedState {
+    // 返回需要通知的新状态
+    fn update(&mut self, legacy: bool, led: u8) -> Option<u8> {
+        if legacy {
+            self.legacy = Some(led);
+        } else {
+            self.composite = Some(led);
+        }
+        let led = self.legacy.or(self.composite);
+        if led == self.current {
+            return None;
+        }
+        self.current = led;
+        led
+    }
+
+    // 主机重新枚举后可能只使用其中一个接口
+    fn reset(&mut self) {
+        self.legacy = None;
+        self.composite = None;
+    }
+}
+
+// boot 键盘报告中普通按键的个数
+const LEGACY_KEY_SLOTS: usize = 6;
+
+#[derive(Clone, Default)]
 pub struct Keyboard {
     pub led: [u8; 0x20],
     pub keys: [u8; 0x20],
     pub sys_control_keys: [u8; 0x2],
+    // Apple 键盘的 Fn/Globe 键
+    pub apple_fn: bool,
+    // boot 键盘中普通按键的按下顺序，新按下的键不会挤掉已经按住的键
+    legacy_key_order: Vec<u16>,
 }
 
 impl Keyboard {
@@ -287,6 +456,38 @@ impl Keyboard {
         self.led = [0; 0x20];
         self.keys = [0; 0x20];
         self.sys_control_keys = [0; 2];
+        self.apple_fn = false;
+        self.legacy_key_order.clear();
+    }
+
+    // 松开所有按键，led 是主机的状态，保持不变
+    pub fn release_all(&mut self) -> bool {
+        let changed = self.keys != [0; 0x20] || self.sys_control_keys != [0; 2] || self.apple_fn;
+        let led = self.led;
+        self.clear();
+        self.led = led;
+        changed
+    }
+
+    // 按下 other 中按下的所有按键，返回是否有变化
+    pub fn press_from(&mut self, other: &Keyboard) -> bool {
+        let prev = (self.keys, self.sys_control_keys, self.apple_fn);
+        // 保持 boot 键盘中的按下顺序
+        for key_id in &other.legacy_key_order {
+            self.set_key(*key_id, true);
+        }
+        for (key, other_key) in self.keys.iter_mut().zip(other.keys) {
+            *key |= other_key;
+        }
+        for (key, other_key) in self.sys_control_keys.iter_mut().zip(other.sys_control_keys) {
+            *key |= other_key;
+        }
+        self.apple_fn |= other.apple_fn;
+        prev != (self.keys, self.sys_control_keys, self.apple_fn)
+    }
+
+    fn is_legacy_key(key_id: u16) -> bool {
+        (usage_id::KEYBOARD_A..=usage_id::KEYBOARD_APPLICATION).contains(&key_id)
     }
 
     pub fn get_led(&self, led_id: u16) -> bool {
@@ -313,6 +514,12 @@ impl Keyboard {
             } else {
                 self.keys[idx] &= !(1 << (key_id % 8) as u8);
             }
+            if Self::is_legacy_key(key_id) {
+                self.legacy_key_order.retain(|id| *id != key_id);
+                if status {
+                    self.legacy_key_order.push(key_id);
+                }
+            }
             return prev != self.keys[idx];
         }
         false
@@ -360,14 +567,25 @@ impl Keyboard {
         }
         ret[0] = ctrl_val;
 
-        let mut current_idx = 2;
-        for i in 0..=usage_id::KEYBOARD_APPLICATION {
-            if current_idx >= ret.len() {
-                break;
+        // keys 是公开的，可能被直接修改，按下顺序中没有记录的键按 usage 顺序补在后面
+        let mut legacy_keys: Vec<u16> = self
+            .legacy_key_order
+            .iter()
+            .copied()
+            .filter(|key_id| self.get_key(*key_id))
+            .collect();
+        for key_id in usage_id::KEYBOARD_A..=usage_id::KEYBOARD_APPLICATION {
+            if self.get_key(key_id) && !legacy_keys.contains(&key_id) {
+                legacy_keys.push(key_id);
             }
-            if self.get_key(i) {
-                ret[current_idx] = i as u8;
-                current_idx += 1;
+        }
+
+        if legacy_keys.len() > LEGACY_KEY_SLOTS {
+            // 按下的键超过 6 个时，boot 协议要求所有按键位置都报告 ErrorRollOver，修饰键照常报告
+            ret[2..].fill(usage_id::KEYBOARD_ERROR_ROLL_OVER as u8);
+        } else {
+            for (slot, key_id) in ret[2..].iter_mut().zip(legacy_keys) {
+                *slot = key_id as u8;
             }
         }
         ret
@@ -376,52 +594,124 @@ impl Keyboard {
 
 pub struct KeyboardDevice {
     pub keyboard: Mutex<Keyboard>,
-    keyboard_legacy_dev_read: Mutex<AsyncFd>,
-    keyboard_legacy_dev_write: Mutex<AsyncFd>,
+    keyboard_legacy_dev_read: Hidg,
+    keyboard_legacy_dev_write: Hidg,
     pub keyboard_update_sender: Sender<[u8; 0x20]>,
-    hid_composite_dev_send_sender: Arc<Sender<[u8; hid_composite::HID_COMPOSITE_SEND_LENGTH]>>,
+    hid_composite_report_queue: Arc<hid_composite::HidCompositeReportQueue>,
+    protocol_detector: std::sync::Mutex<ProtocolDetector>,
+    // 推断错误时可以手动指定
+    forced_protocol: std::sync::Mutex<Option<KeyboardProtocol>>,
+    // 每个接口按顺序发送报告，发送期间不占用 keyboard 的锁
+    composite_send_lock: Mutex<()>,
+    legacy_send_lock: Mutex<()>,
+    // 上一次发给接口的报告是否有按键按下，切换协议时需要先在旧接口上松开
+    composite_pressed: AtomicBool,
+    legacy_pressed: AtomicBool,
+    // 上一次发送的 Apple Fn 状态，没有变化时不重复发送
+    apple_fn_sent: AtomicBool,
+    lock_led: std::sync::Mutex<LockLedState>,
+    lock_led_sender: broadcast::Sender<u8>,
 }
 
 impl KeyboardDevice {
     // AsyncFd::new must call in tokio async runtime
     pub async fn new(
         keyboard_legacy_minor: i32,
-        hid_composite_dev_send_sender: Arc<Sender<[u8; hid_composite::HID_COMPOSITE_SEND_LENGTH]>>,
+        hid_composite_report_queue: Arc<hid_composite::HidCompositeReportQueue>,
     ) -> error::Result<Self> {
-        let keyboard_legacy_dev_name = format!("/dev/hidg{keyboard_legacy_minor}");
-
-        let keyboard_legacy_dev_read = AsyncFd::try_from(
-            fcntl::open(
-                keyboard_legacy_dev_name.as_str(),
-                fcntl::OFlag::O_RDONLY,
-                Mode::empty(),
-            )
-            .map_err(|err| error::ErrorKind::io(err.into(), &keyboard_legacy_dev_name))?,
-        )
-        .unwrap();
-
-        let keyboard_legacy_dev_write = AsyncFd::try_from(
-            fcntl::open(
-                keyboard_legacy_dev_name.as_str(),
-                fcntl::OFlag::O_WRONLY,
-                Mode::empty(),
-            )
-            .map_err(|err| error::ErrorKind::io(err.into(), &keyboard_legacy_dev_name))?,
-        )
-        .unwrap();
+        let keyboard_legacy_dev_read = Hidg::open(keyboard_legacy_minor, fcntl::OFlag::O_RDONLY)?;
+        let keyboard_legacy_dev_write = Hidg::open(keyboard_legacy_minor, fcntl::OFlag::O_WRONLY)?;
 
         let (sender, _) = tokio::sync::watch::channel([0; 0x20]);
         let ret = Self {
             keyboard: Default::default(),
-            keyboard_legacy_dev_read: Mutex::new(keyboard_legacy_dev_read),
-            keyboard_legacy_dev_write: Mutex::new(keyboard_legacy_dev_write),
+            keyboard_legacy_dev_read,
+            keyboard_legacy_dev_write,
             keyboard_update_sender: sender,
-            hid_composite_dev_send_sender,
+            hid_composite_report_queue,
+            protocol_detector: Default::default(),
+            forced_protocol: std::sync::Mutex::new(None),
+            composite_send_lock: Mutex::new(()),
+            legacy_send_lock: Mutex::new(()),
+            composite_pressed: AtomicBool::new(false),
+            legacy_pressed: AtomicBool::new(false),
+            apple_fn_sent: AtomicBool::new(false),
+            lock_led: Default::default(),
+            lock_led_sender: broadcast::channel(LOCK_LED_CHANNEL_CAPACITY).0,
         };
 
         Ok(ret)
     }
 
+    pub async fn reopen(&self) -> error::Result<()> {
+        self.keyboard_legacy_dev_read.reopen().await?;
+        self.keyboard_legacy_dev_write.reopen().await?;
+        Ok(())
+    }
+
+    pub fn set_report_interval(&self, interval: Duration) {
+        self.keyboard_legacy_dev_write.set_write_interval(interval);
+    }
+
+    pub fn detected_protocol(&self) -> KeyboardProtocol {
+        self.detect_protocol(|_, _| {})
+    }
+
+    pub fn forced_protocol(&self) -> Option<KeyboardProtocol> {
+        *self.forced_protocol.lock().unwrap()
+    }
+
+    pub fn set_forced_protocol(&self, protocol: Option<KeyboardProtocol>) {
+        *self.forced_protocol.lock().unwrap() = protocol;
+    }
+
+    // 当前使用的协议，Unknown 时两个接口都发送
+    pub fn protocol(&self) -> KeyboardProtocol {
+        self.forced_protocol()
+            .unwrap_or_else(|| self.detected_protocol())
+    }
+
+    // 主机重新枚举后需要重新推断
+    pub fn reset_protocol(&self) {
+        self.detect_protocol(|detector, _| *detector = ProtocolDetector::default());
+        self.lock_led.lock().unwrap().reset();
+        // 主机重新枚举后所有状态都要重新发送
+        self.apple_fn_sent.store(false, Ordering::Relaxed);
+    }
+
+    // 主机当前的 Num/Caps/Scroll Lock 状态，还没有收到 LED 时认为都是关闭的
+    pub fn lock_led(&self) -> u8 {
+        self.lock_led.lock().unwrap().current.unwrap_or(0)
+    }
+
+    // 每一次 Num/Caps/Scroll Lock 状态变化，不会像 keyboard_update_sender 一样合并
+    pub fn subscribe_lock_led(&self) -> broadcast::Receiver<u8> {
+        self.lock_led_sender.subscribe()
+    }
+
+    fn update_lock_led(&self, legacy: bool, led: u8) {
+        let mut lock_led = self.lock_led.lock().unwrap();
+        if let Some(led) = lock_led.update(legacy, led & LOCK_LED_MASK) {
+            let _ = self.lock_led_sender.send(led);
+        }
+    }
+
+    fn detect_protocol(&self, f: impl FnOnce(&mut ProtocolDetector, Instant)) -> KeyboardProtocol {
+        let now = Instant::now();
+        let mut detector = self.protocol_detector.lock().unwrap();
+        let prev = detector.protocol;
+        f(&mut detector, now);
+        let protocol = detector.protocol(now);
+        if prev != protocol {
+            log::info!(
+                "keyboard protocol: {} -> {}",
+                prev.as_str(),
+                protocol.as_str()
+            );
+        }
+        protocol
+    }
+
     pub async fn set_key(&self, key_id: u16, status: bool) -> bool {
         return self.keyboard.lock().await.set_key(key_id, status);
     }
@@ -434,16 +724,34 @@ impl KeyboardDevice {
             .set_sys_control_key(sys_control_key_id, status);
     }
 
+    pub async fn set_apple_fn(&self, status: bool) -> bool {
+        let mut keyboard = self.keyboard.lock().await;
+        let prev = keyboard.apple_fn;
+        keyboard.apple_fn = status;
+        prev != status
+    }
+
     pub async fn recv_legacy(&self) -> error::Result<()> {
         let mut led_buf = [0_u8];
-        let mut keyboard_legacy_dev_read = self.keyboard_legacy_dev_read.lock().await;
-        keyboard_legacy_dev_read
-            .read_exact(&mut led_buf)
-            .await
-            .map_err(|err| error::ErrorKind::io(err, "keyboard_legacy_dev"))?;
+        self.keyboard_legacy_dev_read.read_exact(&mut led_buf).await?;
         log::debug!("keyboard_legacy_dev: {led_buf:?}");
+        // 主机不重新枚举就切换到 BIOS 时也能切换回 boot 协议
+        self.detect_protocol(ProtocolDetector::recv_legacy_led);
+        self.update_boot_led(true, led_buf[0]).await;
+        Ok(())
+    }
+
+    pub async fn recv_boot_led(&self, led: u8) -> error::Result<()> {
+        log::debug!("hid_composite_dev boot led: {led}");
+        self.detect_protocol(|detector, _| detector.recv_boot_led());
+        self.update_boot_led(false, led).await;
+        Ok(())
+    }
+
+    async fn update_boot_led(&self, legacy: bool, led: u8) {
+        self.update_lock_led(legacy, led);
         let mut keyboard = self.keyboard.lock().await;
-        keyboard.led[0] = (keyboard.led[0] & 0xe0) | (led_buf[0] & 0x1f);
+        keyboard.led[0] = (keyboard.led[0] & 0xe0) | (led & 0x1f);
 
         self.keyboard_update_sender
             .send_if_modified(|keyboard_state| {
@@ -454,12 +762,14 @@ impl KeyboardDevice {
                     false
                 }
             });
-        Ok(())
     }
 
     pub async fn recv(&self, hid_composite_recv_data: &[u8]) -> error::Result<()> {
         let led_buf = &hid_composite_recv_data[1..0x21];
         log::debug!("hid_composite_dev led_buf: {:?}", led_buf);
+        self.detect_protocol(ProtocolDetector::recv_report_led);
+        // report 协议的 LED 从 usage 0 开始，Num Lock 在第 1 位
+        self.update_lock_led(false, led_buf[0] >> 1);
         let mut keyboard = self.keyboard.lock().await;
         keyboard.led.copy_from_slice(&led_buf);
         self.keyboard_update_sender
@@ -477,21 +787,281 @@ impl KeyboardDevice {
     }
 
     pub async fn send(&self) -> error::Result<()> {
+        // 先取得发送锁再读取状态，保证队列中的报告顺序与状态变化顺序一致
+        let _send_guard = self.composite_send_lock.lock().await;
+        let payload = self.keyboard.lock().await.get_payload();
+        self.push_composite(&payload).await
+    }
+
+    // 只有 Apple 键盘配置的复合设备中有这个报告
+    pub async fn send_apple_fn(&self) -> error::Result<()> {
+        let _send_guard = self.composite_send_lock.lock().await;
+        let apple_fn = self.keyboard.lock().await.apple_fn;
+        if self.apple_fn_sent.load(Ordering::Relaxed) == apple_fn {
+            return Ok(());
+        }
+        let mut payload = [0_u8; hid_composite::HID_COMPOSITE_SEND_LENGTH];
+        payload[0] = hid_composite::HID_REPORT_ID_APPLE_FN;
+        payload[1] = apple_fn as u8;
+        log::debug!("hid_composite_dev send apple fn {payload:?}");
+        self.hid_composite_report_queue.push(payload).await?;
+        self.apple_fn_sent.store(apple_fn, Ordering::Relaxed);
+        Ok(())
+    }
+
+    // 复合设备不再使用时松开其中的按键
+    pub async fn release_composite(&self) -> error::Result<()> {
+        let _send_guard = self.composite_send_lock.lock().await;
+        if !self.composite_pressed.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        self.push_composite(&[0; 0x22]).await
+    }
+
+    async fn push_composite(&self, keyboard_payload: &[u8; 0x22]) -> error::Result<()> {
         let mut payload = [0_u8; hid_composite::HID_COMPOSITE_SEND_LENGTH];
         payload[0] = hid_composite::HID_REPORT_ID_KEYBOARD;
-        payload[1..1+0x22].copy_from_slice(&self.keyboard.lock().await.get_payload());
+        payload[1..1 + 0x22].copy_from_slice(keyboard_payload);
         log::debug!("hid_composite_dev send keyboard {payload:?}");
-        self.hid_composite_dev_send_sender.send(payload).unwrap();
+        self.hid_composite_report_queue.push(payload).await?;
+        self.composite_pressed
+            .store(keyboard_payload.iter().any(|b| *b != 0), Ordering::Relaxed);
         Ok(())
     }
+
     pub async fn send_legacy(&self) -> error::Result<()> {
-        let mut keyboard_legacy_dev = self.keyboard_legacy_dev_write.lock().await;
+        let _send_guard = self.legacy_send_lock.lock().await;
         let payload = self.keyboard.lock().await.get_legacy_payload();
+        self.write_legacy(&payload).await
+    }
+
+    pub async fn release_legacy(&self) -> error::Result<()> {
+        let _send_guard = self.legacy_send_lock.lock().await;
+        if !self.legacy_pressed.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        self.write_legacy(&[0; 0x8]).await
+    }
+
+    async fn write_legacy(&self, payload: &[u8; 0x8]) -> error::Result<()> {
         log::debug!("send_legacy {payload:?}");
-        keyboard_legacy_dev
-            .write_all(&payload)
-            .await
-            .map_err(|err| error::ErrorKind::io(err, "keyboard_legacy_dev"))?;
+        self.keyboard_legacy_dev_write.write_all(payload).await?;
+        self.legacy_pressed
+            .store(payload.iter().any(|b| *b != 0), Ordering::Relaxed);
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn payload_maps_keys_to_bitmap() {
+        let mut keyboard = Keyboard::default();
+        assert!(keyboard.set_key(usage_id::KEYBOARD_A, true));
+        assert!(!keyboard.set_key(usage_id::KEYBOARD_A, true));
+        assert!(keyboard.set_key(usage_id::KEYBOARD_LEFT_SHIFT, true));
+        assert!(keyboard.set_sys_control_key(generic_desktop::usage_id::SYSTEM_POWER_DOWN, true));
+
+        let payload = keyboard.get_payload();
+        assert_eq!(payload[0], 1 << 4);
+        assert_eq!(payload[0x1c], 1 << 1);
+        assert_eq!(payload[0x20], 1);
+        assert_eq!(payload.iter().filter(|b| **b != 0).count(), 3);
+
+        assert!(keyboard.set_key(usage_id::KEYBOARD_A, false));
+        assert!(!keyboard.get_key(usage_id::KEYBOARD_A));
+        assert_eq!(keyboard.get_payload()[0], 0);
+    }
+
+    #[test]
+    fn legacy_payload_reports_modifiers() {
+        let mut keyboard = Keyboard::default();
+        keyboard.set_key(usage_id::KEYBOARD_LEFT_CONTROL, true);
+        keyboard.set_key(usage_id::KEYBOARD_RIGHT_GUI, true);
+        keyboard.set_key(usage_id::KEYBOARD_C, true);
+        assert_eq!(
+            keyboard.get_legacy_payload(),
+            [0x81, 0, usage_id::KEYBOARD_C as u8, 0, 0, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn legacy_payload_keeps_press_order() {
+        let mut keyboard = Keyboard::default();
+        keyboard.set_key(usage_id::KEYBOARD_Z, true);
+        keyboard.set_key(usage_id::KEYBOARD_A, true);
+        keyboard.set_key(usage_id::KEYBOARD_M, true);
+        assert_eq!(
+            keyboard.get_legacy_payload()[2..5],
+            [
+                usage_id::KEYBOARD_Z as u8,
+                usage_id::KEYBOARD_A as u8,
+                usage_id::KEYBOARD_M as u8
+            ]
+        );
+
+        keyboard.set_key(usage_id::KEYBOARD_Z, false);
+        keyboard.set_key(usage_id::KEYBOARD_B, true);
+        assert_eq!(
+            keyboard.get_legacy_payload()[2..],
+            [
+                usage_id::KEYBOARD_A as u8,
+                usage_id::KEYBOARD_M as u8,
+                usage_id::KEYBOARD_B as u8,
+                0,
+                0,
+                0
+            ]
+        );
+    }
+
+    #[test]
+    fn legacy_payload_reports_roll_over() {
+        let mut keyboard = Keyboard::default();
+        keyboard.set_key(usage_id::KEYBOARD_LEFT_SHIFT, true);
+        for key_id in usage_id::KEYBOARD_A..usage_id::KEYBOARD_A + 6 {
+            keyboard.set_key(key_id, true);
+        }
+        assert_eq!(keyboard.get_legacy_payload()[7], usage_id::KEYBOARD_F as u8);
+
+        keyboard.set_key(usage_id::KEYBOARD_G, true);
+        assert_eq!(
+            keyboard.get_legacy_payload(),
+            [0x2, 0, 0x1, 0x1, 0x1, 0x1, 0x1, 0x1]
+        );
+
+        // 松开到 6 个键以内后恢复正常报告
+        keyboard.set_key(usage_id::KEYBOARD_A, false);
+        assert_eq!(
+            keyboard.get_legacy_payload()[2..],
+            [
+                usage_id::KEYBOARD_B as u8,
+                usage_id::KEYBOARD_C as u8,
+                usage_id::KEYBOARD_D as u8,
+                usage_id::KEYBOARD_E as u8,
+                usage_id::KEYBOARD_F as u8,
+                usage_id::KEYBOARD_G as u8
+            ]
+        );
+    }
+
+    #[test]
+    fn legacy_payload_includes_keys_set_directly() {
+        let mut keyboard = Keyboard::default();
+        keyboard.set_key(usage_id::KEYBOARD_Z, true);
+        keyboard.keys[0] |= 1 << usage_id::KEYBOARD_A;
+        assert_eq!(
+            keyboard.get_legacy_payload()[2..4],
+            [usage_id::KEYBOARD_Z as u8, usage_id::KEYBOARD_A as u8]
+        );
+    }
+
+    #[test]
+    fn protocol_detector_pairs_leds() {
+        let now = Instant::now();
+        let mut detector = ProtocolDetector::default();
+        detector.recv_legacy_led(now);
+        assert_eq!(detector.protocol(now), KeyboardProtocol::Unknown);
+        detector.recv_report_led(now + Duration::from_millis(10));
+        assert_eq!(
+            detector.protocol(now + LED_PAIR_WINDOW),
+            KeyboardProtocol::Report
+        );
+
+        // 复合设备先收到
+        detector.recv_legacy_led(now + Duration::from_millis(20));
+        assert_eq!(
+            detector.protocol(now + LED_PAIR_WINDOW * 2),
+            KeyboardProtocol::Report
+        );
+    }
+
+    #[test]
+    fn protocol_detector_switches_back_to_boot() {
+        let now = Instant::now();
+        let mut detector = ProtocolDetector::default();
+        detector.recv_report_led(now);
+        let later = now + LED_PAIR_WINDOW * 10;
+        detector.recv_legacy_led(later);
+        assert_eq!(detector.protocol(later), KeyboardProtocol::Report);
+        assert_eq!(
+            detector.protocol(later + LED_PAIR_WINDOW),
+            KeyboardProtocol::Boot
+        );
+
+        detector.recv_report_led(later + LED_PAIR_WINDOW * 2);
+        detector.recv_boot_led();
+        assert_eq!(
+            detector.protocol(later + LED_PAIR_WINDOW * 2),
+            KeyboardProtocol::Boot
+        );
+    }
+
+    #[test]
+    fn lock_led_uses_one_interface() {
+        let mut state = LockLedState::default();
+        assert_eq!(
+            state.update(false, LOCK_LED_NUM_LOCK),
+            Some(LOCK_LED_NUM_LOCK)
+        );
+        // boot 键盘收到后只看它
+        assert_eq!(state.update(true, LOCK_LED_NUM_LOCK), None);
+        let both = LOCK_LED_NUM_LOCK | LOCK_LED_CAPS_LOCK;
+        assert_eq!(state.update(true, both), Some(both));
+        assert_eq!(
+            state.update(true, LOCK_LED_CAPS_LOCK),
+            Some(LOCK_LED_CAPS_LOCK)
+        );
+        // 复合设备晚到的 LED 不会产生多余的变化
+        assert_eq!(state.update(false, both), None);
+        assert_eq!(state.update(false, LOCK_LED_CAPS_LOCK), None);
+
+        // 重新枚举后主机可能只使用复合设备
+        state.reset();
+        assert_eq!(state.update(false, LOCK_LED_CAPS_LOCK), None);
+        assert_eq!(state.update(false, 0), Some(0));
+        assert_eq!(state.current, Some(0));
+    }
+
+    #[test]
+    fn press_from_keeps_order() {
+        let mut saved = Keyboard::default();
+        saved.set_key(usage_id::KEYBOARD_B, true);
+        saved.set_key(usage_id::KEYBOARD_A, true);
+        saved.set_key(usage_id::KEYBOARD_LEFT_SHIFT, true);
+        saved.apple_fn = true;
+        let mut keyboard = Keyboard::default();
+        keyboard.led[0] = 0x2;
+        keyboard.set_key(usage_id::KEYBOARD_C, true);
+        assert!(keyboard.press_from(&saved));
+        assert!(!keyboard.press_from(&saved));
+        assert!(keyboard.apple_fn);
+        assert_eq!(keyboard.led[0], 0x2);
+        assert_eq!(
+            keyboard.get_legacy_payload(),
+            [
+                0x2,
+                0,
+                usage_id::KEYBOARD_C as u8,
+                usage_id::KEYBOARD_B as u8,
+                usage_id::KEYBOARD_A as u8,
+                0,
+                0,
+                0
+            ]
+        );
+    }
+
+    #[test]
+    fn release_all_keeps_led() {
+        let mut keyboard = Keyboard::default();
+        keyboard.led[0] = 0x3;
+        keyboard.set_key(usage_id::KEYBOARD_A, true);
+        assert!(keyboard.release_all());
+        assert!(!keyboard.release_all());
+        assert_eq!(keyboard.led[0], 0x3);
+        assert_eq!(keyboard.get_legacy_payload(), [0; 8]);
+    }
+}
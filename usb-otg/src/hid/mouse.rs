@@ -1,15 +1,13 @@
 use std::sync::Arc;
+use std::time::Duration;
 
 use lazy_static::lazy_static;
 use nix::fcntl;
-use nix::sys::stat::Mode;
-use tokio::io::AsyncWriteExt;
-use tokio::sync::watch::Sender;
 use tokio::sync::Mutex;
 use util::error;
 
-use crate::async_fd::AsyncFd;
 use crate::hid::hid_composite;
+use crate::hid::hidg::Hidg;
 use crate::hid;
 
 lazy_static! {
@@ -65,11 +63,15 @@ lazy_static! {
 #[derive(Default)]
 pub struct Mouse {
     pub button: u8,
+    // 最近一次发送的绝对坐标，释放按键时需要带上，避免光标跳到左上角
+    pub last_x: u16,
+    pub last_y: u16,
 }
 
 impl Mouse {
     pub const ABS_MAX: u16 = 0x7fff;
     pub const REL_MIN: i8 = -127;
+    pub const REL16_MIN: i16 = -32767;
     pub const WHEEL_MIN: i8 = -127;
 
     pub fn clear(&mut self) {
@@ -115,6 +117,19 @@ impl Mouse {
         ret
     }
 
+    pub fn get_relative_payload(&self, x: i16, y: i16, wheel: i8) -> [u8; 6] {
+        let x = x.max(Self::REL16_MIN);
+        let y = y.max(Self::REL16_MIN);
+        let wheel = wheel.max(Self::WHEEL_MIN);
+
+        let mut ret = [0; 6];
+        ret[0] = self.button;
+        ret[1..3].copy_from_slice(&x.to_le_bytes());
+        ret[3..5].copy_from_slice(&y.to_le_bytes());
+        ret[5] = wheel as u8;
+        ret
+    }
+
     pub fn get_legacy_payload(&self, mut x: i8, mut y: i8, mut wheel: i8) -> [u8; 4] {
         if x < Self::REL_MIN {
             x = Self::REL_MIN;
@@ -137,36 +152,34 @@ impl Mouse {
 
 pub struct MouseDevice {
     pub mouse: Mutex<Mouse>,
-    mouse_legacy_dev_write: Mutex<AsyncFd>,
-    hid_composite_dev_send_sender: Arc<Sender<[u8; hid_composite::HID_COMPOSITE_SEND_LENGTH]>>,
+    mouse_legacy_dev_write: Hidg,
+    hid_composite_report_queue: Arc<hid_composite::HidCompositeReportQueue>,
 }
 
 impl MouseDevice {
     pub async fn new(
         mouse_legacy_minor: i32,
-        hid_composite_dev_send_sender: Arc<Sender<[u8; hid_composite::HID_COMPOSITE_SEND_LENGTH]>>,
+        hid_composite_report_queue: Arc<hid_composite::HidCompositeReportQueue>,
     ) -> error::Result<Self> {
-        let mouse_legacy_dev_name = format!("/dev/hidg{mouse_legacy_minor}");
-
-        let mouse_legacy_dev_write = AsyncFd::try_from(
-            fcntl::open(
-                mouse_legacy_dev_name.as_str(),
-                fcntl::OFlag::O_WRONLY,
-                Mode::empty(),
-            )
-            .map_err(|err| error::ErrorKind::io(err.into(), &mouse_legacy_dev_name))?,
-        )
-        .unwrap();
+        let mouse_legacy_dev_write = Hidg::open(mouse_legacy_minor, fcntl::OFlag::O_WRONLY)?;
 
         let ret = Self {
             mouse: Default::default(),
-            mouse_legacy_dev_write: Mutex::new(mouse_legacy_dev_write),
-            hid_composite_dev_send_sender,
+            mouse_legacy_dev_write,
+            hid_composite_report_queue,
         };
 
         Ok(ret)
     }
 
+    pub async fn reopen(&self) -> error::Result<()> {
+        self.mouse_legacy_dev_write.reopen().await
+    }
+
+    pub fn set_report_interval(&self, interval: Duration) {
+        self.mouse_legacy_dev_write.set_write_interval(interval);
+    }
+
     pub async fn set_button(&self, button_id: u16, status: bool) -> bool {
         return self.mouse.lock().await.set_button(button_id, status);
     }
@@ -174,20 +187,40 @@ impl MouseDevice {
     pub async fn send(&self, x: u16, y: u16, wheel: i8) -> error::Result<()> {
         let mut payload = [0_u8; hid_composite::HID_COMPOSITE_SEND_LENGTH];
         payload[0] = hid_composite::HID_REPORT_ID_MOUSE;
-        payload[1..1+6].copy_from_slice(&self.mouse.lock().await.get_payload(x, y, wheel));
+        let mut mouse = self.mouse.lock().await;
+        payload[1..1+6].copy_from_slice(&mouse.get_payload(x, y, wheel));
+        mouse.last_x = x.min(Mouse::ABS_MAX);
+        mouse.last_y = y.min(Mouse::ABS_MAX);
         log::debug!("hid_composite_dev send mouse {payload:?}");
-        self.hid_composite_dev_send_sender.send(payload).unwrap();
-        Ok(())
+        self.hid_composite_report_queue.push(payload).await
+    }
+
+    pub async fn send_relative(&self, x: i16, y: i16, wheel: i8) -> error::Result<()> {
+        let mut payload = [0_u8; hid_composite::HID_COMPOSITE_SEND_LENGTH];
+        payload[0] = hid_composite::HID_REPORT_ID_MOUSE_RELATIVE;
+        let mouse = self.mouse.lock().await;
+        payload[1..1+6].copy_from_slice(&mouse.get_relative_payload(x, y, wheel));
+        log::debug!("hid_composite_dev send relative mouse {payload:?}");
+        self.hid_composite_report_queue.push(payload).await
     }
 
     pub async fn send_legacy(&self, x: i8, y: i8, wheel: i8) -> error::Result<()> {
-        let mut mouse_legacy_dev = self.mouse_legacy_dev_write.lock().await;
-        let payload = self.mouse.lock().await.get_legacy_payload(x, y, wheel);
+        let mouse = self.mouse.lock().await;
+        let payload = mouse.get_legacy_payload(x, y, wheel);
         log::debug!("mouse send_legacy {payload:?}");
-        mouse_legacy_dev
-            .write_all(&payload)
-            .await
-            .map_err(|err| error::ErrorKind::io(err, "mouse_legacy_dev write_all"))?;
+        self.mouse_legacy_dev_write.write_all(&payload).await?;
         Ok(())
     }
+
+    // 松开所有按键，主机把每个鼠标当作独立的设备，所有接口都要发送
+    pub async fn release_buttons(&self) -> error::Result<()> {
+        let (x, y) = {
+            let mut mouse = self.mouse.lock().await;
+            mouse.clear();
+            (mouse.last_x, mouse.last_y)
+        };
+        self.send(x, y, 0).await?;
+        self.send_relative(0, 0, 0).await?;
+        self.send_legacy(0, 0, 0).await
+    }
 }
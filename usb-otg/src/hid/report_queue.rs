@@ -0,0 +1,39 @@
+use tokio::sync::{mpsc, Mutex};
+use util::error;
+
+// 有序且有界的报告队列，队列满时 push 会等待，保证每一次状态变化都能送达主机
+pub struct ReportQueue<const N: usize> {
+    sender: mpsc::Sender<[u8; N]>,
+    receiver: Mutex<mpsc::Receiver<[u8; N]>>,
+}
+
+impl<const N: usize> ReportQueue<N> {
+    pub fn new(capacity: usize) -> Self {
+        let (sender, receiver) = mpsc::channel(capacity);
+        Self {
+            sender,
+            receiver: Mutex::new(receiver),
+        }
+    }
+
+    pub async fn push(&self, report: [u8; N]) -> error::Result<()> {
+        self.sender
+            .send(report)
+            .await
+            .map_err(|_| error::ErrorKind::custom("Report queue closed".into()))?;
+        Ok(())
+    }
+
+    // 同一时间只应该有一个写入任务调用
+    pub async fn pop(&self) -> Option<[u8; N]> {
+        self.receiver.lock().await.recv().await
+    }
+
+    pub fn len(&self) -> usize {
+        self.sender.max_capacity() - self.sender.capacity()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
@@ -0,0 +1,189 @@
+use crate::hid::hid_composite::{
+    HID_COMPOSITE_RECV_LENGTH, HID_COMPOSITE_SEND_LENGTH, HID_REPORT_ID_VENDOR,
+};
+
+// 每个报告：report id, flags|seq, len, data
+const VENDOR_HEADER_LENGTH: usize = 3;
+pub const VENDOR_RECV_CHUNK_LENGTH: usize = HID_COMPOSITE_RECV_LENGTH - VENDOR_HEADER_LENGTH;
+pub const VENDOR_SEND_CHUNK_LENGTH: usize = HID_COMPOSITE_SEND_LENGTH - VENDOR_HEADER_LENGTH;
+pub const VENDOR_MESSAGE_MAX_LENGTH: usize = 64 * 1024;
+
+const VENDOR_FLAG_START: u8 = 0x80;
+const VENDOR_FLAG_END: u8 = 0x40;
+const VENDOR_SEQ_MASK: u8 = 0x3f;
+
+// 一条消息的第一个字节是消息类型
+pub struct VendorMessage {
+    pub msg_type: u8,
+    pub data: Vec<u8>,
+}
+
+// 把主机发来的多个报告拼成消息，丢包或乱序时丢弃整条消息
+#[derive(Default)]
+pub struct VendorDecoder {
+    buf: Option<Vec<u8>>,
+    seq: u8,
+}
+
+impl VendorDecoder {
+    pub fn push(&mut self, report: &[u8; HID_COMPOSITE_RECV_LENGTH]) -> Option<VendorMessage> {
+        let flags = report[1];
+        let len = report[2] as usize;
+        if len > VENDOR_RECV_CHUNK_LENGTH {
+            log::warn!("vendor report invalid length: {len}");
+            self.buf = None;
+            return None;
+        }
+        let chunk = &report[VENDOR_HEADER_LENGTH..VENDOR_HEADER_LENGTH + len];
+        let seq = flags & VENDOR_SEQ_MASK;
+
+        if flags & VENDOR_FLAG_START != 0 {
+            if self.buf.is_some() {
+                log::warn!("vendor message interrupted by a new message");
+            }
+            self.buf = Some(Vec::new());
+            self.seq = 0;
+        }
+        let Some(buf) = &mut self.buf else {
+            log::warn!("vendor report without start, ignore");
+            return None;
+        };
+        if seq != self.seq {
+            log::warn!("vendor report seq mismatch: expect {} got {seq}", self.seq);
+            self.buf = None;
+            return None;
+        }
+        if buf.len() + chunk.len() > VENDOR_MESSAGE_MAX_LENGTH {
+            log::warn!("vendor message too long, ignore");
+            self.buf = None;
+            return None;
+        }
+        buf.extend_from_slice(chunk);
+        self.seq = (self.seq + 1) & VENDOR_SEQ_MASK;
+
+        if flags & VENDOR_FLAG_END == 0 {
+            return None;
+        }
+        let mut data = self.buf.take().unwrap();
+        if data.is_empty() {
+            log::warn!("vendor message without type, ignore");
+            return None;
+        }
+        let msg_type = data.remove(0);
+        Some(VendorMessage { msg_type, data })
+    }
+}
+
+// 编码成可以直接放进复合设备队列的报告
+pub fn encode(msg_type: u8, data: &[u8]) -> Vec<[u8; HID_COMPOSITE_SEND_LENGTH]> {
+    let message = [&[msg_type], data].concat();
+    let chunk_count = message.len().div_ceil(VENDOR_SEND_CHUNK_LENGTH);
+    message
+        .chunks(VENDOR_SEND_CHUNK_LENGTH)
+        .enumerate()
+        .map(|(i, chunk)| {
+            let mut flags = i as u8 & VENDOR_SEQ_MASK;
+            if i == 0 {
+                flags |= VENDOR_FLAG_START;
+            }
+            if i == chunk_count - 1 {
+                flags |= VENDOR_FLAG_END;
+            }
+            let mut report = [0_u8; HID_COMPOSITE_SEND_LENGTH];
+            report[0] = HID_REPORT_ID_VENDOR;
+            report[1] = flags;
+            report[2] = chunk.len() as u8;
+            report[VENDOR_HEADER_LENGTH..VENDOR_HEADER_LENGTH + chunk.len()].copy_from_slice(chunk);
+            report
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 按主机端 agent 的方式把消息切成输出报告
+    fn host_reports(message: &[u8]) -> Vec<[u8; HID_COMPOSITE_RECV_LENGTH]> {
+        let chunks: Vec<_> = message.chunks(VENDOR_RECV_CHUNK_LENGTH).collect();
+        chunks
+            .iter()
+            .enumerate()
+            .map(|(i, chunk)| {
+                let mut report = [0_u8; HID_COMPOSITE_RECV_LENGTH];
+                report[0] = HID_REPORT_ID_VENDOR;
+                report[1] = i as u8 & VENDOR_SEQ_MASK;
+                if i == 0 {
+                    report[1] |= VENDOR_FLAG_START;
+                }
+                if i == chunks.len() - 1 {
+                    report[1] |= VENDOR_FLAG_END;
+                }
+                report[2] = chunk.len() as u8;
+                report[VENDOR_HEADER_LENGTH..VENDOR_HEADER_LENGTH + chunk.len()]
+                    .copy_from_slice(chunk);
+                report
+            })
+            .collect()
+    }
+
+    #[test]
+    fn encode_single_report() {
+        let reports = encode(1, b"hi");
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0][0], HID_REPORT_ID_VENDOR);
+        assert_eq!(reports[0][1], VENDOR_FLAG_START | VENDOR_FLAG_END);
+        assert_eq!(reports[0][2], 3);
+        assert_eq!(&reports[0][3..6], &[1, b'h', b'i']);
+    }
+
+    #[test]
+    fn encode_wraps_seq() {
+        let data = vec![0xaa; VENDOR_SEND_CHUNK_LENGTH * 70 - 1];
+        let reports = encode(1, &data);
+        assert_eq!(reports.len(), 70);
+        assert_eq!(reports[0][1], VENDOR_FLAG_START);
+        assert_eq!(reports[63][1], 63);
+        assert_eq!(reports[64][1], 0);
+        assert_eq!(reports[69][1], VENDOR_FLAG_END | 5);
+        assert!(reports
+            .iter()
+            .all(|report| report[2] as usize == VENDOR_SEND_CHUNK_LENGTH));
+    }
+
+    #[test]
+    fn decode_wraps_seq() {
+        let mut message = vec![2];
+        message.extend((0..VENDOR_RECV_CHUNK_LENGTH * 70).map(|i| i as u8));
+        let mut decoder = VendorDecoder::default();
+        let mut decoded = None;
+        for report in host_reports(&message) {
+            assert!(decoded.is_none());
+            decoded = decoder.push(&report);
+        }
+        let decoded = decoded.unwrap();
+        assert_eq!(decoded.msg_type, 2);
+        assert_eq!(decoded.data, &message[1..]);
+    }
+
+    #[test]
+    fn decode_drops_broken_message() {
+        let message = vec![3; VENDOR_RECV_CHUNK_LENGTH * 3];
+        let reports = host_reports(&message);
+        let mut decoder = VendorDecoder::default();
+        // 丢了中间的报告
+        assert!(decoder.push(&reports[0]).is_none());
+        assert!(decoder.push(&reports[2]).is_none());
+        // 没有开始标志的报告被忽略，之后的完整消息仍然可以解码
+        assert!(decoder.push(&reports[1]).is_none());
+        let mut decoded = None;
+        for report in &reports {
+            decoded = decoder.push(report);
+        }
+        assert_eq!(decoded.unwrap().data.len(), message.len() - 1);
+
+        let mut report = reports[0];
+        report[2] = VENDOR_RECV_CHUNK_LENGTH as u8 + 1;
+        assert!(decoder.push(&report).is_none());
+    }
+}
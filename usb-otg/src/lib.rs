@@ -9,8 +9,10 @@ use crate::hid::FunctionHidOpts;
 use crate::mass_storage::FunctionMsgOpts;
 
 pub mod async_fd;
+pub mod ffs;
 pub mod hid;
 pub mod mass_storage;
+pub mod udc;
 
 pub enum UsbDeviceSpeed {
     // enumerating
@@ -154,7 +156,10 @@ impl Configurable for GadgetInfo {
                 .1
                 .apply_config(&strings_base_dir.join(format!("{:#x}", entry.0)))?;
         }
-        fs::write(base_dir.join("UDC"), &self.udc)?;
+        // 为空时不绑定，functionfs 需要先写入描述符
+        if !self.udc.trim().is_empty() {
+            fs::write(base_dir.join("UDC"), &self.udc)?;
+        }
         Ok(())
     }
 
@@ -170,10 +175,7 @@ impl Configurable for GadgetInfo {
         if !base_dir.is_dir() {
             return Ok(());
         }
-        let udc_path = base_dir.join("UDC");
-        if fs::read(&udc_path)? != vec![0xa_u8] {
-            fs::write(udc_path, "\n")?;
-        }
+        GadgetInfo::unbind_udc(base_dir)?;
         for entry in fs::read_dir(base_dir.join("configs"))? {
             let entry = entry.map_err(|err| error::ErrorKind::io(err, "configs"))?;
             let path = entry.path();
@@ -213,6 +215,18 @@ impl Configurable for GadgetInfo {
 impl GadgetInfo {
     pub const HID: &'static str = "hid";
     pub const MASS_STORAGE: &'static str = "mass_storage";
+
+    pub fn bind_udc<P: AsRef<Path>>(base_dir: P, udc: &str) -> error::Result<()> {
+        fs::write(base_dir.as_ref().join("UDC"), udc)
+    }
+
+    pub fn unbind_udc<P: AsRef<Path>>(base_dir: P) -> error::Result<()> {
+        let udc_path = base_dir.as_ref().join("UDC");
+        if fs::read(&udc_path)? != vec![0xa_u8] {
+            fs::write(udc_path, "\n")?;
+        }
+        Ok(())
+    }
 }
 
 pub struct UsbConfiguration {
@@ -0,0 +1,96 @@
+use std::fmt;
+
+use util::{error, fs};
+
+use crate::UsbDeviceSpeed;
+
+pub const UDC_PATH: &str = "/sys/class/udc";
+
+// 与内核 usb_state_string 的取值一一对应
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UdcState {
+    NotAttached,
+    Attached,
+    Powered,
+    Reconnecting,
+    Unauthenticated,
+    Default,
+    Addressed,
+    Configured,
+    Suspended,
+    Unknown,
+}
+
+impl UdcState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::NotAttached => "not attached",
+            Self::Attached => "attached",
+            Self::Powered => "powered",
+            Self::Reconnecting => "reconnecting",
+            Self::Unauthenticated => "unauthenticated",
+            Self::Default => "default",
+            Self::Addressed => "addressed",
+            Self::Configured => "configured",
+            Self::Suspended => "suspended",
+            Self::Unknown => "UNKNOWN",
+        }
+    }
+
+    pub fn parse(s: &str) -> Self {
+        match s.trim() {
+            "not attached" => Self::NotAttached,
+            "attached" => Self::Attached,
+            "powered" => Self::Powered,
+            "reconnecting" => Self::Reconnecting,
+            "unauthenticated" => Self::Unauthenticated,
+            "default" => Self::Default,
+            "addressed" => Self::Addressed,
+            "configured" => Self::Configured,
+            "suspended" => Self::Suspended,
+            _ => Self::Unknown,
+        }
+    }
+
+    // 只有 configured 时主机才完成了枚举，hid 报告才能送达
+    pub fn is_configured(&self) -> bool {
+        *self == Self::Configured
+    }
+}
+
+impl fmt::Display for UdcState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+pub fn read_state(udc_name: &str) -> error::Result<UdcState> {
+    let state = fs::read_to_string(format!("{UDC_PATH}/{udc_name}/state"))?;
+    Ok(UdcState::parse(&state))
+}
+
+// 按名称排序，保证默认选择的 UDC 是确定的
+pub fn list() -> error::Result<Vec<String>> {
+    let mut udc_names = Vec::new();
+    for entry in fs::read_dir(UDC_PATH)? {
+        let entry = entry.map_err(|err| error::ErrorKind::io(err, UDC_PATH))?;
+        if let Some(udc_name) = entry.file_name().to_str() {
+            udc_names.push(udc_name.to_string());
+        }
+    }
+    udc_names.sort();
+    Ok(udc_names)
+}
+
+pub fn read_speed(udc_name: &str) -> error::Result<UsbDeviceSpeed> {
+    let speed = fs::read_to_string(format!("{UDC_PATH}/{udc_name}/current_speed"))?;
+    Ok(match speed.trim() {
+        "low-speed" => UsbDeviceSpeed::UsbSpeedLow,
+        "full-speed" => UsbDeviceSpeed::UsbSpeedFull,
+        "high-speed" => UsbDeviceSpeed::UsbSpeedHigh,
+        "wireless" => UsbDeviceSpeed::UsbSpeedWireless,
+        "super-speed" => UsbDeviceSpeed::UsbSpeedSuper,
+        "super-speed-plus" => UsbDeviceSpeed::UsbSpeedSuperPlus,
+        _ => UsbDeviceSpeed::UsbSpeedUnknown,
+    })
+}